@@ -0,0 +1,655 @@
+// Copyright 2016 Jeremy Letang.
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Apache Arrow IPC stream encoding of the responses.
+//!
+//! The stream starts with the schema of the selected columns, followed by
+//! a record batch every `BATCH_SIZE` rows. The messages metadata are
+//! flatbuffers, written by the small builder of this module.
+
+use cvt::Cell;
+use postgres::types::Type;
+use schema::Column;
+use serde_json;
+use std::io::{self, Write};
+use std::mem;
+use stream::RowWriter;
+
+/// Number of rows of the record batches.
+pub const BATCH_SIZE: usize = 1024;
+
+// metadata version V5
+const METADATA_VERSION: i16 = 4;
+
+// message headers
+const HEADER_SCHEMA: u8 = 1;
+const HEADER_RECORD_BATCH: u8 = 3;
+
+// types
+const TYPE_INT: u8 = 2;
+const TYPE_FLOATING_POINT: u8 = 3;
+const TYPE_BINARY: u8 = 4;
+const TYPE_UTF8: u8 = 5;
+const TYPE_BOOL: u8 = 6;
+const TYPE_TIMESTAMP: u8 = 10;
+
+const PRECISION_SINGLE: i16 = 1;
+const PRECISION_DOUBLE: i16 = 2;
+const UNIT_MICROSECOND: i16 = 2;
+
+// marks the start of a message, an empty message ends the stream
+const CONTINUATION: u32 = 0xffff_ffff;
+
+/// Arrow type of a column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArrowType {
+    Bool,
+    // signed integer of the given bit width
+    Int(u8),
+    // float of the given bit width
+    Float(u8),
+    Utf8,
+    Binary,
+    // microseconds since the epoch, in UTC if set
+    Timestamp(bool),
+}
+
+impl ArrowType {
+    /// The Arrow type of a postgres type, the unsupported types are written
+    /// as strings.
+    pub fn of(ty: &Type) -> ArrowType {
+        match *ty {
+            Type::Bool => ArrowType::Bool,
+            Type::Char => ArrowType::Int(8),
+            Type::Int2 => ArrowType::Int(16),
+            Type::Int4 => ArrowType::Int(32),
+            Type::Int8 => ArrowType::Int(64),
+            Type::Float4 => ArrowType::Float(32),
+            Type::Float8 => ArrowType::Float(64),
+            Type::Bytea => ArrowType::Binary,
+            Type::Timestamp => ArrowType::Timestamp(false),
+            Type::TimestampTZ => ArrowType::Timestamp(true),
+            _ => ArrowType::Utf8,
+        }
+    }
+
+    // the type union of a field, its discriminant and its table
+    fn to_flatbuffer(&self) -> (u8, Node) {
+        match *self {
+            ArrowType::Bool => (TYPE_BOOL, Node::Table(vec![])),
+            ArrowType::Int(bits) =>
+                (TYPE_INT, Node::Table(vec![(0, Field::I32(bits as i32)), (1, Field::U8(1))])),
+            ArrowType::Float(bits) => {
+                let precision = if bits == 32 { PRECISION_SINGLE } else { PRECISION_DOUBLE };
+                (TYPE_FLOATING_POINT, Node::Table(vec![(0, Field::I16(precision))]))
+            },
+            ArrowType::Utf8 => (TYPE_UTF8, Node::Table(vec![])),
+            ArrowType::Binary => (TYPE_BINARY, Node::Table(vec![])),
+            ArrowType::Timestamp(utc) => {
+                let mut fields = vec![(0, Field::I16(UNIT_MICROSECOND))];
+                if utc {
+                    fields.push((1, Field::Offset(Node::Str("UTC".to_string()))));
+                }
+                (TYPE_TIMESTAMP, Node::Table(fields))
+            },
+        }
+    }
+}
+
+/// Write the rows as an Arrow IPC stream, the schema is derived from the
+/// types and the nullability of the columns.
+pub struct ArrowRowWriter<W> {
+    out: W,
+    columns: Vec<ColumnBuilder>,
+    rows: usize,
+}
+
+impl<W: Write> ArrowRowWriter<W> {
+    pub fn new(out: W) -> ArrowRowWriter<W> {
+        ArrowRowWriter {
+            out: out,
+            columns: vec![],
+            rows: 0,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.out
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn write_batch(&mut self) -> io::Result<()> {
+        let mut body = vec![];
+        let mut nodes = vec![];
+        let mut buffers = vec![];
+        for c in &mut self.columns {
+            nodes.push((self.rows as i64, c.null_count as i64));
+            for b in c.buffers() {
+                buffers.push((body.len() as i64, b.len() as i64));
+                body.extend_from_slice(b);
+                pad(&mut body, 8);
+            }
+            c.clear();
+        }
+        let batch = Node::Table(vec![
+            (0, Field::I64(self.rows as i64)),
+            (1, Field::Offset(Node::Pairs(nodes))),
+            (2, Field::Offset(Node::Pairs(buffers))),
+        ]);
+        self.rows = 0;
+        write_message(&mut self.out, HEADER_RECORD_BATCH, batch, &*body)
+    }
+}
+
+impl<W: Write> RowWriter for ArrowRowWriter<W> {
+    fn begin(&mut self, columns: &[&Column]) -> io::Result<()> {
+        self.columns = columns.iter()
+            .map(|c| ColumnBuilder::new(ArrowType::of(&c.data_type)))
+            .collect();
+        let fields = columns.iter().zip(self.columns.iter())
+            .map(|(c, b)| {
+                let (type_type, ty) = b.ty.to_flatbuffer();
+                Node::Table(vec![
+                    (0, Field::Offset(Node::Str(c.name.clone()))),
+                    (1, Field::U8(c.is_nullable as u8)),
+                    (2, Field::U8(type_type)),
+                    (3, Field::Offset(ty)),
+                    (5, Field::Offset(Node::Tables(vec![]))),
+                ])
+            })
+            .collect();
+        let schema = Node::Table(vec![(1, Field::Offset(Node::Tables(fields)))]);
+        write_message(&mut self.out, HEADER_SCHEMA, schema, &[])
+    }
+
+    fn row(&mut self, values: Vec<Cell>) -> io::Result<()> {
+        for (b, v) in self.columns.iter_mut().zip(values.into_iter()) {
+            b.push(v);
+        }
+        self.rows += 1;
+        if self.rows == BATCH_SIZE {
+            self.write_batch()?;
+            self.out.flush()?;
+        }
+        Ok(())
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        if self.rows > 0 {
+            self.write_batch()?;
+        }
+        write_u32(&mut self.out, CONTINUATION)?;
+        write_u32(&mut self.out, 0)?;
+        self.out.flush()
+    }
+}
+
+// the buffers of a column for the current batch
+struct ColumnBuilder {
+    ty: ArrowType,
+    validity: Vec<u8>,
+    null_count: usize,
+    len: usize,
+    // offsets of the variable length values
+    offsets: Vec<u8>,
+    values: Vec<u8>,
+}
+
+impl ColumnBuilder {
+    fn new(ty: ArrowType) -> ColumnBuilder {
+        let mut b = ColumnBuilder {
+            ty: ty,
+            validity: vec![],
+            null_count: 0,
+            len: 0,
+            offsets: vec![],
+            values: vec![],
+        };
+        b.clear();
+        b
+    }
+
+    fn clear(&mut self) {
+        self.validity.clear();
+        self.null_count = 0;
+        self.len = 0;
+        self.offsets.clear();
+        self.values.clear();
+        if self.is_variable() {
+            self.offsets.extend_from_slice(&le_bytes(0, 4));
+        }
+    }
+
+    fn is_variable(&self) -> bool {
+        self.ty == ArrowType::Utf8 || self.ty == ArrowType::Binary
+    }
+
+    fn push(&mut self, cell: Cell) {
+        let i = self.len;
+        self.len += 1;
+        if i % 8 == 0 {
+            self.validity.push(0);
+            if self.ty == ArrowType::Bool {
+                self.values.push(0);
+            }
+        }
+        let valid = match (self.ty, cell) {
+            (_, Cell::Null) => {
+                self.push_null();
+                false
+            },
+            (ArrowType::Bool, Cell::Bool(b)) => {
+                if b {
+                    self.values[i / 8] |= 1 << (i % 8);
+                }
+                true
+            },
+            (ArrowType::Int(bits), Cell::Int(v)) => {
+                self.values.extend_from_slice(&le_bytes(v as u64, bits as usize / 8));
+                true
+            },
+            (ArrowType::Float(32), Cell::Float(f)) => {
+                let bits = unsafe { mem::transmute::<f32, u32>(f as f32) };
+                self.values.extend_from_slice(&le_bytes(bits as u64, 4));
+                true
+            },
+            (ArrowType::Float(_), Cell::Float(f)) => {
+                let bits = unsafe { mem::transmute::<f64, u64>(f) };
+                self.values.extend_from_slice(&le_bytes(bits, 8));
+                true
+            },
+            (ArrowType::Timestamp(_), Cell::Timestamp(t)) => {
+                self.values.extend_from_slice(&le_bytes(t as u64, 8));
+                true
+            },
+            (ArrowType::Utf8, Cell::Text(s)) => {
+                self.push_variable(s.as_bytes());
+                true
+            },
+            // json and arrays are written as json text
+            (ArrowType::Utf8, Cell::Json(v)) => {
+                self.push_variable(serde_json::to_string(&v).unwrap_or_default().as_bytes());
+                true
+            },
+            (ArrowType::Binary, Cell::Bytes(b)) => {
+                self.push_variable(&*b);
+                true
+            },
+            // a value not matching the type of the column, written as null
+            _ => {
+                self.push_null();
+                false
+            },
+        };
+        if valid {
+            self.validity[i / 8] |= 1 << (i % 8);
+        } else {
+            self.null_count += 1;
+        }
+    }
+
+    // an empty value, keeping the values of the column aligned
+    fn push_null(&mut self) {
+        match self.ty {
+            ArrowType::Bool => {},
+            ArrowType::Int(bits) | ArrowType::Float(bits) =>
+                self.values.extend_from_slice(&le_bytes(0, bits as usize / 8)),
+            ArrowType::Timestamp(_) => self.values.extend_from_slice(&le_bytes(0, 8)),
+            ArrowType::Utf8 | ArrowType::Binary => self.push_variable(&[]),
+        }
+    }
+
+    fn push_variable(&mut self, b: &[u8]) {
+        self.values.extend_from_slice(b);
+        let end = self.values.len() as u64;
+        self.offsets.extend_from_slice(&le_bytes(end, 4));
+    }
+
+    // the buffers of the column in the order of the format, the validity
+    // bitmap is omitted when there are no nulls
+    fn buffers(&self) -> Vec<&[u8]> {
+        let validity: &[u8] = if self.null_count == 0 { &[] } else { &*self.validity };
+        if self.is_variable() {
+            vec![validity, &*self.offsets, &*self.values]
+        } else {
+            vec![validity, &*self.values]
+        }
+    }
+}
+
+// write an encapsulated message: the continuation marker, the length of the
+// metadata, the metadata then the body
+fn write_message<W: Write>(out: &mut W, header_type: u8, header: Node, body: &[u8])
+                           -> io::Result<()> {
+    let message = Node::Table(vec![
+        (0, Field::I16(METADATA_VERSION)),
+        (1, Field::U8(header_type)),
+        (2, Field::Offset(header)),
+        (3, Field::I64(body.len() as i64)),
+    ]);
+    let mut metadata = Builder::finish(&message);
+    pad(&mut metadata, 8);
+    write_u32(out, CONTINUATION)?;
+    write_u32(out, metadata.len() as u32)?;
+    out.write_all(&*metadata)?;
+    out.write_all(body)
+}
+
+fn write_u32<W: Write>(out: &mut W, v: u32) -> io::Result<()> {
+    out.write_all(&le_bytes(v as u64, 4))
+}
+
+fn pad(buf: &mut Vec<u8>, align: usize) {
+    while buf.len() % align != 0 {
+        buf.push(0);
+    }
+}
+
+// the `n` low bytes of v, little endian
+fn le_bytes(v: u64, n: usize) -> Vec<u8> {
+    (0..n).map(|i| (v >> (i * 8)) as u8).collect()
+}
+
+// a scalar or offset field of a flatbuffer table
+enum Field {
+    U8(u8),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    Offset(Node),
+}
+
+impl Field {
+    fn size(&self) -> usize {
+        match *self {
+            Field::U8(_) => 1,
+            Field::I16(_) => 2,
+            Field::I32(_) | Field::Offset(_) => 4,
+            Field::I64(_) => 8,
+        }
+    }
+}
+
+// an object referenced by an offset
+enum Node {
+    // the fields and their ids
+    Table(Vec<(u16, Field)>),
+    Str(String),
+    Tables(Vec<Node>),
+    // vector of structs made of two longs, i.e FieldNode and Buffer
+    Pairs(Vec<(i64, i64)>),
+}
+
+// The buffer is written front to back: the objects are written after the
+// offsets referencing them, which are patched once their position is known.
+struct Builder {
+    buf: Vec<u8>,
+}
+
+impl Builder {
+    fn finish(root: &Node) -> Vec<u8> {
+        let mut b = Builder { buf: vec![0; 4] };
+        let pos = b.node(root);
+        b.patch(0, pos);
+        b.buf
+    }
+
+    // pad so that `extra` bytes later the buffer is aligned
+    fn align(&mut self, align: usize, extra: usize) {
+        while (self.buf.len() + extra) % align != 0 {
+            self.buf.push(0);
+        }
+    }
+
+    fn put(&mut self, v: u64, n: usize) {
+        self.buf.extend_from_slice(&le_bytes(v, n));
+    }
+
+    // set the offset at `slot` to reference `pos`
+    fn patch(&mut self, slot: usize, pos: usize) {
+        let b = le_bytes((pos - slot) as u64, 4);
+        self.buf[slot..slot + 4].copy_from_slice(&*b);
+    }
+
+    // write a node, returns its position
+    fn node(&mut self, node: &Node) -> usize {
+        match *node {
+            Node::Table(ref fields) => self.table(fields),
+            Node::Str(ref s) => {
+                self.align(4, 0);
+                let pos = self.buf.len();
+                self.put(s.len() as u64, 4);
+                self.buf.extend_from_slice(s.as_bytes());
+                self.buf.push(0);
+                pos
+            },
+            Node::Tables(ref nodes) => {
+                self.align(4, 0);
+                let pos = self.buf.len();
+                self.put(nodes.len() as u64, 4);
+                let slots = nodes.iter()
+                    .map(|_| {
+                        self.put(0, 4);
+                        self.buf.len() - 4
+                    })
+                    .collect::<Vec<usize>>();
+                for (slot, n) in slots.into_iter().zip(nodes.iter()) {
+                    let p = self.node(n);
+                    self.patch(slot, p);
+                }
+                pos
+            },
+            Node::Pairs(ref pairs) => {
+                // the structs are aligned on 8 bytes, after the length
+                self.align(8, 4);
+                let pos = self.buf.len();
+                self.put(pairs.len() as u64, 4);
+                for &(a, b) in pairs {
+                    self.put(a as u64, 8);
+                    self.put(b as u64, 8);
+                }
+                pos
+            },
+        }
+    }
+
+    // the vtable is written before the table, which starts with the offset
+    // back to it, followed by the fields by decreasing size so they are
+    // aligned
+    fn table(&mut self, fields: &[(u16, Field)]) -> usize {
+        let mut order = (0..fields.len()).collect::<Vec<usize>>();
+        order.sort_by(|a, b| fields[*b].1.size().cmp(&fields[*a].1.size()));
+        let mut offsets = vec![0usize; fields.len()];
+        let mut size = 4;
+        for i in order {
+            let s = fields[i].1.size();
+            size = (size + s - 1) / s * s;
+            offsets[i] = size;
+            size += s;
+        }
+        let count = fields.iter().map(|&(id, _)| id as usize + 1).max().unwrap_or(0);
+        let vtable_size = 4 + 2 * count;
+        self.align(8, vtable_size);
+        let vtable = self.buf.len();
+        self.put(vtable_size as u64, 2);
+        self.put(size as u64, 2);
+        for id in 0..count {
+            let off = fields.iter()
+                .position(|&(i, _)| i as usize == id)
+                .map_or(0, |i| offsets[i]);
+            self.put(off as u64, 2);
+        }
+        let pos = self.buf.len();
+        self.buf.resize(pos + size, 0);
+        self.buf[pos..pos + 4].copy_from_slice(&*le_bytes((pos - vtable) as u64, 4));
+        let mut children = vec![];
+        for (&(_, ref f), off) in fields.iter().zip(offsets.into_iter()) {
+            let at = pos + off;
+            let b = match *f {
+                Field::U8(v) => le_bytes(v as u64, 1),
+                Field::I16(v) => le_bytes(v as u64, 2),
+                Field::I32(v) => le_bytes(v as u64, 4),
+                Field::I64(v) => le_bytes(v as u64, 8),
+                Field::Offset(ref n) => {
+                    children.push((at, n));
+                    continue;
+                },
+            };
+            self.buf[at..at + b.len()].copy_from_slice(&*b);
+        }
+        for (slot, n) in children {
+            let p = self.node(n);
+            self.patch(slot, p);
+        }
+        pos
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(name: &str, data_type: Type, is_nullable: bool) -> Column {
+        let mut c = Column::new(name, data_type);
+        c.is_nullable = is_nullable;
+        c
+    }
+
+    fn columns() -> Vec<Column> {
+        vec![column("id", Type::Int8, false), column("flag", Type::Bool, true),
+             column("name", Type::Text, true), column("data", Type::Bytea, true),
+             column("at", Type::TimestampTZ, true)]
+    }
+
+    fn rows() -> Vec<Vec<Cell>> {
+        vec![
+            vec![Cell::Int(1), Cell::Bool(true), Cell::Text("a".into()),
+                 Cell::Bytes(vec![1, 2]), Cell::Timestamp(1_500_000_000_000_000)],
+            vec![Cell::Int(2), Cell::Null, Cell::Null, Cell::Null, Cell::Null],
+            vec![Cell::Int(3), Cell::Bool(false), Cell::Text("ccc".into()), Cell::Bytes(vec![]),
+                 Cell::Timestamp(-1)],
+        ]
+    }
+
+    fn write(rows: Vec<Vec<Cell>>) -> Vec<u8> {
+        let columns = columns();
+        let columns = columns.iter().collect::<Vec<&Column>>();
+        let mut w = ArrowRowWriter::new(vec![]);
+        w.begin(&*columns).unwrap();
+        for r in rows {
+            w.row(r).unwrap();
+        }
+        w.end().unwrap();
+        w.into_inner()
+    }
+
+    fn hex(b: &[u8]) -> String {
+        b.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // the messages written for `columns()` and `rows()`, they were checked
+    // by reading them back with the IPC stream reader of arrow-rs
+    const SCHEMA: &'static str = concat!(
+        "ffffffffa8010000100000000c00170014001600100008000c00000000000000",
+        "0000000000000000100000000400010008000800000004000800000004000000",
+        "050000002400000068000000a4000000e00000001c0100001000120004001000",
+        "1100080000000c00100000001000000020000000280000000002000002000000",
+        "6964000000000000080009000400080008000000400000000100000000000000",
+        "10001200040010001100080000000c0010000000100000002000000020000000",
+        "0106000004000000666c61670000000000000000040004000400000000000000",
+        "10001200040010001100080000000c0010000000100000002000000020000000",
+        "01050000040000006e616d650000000000000000040004000400000000000000",
+        "10001200040010001100080000000c0010000000100000002000000020000000",
+        "0104000004000000646174610000000000000000040004000400000000000000",
+        "10001200040010001100080000000c0010000000100000002000000030000000",
+        "010a000002000000617400000000000008000a00080004000800000008000000",
+        "02000000030000005554430000000000",
+    );
+
+    const BATCH: &'static str = concat!(
+        "ffffffff70010000100000000c00170014001600100008000c00000000000000",
+        "880000000000000018000000040003000000000000000a001800080010001400",
+        "0a0000000000000003000000000000000c000000600000000000000005000000",
+        "0300000000000000000000000000000003000000000000000100000000000000",
+        "0300000000000000010000000000000003000000000000000100000000000000",
+        "03000000000000000100000000000000000000000c0000000000000000000000",
+        "0000000000000000000000000000000018000000000000001800000000000000",
+        "0100000000000000200000000000000001000000000000002800000000000000",
+        "0100000000000000300000000000000010000000000000004000000000000000",
+        "0400000000000000480000000000000001000000000000005000000000000000",
+        "1000000000000000600000000000000002000000000000006800000000000000",
+        "0100000000000000700000000000000018000000000000000100000000000000",
+        "0200000000000000030000000000000005000000000000000100000000000000",
+        "0500000000000000000000000100000001000000040000006163636300000000",
+        "0500000000000000000000000200000002000000020000000102000000000000",
+        "050000000000000000c029f73d5405000000000000000000ffffffffffffffff",
+    );
+
+    const END_OF_STREAM: &'static str = "ffffffff00000000";
+
+    #[test]
+    fn schema_message() {
+        assert_eq!(hex(&*write(vec![])), format!("{}{}", SCHEMA, END_OF_STREAM));
+    }
+
+    #[test]
+    fn record_batch() {
+        assert_eq!(hex(&*write(rows())), format!("{}{}{}", SCHEMA, BATCH, END_OF_STREAM));
+    }
+
+    #[test]
+    fn messages_are_aligned() {
+        let out = write(rows());
+        let metadata_len = |pos: usize| {
+            assert_eq!(&out[pos..pos + 4], &[0xff; 4]);
+            out[pos + 4..pos + 8].iter().rev().fold(0, |acc, b| (acc << 8) | *b as usize)
+        };
+        // the schema has no body
+        let schema = metadata_len(0);
+        assert_eq!(schema % 8, 0);
+        let batch = metadata_len(8 + schema);
+        assert_eq!(batch % 8, 0);
+        let body = out.len() - 8 - (16 + schema + batch);
+        assert_eq!(body % 8, 0);
+        assert_eq!(metadata_len(out.len() - 8), 0);
+    }
+
+    #[test]
+    fn batches_of_batch_size_rows() {
+        let columns = columns();
+        let columns = columns.iter().collect::<Vec<&Column>>();
+        let mut w = ArrowRowWriter::new(vec![]);
+        w.begin(&*columns).unwrap();
+        let schema_len = w.get_mut().len();
+        for _ in 0..BATCH_SIZE - 1 {
+            w.row(rows().remove(1)).unwrap();
+        }
+        assert_eq!(w.get_mut().len(), schema_len);
+        // the batch is written once it is full
+        w.row(rows().remove(1)).unwrap();
+        let batch_len = w.get_mut().len();
+        assert!(batch_len > schema_len);
+        w.row(rows().remove(0)).unwrap();
+        assert_eq!(w.get_mut().len(), batch_len);
+        w.end().unwrap();
+        let out = w.into_inner();
+        assert!(out.len() > batch_len + 8);
+        assert_eq!(hex(&out[out.len() - 8..]), END_OF_STREAM);
+    }
+
+    #[test]
+    fn arrow_types() {
+        assert_eq!(ArrowType::of(&Type::Int2), ArrowType::Int(16));
+        assert_eq!(ArrowType::of(&Type::Float4), ArrowType::Float(32));
+        assert_eq!(ArrowType::of(&Type::Timestamp), ArrowType::Timestamp(false));
+        assert_eq!(ArrowType::of(&Type::TimestampTZ), ArrowType::Timestamp(true));
+        assert_eq!(ArrowType::of(&Type::Varchar), ArrowType::Utf8);
+    }
+}
@@ -0,0 +1,507 @@
+// Copyright 2016 Jeremy Letang.
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use config::AuthConfig;
+use context::Context;
+use error::Error;
+use openssl::bn::BigNum;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::sign::{Signer, Verifier};
+use serde_json::{self, Value};
+use serde_json::Map as JsonMap;
+use std::fs::File;
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const HS256: &'static str = "HS256";
+const RS256: &'static str = "RS256";
+
+// keys used to check the signature of the tokens
+enum Keys {
+    // shared secret for HS256
+    Secret(Vec<u8>),
+    // rsa public keys for RS256, with their optional key id
+    Rsa(Vec<(Option<String>, PKey)>),
+}
+
+/// Verify the json web tokens sent by the clients and turn them into the
+/// `Context` the requests are executed with.
+///
+/// Tokens signed with HS256 are checked against a shared secret, tokens
+/// signed with RS256 against a pem public key or the keys of a JWKS file.
+pub struct Authenticator {
+    keys: Keys,
+    role_claim: String,
+    anonymous_role: Option<String>,
+    audience: Option<String>,
+}
+
+impl ::std::fmt::Debug for Authenticator {
+    fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        // do not leak the keys in the logs
+        write!(fmt, "Authenticator {{ role_claim: {:?}, anonymous_role: {:?} }}",
+               self.role_claim, self.anonymous_role)
+    }
+}
+
+impl Authenticator {
+    pub fn from_config(conf: &AuthConfig) -> Result<Authenticator, String> {
+        let keys = match (conf.secret.as_ref(), conf.public_key_file.as_ref(),
+                          conf.jwks_file.as_ref()) {
+            (Some(secret), None, None) => Keys::Secret(secret.as_bytes().to_vec()),
+            (None, Some(path), None) => {
+                let pem = read_file(path)?;
+                match Rsa::public_key_from_pem(pem.as_bytes()).and_then(PKey::from_rsa) {
+                    Ok(k) => Keys::Rsa(vec![(None, k)]),
+                    Err(e) => return Err(format!("invalid public key {}, {}", path, e)),
+                }
+            },
+            (None, None, Some(path)) => Keys::Rsa(read_jwks(&*read_file(path)?)?),
+            _ => return Err(format!("exactly one of the jwt secret, public key file \
+                                     or jwks file must be configured")),
+        };
+        Ok(Authenticator {
+            keys: keys,
+            role_claim: conf.role_claim.clone(),
+            anonymous_role: conf.anonymous_role.clone(),
+            audience: conf.audience.clone(),
+        })
+    }
+
+    /// Build the context of a request from its bearer token. Requests
+    /// without token use the anonymous role if one is configured.
+    pub fn authenticate(&self, token: Option<&str>) -> Result<Context, Error> {
+        let token = match token {
+            Some(t) => t,
+            None => return match self.anonymous_role {
+                Some(ref role) => Ok(Context::with_role(role)),
+                None => Err(Error::Unauthorized("missing authentication token".into())),
+            },
+        };
+
+        let claims = self.verify(token)?;
+        let role = match claims.get(&self.role_claim) {
+            Some(&Value::String(ref r)) => r.clone(),
+            Some(_) => return Err(Error::Unauthorized(
+                format!("claim {} must be a string", self.role_claim))),
+            None => match self.anonymous_role {
+                Some(ref role) => role.clone(),
+                None => return Err(Error::Unauthorized(
+                    format!("missing claim {} in token", self.role_claim))),
+            },
+        };
+        let mut ctx = Context::with_role(&*role);
+        ctx.claims = Some(claims);
+        Ok(ctx)
+    }
+
+    /// Check the signature and the time validity of a token and return its
+    /// claims.
+    pub fn verify(&self, token: &str) -> Result<JsonMap<String, Value>, Error> {
+        let parts = token.split('.').collect::<Vec<&str>>();
+        if parts.len() != 3 {
+            return Err(invalid_token("malformed token"));
+        }
+        let header = decode_json_segment(parts[0])?;
+        let payload = decode_json_segment(parts[1])?;
+        let signature = match base64url_decode(parts[2]) {
+            Some(s) => s,
+            None => return Err(invalid_token("malformed signature")),
+        };
+        // the signature covers the encoded header and payload
+        let signed_len = parts[0].len() + 1 + parts[1].len();
+        let signed = token[..signed_len].as_bytes();
+
+        let alg = header.get("alg").and_then(|a| a.as_str()).unwrap_or("");
+        let kid = header.get("kid").and_then(|k| k.as_str());
+        // the algorithm must match the kind of the configured keys,
+        // otherwise a public key could be used as an hmac secret.
+        let valid = match (&self.keys, alg) {
+            (&Keys::Secret(ref secret), HS256) => verify_hs256(secret, signed, &*signature),
+            (&Keys::Rsa(ref keys), RS256) => verify_rs256(keys, kid, signed, &*signature),
+            _ => return Err(invalid_token(&*format!("unsupported algorithm {}", alg))),
+        };
+        if !valid {
+            return Err(invalid_token("invalid signature"));
+        }
+
+        let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs(),
+            Err(_) => 0,
+        };
+        self.validate_claims(&payload, now)?;
+        Ok(payload)
+    }
+
+    fn validate_claims(&self, claims: &JsonMap<String, Value>, now: u64) -> Result<(), Error> {
+        // the token is expired from the second exp, and valid from nbf
+        if let Some(exp) = numeric_date(claims, "exp")? {
+            if now >= exp {
+                return Err(invalid_token("token expired"));
+            }
+        }
+        if let Some(nbf) = numeric_date(claims, "nbf")? {
+            if now < nbf {
+                return Err(invalid_token("token not yet valid"));
+            }
+        }
+        if let Some(ref aud) = self.audience {
+            let ok = match claims.get("aud") {
+                Some(&Value::String(ref a)) => a == aud,
+                Some(&Value::Array(ref auds)) => auds.iter().any(|a| a.as_str() == Some(&**aud)),
+                _ => false,
+            };
+            if !ok {
+                return Err(invalid_token("invalid audience"));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn invalid_token(s: &str) -> Error {
+    Error::Unauthorized(format!("invalid token, {}", s))
+}
+
+// a time claim, in seconds since the epoch. A claim which is present but not
+// a non negative integer makes the token invalid instead of being ignored.
+fn numeric_date(claims: &JsonMap<String, Value>, name: &str) -> Result<Option<u64>, Error> {
+    match claims.get(name) {
+        None => Ok(None),
+        Some(&Value::U64(t)) => Ok(Some(t)),
+        Some(_) => Err(invalid_token(&*format!("claim {} must be a non negative integer", name))),
+    }
+}
+
+/// Compare two secrets in constant time, only their length can leak.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && memcmp::eq(a, b)
+}
+
+fn verify_hs256(secret: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    let key = match PKey::hmac(secret) {
+        Ok(k) => k,
+        Err(_) => return false,
+    };
+    let mut signer = match Signer::new(MessageDigest::sha256(), &key) {
+        Ok(s) => s,
+        Err(_) => return false,
+    };
+    if signer.update(data).is_err() {
+        return false;
+    }
+    match signer.finish() {
+        Ok(expected) => constant_time_eq(&*expected, signature),
+        Err(_) => false,
+    }
+}
+
+fn verify_rs256(keys: &[(Option<String>, PKey)], kid: Option<&str>,
+                data: &[u8], signature: &[u8]) -> bool {
+    keys.iter()
+        .filter(|&&(ref k, _)| kid.is_none() || k.is_none() || k.as_ref().map(|s| &**s) == kid)
+        .any(|&(_, ref key)| {
+            let mut verifier = match Verifier::new(MessageDigest::sha256(), key) {
+                Ok(v) => v,
+                Err(_) => return false,
+            };
+            if verifier.update(data).is_err() {
+                return false;
+            }
+            verifier.finish(signature).unwrap_or(false)
+        })
+}
+
+fn decode_json_segment(s: &str) -> Result<JsonMap<String, Value>, Error> {
+    let bytes = match base64url_decode(s) {
+        Some(b) => b,
+        None => return Err(invalid_token("malformed base64")),
+    };
+    match serde_json::from_slice(&*bytes) {
+        Ok(Value::Object(m)) => Ok(m),
+        _ => Err(invalid_token("malformed json")),
+    }
+}
+
+fn read_file(path: &str) -> Result<String, String> {
+    let mut s = String::new();
+    match File::open(path).and_then(|mut f| f.read_to_string(&mut s)) {
+        Ok(_) => Ok(s),
+        Err(e) => Err(format!("unable to read {}, {}", path, e)),
+    }
+}
+
+// load the rsa keys of a json web key set
+fn read_jwks(s: &str) -> Result<Vec<(Option<String>, PKey)>, String> {
+    let jwks: Value = match serde_json::from_str(s) {
+        Ok(v) => v,
+        Err(e) => return Err(format!("invalid jwks file, {}", e)),
+    };
+    let keys = match jwks.find("keys").and_then(|k| k.as_array()) {
+        Some(keys) => keys,
+        None => return Err(format!("invalid jwks file, missing keys")),
+    };
+    let mut out = vec![];
+    for k in keys {
+        if k.find("kty").and_then(|v| v.as_str()) != Some("RSA") {
+            continue;
+        }
+        let kid = k.find("kid").and_then(|v| v.as_str()).map(|s| s.to_string());
+        let n = k.find("n").and_then(|v| v.as_str()).and_then(base64url_decode);
+        let e = k.find("e").and_then(|v| v.as_str()).and_then(base64url_decode);
+        let key = match (n, e) {
+            (Some(n), Some(e)) => BigNum::from_slice(&*n)
+                .and_then(|n| BigNum::from_slice(&*e).map(|e| (n, e)))
+                .and_then(|(n, e)| Rsa::from_public_components(n, e))
+                .and_then(PKey::from_rsa),
+            _ => return Err(format!("invalid jwks file, rsa key without modulus or exponent")),
+        };
+        match key {
+            Ok(key) => out.push((kid, key)),
+            Err(e) => return Err(format!("invalid jwks file, {}", e)),
+        }
+    }
+    if out.is_empty() {
+        return Err(format!("invalid jwks file, no rsa key found"));
+    }
+    Ok(out)
+}
+
+// decode unpadded base64url, as used by the json web tokens. Padding, the
+// characters of the standard alphabet and non zero trailing bits are
+// rejected, so a token has a single valid encoding.
+fn base64url_decode(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 4 == 1 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    let mut buf: u32 = 0;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let v = match c {
+            b'A'...b'Z' => c - b'A',
+            b'a'...b'z' => c - b'a' + 26,
+            b'0'...b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        buf = (buf << 6) | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buf >> bits) as u8);
+            buf &= (1 << bits) - 1;
+        }
+    }
+    if buf != 0 {
+        return None;
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &'static str = "secret";
+    const NOW: u64 = 1500000000;
+
+    fn base64url_encode(data: &[u8]) -> String {
+        let alphabet = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+        let mut out = String::new();
+        for chunk in data.chunks(3) {
+            let n = chunk.iter().enumerate()
+                .fold(0u32, |acc, (i, b)| acc | (*b as u32) << (16 - 8 * i));
+            for i in 0..chunk.len() + 1 {
+                out.push(alphabet[(n >> (18 - 6 * i) & 63) as usize] as char);
+            }
+        }
+        out
+    }
+
+    fn authenticator(keys: Keys) -> Authenticator {
+        Authenticator {
+            keys: keys,
+            role_claim: "role".into(),
+            anonymous_role: None,
+            audience: None,
+        }
+    }
+
+    fn sign(header: &str, payload: &str, key: &PKey) -> String {
+        let data = format!("{}.{}", base64url_encode(header.as_bytes()),
+                           base64url_encode(payload.as_bytes()));
+        let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+        signer.update(data.as_bytes()).unwrap();
+        format!("{}.{}", data, base64url_encode(&*signer.finish().unwrap()))
+    }
+
+    fn hs256(payload: &str) -> String {
+        sign(r#"{"alg":"HS256","typ":"JWT"}"#, payload, &PKey::hmac(SECRET.as_bytes()).unwrap())
+    }
+
+    fn invalid(res: Result<JsonMap<String, Value>, Error>) -> String {
+        match res {
+            Err(Error::Unauthorized(e)) => e,
+            r => panic!("expected an invalid token, got {:?}", r),
+        }
+    }
+
+    fn claims(s: &str) -> JsonMap<String, Value> {
+        match serde_json::from_str(s).unwrap() {
+            Value::Object(m) => m,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn base64url() {
+        assert_eq!(base64url_decode(""), Some(vec![]));
+        assert_eq!(base64url_decode("QQ"), Some(b"A".to_vec()));
+        assert_eq!(base64url_decode("QUI"), Some(b"AB".to_vec()));
+        assert_eq!(base64url_decode("QUJD"), Some(b"ABC".to_vec()));
+        assert_eq!(base64url_decode("-_8"), Some(vec![0xfb, 0xff]));
+        // padding, standard alphabet, impossible length and trailing bits
+        assert_eq!(base64url_decode("QQ=="), None);
+        assert_eq!(base64url_decode("+/8"), None);
+        assert_eq!(base64url_decode("QUJDR"), None);
+        assert_eq!(base64url_decode("QR"), None);
+        assert_eq!(base64url_decode("QUJ"), None);
+        let data = (0..256).map(|b| b as u8).collect::<Vec<u8>>();
+        assert_eq!(base64url_decode(&*base64url_encode(&*data)), Some(data));
+    }
+
+    #[test]
+    fn secrets_comparison() {
+        assert!(constant_time_eq(b"reload", b"reload"));
+        assert!(!constant_time_eq(b"reload", b"reloaD"));
+        assert!(!constant_time_eq(b"reload", b"reloa"));
+        assert!(!constant_time_eq(b"", b"reload"));
+    }
+
+    #[test]
+    fn hs256_signature() {
+        let auth = authenticator(Keys::Secret(SECRET.as_bytes().to_vec()));
+        let token = hs256(r#"{"role":"alice"}"#);
+        assert_eq!(auth.verify(&*token).unwrap().get("role"),
+                   Some(&Value::String("alice".into())));
+        assert_eq!(auth.authenticate(Some(&*token)).unwrap().role, Some("alice".into()));
+
+        // the payload of another token with the signature of the first one
+        let other = hs256(r#"{"role":"admin"}"#);
+        let parts = token.split('.').collect::<Vec<&str>>();
+        let forged = format!("{}.{}.{}", parts[0], other.split('.').nth(1).unwrap(), parts[2]);
+        assert_eq!(invalid(auth.verify(&*forged)), "invalid token, invalid signature");
+
+        let auth = authenticator(Keys::Secret(b"other secret".to_vec()));
+        assert_eq!(invalid(auth.verify(&*token)), "invalid token, invalid signature");
+    }
+
+    #[test]
+    fn rs256_signature() {
+        let rsa = Rsa::generate(2048).unwrap();
+        let pem = rsa.public_key_to_pem().unwrap();
+        let private = PKey::from_rsa(rsa).unwrap();
+        let public = || PKey::from_rsa(Rsa::public_key_from_pem(&*pem).unwrap()).unwrap();
+        let token = sign(r#"{"alg":"RS256","kid":"k1"}"#, r#"{"role":"bob"}"#, &private);
+
+        let auth = authenticator(Keys::Rsa(vec![(None, public())]));
+        assert!(auth.verify(&*token).is_ok());
+        let auth = authenticator(Keys::Rsa(vec![(Some("k1".into()), public())]));
+        assert!(auth.verify(&*token).is_ok());
+        // only the key with the kid of the token is tried
+        let auth = authenticator(Keys::Rsa(vec![(Some("k2".into()), public())]));
+        assert_eq!(invalid(auth.verify(&*token)), "invalid token, invalid signature");
+
+        let other = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let token = sign(r#"{"alg":"RS256"}"#, r#"{"role":"bob"}"#, &other);
+        let auth = authenticator(Keys::Rsa(vec![(None, public())]));
+        assert_eq!(invalid(auth.verify(&*token)), "invalid token, invalid signature");
+    }
+
+    #[test]
+    fn algorithm_mismatch() {
+        let rsa = Rsa::generate(2048).unwrap();
+        let pem = rsa.public_key_to_pem().unwrap();
+        let public = PKey::from_rsa(Rsa::public_key_from_pem(&*pem).unwrap()).unwrap();
+
+        // a token signed with the public key as hmac secret
+        let token = sign(r#"{"alg":"HS256"}"#, "{}", &PKey::hmac(&*pem).unwrap());
+        let auth = authenticator(Keys::Rsa(vec![(None, public)]));
+        assert_eq!(invalid(auth.verify(&*token)), "invalid token, unsupported algorithm HS256");
+
+        let auth = authenticator(Keys::Secret(SECRET.as_bytes().to_vec()));
+        let token = sign(r#"{"alg":"RS256"}"#, "{}", &PKey::from_rsa(rsa).unwrap());
+        assert_eq!(invalid(auth.verify(&*token)), "invalid token, unsupported algorithm RS256");
+
+        let token = format!("{}.{}.", base64url_encode(br#"{"alg":"none"}"#),
+                            base64url_encode(b"{}"));
+        assert_eq!(invalid(auth.verify(&*token)), "invalid token, unsupported algorithm none");
+        let token = format!("{}.{}.", base64url_encode(b"{}"), base64url_encode(b"{}"));
+        assert_eq!(invalid(auth.verify(&*token)), "invalid token, unsupported algorithm ");
+    }
+
+    #[test]
+    fn malformed_segments() {
+        let auth = authenticator(Keys::Secret(SECRET.as_bytes().to_vec()));
+        let token = hs256("{}");
+        let parts = token.split('.').collect::<Vec<&str>>();
+        let json = |s: &str| base64url_encode(s.as_bytes());
+
+        assert_eq!(invalid(auth.verify("")), "invalid token, malformed token");
+        assert_eq!(invalid(auth.verify(&*format!("{}.{}", parts[0], parts[1]))),
+                   "invalid token, malformed token");
+        assert_eq!(invalid(auth.verify(&*format!("{}.", token))),
+                   "invalid token, malformed token");
+        assert_eq!(invalid(auth.verify(&*format!("{}=.{}.{}", parts[0], parts[1], parts[2]))),
+                   "invalid token, malformed base64");
+        assert_eq!(invalid(auth.verify(&*format!("{}.{}+.{}", parts[0], parts[1], parts[2]))),
+                   "invalid token, malformed base64");
+        assert_eq!(invalid(auth.verify(&*format!("{}.{}.{}", parts[0], json("[]"), parts[2]))),
+                   "invalid token, malformed json");
+        assert_eq!(invalid(auth.verify(&*format!("{}.{}.{}", json("{"), parts[1], parts[2]))),
+                   "invalid token, malformed json");
+        assert_eq!(invalid(auth.verify(&*format!("{}.{}.{}/", parts[0], parts[1], parts[2]))),
+                   "invalid token, malformed signature");
+    }
+
+    #[test]
+    fn time_claims() {
+        let auth = authenticator(Keys::Secret(vec![]));
+        let check = |s: &str| match auth.validate_claims(&claims(s), NOW) {
+            Ok(()) => "ok".to_string(),
+            Err(Error::Unauthorized(e)) => e,
+            Err(e) => panic!("unexpected error {:?}", e),
+        };
+        assert_eq!(check("{}"), "ok");
+        assert_eq!(check(&*format!(r#"{{"exp":{}}}"#, NOW + 1)), "ok");
+        assert_eq!(check(&*format!(r#"{{"exp":{}}}"#, NOW)), "invalid token, token expired");
+        assert_eq!(check(&*format!(r#"{{"nbf":{}}}"#, NOW)), "ok");
+        assert_eq!(check(&*format!(r#"{{"nbf":{}}}"#, NOW + 1)),
+                   "invalid token, token not yet valid");
+        for exp in &["-1", "1.5e9", "\"1600000000\"", "null", "true"] {
+            assert_eq!(check(&*format!(r#"{{"exp":{}}}"#, exp)),
+                       "invalid token, claim exp must be a non negative integer");
+            assert_eq!(check(&*format!(r#"{{"nbf":{}}}"#, exp)),
+                       "invalid token, claim nbf must be a non negative integer");
+        }
+    }
+
+    #[test]
+    fn audience_claim() {
+        let mut auth = authenticator(Keys::Secret(vec![]));
+        auth.audience = Some("api".into());
+        assert!(auth.validate_claims(&claims(r#"{"aud":"api"}"#), NOW).is_ok());
+        assert!(auth.validate_claims(&claims(r#"{"aud":["web","api"]}"#), NOW).is_ok());
+        assert!(auth.validate_claims(&claims(r#"{"aud":"web"}"#), NOW).is_err());
+        assert!(auth.validate_claims(&claims("{}"), NOW).is_err());
+    }
+}
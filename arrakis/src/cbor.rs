@@ -0,0 +1,466 @@
+// Copyright 2016 Jeremy Letang.
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! CBOR (RFC 7049) encoding of the responses and decoding of the request
+//! bodies.
+//!
+//! Bytes are written as byte strings and timestamps as epoch-based date/time
+//! (tag 1). In request bodies, byte strings are read as hex strings, which
+//! postgres converts to the types of the columns, and epoch-based timestamps
+//! as unix timestamps in seconds like in the json bodies. The bodies are
+//! validated against the JSON Schema of the table, which only accepts whole
+//! seconds.
+
+use cvt::{self, Cell};
+use error::Error;
+use serde_json::{Map, Value};
+use std::io::{self, Write};
+use schema::Column;
+use std::mem;
+use stream::RowWriter;
+
+const UINT: u8 = 0;
+const NEGINT: u8 = 1;
+const BYTES: u8 = 2;
+const TEXT: u8 = 3;
+const ARRAY: u8 = 4;
+const MAP: u8 = 5;
+const TAG: u8 = 6;
+const SIMPLE: u8 = 7;
+
+const TAG_DATETIME: u64 = 0;
+const TAG_EPOCH: u64 = 1;
+
+// additional information of the indefinite length items
+const INDEFINITE: u8 = 31;
+const BREAK: u8 = 0xff;
+
+// maximum nesting of arrays and maps in request bodies
+const MAX_DEPTH: usize = 64;
+
+/// Write the rows as the CBOR encoding of the json response, i.e
+/// `{"data": [{...}, ...]}`. The rows are written in an indefinite length
+/// array as they are read.
+pub struct CborRowWriter<W> {
+    out: W,
+    columns: Vec<String>,
+}
+
+impl<W: Write> CborRowWriter<W> {
+    pub fn new(out: W) -> CborRowWriter<W> {
+        CborRowWriter {
+            out: out,
+            columns: vec![],
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.out
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> RowWriter for CborRowWriter<W> {
+    fn begin(&mut self, columns: &[&Column]) -> io::Result<()> {
+        self.columns = columns.iter().map(|c| c.name.clone()).collect();
+        write_head(&mut self.out, MAP, 1)?;
+        write_str(&mut self.out, "data")?;
+        self.out.write_all(&[(ARRAY << 5) | INDEFINITE])
+    }
+
+    fn row(&mut self, values: Vec<Cell>) -> io::Result<()> {
+        write_head(&mut self.out, MAP, self.columns.len() as u64)?;
+        for (c, v) in self.columns.iter().zip(values.iter()) {
+            write_str(&mut self.out, c)?;
+            write_cell(&mut self.out, v)?;
+        }
+        Ok(())
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        self.out.write_all(&[BREAK])?;
+        self.out.flush()
+    }
+}
+
+pub fn write_cell<W: Write>(out: &mut W, cell: &Cell) -> io::Result<()> {
+    match *cell {
+        Cell::Null => out.write_all(&[0xf6]),
+        Cell::Bool(b) => out.write_all(&[if b { 0xf5 } else { 0xf4 }]),
+        Cell::Int(i) => write_int(out, i),
+        Cell::Float(f) => write_float(out, f),
+        Cell::Text(ref s) => write_str(out, s),
+        Cell::Bytes(ref b) => {
+            write_head(out, BYTES, b.len() as u64)?;
+            out.write_all(b)
+        },
+        Cell::Timestamp(t) => {
+            write_head(out, TAG, TAG_EPOCH)?;
+            match cvt::split_micros(t) {
+                (secs, 0) => write_int(out, secs),
+                _ => write_float(out, t as f64 / 1_000_000.0),
+            }
+        },
+        Cell::Json(ref v) => write_value(out, v),
+    }
+}
+
+/// Write a json value, e.g the response of a POST request.
+pub fn write_value<W: Write>(out: &mut W, v: &Value) -> io::Result<()> {
+    match *v {
+        Value::Null => out.write_all(&[0xf6]),
+        Value::Bool(b) => out.write_all(&[if b { 0xf5 } else { 0xf4 }]),
+        Value::I64(i) => write_int(out, i),
+        Value::U64(u) => write_head(out, UINT, u),
+        Value::F64(f) => write_float(out, f),
+        Value::String(ref s) => write_str(out, s),
+        Value::Array(ref values) => {
+            write_head(out, ARRAY, values.len() as u64)?;
+            for v in values {
+                write_value(out, v)?;
+            }
+            Ok(())
+        },
+        Value::Object(ref map) => {
+            write_head(out, MAP, map.len() as u64)?;
+            for (k, v) in map {
+                write_str(out, k)?;
+                write_value(out, v)?;
+            }
+            Ok(())
+        },
+    }
+}
+
+fn write_int<W: Write>(out: &mut W, i: i64) -> io::Result<()> {
+    if i >= 0 {
+        write_head(out, UINT, i as u64)
+    } else {
+        // -1 - i
+        write_head(out, NEGINT, !i as u64)
+    }
+}
+
+fn write_float<W: Write>(out: &mut W, f: f64) -> io::Result<()> {
+    out.write_all(&[0xfb])?;
+    let bits = unsafe { mem::transmute::<f64, u64>(f) };
+    out.write_all(&be_bytes(bits, 8))
+}
+
+fn write_str<W: Write>(out: &mut W, s: &str) -> io::Result<()> {
+    write_head(out, TEXT, s.len() as u64)?;
+    out.write_all(s.as_bytes())
+}
+
+// the major type and its argument, in the shortest form
+fn write_head<W: Write>(out: &mut W, major: u8, n: u64) -> io::Result<()> {
+    let major = major << 5;
+    match n {
+        n if n < 24 => out.write_all(&[major | n as u8]),
+        n if n <= 0xff => out.write_all(&[major | 24, n as u8]),
+        n if n <= 0xffff => {
+            out.write_all(&[major | 25])?;
+            out.write_all(&be_bytes(n, 2))
+        },
+        n if n <= 0xffff_ffff => {
+            out.write_all(&[major | 26])?;
+            out.write_all(&be_bytes(n, 4))
+        },
+        n => {
+            out.write_all(&[major | 27])?;
+            out.write_all(&be_bytes(n, 8))
+        },
+    }
+}
+
+// the `n` low bytes of v, big endian
+fn be_bytes(v: u64, n: usize) -> Vec<u8> {
+    (0..n).rev().map(|i| (v >> (i * 8)) as u8).collect()
+}
+
+/// Read a request body as a json value.
+pub fn decode(body: &[u8]) -> Result<Value, Error> {
+    let mut reader = Reader { buf: body, pos: 0 };
+    let value = match reader.item(0)? {
+        Item::Value(v) => v,
+        Item::Break => return Err(invalid("unexpected break")),
+    };
+    if reader.pos != body.len() {
+        return Err(invalid("trailing bytes after the value"));
+    }
+    Ok(value)
+}
+
+enum Item {
+    Value(Value),
+    // end of an indefinite length item
+    Break,
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.buf.len() - self.pos < n {
+            return Err(invalid("unexpected end of body"));
+        }
+        let b = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(b)
+    }
+
+    fn uint(&mut self, n: usize) -> Result<u64, Error> {
+        Ok(self.bytes(n)?.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64))
+    }
+
+    // the argument of an item, None for the indefinite length
+    fn arg(&mut self, info: u8) -> Result<Option<u64>, Error> {
+        match info {
+            0...23 => Ok(Some(info as u64)),
+            24...27 => Ok(Some(self.uint(1 << (info - 24))?)),
+            INDEFINITE => Ok(None),
+            _ => Err(invalid("invalid additional information")),
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, Error> {
+        match self.item(depth)? {
+            Item::Value(v) => Ok(v),
+            Item::Break => Err(invalid("unexpected break")),
+        }
+    }
+
+    fn item(&mut self, depth: usize) -> Result<Item, Error> {
+        if depth > MAX_DEPTH {
+            return Err(invalid("too many nested values"));
+        }
+        let initial = self.bytes(1)?[0];
+        let (major, info) = (initial >> 5, initial & 0x1f);
+        if initial == BREAK {
+            return Ok(Item::Break);
+        }
+        let v = match (major, self.arg(info)?) {
+            (UINT, Some(n)) => Value::U64(n),
+            (NEGINT, Some(n)) if n <= i64::max_value() as u64 => Value::I64(!(n as i64)),
+            (NEGINT, Some(_)) => return Err(invalid("integer out of range")),
+            (BYTES, len) => {
+                let b = self.chunks(BYTES, len)?;
+                Value::String(cvt::bytes_to_hex(&*b))
+            },
+            (TEXT, len) => match String::from_utf8(self.chunks(TEXT, len)?) {
+                Ok(s) => Value::String(s),
+                Err(_) => return Err(invalid("invalid utf-8 string")),
+            },
+            (ARRAY, len) => {
+                let mut arr = vec![];
+                while let Some(v) = self.next_item(len, arr.len(), depth)? {
+                    arr.push(v);
+                }
+                Value::Array(arr)
+            },
+            (MAP, len) => {
+                let mut map = Map::new();
+                while let Some(k) = self.next_item(len, map.len(), depth)? {
+                    let key = match k {
+                        Value::String(s) => s,
+                        _ => return Err(invalid("map keys must be strings")),
+                    };
+                    let value = self.value(depth + 1)?;
+                    map.insert(key, value);
+                }
+                Value::Object(map)
+            },
+            (TAG, Some(tag)) => {
+                let v = self.value(depth + 1)?;
+                let micros = match (tag, v) {
+                    (TAG_DATETIME, v @ Value::String(_)) => return Ok(Item::Value(v)),
+                    (TAG_EPOCH, Value::U64(n)) => (n as i64).saturating_mul(1_000_000),
+                    (TAG_EPOCH, Value::I64(n)) => n.saturating_mul(1_000_000),
+                    (TAG_EPOCH, Value::F64(f)) => (f * 1_000_000.0) as i64,
+                    (TAG_DATETIME, _) | (TAG_EPOCH, _) =>
+                        return Err(invalid("invalid timestamp")),
+                    // the other tags are ignored
+                    (_, v) => return Ok(Item::Value(v)),
+                };
+                let (secs, nanos) = cvt::split_micros(micros);
+                cvt::timestamp_value(secs, nanos)
+            },
+            // the argument of the floats is their bits
+            (SIMPLE, arg) => match (info, arg) {
+                (20, _) => Value::Bool(false),
+                (21, _) => Value::Bool(true),
+                (22, _) | (23, _) => Value::Null,
+                (25, Some(n)) => Value::F64(half_to_f64(n as u16)),
+                (26, Some(n)) => Value::F64(unsafe { mem::transmute::<u32, f32>(n as u32) } as f64),
+                (27, Some(n)) => Value::F64(unsafe { mem::transmute::<u64, f64>(n) }),
+                _ => return Err(invalid("unsupported simple value")),
+            },
+            _ => return Err(invalid("indefinite length item")),
+        };
+        Ok(Item::Value(v))
+    }
+
+    // the next element of an array or key of a map of `len` elements, None
+    // at the end
+    fn next_item(&mut self, len: Option<u64>, read: usize, depth: usize)
+                 -> Result<Option<Value>, Error> {
+        match len {
+            Some(n) if read as u64 >= n => Ok(None),
+            Some(_) => Ok(Some(self.value(depth + 1)?)),
+            None => match self.item(depth + 1)? {
+                Item::Value(v) => Ok(Some(v)),
+                Item::Break => Ok(None),
+            },
+        }
+    }
+
+    // the content of a byte or text string, an indefinite length string is
+    // made of definite length chunks of the same type
+    fn chunks(&mut self, major: u8, len: Option<u64>) -> Result<Vec<u8>, Error> {
+        if let Some(n) = len {
+            return Ok(self.bytes(n as usize)?.to_vec());
+        }
+        let mut buf = vec![];
+        loop {
+            let initial = self.bytes(1)?[0];
+            if initial == BREAK {
+                return Ok(buf);
+            }
+            match (initial >> 5, self.arg(initial & 0x1f)?) {
+                (m, Some(n)) if m == major => buf.extend_from_slice(self.bytes(n as usize)?),
+                _ => return Err(invalid("invalid string chunk")),
+            }
+        }
+    }
+}
+
+fn half_to_f64(h: u16) -> f64 {
+    let exp = (h >> 10) & 0x1f;
+    let mant = (h & 0x3ff) as f64;
+    let v = match exp {
+        0 => mant * 2f64.powi(-24),
+        31 if mant == 0.0 => ::std::f64::INFINITY,
+        31 => ::std::f64::NAN,
+        _ => (mant + 1024.0) * 2f64.powi(exp as i32 - 25),
+    };
+    if h & 0x8000 != 0 { -v } else { v }
+}
+
+fn invalid(s: &str) -> Error {
+    Error::InvalidInputError(format!("invalid cbor body, {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(v: &Value) -> Vec<u8> {
+        let mut out = vec![];
+        write_value(&mut out, v).unwrap();
+        out
+    }
+
+    fn encode_cell(c: &Cell) -> Vec<u8> {
+        let mut out = vec![];
+        write_cell(&mut out, c).unwrap();
+        out
+    }
+
+    #[test]
+    fn integer_boundaries() {
+        let unsigned = [(0, vec![0x00]), (23, vec![0x17]), (24, vec![0x18, 0x18]),
+                        (255, vec![0x18, 0xff]), (256, vec![0x19, 0x01, 0x00]),
+                        (65535, vec![0x19, 0xff, 0xff]),
+                        (65536, vec![0x1a, 0x00, 0x01, 0x00, 0x00]),
+                        (0xffff_ffff, vec![0x1a, 0xff, 0xff, 0xff, 0xff]),
+                        (0x1_0000_0000, vec![0x1b, 0, 0, 0, 0x01, 0, 0, 0, 0]),
+                        (u64::max_value(), vec![0x1b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+                                                0xff])];
+        for &(n, ref bytes) in &unsigned {
+            assert_eq!(&encode(&Value::U64(n)), bytes, "{}", n);
+            assert_eq!(decode(bytes).unwrap(), Value::U64(n));
+        }
+        let signed = [(-1, vec![0x20]), (-24, vec![0x37]), (-25, vec![0x38, 0x18]),
+                      (-256, vec![0x38, 0xff]), (-257, vec![0x39, 0x01, 0x00]),
+                      (-65537, vec![0x3a, 0x00, 0x01, 0x00, 0x00]),
+                      (i64::min_value(), vec![0x3b, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+                                              0xff])];
+        for &(n, ref bytes) in &signed {
+            assert_eq!(&encode(&Value::I64(n)), bytes, "{}", n);
+            assert_eq!(decode(bytes).unwrap(), Value::I64(n));
+        }
+        // -2^64 does not fit an i64
+        assert!(decode(&[0x3b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn half_floats() {
+        let halves = [([0x00, 0x00], 0.0), ([0x80, 0x00], -0.0), ([0x3c, 0x00], 1.0),
+                      ([0x3e, 0x00], 1.5), ([0x7b, 0xff], 65504.0), ([0xc4, 0x00], -4.0),
+                      ([0x00, 0x01], 5.960464477539063e-8), ([0x04, 0x00], 0.00006103515625),
+                      ([0x7c, 0x00], ::std::f64::INFINITY),
+                      ([0xfc, 0x00], ::std::f64::NEG_INFINITY)];
+        for &(bytes, f) in &halves {
+            let v = decode(&[0xf9, bytes[0], bytes[1]]).unwrap();
+            assert_eq!(v, Value::F64(f), "{:?}", bytes);
+            assert_eq!(v.as_f64().unwrap().is_sign_negative(), f.is_sign_negative());
+        }
+        assert!(decode(&[0xf9, 0x7e, 0x00]).unwrap().as_f64().unwrap().is_nan());
+        assert_eq!(decode(&[0xfa, 0x47, 0xc3, 0x50, 0x00]).unwrap(), Value::F64(100000.0));
+        assert_eq!(encode(&Value::F64(1.1)),
+                   vec![0xfb, 0x3f, 0xf1, 0x99, 0x99, 0x99, 0x99, 0x99, 0x9a]);
+    }
+
+    #[test]
+    fn timestamps() {
+        // whole seconds are written as integers, the others as floats
+        let bytes = encode_cell(&Cell::Timestamp(1_363_896_240_000_000));
+        assert_eq!(bytes, vec![0xc1, 0x1a, 0x51, 0x4b, 0x67, 0xb0]);
+        assert_eq!(decode(&*bytes).unwrap(), Value::I64(1_363_896_240));
+        let bytes = encode_cell(&Cell::Timestamp(1_363_896_240_500_000));
+        assert_eq!(bytes, vec![0xc1, 0xfb, 0x41, 0xd4, 0x52, 0xd9, 0xec, 0x20, 0x00, 0x00]);
+        assert_eq!(decode(&*bytes).unwrap(), Value::F64(1_363_896_240.5));
+        // before the epoch
+        assert_eq!(decode(&[0xc1, 0x20]).unwrap(), Value::I64(-1));
+        // rfc 3339 strings are kept as they are
+        let mut bytes = vec![0xc0, 0x74];
+        bytes.extend_from_slice(b"2013-03-21T20:04:00Z");
+        assert_eq!(decode(&*bytes).unwrap(), Value::String("2013-03-21T20:04:00Z".into()));
+        assert!(decode(&[0xc1, 0x61, b'x']).is_err());
+        // the other tags are ignored
+        assert_eq!(decode(&[0xd8, 0x20, 0x01]).unwrap(), Value::U64(1));
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let long = "x".repeat(40);
+        let v: Value = ::serde_json::from_str(&*format!(
+            r#"{{"a":[1,-2,"x",null,true,false,{{"b":2.25}}],"long":"{}"}}"#, long)).unwrap();
+        assert_eq!(decode(&*encode(&v)).unwrap(), v);
+        assert_eq!(decode(&*encode_cell(&Cell::Bytes(vec![1, 0xab]))).unwrap(),
+                   Value::String("\\x01ab".into()));
+        assert_eq!(decode(&*encode_cell(&Cell::Json(v.clone()))).unwrap(), v);
+    }
+
+    #[test]
+    fn indefinite_lengths() {
+        // ["a", {"b": 1}] with indefinite length items and a chunked string
+        let bytes = [0x9f, 0x7f, 0x61, b'a', 0xff, 0xbf, 0x61, b'b', 0x01, 0xff, 0xff];
+        assert_eq!(decode(&bytes).unwrap(),
+                   ::serde_json::from_str::<Value>(r#"["a",{"b":1}]"#).unwrap());
+        assert!(decode(&[0x7f, 0x41, b'a', 0xff]).is_err());
+        assert!(decode(&[0xff]).is_err());
+        assert!(decode(&[0x9f, 0x01]).is_err());
+        assert!(decode(&vec![0x81; MAX_DEPTH + 2]).is_err());
+    }
+}
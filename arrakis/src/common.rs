@@ -5,24 +5,99 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use cvt;
 use error::Error;
-use queries::{FetchQueries, Queries};
-use schema::Table;
-use serde_json::Value;
+use method::Method;
+use query::Query;
+use postgres::types::Type;
+use schema::{Table, Visibility};
+use serde_json::{Map, Value};
 
-pub fn generate_from(query: String, table_name: &str) -> String {
-    format!("{} FROM {}", query, table_name)
+/// Quote an identifier (table, column, channel ...) for postgres, every
+/// name spliced into the generated sql must go through this function.
+pub fn quote_ident(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "\"\""))
 }
 
-pub fn generate_where(mut query: String, table: &Table, queries: &Queries)
+/// Quote a string literal for postgres, every value spliced into the
+/// generated sql must go through this function. Literals containing a
+/// backslash use the escape string syntax, so they are read the same
+/// whatever the value of standard_conforming_strings.
+pub fn quote_literal(s: &str) -> String {
+    let quoted = s.replace('\'', "''");
+    if quoted.contains('\\') {
+        format!("E'{}'", quoted.replace('\\', "\\\\"))
+    } else {
+        format!("'{}'", quoted)
+    }
+}
+
+/// The row of a body with the unix timestamps of the timestamp columns, the
+/// representation of the responses and of the JSON Schema, replaced by
+/// timestamp literals postgres can read. The row must have been validated.
+pub fn timestamp_literals(table: &Table, row: &Value) -> Value {
+    let row = match row.as_object() {
+        Some(row) => row,
+        None => return row.clone(),
+    };
+    let mut m = Map::new();
+    for (name, value) in row {
+        let value = match (table.columns.get(name), value.as_i64()) {
+            (Some(c), Some(secs)) if c.data_type == Type::Timestamp
+                || c.data_type == Type::TimestampTZ =>
+                Value::String(cvt::format_timestamp(secs, 0)),
+            _ => value.clone(),
+        };
+        m.insert(name.clone(), value);
+    }
+    Value::Object(m)
+}
+
+/// The sql literal of a json value.
+pub fn quote_value(v: &Value) -> Result<String, Error> {
+    match v {
+        &Value::Null => Ok("NULL".to_string()),
+        _ => Ok(quote_literal(&*cvt::json_value_to_string(v)?)),
+    }
+}
+
+/// Quote a column, optionally qualified by its table.
+pub fn quote_column(table: Option<&str>, column: &str) -> String {
+    match table {
+        Some(t) => format!("{}.{}", quote_ident(t), quote_ident(column)),
+        None => quote_ident(column),
+    }
+}
+
+/// Quote the name of a table qualified by its schema.
+pub fn quote_table(table: &Table) -> String {
+    format!("{}.{}", quote_ident(&*table.schema), quote_ident(&*table.name))
+}
+
+/// The id column returned by the POST and PATCH requests, selected so that
+/// it can be read whatever its type, see `cvt::select_expr`.
+pub fn select_id(table: &Table) -> String {
+    let id = quote_ident("id");
+    match table.columns.get("id") {
+        Some(c) => cvt::select_expr(&*id, &c.data_type),
+        None => id,
+    }
+}
+
+pub fn generate_from(query: String, table: &Table) -> String {
+    format!("{} FROM {}", query, quote_table(table))
+}
+
+pub fn generate_where(mut query: String, table: &Table, q: &Query)
                       -> Result<String, Error> {
-    let filters = queries.filters()?;
+    let filters = q.filters();
     if !filters.is_empty() {
         query = format!("{} WHERE ", query);
     }
     let mut filters_str = vec![];
-    for (col, filter) in filters {
-        if !table.columns.contains_key(col) {
+    for filter in filters {
+        let col = filter.column();
+        if !table.columns.get(col).map_or(false, |c| c.visibility.is_readable()) {
             let estr = format!("column {} do not exist for table {}", col, table.name);
             return Err(Error::InvalidFilterSyntax(estr));
         }
@@ -30,17 +105,32 @@ pub fn generate_where(mut query: String, table: &Table, queries: &Queries)
     }
     query += &*filters_str.iter().map(|s| &**s)
         .collect::<Vec<&str>>()
-        .join("AND ");
+        .join(" AND ");
     Ok(query)
 }
 
-pub fn validate_table_fields(table: &Table, val: &Value) -> Result<(), Error> {
+pub fn validate_table_fields(table: &Table, val: &Value, method: &Method)
+                             -> Result<(), Error> {
     match val {
         &Value::Object(ref m) => {
             // TODO(JEREMY): ensure the types match with the db types
             for (k, _) in m {
-                if !table.columns.contains_key(k) {
-                    return Err(Error::UnknowColumn(k.clone(), table.name.clone()));
+                let c = match table.columns.get(k) {
+                    Some(c) if c.visibility != Visibility::Hidden => c,
+                    _ => return Err(Error::UnknowColumn(k.clone(), table.name.clone())),
+                };
+                if c.visibility == Visibility::ReadOnly {
+                    return Err(Error::InvalidInputError(
+                        format!("column {} of table {} is read only", k, table.name)));
+                }
+                // the insert and update privileges are granted separately
+                let (allowed, action) = match *method {
+                    Method::Patch => (c.can_update(), "updated"),
+                    _ => (c.can_insert(), "inserted"),
+                };
+                if !allowed {
+                    return Err(Error::Forbidden(
+                        format!("column {} of table {} cannot be {}", k, table.name, action)));
                 }
             }
             Ok(())
@@ -48,3 +138,123 @@ pub fn validate_table_fields(table: &Table, val: &Value) -> Result<(), Error> {
         _ => Err(Error::InvalidInputError("expect json object as top level value".into()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::Error;
+    use filters::{Filter, IsKind};
+    use method::Method;
+    use postgres::types::Type;
+    use schema::{Column, ColumnPrivileges, Table, Visibility};
+    use serde_json::{self, Value};
+
+    fn column(name: &str, visibility: Visibility, insert: bool, update: bool) -> Column {
+        let mut c = Column::new(name, Type::Text);
+        c.visibility = visibility;
+        c.privileges = ColumnPrivileges { select: true, insert: insert, update: update };
+        c
+    }
+
+    fn table() -> Table {
+        Table::new("public", "t", vec![
+            column("name", Visibility::Visible, true, true),
+            column("created_by", Visibility::Visible, true, false),
+            column("status", Visibility::Visible, false, true),
+            column("id", Visibility::ReadOnly, true, true),
+            column("secret", Visibility::Hidden, true, true),
+        ])
+    }
+
+    fn validate(body: &str, method: Method) -> Result<(), Error> {
+        validate_table_fields(&table(), &serde_json::from_str(body).unwrap(), &method)
+    }
+
+    #[test]
+    fn insert_and_update_privileges() {
+        assert!(validate(r#"{"name":"a","created_by":"b"}"#, Method::Post).is_ok());
+        assert!(validate(r#"{"name":"a","status":"b"}"#, Method::Patch).is_ok());
+        match validate(r#"{"created_by":"b"}"#, Method::Patch) {
+            Err(Error::Forbidden(e)) =>
+                assert_eq!(e, "column created_by of table t cannot be updated"),
+            r => panic!("unexpected {:?}", r),
+        }
+        match validate(r#"{"status":"b"}"#, Method::Post) {
+            Err(Error::Forbidden(e)) =>
+                assert_eq!(e, "column status of table t cannot be inserted"),
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn visibility_of_fields() {
+        match validate(r#"{"id":1}"#, Method::Post) {
+            Err(Error::InvalidInputError(_)) => {},
+            r => panic!("unexpected {:?}", r),
+        }
+        for body in &[r#"{"secret":1}"#, r#"{"unknown":1}"#] {
+            match validate(body, Method::Patch) {
+                Err(Error::UnknowColumn(..)) => {},
+                r => panic!("unexpected {:?}", r),
+            }
+        }
+        assert!(validate("[]", Method::Post).is_err());
+    }
+
+    #[test]
+    fn quote_literal_doubles_single_quotes() {
+        assert_eq!(quote_literal("abc"), "'abc'");
+        assert_eq!(quote_literal(""), "''");
+        assert_eq!(quote_literal("x' OR '1'='1"), "'x'' OR ''1''=''1'");
+        assert_eq!(quote_literal("'; DROP TABLE t; --"), "'''; DROP TABLE t; --'");
+    }
+
+    #[test]
+    fn quote_literal_escapes_backslashes() {
+        assert_eq!(quote_literal("a\\b"), "E'a\\\\b'");
+        assert_eq!(quote_literal("\\' OR 1=1 --"), "E'\\\\'' OR 1=1 --'");
+    }
+
+    #[test]
+    fn quote_value_of_json_values() {
+        assert_eq!(quote_value(&Value::Null).unwrap(), "NULL");
+        assert_eq!(quote_value(&Value::I64(-3)).unwrap(), "'-3'");
+        assert_eq!(quote_value(&Value::Bool(true)).unwrap(), "'true'");
+        assert_eq!(quote_value(&Value::String("it's".into())).unwrap(), "'it''s'");
+        match quote_value(&Value::Array(vec![Value::U64(1)])) {
+            Err(Error::InvalidInputError(..)) => {},
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(quote_value(&serde_json::from_str(r#"{"a":1}"#).unwrap()).is_err());
+    }
+
+    #[test]
+    fn timestamps_of_the_bodies() {
+        let mut table = table();
+        let mut at = column("at", Visibility::Visible, true, true);
+        at.data_type = Type::TimestampTZ;
+        table.columns.insert(at);
+        let row = serde_json::from_str(r#"{"at":86400,"name":86400}"#).unwrap();
+        let row = timestamp_literals(&table, &row);
+        let at = Value::String("1970-01-02 00:00:00.000000+00".into());
+        assert_eq!(row.pointer("/at"), Some(&at));
+        assert_eq!(row.pointer("/name"), Some(&Value::U64(86400)));
+        assert_eq!(timestamp_literals(&table, &Value::Null), Value::Null);
+    }
+
+    #[test]
+    fn where_clauses() {
+        let q = Query::builder()
+            .filter(Filter::Is("name".into(), IsKind::Null))
+            .filter(Filter::Equal("status".into(), "a".into()))
+            .filter(Filter::GreaterThan("id".into(), "1".into()))
+            .build();
+        assert_eq!(generate_where("SELECT 1".into(), &table(), &q).unwrap(),
+                   "SELECT 1 WHERE \"t\".\"name\" IS NULL AND \"t\".\"status\" = 'a' \
+                    AND \"t\".\"id\" > '1'");
+        assert_eq!(generate_where("SELECT 1".into(), &table(), &Query::new()).unwrap(),
+                   "SELECT 1");
+        let q = Query::builder().filter(Filter::Equal("secret".into(), "a".into())).build();
+        assert!(generate_where("SELECT 1".into(), &table(), &q).is_err());
+    }
+}
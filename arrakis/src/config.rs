@@ -5,66 +5,876 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use method::Method;
+use schema::Visibility;
+use serde::Deserialize;
+use serde_json;
+use serde_yaml;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+use tls::SslMode;
+use toml;
+
 const DEFAULT_CONNECTION_TIMEOUT: u64 = 5;
+const DEFAULT_POOL_SIZE: u32 = 10;
+// in milliseconds
+const DEFAULT_STREAM_TIMEOUT: u64 = 30000;
+
+const ENV_PREFIX: &'static str = "ARRAKIS_";
 
-#[derive(Debug, Clone)]
-pub struct Config<'r> {
+fn default_timeout() -> u64 { DEFAULT_CONNECTION_TIMEOUT }
+fn default_pool_size() -> u32 { DEFAULT_POOL_SIZE }
+fn default_stream_timeout() -> u64 { DEFAULT_STREAM_TIMEOUT }
+fn default_cors_allowed_headers() -> Vec<String> { vec!["content-type".into()] }
+fn default_ssl_mode() -> String { SslMode::Disable.to_string() }
+fn default_role_claim() -> String { "role".into() }
+
+/// Configuration of an arrakis instance.
+///
+/// The configuration can be built programmatically using `Config::builder`,
+/// or loaded from a toml, yaml or json file with `Config::from_file`. In
+/// both cases `merge_env` can then be used to override the values with the
+/// `ARRAKIS_*` environment variables.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(rename = "database_url", default)]
+    database_url_: Option<String>,
+    #[serde(rename = "timeout", default = "default_timeout")]
     timeout_: u64,
-    excluded_: Vec<&'r str>,
-    included_: Vec<&'r str>,
+    #[serde(rename = "pool_size", default = "default_pool_size")]
+    pool_size_: u32,
+    #[serde(rename = "min_idle", default)]
+    min_idle_: Option<u32>,
+    // in seconds
+    #[serde(rename = "idle_timeout", default)]
+    idle_timeout_: Option<u64>,
+    // in seconds
+    #[serde(rename = "max_lifetime", default)]
+    max_lifetime_: Option<u64>,
+    // in milliseconds
+    #[serde(rename = "statement_timeout", default)]
+    statement_timeout_: Option<u64>,
+    #[serde(rename = "schemas", default)]
+    schemas_: Vec<String>,
+    #[serde(rename = "exclude", default)]
+    excluded_: Vec<String>,
+    #[serde(rename = "include", default)]
+    included_: Vec<String>,
+    // table name -> allowed methods, tables not listed allow every methods
+    #[serde(rename = "permissions", default)]
+    permissions_: BTreeMap<String, Vec<String>>,
+    // role -> table -> allowed methods, override the table permissions for
+    // the requests executed as this role
+    #[serde(rename = "role_permissions", default)]
+    role_permissions_: BTreeMap<String, BTreeMap<String, Vec<String>>>,
+    // table -> column -> visibility (hidden, read_only or write_only)
+    #[serde(rename = "columns", default)]
+    columns_: BTreeMap<String, BTreeMap<String, String>>,
+    // prefix of the control query parameters (select, limit, offset, order)
+    #[serde(rename = "control_prefix", default)]
+    control_prefix_: String,
+    // threads executing the requests of the async api, default to the pool size
+    #[serde(rename = "workers", default)]
+    workers_: Option<usize>,
+    // stream the responses of the GET requests
+    #[serde(rename = "stream", default)]
+    stream_: bool,
+    // in milliseconds, time a streamed response can wait for the client
+    // before its transaction is aborted
+    #[serde(rename = "stream_timeout", default = "default_stream_timeout")]
+    stream_timeout_: u64,
+    // token required by the reload builtin, disabled if None
+    #[serde(rename = "reload_token", default)]
+    reload_token_: Option<String>,
+    // channel listened to reload the schema, e.g arrakis_reload
+    #[serde(rename = "reload_channel", default)]
+    reload_channel_: Option<String>,
+    // only allow GET requests, and open the transactions read only
+    #[serde(rename = "read_only", default)]
+    read_only_: bool,
+    #[serde(rename = "max_limit", default)]
+    max_limit_: Option<u32>,
+    #[serde(rename = "cors", default)]
+    cors_: CorsConfig,
+    #[serde(rename = "tls", default)]
+    tls_: TlsConfig,
+    #[serde(rename = "auth", default)]
+    auth_: Option<AuthConfig>,
+    // http headers exposed to the database as request.header.<name>
+    #[serde(rename = "request_headers", default)]
+    request_headers_: Vec<String>,
+    // cookies exposed to the database as request.cookie.<name>
+    #[serde(rename = "request_cookies", default)]
+    request_cookies_: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CorsConfig {
+    // empty means any origin
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    #[serde(default = "default_cors_allowed_headers")]
+    pub allowed_headers: Vec<String>,
+    #[serde(default)]
+    pub max_age: Option<u32>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> CorsConfig {
+        CorsConfig {
+            allowed_origins: vec![],
+            allowed_headers: default_cors_allowed_headers(),
+            max_age: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    // one of disable, prefer, require or verify-full
+    #[serde(default = "default_ssl_mode")]
+    pub mode: String,
+    // pem bundle used to verify the server certificate, default to the
+    // system certificates
+    #[serde(default)]
+    pub ca_file: Option<String>,
+    // pem client certificate and key
+    #[serde(default)]
+    pub cert_file: Option<String>,
+    #[serde(default)]
+    pub key_file: Option<String>,
+}
+
+impl TlsConfig {
+    pub fn ssl_mode(&self) -> SslMode {
+        SslMode::from_str(&*self.mode).unwrap_or(SslMode::Disable)
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> TlsConfig {
+        TlsConfig {
+            mode: default_ssl_mode(),
+            ca_file: None,
+            cert_file: None,
+            key_file: None,
+        }
+    }
+}
+
+/// Json web token authentication, exactly one of `secret` (HS256),
+/// `public_key_file` or `jwks_file` (RS256) must be set.
+///
+/// The schema is introspected once and not per role, so with authentication
+/// the tables, methods and columns are not restricted to the privileges of
+/// the login role: the routes, the OpenAPI document and the JSON schemas
+/// describe every table of the schemas, and the requests a role has no
+/// privilege for are rejected by the database.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub secret: Option<String>,
+    // pem encoded rsa public key
+    #[serde(default)]
+    pub public_key_file: Option<String>,
+    // json web key set
+    #[serde(default)]
+    pub jwks_file: Option<String>,
+    // the claim containing the postgres role of the request
+    #[serde(default = "default_role_claim")]
+    pub role_claim: String,
+    // role of the requests without token, such requests are rejected if None
+    #[serde(default)]
+    pub anonymous_role: Option<String>,
+    // expected aud claim, not checked if None
+    #[serde(default)]
+    pub audience: Option<String>,
 }
 
-impl<'r> Config<'r> {
-    pub fn builder() -> Builder<'r> {
+impl Default for AuthConfig {
+    fn default() -> AuthConfig {
+        AuthConfig {
+            secret: None,
+            public_key_file: None,
+            jwks_file: None,
+            role_claim: default_role_claim(),
+            anonymous_role: None,
+            audience: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn builder() -> Builder {
         Builder {
             config: Config::default(),
         }
     }
 
+    /// Get a builder initialized with this configuration, e.g to override
+    /// a loaded configuration with command line arguments.
+    pub fn into_builder(self) -> Builder {
+        Builder {
+            config: self,
+        }
+    }
+
+    /// Load the configuration from a file, the format is deduced from the
+    /// extension of the file (toml, yaml / yml or json).
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config, String> {
+        let path = path.as_ref();
+        let mut s = String::new();
+        match File::open(path) {
+            Ok(mut f) => if let Err(e) = f.read_to_string(&mut s) {
+                return Err(format!("unable to read config file {}, {}", path.display(), e));
+            },
+            Err(e) => return Err(format!("unable to open config file {}, {}", path.display(), e)),
+        };
+
+        let config = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => from_toml(&*s),
+            Some("yaml") | Some("yml") =>
+                serde_yaml::from_str::<Config>(&*s).map_err(|e| format!("{}", e)),
+            Some("json") =>
+                serde_json::from_str::<Config>(&*s).map_err(|e| format!("{}", e)),
+            _ => return Err(format!("unknown config file format for {}, \
+                                     expected toml, yaml or json", path.display())),
+        };
+
+        match config {
+            Ok(c) => Ok(c),
+            Err(e) => Err(format!("invalid config file {}, {}", path.display(), e)),
+        }
+    }
+
+    /// Override the configuration with the `ARRAKIS_*` environment variables.
+    ///
+    /// Lists (schemas, include, exclude, cors allowed origins and headers,
+    /// request headers and cookies) are comma separated.
+    pub fn merge_env(mut self) -> Result<Config, String> {
+        if let Some(v) = env_var("DATABASE_URL") {
+            self.database_url_ = Some(v);
+        }
+        if let Some(v) = env_var("TIMEOUT") {
+            self.timeout_ = parse_env("TIMEOUT", &*v)?;
+        }
+        if let Some(v) = env_var("POOL_SIZE") {
+            self.pool_size_ = parse_env("POOL_SIZE", &*v)?;
+        }
+        if let Some(v) = env_var("MIN_IDLE") {
+            self.min_idle_ = Some(parse_env("MIN_IDLE", &*v)?);
+        }
+        if let Some(v) = env_var("IDLE_TIMEOUT") {
+            self.idle_timeout_ = Some(parse_env("IDLE_TIMEOUT", &*v)?);
+        }
+        if let Some(v) = env_var("MAX_LIFETIME") {
+            self.max_lifetime_ = Some(parse_env("MAX_LIFETIME", &*v)?);
+        }
+        if let Some(v) = env_var("STATEMENT_TIMEOUT") {
+            self.statement_timeout_ = Some(parse_env("STATEMENT_TIMEOUT", &*v)?);
+        }
+        if let Some(v) = env_var("SCHEMAS") {
+            self.schemas_ = split_list(&*v);
+        }
+        if let Some(v) = env_var("INCLUDE") {
+            self.included_ = split_list(&*v);
+        }
+        if let Some(v) = env_var("EXCLUDE") {
+            self.excluded_ = split_list(&*v);
+        }
+        if let Some(v) = env_var("MAX_LIMIT") {
+            self.max_limit_ = Some(parse_env("MAX_LIMIT", &*v)?);
+        }
+        if let Some(v) = env_var("CORS_ALLOWED_ORIGINS") {
+            self.cors_.allowed_origins = split_list(&*v);
+        }
+        if let Some(v) = env_var("CORS_ALLOWED_HEADERS") {
+            self.cors_.allowed_headers = split_list(&*v);
+        }
+        if let Some(v) = env_var("CORS_MAX_AGE") {
+            self.cors_.max_age = Some(parse_env("CORS_MAX_AGE", &*v)?);
+        }
+        if let Some(v) = env_var("SSLMODE") {
+            self.tls_.mode = v;
+        }
+        if let Some(v) = env_var("SSLROOTCERT") {
+            self.tls_.ca_file = Some(v);
+        }
+        if let Some(v) = env_var("SSLCERT") {
+            self.tls_.cert_file = Some(v);
+        }
+        if let Some(v) = env_var("SSLKEY") {
+            self.tls_.key_file = Some(v);
+        }
+        if let Some(v) = env_var("JWT_SECRET") {
+            self.auth_mut().secret = Some(v);
+        }
+        if let Some(v) = env_var("JWT_PUBLIC_KEY_FILE") {
+            self.auth_mut().public_key_file = Some(v);
+        }
+        if let Some(v) = env_var("JWT_JWKS_FILE") {
+            self.auth_mut().jwks_file = Some(v);
+        }
+        if let Some(v) = env_var("JWT_ROLE_CLAIM") {
+            self.auth_mut().role_claim = v;
+        }
+        if let Some(v) = env_var("JWT_AUDIENCE") {
+            self.auth_mut().audience = Some(v);
+        }
+        if let Some(v) = env_var("ANONYMOUS_ROLE") {
+            self.auth_mut().anonymous_role = Some(v);
+        }
+        if let Some(v) = env_var("WORKERS") {
+            self.workers_ = Some(parse_env("WORKERS", &*v)?);
+        }
+        if let Some(v) = env_var("STREAM") {
+            self.stream_ = parse_env("STREAM", &*v)?;
+        }
+        if let Some(v) = env_var("STREAM_TIMEOUT") {
+            self.stream_timeout_ = parse_env("STREAM_TIMEOUT", &*v)?;
+        }
+        if let Some(v) = env_var("CONTROL_PREFIX") {
+            self.control_prefix_ = v;
+        }
+        if let Some(v) = env_var("RELOAD_TOKEN") {
+            self.reload_token_ = Some(v);
+        }
+        if let Some(v) = env_var("RELOAD_CHANNEL") {
+            self.reload_channel_ = Some(v);
+        }
+        if let Some(v) = env_var("READ_ONLY") {
+            self.read_only_ = parse_env("READ_ONLY", &*v)?;
+        }
+        if let Some(v) = env_var("REQUEST_HEADERS") {
+            self.request_headers_ = split_list(&*v);
+        }
+        if let Some(v) = env_var("REQUEST_COOKIES") {
+            self.request_cookies_ = split_list(&*v);
+        }
+        Ok(self)
+    }
+
+    fn auth_mut(&mut self) -> &mut AuthConfig {
+        if self.auth_.is_none() {
+            self.auth_ = Some(Default::default());
+        }
+        self.auth_.as_mut().unwrap()
+    }
+
+    /// Ensure the configuration is coherent.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.excluded_.len() != 0 && self.included_.len() != 0 {
+            return Err(format!("cannot specify both excluded and included schemas"));
+        }
+        if self.pool_size_ == 0 {
+            return Err(format!("pool size must be greater than 0"));
+        }
+        if self.workers_ == Some(0) {
+            return Err(format!("workers must be greater than 0"));
+        }
+        if self.min_idle_.map_or(false, |m| m > self.pool_size_) {
+            return Err(format!("min idle connections cannot be greater than the pool size"));
+        }
+        if SslMode::from_str(&*self.tls_.mode).is_err() {
+            return Err(format!("invalid tls mode {}, expected disable, prefer, require \
+                                or verify-full", self.tls_.mode));
+        }
+        for (table, methods) in &self.permissions_ {
+            validate_methods(table, methods)?;
+        }
+        for (table, columns) in &self.columns_ {
+            for (column, visibility) in columns {
+                if Visibility::from_str(visibility).is_err() {
+                    return Err(format!("invalid visibility {} for column {}.{}, expected \
+                                        visible, hidden, read_only or write_only",
+                                       visibility, table, column));
+                }
+            }
+        }
+        for (role, tables) in &self.role_permissions_ {
+            if self.auth_.is_none() {
+                return Err(format!("role permissions of {} require authentication to be \
+                                    configured", role));
+            }
+            for (table, methods) in tables {
+                validate_methods(table, methods)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn database_url(&self) -> Option<&str> {
+        self.database_url_.as_ref().map(|s| &**s)
+    }
+
     pub fn timeout(&self) -> u64 {
         self.timeout_
     }
 
-    pub fn excluded(& self) -> &[&str] {
+    pub fn pool_size(&self) -> u32 {
+        self.pool_size_
+    }
+
+    pub fn min_idle(&self) -> Option<u32> {
+        self.min_idle_
+    }
+
+    pub fn idle_timeout(&self) -> Option<u64> {
+        self.idle_timeout_
+    }
+
+    pub fn max_lifetime(&self) -> Option<u64> {
+        self.max_lifetime_
+    }
+
+    pub fn statement_timeout(&self) -> Option<u64> {
+        self.statement_timeout_
+    }
+
+    pub fn schemas(&self) -> &[String] {
+        &*self.schemas_
+    }
+
+    pub fn excluded(&self) -> &[String] {
         &*self.excluded_
     }
 
-    pub fn included(&self) -> &[&str] {
+    pub fn included(&self) -> &[String] {
         &*self.included_
     }
+
+    /// The methods allowed on a table, `None` if the table has no
+    /// restrictions.
+    pub fn permissions(&self, table: &str) -> Option<Vec<Method>> {
+        self.permissions_.get(table).map(|ms| parse_methods(ms))
+    }
+
+    /// The methods allowed on a table for a role, the permissions of the role
+    /// take precedence over the permissions of the table. The read only mode
+    /// is applied on top of both.
+    pub fn allowed_methods(&self, role: Option<&str>, table: &str) -> Vec<Method> {
+        let role_permissions = role
+            .and_then(|r| self.role_permissions_.get(r))
+            .and_then(|tables| tables.get(table))
+            .map(|ms| parse_methods(ms));
+        let allowed = match role_permissions {
+            Some(allowed) => allowed,
+            None => self.permissions(table).unwrap_or(Method::all()),
+        };
+        if self.read_only_ {
+            return allowed.into_iter().filter(|m| *m == Method::Get).collect();
+        }
+        allowed
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only_
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers_.unwrap_or(self.pool_size_ as usize)
+    }
+
+    pub fn stream(&self) -> bool {
+        self.stream_
+    }
+
+    pub fn stream_timeout(&self) -> u64 {
+        self.stream_timeout_
+    }
+
+    pub fn control_prefix(&self) -> &str {
+        &*self.control_prefix_
+    }
+
+    pub fn reload_token(&self) -> Option<&str> {
+        self.reload_token_.as_ref().map(|s| &**s)
+    }
+
+    pub fn reload_channel(&self) -> Option<&str> {
+        self.reload_channel_.as_ref().map(|s| &**s)
+    }
+
+    /// The configured visibility of the columns of a table.
+    pub fn column_visibilities(&self, table: &str) -> Vec<(&str, Visibility)> {
+        match self.columns_.get(table) {
+            Some(columns) => columns.iter()
+                .filter_map(|(c, v)| Visibility::from_str(v).ok().map(|v| (&**c, v)))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Every table with configured column visibilities.
+    pub fn column_visibility_tables(&self) -> Vec<&str> {
+        self.columns_.keys().map(|t| &**t).collect()
+    }
+
+    pub fn max_limit(&self) -> Option<u32> {
+        self.max_limit_
+    }
+
+    pub fn cors(&self) -> &CorsConfig {
+        &self.cors_
+    }
+
+    pub fn tls(&self) -> &TlsConfig {
+        &self.tls_
+    }
+
+    /// The authentication configuration, None if authentication is disabled.
+    pub fn auth(&self) -> Option<&AuthConfig> {
+        self.auth_.as_ref()
+    }
+
+    pub fn request_headers(&self) -> &[String] {
+        &*self.request_headers_
+    }
+
+    pub fn request_cookies(&self) -> &[String] {
+        &*self.request_cookies_
+    }
 }
 
-impl<'r> Default for Config<'r> {
-    fn default() -> Config<'r> {
+impl Default for Config {
+    fn default() -> Config {
         Config {
+            database_url_: None,
             timeout_: DEFAULT_CONNECTION_TIMEOUT,
+            pool_size_: DEFAULT_POOL_SIZE,
+            min_idle_: None,
+            idle_timeout_: None,
+            max_lifetime_: None,
+            statement_timeout_: None,
+            schemas_: vec![],
             excluded_: vec![],
             included_: vec![],
+            permissions_: BTreeMap::new(),
+            role_permissions_: BTreeMap::new(),
+            columns_: BTreeMap::new(),
+            control_prefix_: String::new(),
+            workers_: None,
+            stream_: false,
+            stream_timeout_: DEFAULT_STREAM_TIMEOUT,
+            reload_token_: None,
+            reload_channel_: None,
+            read_only_: false,
+            max_limit_: None,
+            cors_: Default::default(),
+            tls_: Default::default(),
+            auth_: None,
+            request_headers_: vec![],
+            request_cookies_: vec![],
         }
     }
 }
 
-pub struct Builder<'r> {
-    config: Config<'r>,
+pub struct Builder {
+    config: Config,
 }
 
-impl<'r> Builder<'r> {
-    pub fn build(self) -> Config<'r> {
+impl Builder {
+    pub fn build(self) -> Config {
         self.config
     }
 
-    pub fn timeout(mut self, timeout: u64) -> Builder<'r> {
+    pub fn database_url(mut self, url: &str) -> Builder {
+        self.config.database_url_ = Some(url.into());
+        self
+    }
+
+    pub fn timeout(mut self, timeout: u64) -> Builder {
         self.config.timeout_ = timeout;
         self
     }
 
-    pub fn excluded(mut self, excluded: Vec<&'r str>) -> Builder<'r> {
-        self.config.excluded_ = excluded;
+    pub fn pool_size(mut self, pool_size: u32) -> Builder {
+        self.config.pool_size_ = pool_size;
+        self
+    }
+
+    pub fn min_idle(mut self, min_idle: u32) -> Builder {
+        self.config.min_idle_ = Some(min_idle);
+        self
+    }
+
+    pub fn idle_timeout(mut self, idle_timeout: u64) -> Builder {
+        self.config.idle_timeout_ = Some(idle_timeout);
+        self
+    }
+
+    pub fn max_lifetime(mut self, max_lifetime: u64) -> Builder {
+        self.config.max_lifetime_ = Some(max_lifetime);
+        self
+    }
+
+    pub fn statement_timeout(mut self, statement_timeout: u64) -> Builder {
+        self.config.statement_timeout_ = Some(statement_timeout);
+        self
+    }
+
+    pub fn schemas(mut self, schemas: Vec<&str>) -> Builder {
+        self.config.schemas_ = schemas.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    pub fn excluded(mut self, excluded: Vec<&str>) -> Builder {
+        self.config.excluded_ = excluded.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    pub fn included(mut self, included: Vec<&str>) -> Builder {
+        self.config.included_ = included.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    pub fn permissions(mut self, table: &str, methods: Vec<Method>) -> Builder {
+        self.config.permissions_.insert(table.into(),
+                                        methods.iter().map(|m| m.to_string()).collect());
+        self
+    }
+
+    pub fn role_permissions(mut self, role: &str, table: &str, methods: Vec<Method>) -> Builder {
+        self.config.role_permissions_.entry(role.into()).or_insert(BTreeMap::new())
+            .insert(table.into(), methods.iter().map(|m| m.to_string()).collect());
+        self
+    }
+
+    pub fn column_visibility(mut self, table: &str, column: &str, visibility: Visibility)
+                             -> Builder {
+        self.config.columns_.entry(table.into()).or_insert(BTreeMap::new())
+            .insert(column.into(), visibility.to_string());
+        self
+    }
+
+    pub fn workers(mut self, workers: usize) -> Builder {
+        self.config.workers_ = Some(workers);
+        self
+    }
+
+    pub fn stream(mut self, stream: bool) -> Builder {
+        self.config.stream_ = stream;
+        self
+    }
+
+    pub fn stream_timeout(mut self, stream_timeout: u64) -> Builder {
+        self.config.stream_timeout_ = stream_timeout;
+        self
+    }
+
+    pub fn control_prefix(mut self, prefix: &str) -> Builder {
+        self.config.control_prefix_ = prefix.into();
         self
     }
 
-    pub fn included(mut self, included: Vec<&'r str>) -> Builder<'r> {
-        self.config.included_ = included;
+    pub fn reload_token(mut self, token: &str) -> Builder {
+        self.config.reload_token_ = Some(token.into());
         self
     }
+
+    pub fn reload_channel(mut self, channel: &str) -> Builder {
+        self.config.reload_channel_ = Some(channel.into());
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Builder {
+        self.config.read_only_ = read_only;
+        self
+    }
+
+    pub fn max_limit(mut self, max_limit: u32) -> Builder {
+        self.config.max_limit_ = Some(max_limit);
+        self
+    }
+
+    pub fn cors(mut self, cors: CorsConfig) -> Builder {
+        self.config.cors_ = cors;
+        self
+    }
+
+    pub fn tls(mut self, tls: TlsConfig) -> Builder {
+        self.config.tls_ = tls;
+        self
+    }
+
+    pub fn auth(mut self, auth: AuthConfig) -> Builder {
+        self.config.auth_ = Some(auth);
+        self
+    }
+
+    pub fn request_headers(mut self, headers: Vec<&str>) -> Builder {
+        self.config.request_headers_ = headers.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    pub fn request_cookies(mut self, cookies: Vec<&str>) -> Builder {
+        self.config.request_cookies_ = cookies.iter().map(|s| s.to_string()).collect();
+        self
+    }
+}
+
+fn from_toml(s: &str) -> Result<Config, String> {
+    let mut parser = toml::Parser::new(s);
+    match parser.parse() {
+        Some(table) => {
+            let mut decoder = toml::Decoder::new(toml::Value::Table(table));
+            Config::deserialize(&mut decoder).map_err(|e| format!("{}", e))
+        },
+        None => Err(parser.errors.iter()
+                    .map(|e| format!("{}", e))
+                    .collect::<Vec<String>>()
+                    .join(", ")),
+    }
+}
+
+fn parse_methods(methods: &[String]) -> Vec<Method> {
+    methods.iter().filter_map(|m| Method::from_str(m).ok()).collect()
+}
+
+fn validate_methods(table: &str, methods: &[String]) -> Result<(), String> {
+    for m in methods {
+        if Method::from_str(m).is_err() {
+            return Err(format!("invalid method {} in permissions of table {}", m, table));
+        }
+    }
+    Ok(())
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(format!("{}{}", ENV_PREFIX, name)).ok()
+}
+
+fn parse_env<T: FromStr>(name: &str, v: &str) -> Result<T, String> {
+    match v.trim().parse::<T>() {
+        Ok(v) => Ok(v),
+        Err(_) => Err(format!("invalid value '{}' for {}{}", v, ENV_PREFIX, name)),
+    }
+}
+
+pub fn split_list(s: &str) -> Vec<String> {
+    s.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()).map(|s| s.to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    // write a config file in the temp dir and load it
+    fn load(name: &str, content: &str) -> Result<Config, String> {
+        let path = env::temp_dir().join(name);
+        File::create(&path).unwrap().write_all(content.as_bytes()).unwrap();
+        let config = Config::from_file(&path);
+        fs::remove_file(&path).unwrap();
+        config
+    }
+
+    #[test]
+    fn from_toml_file() {
+        let config = load("arrakis_config_test.toml", "\
+            database_url = \"postgres://localhost/db\"\n\
+            pool_size = 4\n\
+            schemas = [\"public\", \"api\"]\n\
+            [permissions]\n\
+            users = [\"GET\", \"PATCH\"]\n\
+            [cors]\n\
+            max_age = 60\n").unwrap();
+        assert_eq!(config.database_url(), Some("postgres://localhost/db"));
+        assert_eq!(config.pool_size(), 4);
+        assert_eq!(config.timeout(), DEFAULT_CONNECTION_TIMEOUT);
+        assert_eq!(config.schemas(), &["public".to_string(), "api".to_string()]);
+        assert_eq!(config.permissions("users"), Some(vec![Method::Get, Method::Patch]));
+        assert_eq!(config.cors().max_age, Some(60));
+    }
+
+    #[test]
+    fn from_yaml_and_json_files() {
+        let yaml = load("arrakis_config_test.yml", "pool_size: 3\nread_only: true\n").unwrap();
+        assert_eq!(yaml.pool_size(), 3);
+        assert!(yaml.read_only());
+        let json = load("arrakis_config_test.json",
+                        "{\"pool_size\": 2, \"include\": [\"users\"]}").unwrap();
+        assert_eq!(json.pool_size(), 2);
+        assert_eq!(json.included(), &["users".to_string()]);
+    }
+
+    #[test]
+    fn invalid_files() {
+        assert!(load("arrakis_config_test_unknown.toml", "pool_sise = 4\n").is_err());
+        assert!(load("arrakis_config_test_type.json", "{\"pool_size\": \"a\"}").is_err());
+        assert!(load("arrakis_config_test.ini", "pool_size = 4\n").is_err());
+        assert!(Config::from_file("/nonexistent/arrakis.toml").is_err());
+    }
+
+    // the only test touching the ARRAKIS_* variables, they are shared by the
+    // tests running in parallel
+    #[test]
+    fn env_overrides_file() {
+        let config = load("arrakis_config_test_env.toml", "\
+            pool_size = 4\n\
+            read_only = false\n\
+            schemas = [\"public\"]\n").unwrap();
+        env::set_var("ARRAKIS_POOL_SIZE", "8");
+        env::set_var("ARRAKIS_READ_ONLY", "true");
+        env::set_var("ARRAKIS_SCHEMAS", "api, private,");
+        let merged = config.clone().merge_env();
+        env::set_var("ARRAKIS_POOL_SIZE", "eight");
+        let invalid = config.merge_env();
+        env::remove_var("ARRAKIS_POOL_SIZE");
+        env::remove_var("ARRAKIS_READ_ONLY");
+        env::remove_var("ARRAKIS_SCHEMAS");
+
+        let merged = merged.unwrap();
+        assert_eq!(merged.pool_size(), 8);
+        assert!(merged.read_only());
+        assert_eq!(merged.schemas(), &["api".to_string(), "private".to_string()]);
+        assert_eq!(invalid.unwrap_err(), "invalid value 'eight' for ARRAKIS_POOL_SIZE");
+    }
+
+    #[test]
+    fn validate_rejects_invalid_values() {
+        assert!(Config::builder().build().validate().is_ok());
+        assert!(Config::builder().pool_size(0).build().validate().is_err());
+        assert!(Config::builder().workers(0).build().validate().is_err());
+        assert!(Config::builder().pool_size(2).min_idle(3).build().validate().is_err());
+        assert!(Config::builder().included(vec!["users"]).excluded(vec!["logs"])
+                .build().validate().is_err());
+        assert!(Config::builder().role_permissions("admin", "users", vec![Method::Get])
+                .build().validate().is_err());
+        let tls = TlsConfig { mode: "sometimes".into(), ..Default::default() };
+        assert!(Config::builder().tls(tls).build().validate().is_err());
+        let method = load("arrakis_config_test_method.toml",
+                          "[permissions]\nusers = [\"FETCH\"]\n").unwrap();
+        assert!(method.validate().is_err());
+        let visibility = load("arrakis_config_test_visibility.toml",
+                              "[columns.users]\npassword = \"secret\"\n").unwrap();
+        assert!(visibility.validate().is_err());
+    }
+
+    #[test]
+    fn allowed_methods() {
+        let config = Config::builder()
+            .permissions("users", vec![Method::Get, Method::Patch])
+            .role_permissions("admin", "users", Method::all())
+            .auth(Default::default())
+            .build();
+        assert_eq!(config.allowed_methods(None, "logs"), Method::all());
+        assert_eq!(config.allowed_methods(None, "users"), vec![Method::Get, Method::Patch]);
+        assert_eq!(config.allowed_methods(Some("guest"), "users"),
+                   vec![Method::Get, Method::Patch]);
+        assert_eq!(config.allowed_methods(Some("admin"), "users"), Method::all());
+
+        let read_only = config.into_builder().read_only(true).build();
+        assert_eq!(read_only.allowed_methods(None, "logs"), vec![Method::Get]);
+        assert_eq!(read_only.allowed_methods(Some("admin"), "users"), vec![Method::Get]);
+    }
 }
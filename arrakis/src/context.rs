@@ -0,0 +1,222 @@
+// Copyright 2016 Jeremy Letang.
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use common;
+use error::Error;
+use postgres::GenericConnection;
+use serde_json::{self, Value};
+use serde_json::Map as JsonMap;
+use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const CLAIMS_SETTING: &'static str = "request.jwt.claims";
+const CLAIM_SETTING_PREFIX: &'static str = "request.jwt.claim.";
+const HEADER_SETTING_PREFIX: &'static str = "request.header.";
+const COOKIE_SETTING_PREFIX: &'static str = "request.cookie.";
+const ID_SETTING: &'static str = "request.id";
+const CLIENT_IP_SETTING: &'static str = "request.client_ip";
+
+// requests created by this process, makes the request ids unique
+static REQUEST_COUNT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Per request context, applied at the beginning of the transaction of
+/// each request so postgres grants and row level security policies can
+/// rely on it.
+#[derive(Debug, Clone)]
+pub struct Context {
+    // the role the request is executed as, None to keep the role of the
+    // database login
+    pub role: Option<String>,
+    // the claims of the authentication token
+    pub claims: Option<JsonMap<String, Value>>,
+    // additional settings of the request (headers, cookies ...)
+    pub settings: Vec<(String, String)>,
+    // generated when the context is created
+    pub request_id: String,
+    // the address of the client, set by the http adapters
+    pub client_ip: Option<String>,
+}
+
+impl Default for Context {
+    fn default() -> Context {
+        Context {
+            role: None,
+            claims: None,
+            settings: vec![],
+            request_id: generate_request_id(),
+            client_ip: None,
+        }
+    }
+}
+
+impl Context {
+    pub fn new() -> Context {
+        Default::default()
+    }
+
+    pub fn with_role(role: &str) -> Context {
+        Context {
+            role: Some(role.into()),
+            ..Default::default()
+        }
+    }
+
+    /// Add a setting, available with `current_setting(name)` during the
+    /// request.
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.settings.push((name.into(), value.into()));
+    }
+
+    /// Expose the given http headers and cookies of the request to the
+    /// database.
+    ///
+    /// Headers are available as `request.header.<name>` and cookies as
+    /// `request.cookie.<name>`, names are lowercased and `-` replaced by `_`
+    /// to form valid setting names, e.g `request.header.x_tenant_id`.
+    /// Headers or cookies not sent with the request are not set.
+    pub fn set_request_metadata(&mut self, headers: &[(String, String)],
+                                wanted_headers: &[String], wanted_cookies: &[String]) {
+        for name in wanted_headers {
+            if let Some(v) = find_header(headers, name) {
+                let setting = format!("{}{}", HEADER_SETTING_PREFIX, setting_name(name));
+                self.set(&*setting, v);
+            }
+        }
+        if wanted_cookies.is_empty() {
+            return;
+        }
+        let cookies = match find_header(headers, "cookie") {
+            Some(c) => parse_cookies(c),
+            None => return,
+        };
+        for name in wanted_cookies {
+            if let Some(&(_, v)) = cookies.iter().find(|&&(k, _)| k == &**name) {
+                let setting = format!("{}{}", COOKIE_SETTING_PREFIX, setting_name(name));
+                self.set(&*setting, v);
+            }
+        }
+    }
+
+    /// Switch to the role of the request and expose its claims and settings.
+    ///
+    /// Every setting is local to the current transaction. The claims are
+    /// available as a json document with `current_setting('request.jwt.claims')`,
+    /// and one by one with `current_setting('request.jwt.claim.<name>')` when
+    /// their name only contains ascii letters, digits and underscores. The
+    /// request id is available as `request.id` and the address of the client
+    /// as `request.client_ip`.
+    pub fn apply(&self, conn: &GenericConnection) -> Result<(), Error> {
+        if let Some(ref role) = self.role {
+            conn.batch_execute(&*format!("SET LOCAL ROLE {}", common::quote_ident(role)))?;
+        }
+        set_local(conn, ID_SETTING, &*self.request_id)?;
+        if let Some(ref ip) = self.client_ip {
+            set_local(conn, CLIENT_IP_SETTING, ip)?;
+        }
+        if let Some(ref claims) = self.claims {
+            let json = serde_json::to_string(claims).unwrap_or("{}".into());
+            set_local(conn, CLAIMS_SETTING, &*json)?;
+            // the names come from the token, they cannot choose the setting
+            for (k, v) in claims.iter().filter(|&(k, _)| is_claim_name(k)) {
+                let v = match *v {
+                    Value::String(ref s) => s.clone(),
+                    Value::Null => continue,
+                    ref other => serde_json::to_string(other).unwrap_or(String::new()),
+                };
+                set_local(conn, &*format!("{}{}", CLAIM_SETTING_PREFIX, k), &*v)?;
+            }
+        }
+        for &(ref name, ref value) in &self.settings {
+            set_local(conn, name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Set a configuration parameter for the duration of the current transaction.
+pub fn set_local(conn: &GenericConnection, name: &str, value: &str) -> Result<(), Error> {
+    conn.execute("SELECT set_config($1, $2, true)", &[&name, &value])?;
+    Ok(())
+}
+
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter()
+        .find(|&&(ref k, _)| k.to_lowercase() == name.to_lowercase())
+        .map(|&(_, ref v)| &**v)
+}
+
+// parse the value of a Cookie header, e.g "a=1; b=2"
+fn parse_cookies(s: &str) -> Vec<(&str, &str)> {
+    s.split(';')
+        .filter_map(|c| {
+            let mut kv = c.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) => Some((k.trim(), v.trim().trim_matches('"'))),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn setting_name(name: &str) -> String {
+    name.to_lowercase().replace('-', "_")
+}
+
+fn is_claim_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| match b {
+        b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' | b'_' => true,
+        _ => false,
+    })
+}
+
+// the milliseconds since the epoch and a counter, unique for this process
+// and unlikely to be reused after a restart
+fn generate_request_id() -> String {
+    let millis = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1000000,
+        Err(_) => 0,
+    };
+    let n = REQUEST_COUNT.fetch_add(1, Ordering::Relaxed);
+    format!("{:x}-{:x}", millis, n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn claim_names() {
+        assert!(is_claim_name("sub"));
+        assert!(is_claim_name("tenant_ID_2"));
+        for name in &["", "a.b", "a-b", "a b", "é", "role'", "x\\y"] {
+            assert!(!is_claim_name(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn request_ids_are_unique() {
+        let a = Context::new();
+        let b = Context::with_role("web");
+        assert!(!a.request_id.is_empty());
+        assert!(a.request_id != b.request_id);
+        assert_eq!(a.clone().request_id, a.request_id);
+    }
+
+    #[test]
+    fn request_metadata() {
+        let headers = vec![("X-Tenant-Id".to_string(), "42".to_string()),
+                           ("User-Agent".to_string(), "curl".to_string()),
+                           ("Cookie".to_string(), "session=abc; theme=\"dark\"".to_string())];
+        let mut ctx = Context::new();
+        ctx.set_request_metadata(&*headers, &["x-tenant-id".into(), "accept".into()],
+                                 &["theme".into(), "missing".into()]);
+        assert_eq!(ctx.settings, vec![("request.header.x_tenant_id".to_string(), "42".to_string()),
+                                      ("request.cookie.theme".to_string(), "dark".to_string())]);
+        assert_eq!(parse_cookies("a=1;b"), vec![("a", "1")]);
+    }
+}
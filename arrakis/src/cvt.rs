@@ -6,51 +6,221 @@
 // except according to those terms.
 
 use postgres::rows::Row;
-use postgres::types::{FromSql, Type};
-use serde_json::value::{Value, ToJson};
-use serde::Serialize;
+use postgres::types::{FromSql, Kind, SessionInfo, Type};
+use error::Error;
+use serde_json::{self, Value};
+use std::error::Error as StdError;
 
-pub fn to_json<T>(row: &Row, idx: usize, is_nullable: bool) -> Value
-    where T: ToJson + FromSql + Serialize {
+// microseconds between the unix epoch and 2000-01-01, the postgres epoch
+const POSTGRES_EPOCH_MICROS: i64 = 946_684_800_000_000;
+const MICROS_PER_SEC: i64 = 1_000_000;
+
+/// A value of a row, typed from the type of its column. The response
+/// formats are written from it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cell {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Text(String),
+    Bytes(Vec<u8>),
+    // microseconds since the unix epoch
+    Timestamp(i64),
+    // json, jsonb and array values
+    Json(Value),
+}
+
+impl Cell {
+    /// The json value of the cell. Bytes are written as hex strings
+    /// (`\x0102`) and timestamps as unix timestamps in seconds.
+    pub fn into_json(self) -> Value {
+        match self {
+            Cell::Null => Value::Null,
+            Cell::Bool(b) => Value::Bool(b),
+            Cell::Int(i) => Value::I64(i),
+            Cell::Float(f) => Value::F64(f),
+            Cell::Text(s) => Value::String(s),
+            Cell::Bytes(b) => Value::String(bytes_to_hex(&*b)),
+            Cell::Timestamp(t) => Value::I64(split_micros(t).0),
+            Cell::Json(v) => v,
+        }
+    }
+}
+
+// a timestamp read in its binary format, without depending on a date crate
+struct RawTimestamp(i64);
+
+impl FromSql for RawTimestamp {
+    fn from_sql(_: &Type, raw: &[u8], _: &SessionInfo)
+                -> Result<RawTimestamp, Box<StdError + Sync + Send>> {
+        if raw.len() != 8 {
+            return Err("invalid timestamp".into());
+        }
+        let micros = raw.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64) as i64;
+        // infinite timestamps are clamped
+        Ok(RawTimestamp(micros.saturating_add(POSTGRES_EPOCH_MICROS)))
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::Timestamp || *ty == Type::TimestampTZ
+    }
+}
+
+fn get<T: FromSql>(row: &Row, idx: usize, is_nullable: bool) -> Option<T> {
     if is_nullable {
-        let value: Option<T> = row.get(idx);
-        value.to_json()
+        row.get(idx)
     } else {
-        let value: T = row.get(idx);
-        value.to_json()
+        Some(row.get(idx))
     }
 }
 
-pub fn timestamp_to_json(row: &Row, idx: usize, is_nullable: bool) -> Value {
-    return Value::I64(42);
+// the types read in their binary format, the others are selected as text
+fn is_binary(ty: &Type) -> bool {
+    match *ty {
+        Type::Bool | Type::Char | Type::Int2 | Type::Int4 | Type::Int8 | Type::Float4
+            | Type::Float8 | Type::Varchar | Type::Text | Type::Bytea | Type::Timestamp
+            | Type::TimestampTZ => true,
+        _ => false,
+    }
+}
+
+fn is_json(ty: &Type) -> bool {
+    match *ty.kind() {
+        Kind::Array(_) => true,
+        _ => *ty == Type::Json || *ty == Type::Jsonb,
+    }
+}
+
+/// The expression a column of type `ty` must be selected with to be read by
+/// `row_field_to_cell`: json, jsonb and arrays are selected as json text,
+/// the other types without a binary reader (numeric, uuid, dates, enums
+/// ...) as their text representation.
+pub fn select_expr(column: &str, ty: &Type) -> String {
+    match *ty.kind() {
+        _ if is_binary(ty) => column.to_string(),
+        Kind::Array(_) => format!("array_to_json({})::text", column),
+        _ => format!("{}::text", column),
+    }
+}
+
+/// The expression of a column in a json object built by postgres (e.g with
+/// `row_to_json`), the values are the ones of the json responses.
+pub fn json_expr(column: &str, ty: &Type) -> String {
+    match *ty {
+        _ if is_json(ty) => column.to_string(),
+        Type::Bool | Type::Int2 | Type::Int4 | Type::Int8 | Type::Float4 | Type::Float8
+            | Type::Varchar | Type::Text | Type::Bytea => column.to_string(),
+        Type::Char => format!("{}::int", column),
+        Type::Timestamp | Type::TimestampTZ =>
+            format!("floor(extract(epoch FROM {}))::bigint", column),
+        _ => format!("{}::text", column),
+    }
+}
+
+// the json of a json text, kept as text if postgres sent something else
+fn json_cell(s: String) -> Cell {
+    match serde_json::from_str(&*s) {
+        Ok(v) => Cell::Json(v),
+        Err(_) => Cell::Text(s),
+    }
+}
+
+/// Read a value of a row, the column must have been selected with
+/// `select_expr`.
+pub fn row_field_to_cell(row: &Row, idx: usize, is_nullable: bool, ty: Type) -> Cell {
+    let cell = match ty {
+        Type::Bool => get::<bool>(row, idx, is_nullable).map(Cell::Bool),
+        Type::Char => get::<i8>(row, idx, is_nullable).map(|v| Cell::Int(v as i64)),
+        Type::Int2 => get::<i16>(row, idx, is_nullable).map(|v| Cell::Int(v as i64)),
+        Type::Int4 => get::<i32>(row, idx, is_nullable).map(|v| Cell::Int(v as i64)),
+        Type::Int8 => get::<i64>(row, idx, is_nullable).map(Cell::Int),
+        Type::Float4 => get::<f32>(row, idx, is_nullable).map(|v| Cell::Float(v as f64)),
+        Type::Float8 => get::<f64>(row, idx, is_nullable).map(Cell::Float),
+        Type::Varchar => get::<String>(row, idx, is_nullable).map(Cell::Text),
+        Type::Text => get::<String>(row, idx, is_nullable).map(Cell::Text),
+        Type::Bytea => get::<Vec<u8>>(row, idx, is_nullable).map(Cell::Bytes),
+        Type::Timestamp | Type::TimestampTZ =>
+            get::<RawTimestamp>(row, idx, is_nullable).map(|t| Cell::Timestamp(t.0)),
+        _ if is_json(&ty) => get::<String>(row, idx, is_nullable).map(json_cell),
+        _ => get::<String>(row, idx, is_nullable).map(Cell::Text),
+    };
+    cell.unwrap_or(Cell::Null)
 }
 
 pub fn row_field_to_json_value(row: &Row, idx: usize, is_nullable: bool, ty: Type) -> Value {
-    match ty {
-        Type::Bool => to_json::<bool>(row, idx, is_nullable),
-        Type::Char => to_json::<i8>(row, idx, is_nullable),
-        Type::Int2 => to_json::<i16>(row, idx, is_nullable),
-        Type::Int4 => to_json::<i32>(row, idx, is_nullable),
-        Type::Int8 => to_json::<i64>(row, idx, is_nullable),
-        Type::Float4 => to_json::<f32>(row, idx, is_nullable),
-        Type::Float8 => to_json::<f64>(row, idx, is_nullable),
-        Type::Varchar => to_json::<String>(row, idx, is_nullable),
-        Type::Text => to_json::<String>(row, idx, is_nullable),
-        Type::Timestamp => timestamp_to_json(row, idx, is_nullable),
-        _ => Value::String("".to_string()),
-    }
-}
-
-pub fn json_value_to_string(v: &Value) -> String {
+    row_field_to_cell(row, idx, is_nullable, ty).into_json()
+}
+
+/// Write bytes in the hex format of postgres, e.g `\x0102`.
+pub fn bytes_to_hex(b: &[u8]) -> String {
+    let mut s = String::with_capacity(2 + b.len() * 2);
+    s.push_str("\\x");
+    for byte in b {
+        s.push_str(&*format!("{:02x}", byte));
+    }
+    s
+}
+
+/// Split a unix timestamp in microseconds in seconds and nanoseconds.
+pub fn split_micros(micros: i64) -> (i64, u32) {
+    let mut secs = micros / MICROS_PER_SEC;
+    let mut rem = micros % MICROS_PER_SEC;
+    if rem < 0 {
+        secs -= 1;
+        rem += MICROS_PER_SEC;
+    }
+    (secs, (rem * 1000) as u32)
+}
+
+/// The value of a timestamp of a request body: a unix timestamp in seconds,
+/// as in the json bodies, with a fractional part if it is not a whole
+/// second.
+pub fn timestamp_value(secs: i64, nanos: u32) -> Value {
+    match nanos {
+        0 => Value::I64(secs),
+        _ => Value::F64(secs as f64 + nanos as f64 / 1e9),
+    }
+}
+
+/// Format a unix timestamp as an utc timestamp literal postgres can read,
+/// e.g `2017-01-31 12:00:00.000000+00`.
+pub fn format_timestamp(secs: i64, nanos: u32) -> String {
+    let days = if secs >= 0 { secs / 86400 } else { (secs - 86399) / 86400 };
+    let secs_of_day = secs - days * 86400;
+    let (y, m, d) = civil_from_days(days);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:06}+00", y, m, d, secs_of_day / 3600,
+            secs_of_day / 60 % 60, secs_of_day % 60, nanos / 1000)
+}
+
+// the date of a number of days since the unix epoch, in the proleptic
+// gregorian calendar
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let y = yoe + era * 400;
+    (if m <= 2 { y + 1 } else { y }, m, d)
+}
+
+/// The text of a scalar json value, arrays and objects cannot be written as
+/// the value of a column.
+pub fn json_value_to_string(v: &Value) -> Result<String, Error> {
     use serde_json::Value::*;
     match v {
-        &Null => "NULL".to_string(),
-        &Bool(b) => b.to_string(),
-        &I64(i) => i.to_string(),
-        &U64(u) => u.to_string(),
-        &F64(f) => f.to_string(),
-        &String(ref s) => s.clone(),
-        _ => unimplemented!(),
+        &Null => Ok("NULL".to_string()),
+        &Bool(b) => Ok(b.to_string()),
+        &I64(i) => Ok(i.to_string()),
+        &U64(u) => Ok(u.to_string()),
+        &F64(f) => Ok(f.to_string()),
+        &String(ref s) => Ok(s.clone()),
+        &Array(_) | &Object(_) => Err(Error::InvalidInputError(
+            "arrays and objects cannot be used as the value of a column".into())),
     }
 }
 
@@ -65,7 +235,97 @@ pub fn postgres_to_json_type(ty: &Type) -> &'static str {
         Type::Float8 => "number",
         Type::Varchar => "string",
         Type::Text => "string",
+        Type::Bytea => "string",
         Type::Timestamp => "number",
+        Type::TimestampTZ => "number",
         _ => "unknown",
     }
 }
+
+/// The GraphQL scalar of a column type, see the `graphql` module. The 64
+/// bits integers do not fit the 32 bits `Int` scalar, and the timestamps are
+/// unix timestamps in seconds as in the json responses.
+pub fn postgres_to_graphql_type(ty: &Type) -> &'static str {
+    match *ty {
+        Type::Bool => "Boolean",
+        Type::Char => "Int",
+        Type::Int2 => "Int",
+        Type::Int4 => "Int",
+        Type::Int8 => "BigInt",
+        Type::Float4 => "Float",
+        Type::Float8 => "Float",
+        Type::Timestamp => "Timestamp",
+        Type::TimestampTZ => "Timestamp",
+        _ => "String",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_of_cells() {
+        assert_eq!(Cell::Bytes(vec![1, 0xab]).into_json(), Value::String("\\x01ab".into()));
+        assert_eq!(Cell::Timestamp(1_500_000_000_999_999).into_json(),
+                   Value::I64(1_500_000_000));
+        // the seconds are rounded down before the epoch too
+        assert_eq!(Cell::Timestamp(-1).into_json(), Value::I64(-1));
+        assert_eq!(Cell::Null.into_json(), Value::Null);
+        assert_eq!(bytes_to_hex(&[]), "\\x");
+        let v = Value::Array(vec![Value::I64(1), Value::Null]);
+        assert_eq!(Cell::Json(v.clone()).into_json(), v);
+    }
+
+    #[test]
+    fn selected_expressions() {
+        assert_eq!(select_expr("\"t\".\"id\"", &Type::Int4), "\"t\".\"id\"");
+        assert_eq!(select_expr("\"t\".\"at\"", &Type::TimestampTZ), "\"t\".\"at\"");
+        assert_eq!(select_expr("\"t\".\"doc\"", &Type::Jsonb), "\"t\".\"doc\"::text");
+        assert_eq!(select_expr("\"t\".\"tags\"", &Type::TextArray),
+                   "array_to_json(\"t\".\"tags\")::text");
+        assert_eq!(select_expr("\"t\".\"price\"", &Type::Numeric), "\"t\".\"price\"::text");
+        assert_eq!(select_expr("\"t\".\"mood\"", &Type::Unknown), "\"t\".\"mood\"::text");
+    }
+
+    #[test]
+    fn json_expressions() {
+        assert_eq!(json_expr("\"t\".\"id\"", &Type::Int4), "\"t\".\"id\"");
+        assert_eq!(json_expr("\"t\".\"doc\"", &Type::Jsonb), "\"t\".\"doc\"");
+        assert_eq!(json_expr("\"t\".\"tags\"", &Type::TextArray), "\"t\".\"tags\"");
+        assert_eq!(json_expr("\"t\".\"at\"", &Type::TimestampTZ),
+                   "floor(extract(epoch FROM \"t\".\"at\"))::bigint");
+        assert_eq!(json_expr("\"t\".\"price\"", &Type::Numeric), "\"t\".\"price\"::text");
+    }
+
+    #[test]
+    fn json_cells() {
+        assert_eq!(json_cell("[1,2]".into()),
+                   Cell::Json(Value::Array(vec![Value::U64(1), Value::U64(2)])));
+        assert_eq!(json_cell("{not json".into()), Cell::Text("{not json".into()));
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(split_micros(1_500_000), (1, 500_000_000));
+        assert_eq!(split_micros(-1_500_000), (-2, 500_000_000));
+        assert_eq!(format_timestamp(0, 0), "1970-01-01 00:00:00.000000+00");
+        assert_eq!(format_timestamp(951_782_400, 0), "2000-02-29 00:00:00.000000+00");
+        assert_eq!(format_timestamp(1_500_000_000, 123_456_789),
+                   "2017-07-14 02:40:00.123456+00");
+        assert_eq!(format_timestamp(-1, 999_999_000), "1969-12-31 23:59:59.999999+00");
+        assert_eq!(format_timestamp(-31_536_000, 0), "1969-01-01 00:00:00.000000+00");
+        assert_eq!(format_timestamp(-62_135_596_800, 0), "0001-01-01 00:00:00.000000+00");
+        assert_eq!(timestamp_value(-2, 0), Value::I64(-2));
+        assert_eq!(timestamp_value(-2, 500_000_000), Value::F64(-1.5));
+    }
+
+    #[test]
+    fn text_of_json_values() {
+        assert_eq!(json_value_to_string(&Value::F64(1.5)).unwrap(), "1.5");
+        assert_eq!(json_value_to_string(&Value::U64(::std::u64::MAX)).unwrap(),
+                   "18446744073709551615");
+        assert_eq!(json_value_to_string(&Value::Bool(false)).unwrap(), "false");
+        assert!(json_value_to_string(&Value::Array(vec![])).is_err());
+    }
+}
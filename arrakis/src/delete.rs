@@ -7,22 +7,24 @@
 
 use common;
 use error::Error;
-use queries::Queries;
-use postgres::Connection;
+use query::Query;
+use postgres::GenericConnection;
 use schema::Table;
 
 pub fn generate_delete() -> String {
     return "DELETE".into();
 }
 
-pub fn query(conn: &Connection, table: &Table, queries: &Queries)
-             -> Result<(), Error> {
+/// Delete the rows matching the filters of the query, and return their
+/// number.
+pub fn query(conn: &GenericConnection, table: &Table, q: &Query)
+             -> Result<u64, Error> {
     let query = generate_delete();
-    let query = common::generate_from(query, &*table.name);
-    let query = common::generate_where(query, table, queries)?;
+    let query = common::generate_from(query, table);
+    let query = common::generate_where(query, table, q)?;
     debug!("arrakis query: {}", query);
-    match conn.query(&*query, &[]) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(Error::InternalError("internal database error".into()))
+    match conn.execute(&*query, &[]) {
+        Ok(n) => Ok(n),
+        Err(e) => Err(Error::from(e)),
     }
 }
@@ -16,10 +16,16 @@ pub const DOC_BASE: &'static str = "
 <head>
 <body>
 <article class=\"markdown-body\">
+<p>An OpenAPI 3 document of the api, generated from the schema of the database, is served at <i>/builtins/openapi.json</i>.</p>
+<p>The tables, methods and columns served are restricted to the privileges of the database role arrakis connects with. With json web token authentication the requests are executed as the role of their token instead: every table is served and documented, and the database rejects the requests the role has no privilege for.</p>
+<p>A GraphQL endpoint is served at <i>/graphql</i>, with GET (queries only) and POST requests, the bodies being a json object with <i>query</i>, <i>variables</i> and <i>operationName</i> members or an <i>application/graphql</i> query. Each table readable by the role of the request is a list field taking <i>where</i>, <i>order_by</i>, <i>limit</i> and <i>offset</i> arguments and a <i>mytable_by_pk</i> field, the foreign keys are fields of both tables, and the writable tables have <i>insert_mytable</i>, <i>update_mytable</i> and <i>delete_mytable</i> mutations. Each root field is a single sql query, and the schema can be introspected, e.g by GraphiQL.</p>
 <h2>Selecting fields</h2>
 <p>You can easily control what is return by the api, just specify the fields list you want inside the json response using the <i>select</i> query parameter:</p>
 <code>https://myapi.com/mymodel?select=id,name</code> <br/>
 <p>This call will return a json list of objects mymodel, with only the id and name fields.</p>
+<p>The rows related to each row by a foreign key can be embedded, as an object for the row referenced by the table or as a list for the rows referencing it, <i>*</i> selecting every field:</p>
+<code>https://myapi.com/posts?select=id,title,users(name),comments(*)</code> <br/>
+<p>When several foreign keys relate the two tables, the column of the foreign key follows a <i>!</i>, and the embedded rows can be given another name: <i>select=*,author:users!author_id(name),editor:users!editor_id(name)</i>. Embedded rows can embed their own related rows, and require the GET method on their table.</p>
 <h2>Availables filters</h2>
 <table>
   <thread>
@@ -45,6 +51,52 @@ pub const DOC_BASE: &'static str = "
     <tr><td>not</td><td>NOT</td><td>myfield=not.eq.42</td></tr>
   </tbody>
 <table>
+<h2>Errors</h2>
+<p>Errors are returned as <i>application/problem+json</i> documents (RFC 7807). On top of the <i>type</i>, <i>title</i>, <i>status</i> and <i>detail</i> members, every error contains a stable <i>code</i>, and when relevant the offending <i>table</i>, <i>column</i> or <i>filter</i>, and a <i>hint</i>:</p>
+<pre><code>{\"type\": \"urn:arrakis:error:unknown_column\", \"title\": \"unknow column\", \"status\": 400, \"code\": \"unknown_column\", \"column\": \"nme\", \"table\": \"mymodel\", \"detail\": \"column 'nme' do not exist for table 'mymodel'\"}</code></pre>
+<table>
+  <thread>
+    <tr>
+      <th>Code</th>
+      <th>Status</th>
+    </tr>
+  </thread>
+  <tbody>
+    <tr><td>not_found</td><td>404</td></tr>
+    <tr><td>invalid_filter</td><td>400</td></tr>
+    <tr><td>invalid_filter_syntax</td><td>400</td></tr>
+    <tr><td>invalid_filter_type</td><td>400</td></tr>
+    <tr><td>invalid_column_type</td><td>400</td></tr>
+    <tr><td>unknown_model</td><td>400</td></tr>
+    <tr><td>unknown_column</td><td>400</td></tr>
+    <tr><td>invalid_input</td><td>400</td></tr>
+    <tr><td>import_failed</td><td>400</td></tr>
+    <tr><td>validation_failed</td><td>400</td></tr>
+    <tr><td>unauthorized</td><td>401</td></tr>
+    <tr><td>forbidden</td><td>403</td></tr>
+    <tr><td>method_not_allowed</td><td>405</td></tr>
+    <tr><td>not_acceptable</td><td>406</td></tr>
+    <tr><td>internal_error</td><td>500</td></tr>
+    <tr><td>unavailable</td><td>503</td></tr>
+    <tr><td>timeout</td><td>504</td></tr>
+  </tbody>
+</table>
+";
+
+// PREFIX is replaced by the configured control prefix
+pub const CONTROL_PARAMETERS: &'static str = "
+<h2>Control parameters</h2>
+<p>The <i>PREFIXselect</i>, <i>PREFIXlimit</i>, <i>PREFIXoffset</i>, <i>PREFIXorder</i>, <i>PREFIXformat</i>, <i>PREFIXdelimiter</i>, <i>PREFIXnull</i>, <i>PREFIXon_conflict</i> and <i>PREFIXvariant</i> query parameters control the request, every other parameter is a filter. To filter on a column named like a control parameter, quote its name:</p>
+<code>https://myapi.com/mymodel?\"PREFIXorder\"=eq.42</code> <br/>
+<p>GET responses are rendered as csv, with a header line, when the request sends <i>Accept: text/csv</i> or <i>PREFIXformat=csv</i>. The null values are empty fields and the empty strings are written <i>\"\"</i>, so the responses can be loaded back by the csv import:</p>
+<code>https://myapi.com/mymodel?PREFIXformat=csv</code> <br/>
+<p>With <i>Accept: application/x-ndjson</i> or <i>PREFIXformat=ndjson</i>, GET responses are written one json object per line, without the <i>data</i> envelope. A POST request with <i>Content-Type: application/x-ndjson</i> inserts one row per line of the body, the body is read whole and the rows are inserted in a single transaction.</p>
+<p>GET responses are encoded in MessagePack or CBOR with <i>Accept: application/msgpack</i> or <i>Accept: application/cbor</i> (or <i>PREFIXformat=msgpack</i>, <i>PREFIXformat=cbor</i>), bytea and timestamp columns use the native types of these formats. The same content types are accepted for the request bodies, and the responses of the other methods are encoded in the format negotiated from their Accept header. MessagePack arrays start with their length, a MessagePack GET response is therefore held in memory until its last row is read, CBOR responses are streamed.</p>
+<p>GET responses are written as an Apache Arrow IPC stream with <i>Accept: application/vnd.apache.arrow.stream</i> (or <i>PREFIXformat=arrow</i>). The schema is derived from the types and the nullability of the selected columns, the unsupported types are written as strings, and a record batch is sent every 1024 rows. Arrow is not accepted for the request bodies.</p>
+<p>The media types of the Accept header are tried by decreasing quality value, the ones with <i>q=0</i> are never used. Responses are sent as json when there is no Accept header, and a not_acceptable error is returned when the header only lists unsupported media types.</p>
+<p>A POST request with <i>Content-Type: text/csv</i> loads every row of the body, the first line contains the names of the columns. <i>PREFIXdelimiter</i> sets the separator (<i>,</i> by default), <i>PREFIXnull</i> the string of the null values (empty by default) and <i>PREFIXon_conflict=update</i> or <i>PREFIXon_conflict=nothing</i> updates or skips the rows whose primary key exists. Nothing is loaded if a row fails, the failing lines are listed in the error:</p>
+<code>curl -X POST -H 'Content-Type: text/csv' --data-binary @rows.csv https://myapi.com/mymodel?PREFIXon_conflict=update</code> <br/>
+<p>The JSON Schema (draft 2020-12) of the bodies of a table is served at <i>/builtins/schema/mytable</i>, and at <i>/builtins/schema/mytable?PREFIXvariant=update</i> for the PATCH requests. It is derived from the types, lengths, nullability, defaults, enum labels and check constraints of the columns, and the bodies of the POST and PATCH requests are validated against it, the invalid values are listed in the <i>errors</i> member of a validation_failed error. Timestamps are unix timestamps in seconds, as in the responses. The regular expressions of the check constraints are only checked when their syntax means the same for postgres and for the validator, the database enforces the other ones.</p>
 ";
 
 pub const TABLE_HEADER: &'static str = "
@@ -54,6 +106,7 @@ pub const TABLE_HEADER: &'static str = "
       <th>Field</th>
       <th>Postgres type</th>
       <th>JSON type</th>
+      <th>Access</th>
     </tr>
   </thread>
   <tbody>
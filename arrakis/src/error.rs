@@ -5,9 +5,22 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use method::Method;
+use postgres::error::{Error as PgError, SqlState};
+use serde_json::Value;
+use serde_json::Map as JsonMap;
 use std::error;
 use std::fmt;
 
+/// Media type of the error bodies built by `Error::to_problem` (RFC 7807).
+pub const PROBLEM_CONTENT_TYPE: &'static str = "application/problem+json";
+
+const PROBLEM_TYPE_PREFIX: &'static str = "urn:arrakis:error:";
+
+// delay in seconds clients are asked to wait before retrying a request which
+// failed because the database was busy.
+const RETRY_AFTER: u64 = 1;
+
 #[derive(Debug)]
 pub enum Error {
     NotFound(String),
@@ -19,6 +32,18 @@ pub enum Error {
     UnknowColumn(String, String),
     InvalidInputError(String),
     InternalError(String),
+    // the method and the allowed methods
+    MethodNotAllowed(String, Vec<Method>),
+    Unavailable(String),
+    Timeout(String),
+    Unauthorized(String),
+    Forbidden(String),
+    // the failing lines with their error, and a message
+    ImportFailed(Vec<(u64, String)>, String),
+    // the table and the json pointers of the invalid values with their error
+    ValidationFailed(String, Vec<(String, String)>),
+    // none of the media types of the Accept header can be produced
+    NotAcceptable(String),
 }
 
 impl error::Error for Error {
@@ -33,6 +58,14 @@ impl error::Error for Error {
             Error::UnknowColumn(..) => "unknow column",
             Error::InvalidInputError(..) => "invalid input",
             Error::InternalError(..) => "internal error",
+            Error::MethodNotAllowed(..) => "method not allowed",
+            Error::Unavailable(..) => "service unavailable",
+            Error::Timeout(..) => "timeout",
+            Error::Unauthorized(..) => "unauthorized",
+            Error::Forbidden(..) => "forbidden",
+            Error::ImportFailed(..) => "import failed",
+            Error::ValidationFailed(..) => "validation failed",
+            Error::NotAcceptable(..) => "not acceptable",
         }
     }
 
@@ -58,6 +91,219 @@ impl fmt::Display for Error {
                 write!(fmt, "column '{}' do not exist for table '{}'", c, m),
             Error::InvalidInputError(ref s) => write!(fmt, "invalid input: {}", s),
             Error::InternalError(ref s) => write!(fmt, "internal error, {}", s),
+            Error::MethodNotAllowed(ref m, _) => write!(fmt, "method not allowed {}", m),
+            Error::Unavailable(ref s) => write!(fmt, "service unavailable, {}", s),
+            Error::Timeout(ref s) => write!(fmt, "timeout, {}", s),
+            Error::Unauthorized(ref s) => write!(fmt, "unauthorized, {}", s),
+            Error::Forbidden(ref s) => write!(fmt, "forbidden, {}", s),
+            Error::ImportFailed(_, ref s) => write!(fmt, "import failed, {}", s),
+            Error::ValidationFailed(ref t, ref errors) => {
+                let errors = errors.iter()
+                    .map(|&(ref p, ref e)| match p.is_empty() {
+                        true => e.clone(),
+                        false => format!("{}: {}", p, e),
+                    })
+                    .collect::<Vec<String>>();
+                write!(fmt, "invalid row for table '{}', {}", t, errors.join(", "))
+            },
+            Error::NotAcceptable(ref s) => write!(fmt, "not acceptable, {}", s),
+        }
+    }
+}
+
+impl Error {
+    /// Stable identifier of the error kind, clients should match on this
+    /// instead of the human readable message.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            Error::NotFound(..) => "not_found",
+            Error::InvalidFilter(..) => "invalid_filter",
+            Error::InvalidFilterSyntax(..) => "invalid_filter_syntax",
+            Error::InvalidFilterType(..) => "invalid_filter_type",
+            Error::InvalidColumnType(..) => "invalid_column_type",
+            Error::UnknowModel(..) => "unknown_model",
+            Error::UnknowColumn(..) => "unknown_column",
+            Error::InvalidInputError(..) => "invalid_input",
+            Error::InternalError(..) => "internal_error",
+            Error::MethodNotAllowed(..) => "method_not_allowed",
+            Error::Unavailable(..) => "unavailable",
+            Error::Timeout(..) => "timeout",
+            Error::Unauthorized(..) => "unauthorized",
+            Error::Forbidden(..) => "forbidden",
+            Error::ImportFailed(..) => "import_failed",
+            Error::ValidationFailed(..) => "validation_failed",
+            Error::NotAcceptable(..) => "not_acceptable",
+        }
+    }
+
+    /// The http status code to use when this error is sent back to a client.
+    pub fn status(&self) -> u16 {
+        match *self {
+            Error::NotFound(..) => 404,
+            Error::InvalidFilter(..) => 400,
+            Error::InvalidFilterSyntax(..) => 400,
+            Error::InvalidFilterType(..) => 400,
+            Error::InvalidColumnType(..) => 400,
+            Error::UnknowModel(..) => 400,
+            Error::UnknowColumn(..) => 400,
+            Error::InvalidInputError(..) => 400,
+            Error::InternalError(..) => 500,
+            Error::MethodNotAllowed(..) => 405,
+            Error::Unavailable(..) => 503,
+            Error::Timeout(..) => 504,
+            Error::Unauthorized(..) => 401,
+            Error::Forbidden(..) => 403,
+            Error::ImportFailed(..) => 400,
+            Error::ValidationFailed(..) => 400,
+            Error::NotAcceptable(..) => 406,
+        }
+    }
+
+    /// Headers the http adapters must add to the response of this error.
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![];
+        if let Some(secs) = self.retry_after() {
+            headers.push(("Retry-After", secs.to_string()));
+        }
+        match *self {
+            Error::Unauthorized(..) => headers.push(("WWW-Authenticate", "Bearer".to_string())),
+            Error::MethodNotAllowed(_, ref allowed) => {
+                let allowed = allowed.iter().map(|m| m.to_string()).collect::<Vec<String>>();
+                headers.push(("Allow", allowed.join(", ")));
+            },
+            _ => {},
+        }
+        headers
+    }
+
+    /// Number of seconds after which the request can be retried, used to
+    /// fill the Retry-After header.
+    pub fn retry_after(&self) -> Option<u64> {
+        match *self {
+            Error::Unavailable(..) | Error::Timeout(..) => Some(RETRY_AFTER),
+            _ => None,
         }
     }
+
+    pub fn column(&self) -> Option<&str> {
+        match *self {
+            Error::InvalidFilterType(ref c, _) => Some(c),
+            Error::InvalidColumnType(ref c, _, _) => Some(c),
+            Error::UnknowColumn(ref c, _) => Some(c),
+            _ => None,
+        }
+    }
+
+    pub fn table(&self) -> Option<&str> {
+        match *self {
+            Error::NotFound(ref t) => Some(t),
+            Error::UnknowModel(ref t) => Some(t),
+            Error::UnknowColumn(_, ref t) => Some(t),
+            Error::ValidationFailed(ref t, _) => Some(t),
+            _ => None,
+        }
+    }
+
+    pub fn filter(&self) -> Option<&str> {
+        match *self {
+            Error::InvalidFilter(ref f) => Some(f),
+            _ => None,
+        }
+    }
+
+    pub fn hint(&self) -> Option<&'static str> {
+        match *self {
+            Error::InvalidFilter(..) =>
+                Some("available filters are eq, gte, gt, lte, lt, ne, like, ilike, \
+                      in, notin, is, isnot and not"),
+            Error::InvalidFilterSyntax(..) =>
+                Some("filters are written as column=filter.value, e.g. id=eq.42"),
+            Error::InvalidFilterType(..) => Some("limit and offset expect positive integers"),
+            Error::UnknowModel(..) | Error::UnknowColumn(..) =>
+                Some("the builtin docs list the available tables and columns"),
+            Error::Unavailable(..) | Error::Timeout(..) =>
+                Some("the database is busy, retry after the delay of the Retry-After header"),
+            Error::ImportFailed(..) =>
+                Some("nothing was loaded, the lines are numbered from the header line"),
+            Error::ValidationFailed(..) =>
+                Some("the json schema of the table is served at /builtins/schema/<table>"),
+            Error::NotAcceptable(..) =>
+                Some("responses are sent as json, csv, ndjson, msgpack, cbor or arrow, the \
+                      format query parameter can be used instead of the Accept header"),
+            _ => None,
+        }
+    }
+
+    /// Serialize the error as an RFC 7807 problem details object.
+    ///
+    /// On top of the standard members (type, title, status, detail) the
+    /// object always contains the stable `code`, and the `table`, `column`,
+    /// `filter`, `hint`, `lines` and `errors` members when they apply to the
+    /// error.
+    pub fn to_problem(&self) -> Value {
+        let mut map = make_problem_map(self.status(), self.code(), error::Error::description(self),
+                                       &*format!("{}", self));
+        if let Some(t) = self.table() {
+            map.insert("table".into(), Value::String(t.into()));
+        }
+        if let Some(c) = self.column() {
+            map.insert("column".into(), Value::String(c.into()));
+        }
+        if let Some(f) = self.filter() {
+            map.insert("filter".into(), Value::String(f.into()));
+        }
+        if let Some(h) = self.hint() {
+            map.insert("hint".into(), Value::String(h.into()));
+        }
+        if let Error::ImportFailed(ref lines, _) = *self {
+            let lines = lines.iter().map(|&(line, ref detail)| {
+                let mut m = JsonMap::new();
+                m.insert("line".into(), Value::U64(line));
+                m.insert("detail".into(), Value::String(detail.clone()));
+                Value::Object(m)
+            }).collect();
+            map.insert("lines".into(), Value::Array(lines));
+        }
+        if let Error::ValidationFailed(_, ref errors) = *self {
+            let errors = errors.iter().map(|&(ref pointer, ref detail)| {
+                let mut m = JsonMap::new();
+                m.insert("pointer".into(), Value::String(pointer.clone()));
+                m.insert("detail".into(), Value::String(detail.clone()));
+                Value::Object(m)
+            }).collect();
+            map.insert("errors".into(), Value::Array(errors));
+        }
+        Value::Object(map)
+    }
+}
+
+impl From<PgError> for Error {
+    fn from(e: PgError) -> Error {
+        error!("database error: {}", e);
+        match e {
+            PgError::Db(ref db) if db.code == SqlState::QueryCanceled =>
+                Error::Timeout("statement timeout exceeded".into()),
+            PgError::Db(ref db) if db.code == SqlState::InsufficientPrivilege =>
+                Error::Forbidden(db.message.clone()),
+            _ => Error::InternalError("internal database error".into()),
+        }
+    }
+}
+
+/// Build a problem details object for errors which are not produced by
+/// arrakis itself (e.g unknown routes in the http adapters), so that every
+/// error body share the same shape.
+pub fn problem(status: u16, code: &str, title: &str, detail: &str) -> Value {
+    Value::Object(make_problem_map(status, code, title, detail))
+}
+
+fn make_problem_map(status: u16, code: &str, title: &str, detail: &str)
+                    -> JsonMap<String, Value> {
+    let mut map = JsonMap::new();
+    map.insert("type".into(), Value::String(format!("{}{}", PROBLEM_TYPE_PREFIX, code)));
+    map.insert("title".into(), Value::String(title.into()));
+    map.insert("status".into(), Value::U64(status as u64));
+    map.insert("detail".into(), Value::String(detail.into()));
+    map.insert("code".into(), Value::String(code.into()));
+    return map;
 }
@@ -5,10 +5,12 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use common::{quote_column, quote_literal};
 use error::Error;
 use std::str::FromStr;
 use std::string::ToString;
 
+#[derive(Debug, Clone)]
 pub enum IsKind {
     Null,
     True,
@@ -43,6 +45,7 @@ impl ToString for IsKind {
     }
 }
 
+#[derive(Debug, Clone)]
 pub enum Filter {
     Equal(String, String),
     GreaterThanEqual(String, String),
@@ -142,6 +145,41 @@ impl Filter {
         }
     }
 
+    /// The column the filter applies to.
+    pub fn column(&self) -> &str {
+        use self::Filter::*;
+        match self {
+            &Equal(ref n, _) | &GreaterThanEqual(ref n, _) | &GreaterThan(ref n, _)
+                | &LesserThanEqual(ref n, _) | &LesserThan(ref n, _) | &NotEqual(ref n, _)
+                | &Like(ref n, _) | &ILike(ref n, _) => n,
+            &In(ref n, _) | &NotIn(ref n, _) => n,
+            &Is(ref n, _) | &IsNot(ref n, _) => n,
+            &Not(ref f) => f.column(),
+        }
+    }
+
+    /// Format the filter as the value of a query parameter, this is the
+    /// inverse of `Filter::new`.
+    pub fn to_query_value(&self) -> String {
+        use self::Filter::*;
+        let (filter, value) = match self {
+            &Equal(_, ref v) => (EQ, v.clone()),
+            &GreaterThanEqual(_, ref v) => (GTE, v.clone()),
+            &GreaterThan(_, ref v) => (GT, v.clone()),
+            &LesserThanEqual(_, ref v) => (LTE, v.clone()),
+            &LesserThan(_, ref v) => (LT, v.clone()),
+            &NotEqual(_, ref v) => (NE, v.clone()),
+            &Like(_, ref patt) => (LIKE, patt.replace('%', "*")),
+            &ILike(_, ref patt) => (ILIKE, patt.replace('%', "*")),
+            &In(_, ref v) => (IN, v.join(",")),
+            &NotIn(_, ref v) => (NOT_IN, v.join(",")),
+            &Is(_, ref k) => (IS, k.to_string()),
+            &IsNot(_, ref k) => (IS_NOT, k.to_string()),
+            &Not(ref f) => (NOT, f.to_query_value()),
+        };
+        format!("{}.{}", filter.to_lowercase(), value)
+    }
+
     pub fn to_string(&self, table: Option<&str>) -> String {
         use self::Filter::*;
         match self {
@@ -163,25 +201,16 @@ impl Filter {
 }
 
 fn fmt_is_filter(f: &str, name: &str, k: &IsKind, table: Option<&str>) -> String {
-    match table {
-        Some(t) => format!("{}.{} {} {}", t, name, f, k.to_string()),
-        None => format!("{} {} {}", name, f, k.to_string()),
-    }
+    format!("{} {} {}", quote_column(table, name), f, k.to_string())
 }
 
 fn fmt_like_filter(f: &str, name: &str, patt: &str, table: Option<&str>) -> String {
-    match table {
-        Some(t) => format!("{}.{} {} '{}'", t, name, f, patt),
-        None => format!("{} {} '{}'", name, f, patt),
-    }
+    format!("{} {} {}", quote_column(table, name), f, quote_literal(patt))
 }
 
 fn fmt_in_filter(f: &str, name: &str, val: &Vec<String>, table: Option<&str>) -> String {
-    let l = val.iter().map(|s| format!("'{}'", s)).collect::<Vec<String>>().join(", ");
-    match table {
-        Some(t) => format!("{}.{} {} ({})", t, name, f, l),
-        None => format!("{} {} ({})", name, f, l),
-    }
+    let l = val.iter().map(|s| quote_literal(s)).collect::<Vec<String>>().join(", ");
+    format!("{} {} ({})", quote_column(table, name), f, l)
 }
 
 fn fmt_not_filter(f: &Filter, table: Option<&str>) -> String {
@@ -189,8 +218,22 @@ fn fmt_not_filter(f: &Filter, table: Option<&str>) -> String {
 }
 
 fn fmt_basic_filter(filter: &str, col: &str, val: &str, table: Option<&str>) -> String {
-    match table {
-        Some(t) => format!("{}.{} {} '{}'", t, col, filter, val),
-        None =>  format!("{} {} '{}'", col, filter, val),
+    format!("{} {} {}", quote_column(table, col), filter, quote_literal(val))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_quoted_literals() {
+        let f = Filter::new("name", "eq.x' OR '1'='1").unwrap();
+        assert_eq!(f.to_string(None), "\"name\" = 'x'' OR ''1''=''1'");
+        let f = Filter::new("name", "like.*'*").unwrap();
+        assert_eq!(f.to_string(Some("t")), "\"t\".\"name\" LIKE '%''%'");
+        let f = Filter::new("id", "in.1,2');--").unwrap();
+        assert_eq!(f.to_string(None), "\"id\" IN ('1', '2'');--')");
+        let f = Filter::new("id", "not.eq.a\\'b").unwrap();
+        assert_eq!(f.to_string(None), "NOT (\"id\" = E'a\\\\''b')");
     }
 }
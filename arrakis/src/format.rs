@@ -0,0 +1,514 @@
+// Copyright 2016 Jeremy Letang.
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use arrow::ArrowRowWriter;
+use cbor::{self, CborRowWriter};
+use cvt::Cell;
+use error::Error;
+use get;
+use msgpack::{self, MsgpackRowWriter};
+use schema::Column;
+use serde_json::{self, Map, Value};
+use std::cmp::Ordering;
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+use stream::{JsonRowWriter, RowWriter};
+
+const JSON: &'static str = "json";
+const CSV: &'static str = "csv";
+const NDJSON: &'static str = "ndjson";
+const JSONL: &'static str = "jsonl";
+const MSGPACK: &'static str = "msgpack";
+const CBOR: &'static str = "cbor";
+const ARROW: &'static str = "arrow";
+
+const JSON_CONTENT_TYPE: &'static str = "application/json";
+const CSV_CONTENT_TYPE: &'static str = "text/csv";
+const NDJSON_CONTENT_TYPE: &'static str = "application/x-ndjson";
+// accepted as an alias of ndjson
+const JSONL_CONTENT_TYPE: &'static str = "application/jsonl";
+const MSGPACK_CONTENT_TYPE: &'static str = "application/msgpack";
+// accepted as an alias of msgpack
+const X_MSGPACK_CONTENT_TYPE: &'static str = "application/x-msgpack";
+const CBOR_CONTENT_TYPE: &'static str = "application/cbor";
+const ARROW_CONTENT_TYPE: &'static str = "application/vnd.apache.arrow.stream";
+
+// formats produced for the wildcard media types, by order of preference
+const ANY_FORMAT: &'static [Format] = &[Format::Json, Format::Csv, Format::Ndjson,
+                                        Format::Msgpack, Format::Cbor, Format::Arrow];
+const ANY_APPLICATION_FORMAT: &'static [Format] = &[Format::Json, Format::Ndjson,
+                                                    Format::Msgpack, Format::Cbor,
+                                                    Format::Arrow];
+const ANY_TEXT_FORMAT: &'static [Format] = &[Format::Csv];
+// formats of the responses holding a single value
+const VALUE_FORMAT: &'static [Format] = &[Format::Json, Format::Msgpack, Format::Cbor];
+
+// the row writer of a format, the box is coerced to the expected type
+macro_rules! row_writer {
+    ($format:expr, $out:expr) => {
+        match $format {
+            Format::Json => Box::new(JsonRowWriter::new($out)),
+            Format::Csv => Box::new(CsvRowWriter::new($out)),
+            Format::Ndjson => Box::new(NdjsonRowWriter::new($out)),
+            Format::Msgpack => Box::new(MsgpackRowWriter::new($out)),
+            Format::Cbor => Box::new(CborRowWriter::new($out)),
+            Format::Arrow => Box::new(ArrowRowWriter::new($out)),
+        }
+    }
+}
+
+/// Format of the GET responses and of the request bodies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Csv,
+    // one json object per line, without the data envelope
+    Ndjson,
+    Msgpack,
+    Cbor,
+    // Arrow IPC stream, only for the responses
+    Arrow,
+}
+
+impl Format {
+    /// Choose the format of a response from the `format` query parameter,
+    /// which takes precedence, or from the Accept header.
+    ///
+    /// The media types of the header are tried by decreasing quality value,
+    /// in the order of the header for equal values, and the ones with a
+    /// quality of 0 are never used. Json is used without an Accept header,
+    /// and `NotAcceptable` is returned when the header only lists media types
+    /// which are not supported.
+    pub fn negotiate(accept: Option<&str>, param: Option<&str>) -> Result<Format, Error> {
+        negotiate(accept, param, ANY_FORMAT)
+    }
+
+    /// Same as `negotiate` for the responses holding a single value, i.e the
+    /// responses of the POST, PUT, PATCH and DELETE requests, which can only
+    /// be sent as json, msgpack or cbor.
+    pub fn negotiate_value(accept: Option<&str>, param: Option<&str>) -> Result<Format, Error> {
+        negotiate(accept, param, VALUE_FORMAT)
+    }
+
+    /// The format of a request body from its Content-Type header, if known.
+    pub fn of_content_type(content_type: &str) -> Option<Format> {
+        let media = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+        match &*media {
+            JSON_CONTENT_TYPE => Some(Format::Json),
+            CSV_CONTENT_TYPE => Some(Format::Csv),
+            NDJSON_CONTENT_TYPE | JSONL_CONTENT_TYPE => Some(Format::Ndjson),
+            MSGPACK_CONTENT_TYPE | X_MSGPACK_CONTENT_TYPE => Some(Format::Msgpack),
+            CBOR_CONTENT_TYPE => Some(Format::Cbor),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match *self {
+            Format::Json => JSON_CONTENT_TYPE,
+            Format::Csv => CSV_CONTENT_TYPE,
+            Format::Ndjson => NDJSON_CONTENT_TYPE,
+            Format::Msgpack => MSGPACK_CONTENT_TYPE,
+            Format::Cbor => CBOR_CONTENT_TYPE,
+            Format::Arrow => ARROW_CONTENT_TYPE,
+        }
+    }
+
+    /// Value of the Content-Disposition header for a response of the given
+    /// table, if any.
+    pub fn content_disposition(&self, model: &str) -> Option<String> {
+        match *self {
+            Format::Csv => Some(format!("attachment; filename=\"{}.csv\"",
+                                        model.replace('"', ""))),
+            _ => None,
+        }
+    }
+
+    /// A writer of the rows of a GET response in this format.
+    pub fn row_writer<'a, W: Write + 'a>(&self, out: W) -> Box<RowWriter + 'a> {
+        row_writer!(*self, out)
+    }
+
+    /// Same as `row_writer`, for writers sent to the worker threads.
+    pub fn send_row_writer<'a, W: Write + Send + 'a>(&self, out: W)
+                                                    -> Box<RowWriter + Send + 'a> {
+        row_writer!(*self, out)
+    }
+
+    /// Encode the response of a request holding a single value, i.e
+    /// `{"data": value}`. Json is used for the formats which only apply to
+    /// rows, see `Format::negotiate_value`.
+    pub fn encode_value(&self, value: Value) -> Vec<u8> {
+        let mut map = Map::new();
+        map.insert("data".to_string(), value);
+        let value = Value::Object(map);
+        let mut out = vec![];
+        // writing to a vec cannot fail
+        match *self {
+            Format::Msgpack => msgpack::write_value(&mut out, &value).unwrap(),
+            Format::Cbor => cbor::write_value(&mut out, &value).unwrap(),
+            _ => serde_json::to_writer(&mut out, &value).unwrap(),
+        }
+        out
+    }
+
+    /// Read a request body holding a single value, i.e a json, msgpack or
+    /// cbor body.
+    pub fn read_value(&self, body: &[u8]) -> Result<Value, Error> {
+        match *self {
+            Format::Json => match serde_json::from_slice(body) {
+                Ok(v) => Ok(v),
+                Err(e) => Err(Error::InvalidInputError(format!("{}", e))),
+            },
+            Format::Msgpack => msgpack::decode(body),
+            Format::Cbor => cbor::decode(body),
+            Format::Csv | Format::Ndjson => Err(Error::InvalidInputError(
+                format!("{} bodies hold several rows, only POST requests accept them", self))),
+            Format::Arrow => Err(Error::InvalidInputError(
+                "arrow is only supported for the responses".into())),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_lowercase() {
+            JSON => Ok(Format::Json),
+            CSV => Ok(Format::Csv),
+            NDJSON | JSONL => Ok(Format::Ndjson),
+            MSGPACK => Ok(Format::Msgpack),
+            CBOR => Ok(Format::Cbor),
+            ARROW => Ok(Format::Arrow),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Format::Json => JSON,
+            Format::Csv => CSV,
+            Format::Ndjson => NDJSON,
+            Format::Msgpack => MSGPACK,
+            Format::Cbor => CBOR,
+            Format::Arrow => ARROW,
+        };
+        write!(fmt, "{}", s)
+    }
+}
+
+/// Write the rows as csv (RFC 4180), with a header line containing the
+/// columns. Arrays and objects are written as json text, nulls as empty
+/// fields and empty strings as `""`, so that they are read back as null and
+/// as an empty string by the csv imports.
+pub struct CsvRowWriter<W> {
+    out: W,
+}
+
+impl<W: Write> CsvRowWriter<W> {
+    pub fn new(out: W) -> CsvRowWriter<W> {
+        CsvRowWriter {
+            out: out,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.out
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    // the fields are quoted if needed, the null ones are left empty
+    fn write_record<I>(&mut self, fields: I) -> io::Result<()>
+        where I: Iterator<Item=Option<String>> {
+        let mut first = true;
+        for f in fields {
+            if !first {
+                self.out.write_all(b",")?;
+            }
+            first = false;
+            if let Some(f) = f {
+                self.out.write_all(csv_escape(&*f).as_bytes())?;
+            }
+        }
+        self.out.write_all(b"\r\n")
+    }
+}
+
+impl<W: Write> RowWriter for CsvRowWriter<W> {
+    fn begin(&mut self, columns: &[&Column]) -> io::Result<()> {
+        self.write_record(columns.iter().map(|c| Some(c.name.clone())))
+    }
+
+    fn row(&mut self, values: Vec<Cell>) -> io::Result<()> {
+        self.write_record(values.into_iter().map(|v| csv_field(&v.into_json())))
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Write the rows as json objects, one per line.
+pub struct NdjsonRowWriter<W> {
+    out: W,
+    columns: Vec<String>,
+}
+
+impl<W: Write> NdjsonRowWriter<W> {
+    pub fn new(out: W) -> NdjsonRowWriter<W> {
+        NdjsonRowWriter {
+            out: out,
+            columns: vec![],
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.out
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> RowWriter for NdjsonRowWriter<W> {
+    fn begin(&mut self, columns: &[&Column]) -> io::Result<()> {
+        self.columns = columns.iter().map(|c| c.name.clone()).collect();
+        Ok(())
+    }
+
+    fn row(&mut self, values: Vec<Cell>) -> io::Result<()> {
+        let values = values.into_iter().map(Cell::into_json).collect();
+        let row = get::row_to_json(&*self.columns, values);
+        if let Err(e) = serde_json::to_writer(&mut self.out, &row) {
+            return Err(io::Error::new(io::ErrorKind::Other, format!("{}", e)));
+        }
+        self.out.write_all(b"\n")
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn csv_field(v: &Value) -> Option<String> {
+    match *v {
+        Value::Null => None,
+        Value::String(ref s) => Some(s.clone()),
+        ref other => Some(serde_json::to_string(other).unwrap_or(String::new())),
+    }
+}
+
+// the format of a response among the supported ones, see `Format::negotiate`
+fn negotiate(accept: Option<&str>, param: Option<&str>, supported: &[Format])
+             -> Result<Format, Error> {
+    if let Some(f) = param {
+        return match Format::from_str(f) {
+            Ok(f) if supported.contains(&f) => Ok(f),
+            Ok(f) => Err(Error::InvalidInputError(
+                format!("the {} format is only supported for the GET responses", f))),
+            Err(_) => Err(Error::InvalidInputError(
+                format!("unknown format {}, expected json, csv, ndjson, msgpack, cbor \
+                         or arrow", f))),
+        };
+    }
+    let accept = match accept {
+        Some(a) if !a.trim().is_empty() => a,
+        _ => return Ok(Format::Json),
+    };
+    // the formats of each accepted media type, with its quality value
+    let mut accepted: Vec<(f32, &'static [Format])> = vec![];
+    let mut refused: Vec<Format> = vec![];
+    for media in accept.split(',') {
+        let mut params = media.split(';');
+        let media = params.next().unwrap_or("").trim().to_lowercase();
+        if media.is_empty() {
+            continue;
+        }
+        let q = params
+            .filter_map(|p| {
+                let p = p.trim();
+                match p.starts_with("q=") || p.starts_with("Q=") {
+                    true => f32::from_str(&p[2..]).ok(),
+                    false => None,
+                }
+            })
+            .next()
+            .unwrap_or(1.);
+        let formats = media_formats(&*media);
+        if q > 0. {
+            accepted.push((q, formats));
+        } else if !media.ends_with("/*") {
+            // an explicit refusal is not overridden by a wildcard
+            refused.extend(formats);
+        }
+    }
+    // the sort is stable, the order of the header is kept for equal values
+    accepted.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+    for &(_, formats) in &accepted {
+        if let Some(f) = formats.iter().find(|f| supported.contains(f) && !refused.contains(f)) {
+            return Ok(*f);
+        }
+    }
+    Err(Error::NotAcceptable(format!("cannot produce any of the media types {}",
+                                     accept.trim())))
+}
+
+// the formats a media type of an Accept header can be answered with
+fn media_formats(media: &str) -> &'static [Format] {
+    const JSON_FORMAT: &'static [Format] = &[Format::Json];
+    const CSV_FORMAT: &'static [Format] = &[Format::Csv];
+    const NDJSON_FORMAT: &'static [Format] = &[Format::Ndjson];
+    const MSGPACK_FORMAT: &'static [Format] = &[Format::Msgpack];
+    const CBOR_FORMAT: &'static [Format] = &[Format::Cbor];
+    const ARROW_FORMAT: &'static [Format] = &[Format::Arrow];
+    const NO_FORMAT: &'static [Format] = &[];
+    match media {
+        JSON_CONTENT_TYPE => JSON_FORMAT,
+        CSV_CONTENT_TYPE => CSV_FORMAT,
+        NDJSON_CONTENT_TYPE | JSONL_CONTENT_TYPE => NDJSON_FORMAT,
+        MSGPACK_CONTENT_TYPE | X_MSGPACK_CONTENT_TYPE => MSGPACK_FORMAT,
+        CBOR_CONTENT_TYPE => CBOR_FORMAT,
+        ARROW_CONTENT_TYPE => ARROW_FORMAT,
+        "*/*" => ANY_FORMAT,
+        "application/*" => ANY_APPLICATION_FORMAT,
+        "text/*" => ANY_TEXT_FORMAT,
+        _ => NO_FORMAT,
+    }
+}
+
+/// Quote a csv field if it is empty or contains a separator, a quote or a
+/// line break, an empty field without quotes being a null.
+pub fn csv_escape(s: &str) -> String {
+    if s.is_empty() || s.contains(|c: char| c == ',' || c == '"' || c == '\r' || c == '\n') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use postgres::types::Type;
+
+    fn negotiate(accept: &str) -> Result<Format, Error> {
+        Format::negotiate(Some(accept), None)
+    }
+
+    #[test]
+    fn format_parameter_takes_precedence() {
+        assert_eq!(Format::negotiate(Some("text/csv"), Some("cbor")).unwrap(), Format::Cbor);
+        assert_eq!(Format::negotiate(Some("text/csv"), Some("JSONL")).unwrap(), Format::Ndjson);
+        assert!(Format::negotiate(None, Some("xml")).is_err());
+    }
+
+    #[test]
+    fn json_by_default() {
+        assert_eq!(Format::negotiate(None, None).unwrap(), Format::Json);
+        assert_eq!(negotiate("").unwrap(), Format::Json);
+        assert_eq!(negotiate("*/*").unwrap(), Format::Json);
+        assert_eq!(negotiate("application/*").unwrap(), Format::Json);
+        assert_eq!(negotiate("text/html, */*;q=0.8").unwrap(), Format::Json);
+    }
+
+    #[test]
+    fn media_types() {
+        assert_eq!(negotiate("text/csv").unwrap(), Format::Csv);
+        assert_eq!(negotiate("text/*").unwrap(), Format::Csv);
+        assert_eq!(negotiate("Application/JSONL").unwrap(), Format::Ndjson);
+        assert_eq!(negotiate("application/x-msgpack").unwrap(), Format::Msgpack);
+        assert_eq!(negotiate("application/cbor; charset=x").unwrap(), Format::Cbor);
+        assert_eq!(negotiate("application/vnd.apache.arrow.stream").unwrap(), Format::Arrow);
+        assert_eq!(negotiate("text/html, application/msgpack").unwrap(), Format::Msgpack);
+    }
+
+    #[test]
+    fn quality_values() {
+        assert_eq!(negotiate("application/json;q=0.5, text/csv").unwrap(), Format::Csv);
+        assert_eq!(negotiate("text/csv;q=0.9, application/cbor;q=0.9").unwrap(), Format::Csv);
+        assert_eq!(negotiate("application/cbor;q=0.1, text/csv;q=0.2").unwrap(), Format::Csv);
+        assert_eq!(negotiate("text/csv;q=0, */*").unwrap(), Format::Json);
+        assert_eq!(negotiate("application/json;q=0, */*").unwrap(), Format::Csv);
+        assert_eq!(negotiate("application/json;q=0, application/*").unwrap(), Format::Ndjson);
+    }
+
+    #[test]
+    fn not_acceptable() {
+        for accept in &["text/html", "application/xml, image/png", "application/json;q=0",
+                        "*/*;q=0", "text/csv;q=0, text/*"] {
+            match negotiate(accept) {
+                Err(e @ Error::NotAcceptable(..)) => assert_eq!(e.status(), 406),
+                res => panic!("{}: unexpected {:?}", accept, res),
+            }
+        }
+    }
+
+    #[test]
+    fn value_formats() {
+        let value = |accept| Format::negotiate_value(Some(accept), None);
+        assert_eq!(Format::negotiate_value(None, None).unwrap(), Format::Json);
+        assert_eq!(value("*/*").unwrap(), Format::Json);
+        assert_eq!(value("application/msgpack").unwrap(), Format::Msgpack);
+        assert_eq!(value("text/csv, application/cbor;q=0.5").unwrap(), Format::Cbor);
+        assert_eq!(value("application/json;q=0, application/*").unwrap(), Format::Msgpack);
+        match value("text/csv") {
+            Err(Error::NotAcceptable(..)) => {},
+            res => panic!("unexpected {:?}", res),
+        }
+        assert_eq!(Format::negotiate_value(None, Some("cbor")).unwrap(), Format::Cbor);
+        match Format::negotiate_value(None, Some("csv")) {
+            Err(Error::InvalidInputError(..)) => {},
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn encoded_values() {
+        let v = Value::Array(vec![Value::U64(1)]);
+        assert_eq!(Format::Json.encode_value(v.clone()), br#"{"data":[1]}"#.to_vec());
+        assert_eq!(Format::Csv.encode_value(v.clone()), br#"{"data":[1]}"#.to_vec());
+        assert_eq!(Format::Msgpack.encode_value(v.clone()),
+                   vec![0x81, 0xa4, b'd', b'a', b't', b'a', 0x91, 0x01]);
+        assert_eq!(Format::Cbor.encode_value(v),
+                   vec![0xa1, 0x64, b'd', b'a', b't', b'a', 0x81, 0x01]);
+    }
+
+    #[test]
+    fn csv_fields() {
+        assert_eq!(csv_escape("plain"), "plain");
+        assert_eq!(csv_escape(""), "\"\"");
+        assert_eq!(csv_escape("a,b"), "\"a,b\"");
+        assert_eq!(csv_escape("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_escape("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_escape("cr\r"), "\"cr\r\"");
+        assert_eq!(csv_field(&Value::Null), None);
+        assert_eq!(csv_field(&Value::String("x".into())), Some("x".into()));
+        assert_eq!(csv_field(&Value::Array(vec![Value::U64(1), Value::Bool(true)])),
+                   Some("[1,true]".into()));
+    }
+
+    #[test]
+    fn csv_rows() {
+        let columns = vec![Column::new("name", Type::Text), Column::new("tags", Type::TextArray)];
+        let mut w = CsvRowWriter::new(vec![]);
+        w.begin(&*columns.iter().collect::<Vec<&Column>>()).unwrap();
+        // an empty string is not a null
+        w.row(vec![Cell::Text("".into()), Cell::Null]).unwrap();
+        w.row(vec![Cell::Text("a,b".into()),
+                   Cell::Json(Value::Array(vec![Value::String("x".into())]))]).unwrap();
+        w.end().unwrap();
+        assert_eq!(String::from_utf8(w.into_inner()).unwrap(),
+                   "name,tags\r\n\"\",\r\n\"a,b\",\"[\"\"x\"\"]\"\r\n");
+    }
+}
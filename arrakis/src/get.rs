@@ -6,75 +6,183 @@
 // except according to those terms.
 
 use common;
-use cvt;
+use cvt::{self, Cell};
 use error::Error;
-use queries::{FetchQueries, Queries};
+use query::{Embed, Query};
 use ordering;
-use postgres::Connection;
-use postgres::rows::Rows;
-use schema::Table;
+use fallible_iterator::FallibleIterator;
+use postgres::GenericConnection;
+use postgres::rows::{Row, Rows};
+use postgres::transaction::Transaction;
+use postgres::types::Type;
+use schema::{Column, Table};
 use serde_json::Value as JsonValue;
 use serde_json::Map as JsonMap;
+use std::collections::HashMap;
+use stream::{self, RowWriter};
 
-pub fn generate_select(mut query: String, table: &Table, queries: &Queries)
-                       -> Result<(String, Vec<String>), Error> {
+// number of rows fetched at once by the streamed requests
+const STREAM_FETCH_SIZE: i32 = 500;
+
+pub fn generate_select(mut query: String, tables: &HashMap<String, Table>, table: &Table,
+                       q: &Query) -> Result<(String, Vec<Column>), Error> {
     query += "SELECT ".into();
-    let columns: Vec<String> = match queries.select() {
+    let mut columns = selected_columns(table, q.select())?;
+    let mut exprs = columns.iter()
+        .map(|c| {
+            let column = common::quote_column(Some(&*table.name), &*c.name);
+            cvt::select_expr(&*column, &c.data_type)
+        })
+        .collect::<Vec<String>>();
+
+    // the embedded resources are read as json columns
+    let mut aliases = 0;
+    for e in q.embedded() {
+        if columns.iter().any(|c| c.name == e.key()) {
+            return Err(Error::InvalidInputError(format!("{} is selected twice", e.key())));
+        }
+        let expr = embed_expr(tables, table, &*table.name, e, &mut aliases)?;
+        exprs.push(cvt::select_expr(&*expr, &Type::Json));
+        columns.push(Column::new(e.key(), Type::Json));
+    }
+    query += &*exprs.join(", ");
+
+    return Ok((query, columns));
+}
+
+// the selected columns, every readable one if select is None
+fn selected_columns(table: &Table, select: Option<&[String]>) -> Result<Vec<Column>, Error> {
+    let columns: Vec<String> = match select {
         Some(columns) => columns.iter().map(|v| v.to_string()).collect(),
-        None => table.columns.iter().map(|(k, _)| k.clone()).collect()
+        None => table.columns.iter()
+            .filter(|&(_, c)| c.visibility.is_readable())
+            .map(|(k, _)| k.clone())
+            .collect()
     };
 
-    query += &*columns.iter()
-        .map(|ref s| format!("{}.{}", &*table.name, s))
-        .collect::<Vec<String>>()
-        .join(", ");
-
     // ensure that possible user specified select column exists
     if let Some(e) = validate_columns(table, &columns) {
         return Err(e);
     }
+    Ok(columns.iter().map(|c| table.columns.get(c).unwrap().clone()).collect())
+}
 
-    return Ok((query, columns));
+// a foreign key relating two tables, column is a column of the table of the
+// selected rows and foreign_column a column of the embedded table
+enum Relation<'a> {
+    // the embedded table is referenced by the selected rows
+    Parent { column: &'a str, foreign_column: &'a str },
+    // the embedded table references the selected rows
+    Children { column: &'a str, foreign_column: &'a str },
 }
 
-pub fn generate_limit(query: String, queries: &Queries) -> Result<String, Error> {
-    match queries.limit() {
-        Some(limit) => {
-            match limit.trim().parse::<u32>() {
-                Ok(i) => Ok(format!("{} LIMIT {}", query, i)),
-                Err(_) => Err(Error::InvalidFilterType("limit".into(), "u32".into()))
-            }
+// the foreign key relating a table to an embedded table, the columns of both
+// sides must be readable
+fn find_relation<'a>(table: &'a Table, other: &'a Table, hint: Option<&str>)
+                     -> Result<Relation<'a>, Error> {
+    let readable = |t: &Table, c: &str| {
+        t.columns.get(c).map_or(false, |c| c.visibility.is_readable())
+    };
+    let mut relations = vec![];
+    for (name, c) in table.columns.iter() {
+        match c.foreign_key {
+            Some(ref fk) if fk.table == other.name && c.visibility.is_readable()
+                && readable(other, &*fk.column) && hint.map_or(true, |h| h == &**name) =>
+                relations.push(Relation::Parent { column: name, foreign_column: &*fk.column }),
+            _ => {},
         }
-        None => Ok(query)
+    }
+    for (name, c) in other.columns.iter() {
+        match c.foreign_key {
+            Some(ref fk) if fk.table == table.name && c.visibility.is_readable()
+                && readable(table, &*fk.column) && hint.map_or(true, |h| h == &**name) =>
+                relations.push(Relation::Children { column: &*fk.column, foreign_column: name }),
+            _ => {},
+        }
+    }
+    match relations.len() {
+        0 => Err(Error::InvalidInputError(format!("cannot embed {} in {}, no foreign key \
+                                                   relates them", other.name, table.name))),
+        1 => Ok(relations.pop().unwrap()),
+        _ => Err(Error::InvalidInputError(format!("cannot embed {} in {}, several foreign keys \
+                                                   relate them, use {}!column to choose one",
+                                                  other.name, table.name, other.name))),
     }
 }
 
-pub fn generate_offset(query: String, queries: &Queries) -> Result<String, Error> {
-    match queries.offset() {
-        Some(offset) => {
-            match offset.trim().parse::<u32>() {
-                Ok(i) => Ok(format!("{} OFFSET {}", query, i)),
-                Err(_) => Err(Error::InvalidFilterType("offset".into(), "u32".into()))
-            }
+// the json of an embedded resource, a correlated subquery on the rows of the
+// table aliased as alias. aliases counts the aliases of the embedded tables.
+fn embed_expr(tables: &HashMap<String, Table>, table: &Table, alias: &str, embed: &Embed,
+              aliases: &mut usize) -> Result<String, Error> {
+    let other = tables.get(&embed.table).ok_or(Error::UnknowModel(embed.table.clone()))?;
+    let relation = find_relation(table, other, embed.column.as_ref().map(|c| &**c))?;
+    *aliases += 1;
+    let other_alias = format!("e{}", aliases);
+
+    let columns = selected_columns(other, embed.select.as_ref().map(|s| &**s))?;
+    let mut exprs = columns.iter()
+        .map(|c| {
+            let column = common::quote_column(Some(&*other_alias), &*c.name);
+            format!("{} AS {}", cvt::json_expr(&*column, &c.data_type),
+                    common::quote_ident(&*c.name))
+        })
+        .collect::<Vec<String>>();
+    for (i, e) in embed.embedded.iter().enumerate() {
+        if columns.iter().any(|c| c.name == e.key())
+            || embed.embedded[..i].iter().any(|o| o.key() == e.key()) {
+            return Err(Error::InvalidInputError(format!("{} is selected twice in {}",
+                                                        e.key(), embed.key())));
         }
-        None => Ok(query)
+        let expr = embed_expr(tables, other, &*other_alias, e, aliases)?;
+        exprs.push(format!("{} AS {}", expr, common::quote_ident(e.key())));
+    }
+
+    let (column, foreign_column, aggregate) = match relation {
+        Relation::Parent { column, foreign_column } =>
+            (column, foreign_column, "row_to_json(_r)"),
+        Relation::Children { column, foreign_column } =>
+            (column, foreign_column, "coalesce(json_agg(_r), '[]')"),
+    };
+    Ok(format!("(SELECT {} FROM (SELECT {} FROM {} AS {} WHERE {} = {}) AS _r)", aggregate,
+               exprs.join(", "), common::quote_table(other), common::quote_ident(&*other_alias),
+               common::quote_column(Some(&*other_alias), foreign_column),
+               common::quote_column(Some(alias), column)))
+}
+
+pub fn generate_limit(query: String, q: &Query, max_limit: Option<u32>) -> String {
+    // the configured max limit caps both the user limit and the default
+    let limit = match (q.limit(), max_limit) {
+        (Some(limit), Some(max)) => Some(::std::cmp::min(limit, max)),
+        (limit, max) => limit.or(max),
+    };
+    match limit {
+        Some(limit) => format!("{} LIMIT {}", query, limit),
+        None => query
     }
 }
 
-pub fn generate_order(mut query: String, table: &Table, queries: &Queries) -> String {
-    match queries.order() {
-        Some(orders) => {
-            if orders.len() > 0 { query = format!("{} {}", query, "ORDER BY "); }
-            query += &*orders.iter()
-                .map(|ref o| format!("{}", ordering::to_string(&o, Some(&*table.name))))
-                .collect::<Vec<String>>()
-                .join(", ");
-            return query;
-        },
-        None => query,
+pub fn generate_offset(query: String, q: &Query) -> String {
+    match q.offset() {
+        Some(offset) => format!("{} OFFSET {}", query, offset),
+        None => query
     }
 }
 
+pub fn generate_order(mut query: String, table: &Table, q: &Query) -> Result<String, Error> {
+    let orders = q.order();
+    for o in orders {
+        if !table.columns.get(o.column()).map_or(false, |c| c.visibility.is_readable()) {
+            return Err(Error::UnknowColumn(o.column().to_string(), table.name.clone()));
+        }
+    }
+    if orders.len() > 0 { query = format!("{} {}", query, "ORDER BY "); }
+    query += &*orders.iter()
+        .map(|ref o| format!("{}", ordering::to_string(&o, Some(&*table.name))))
+        .collect::<Vec<String>>()
+        .join(", ");
+    return Ok(query);
+}
+
 pub fn collect_ids<'stmt>(rows: Rows<'stmt>) -> JsonValue {
     let mut arr = vec![];
     for r in &rows {
@@ -85,20 +193,34 @@ pub fn collect_ids<'stmt>(rows: Rows<'stmt>) -> JsonValue {
     return JsonValue::Array(arr);
 }
 
-pub fn collect_row_to_json<'stmt>(columns: Vec<String>, table: &Table, rows: Rows<'stmt>)
-                                  -> JsonValue {
+/// The values of a row, in the order of the selected columns.
+pub fn row_values(r: &Row, columns: &[Column]) -> Vec<Cell> {
+    let mut values = Vec::with_capacity(columns.len());
+    let mut i = 0;
+    while i != columns.len() {
+        let col = &columns[i];
+        values.push(cvt::row_field_to_cell(r, i, col.is_nullable, col.data_type.clone()));
+        i += 1;
+    }
+    values
+}
+
+/// Build a json object from the selected columns and the values of a row,
+/// the object keeps the order of the columns.
+pub fn row_to_json(columns: &[String], values: Vec<JsonValue>) -> JsonValue {
+    let mut map = JsonMap::new();
+    for (c, v) in columns.iter().zip(values.into_iter()) {
+        map.insert(c.clone(), v);
+    }
+    JsonValue::Object(map)
+}
+
+pub fn collect_row_to_json<'stmt>(columns: Vec<Column>, rows: Rows<'stmt>) -> JsonValue {
+    let names = columns.iter().map(|c| c.name.clone()).collect::<Vec<String>>();
     let mut arr = vec![];
     for r in &rows {
-        let mut map = JsonMap::new();
-        let mut i = 0;
-        while i != columns.len() {
-            let col = table.columns.get(&columns[i]).unwrap();
-            let val = cvt::row_field_to_json_value(&r, i, col.is_nullable, col.data_type.clone());
-            map.insert(columns[i].clone(), val);
-            i += 1;
-        }
-        let val = JsonValue::Object(map);
-        arr.push(val);
+        let values = row_values(&r, &*columns).into_iter().map(Cell::into_json).collect();
+        arr.push(row_to_json(&*names, values));
     }
 
     return JsonValue::Array(arr);
@@ -106,25 +228,139 @@ pub fn collect_row_to_json<'stmt>(columns: Vec<String>, table: &Table, rows: Row
 
 fn validate_columns(table: &Table, columns: &Vec<String>) -> Option<Error> {
     for c in columns {
-        if !table.columns.contains_key(c) {
+        // columns which cannot be read are reported as unknown
+        if !table.columns.get(c).map_or(false, |c| c.visibility.is_readable()) {
             return Some(Error::UnknowColumn(c.to_string(), table.name.clone()));
         }
     }
     return None;
 }
 
-pub fn query(conn: &Connection, table: &Table, queries: &Queries)
-             -> Result<Option<JsonValue>, Error> {
+/// Generate the sql of a GET request, and the selected columns. The
+/// embedded resources are selected as json columns, their tables are looked
+/// up in `tables`.
+pub fn generate_query(tables: &HashMap<String, Table>, table: &Table, q: &Query,
+                      max_limit: Option<u32>) -> Result<(String, Vec<Column>), Error> {
     let query = String::new();
-    let (query, columns) = generate_select(query, table, queries)?;
-    let query = common::generate_from(query, &*table.name);
-    let query = common::generate_where(query, table, queries)?;
-    let query = generate_order(query, table, queries);
-    let query = generate_limit(query, queries)?;
-    let query = generate_offset(query, queries)?;
+    let (query, columns) = generate_select(query, tables, table, q)?;
+    let query = common::generate_from(query, table);
+    let query = common::generate_where(query, table, q)?;
+    let query = generate_order(query, table, q)?;
+    let query = generate_limit(query, q, max_limit);
+    let query = generate_offset(query, q);
+    Ok((query, columns))
+}
+
+pub fn query(conn: &GenericConnection, tables: &HashMap<String, Table>, table: &Table, q: &Query,
+             max_limit: Option<u32>) -> Result<Option<JsonValue>, Error> {
+    let (query, columns) = generate_query(tables, table, q, max_limit)?;
     debug!("arrakis query: {}", query);
     match conn.query(&*query, &[]) {
-        Ok(rows) => Ok(Some(collect_row_to_json(columns, table, rows))),
-        Err(e) => Err(Error::InternalError("internal database error".into()))
+        Ok(rows) => Ok(Some(collect_row_to_json(columns, rows))),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// Execute a GET request through a portal, the rows are fetched by batches
+/// of `STREAM_FETCH_SIZE` and given to the writer as they arrive. The writer
+/// is only started if the query succeeded.
+pub fn stream(trans: &Transaction, tables: &HashMap<String, Table>, table: &Table, q: &Query,
+              max_limit: Option<u32>, writer: &mut RowWriter) -> Result<(), Error> {
+    let (query, columns) = generate_query(tables, table, q, max_limit)?;
+    debug!("arrakis streamed query: {}", query);
+    let stmt = trans.prepare(&*query)?;
+    let mut rows = stmt.lazy_query(trans, &[], STREAM_FETCH_SIZE)?;
+    let selected = columns.iter().collect::<Vec<&Column>>();
+    writer.begin(&*selected).map_err(stream::write_error)?;
+    while let Some(r) = rows.next()? {
+        writer.row(row_values(&r, &*columns)).map_err(stream::write_error)?;
+    }
+    writer.end().map_err(stream::write_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use schema::{ForeignKey, Visibility};
+
+    fn references(name: &str, table: &str) -> Column {
+        let mut c = Column::new(name, Type::Int4);
+        c.foreign_key = Some(ForeignKey { table: table.into(), column: "id".into() });
+        c
+    }
+
+    // posts reference their author and their editor, comments their post
+    fn tables() -> HashMap<String, Table> {
+        let mut password = Column::new("password", Type::Text);
+        password.visibility = Visibility::Hidden;
+        let tables = vec![
+            Table::new("public", "users", vec![Column::new("id", Type::Int4),
+                                               Column::new("name", Type::Text), password]),
+            Table::new("public", "posts", vec![Column::new("id", Type::Int4),
+                                               references("author_id", "users"),
+                                               references("editor_id", "users"),
+                                               Column::new("title", Type::Text)]),
+            Table::new("public", "comments", vec![Column::new("id", Type::Int4),
+                                                  references("post_id", "posts"),
+                                                  Column::new("body", Type::Text)]),
+        ];
+        tables.into_iter().map(|t| (t.name.clone(), t)).collect()
+    }
+
+    fn generate(table: &str, query: &str) -> Result<(String, Vec<Column>), Error> {
+        let tables = tables();
+        generate_query(&tables, &tables[table], &Query::parse(query).unwrap(), None)
+    }
+
+    #[test]
+    fn embedded_parent() {
+        let (sql, columns) = generate("posts", "select=id,author:users!author_id(name)").unwrap();
+        assert_eq!(sql, "SELECT \"posts\".\"id\", (SELECT row_to_json(_r) FROM (\
+                         SELECT \"e1\".\"name\" AS \"name\" FROM \"public\".\"users\" AS \"e1\" \
+                         WHERE \"e1\".\"id\" = \"posts\".\"author_id\") AS _r)::text \
+                         FROM \"public\".\"posts\"");
+        assert_eq!(columns.iter().map(|c| &*c.name).collect::<Vec<&str>>(), vec!["id", "author"]);
+        assert_eq!(columns[1].data_type, Type::Json);
+    }
+
+    #[test]
+    fn embedded_children() {
+        let (sql, _) = generate("users", "select=name,posts!author_id(title,comments(body))")
+            .unwrap();
+        assert_eq!(sql, "SELECT \"users\".\"name\", (SELECT coalesce(json_agg(_r), '[]') FROM (\
+                         SELECT \"e1\".\"title\" AS \"title\", \
+                         (SELECT coalesce(json_agg(_r), '[]') FROM (\
+                         SELECT \"e2\".\"body\" AS \"body\" FROM \"public\".\"comments\" AS \"e2\" \
+                         WHERE \"e2\".\"post_id\" = \"e1\".\"id\") AS _r) AS \"comments\" \
+                         FROM \"public\".\"posts\" AS \"e1\" \
+                         WHERE \"e1\".\"author_id\" = \"users\".\"id\") AS _r)::text \
+                         FROM \"public\".\"users\"");
+        // every readable column of the embedded table
+        let (sql, _) = generate("comments", "select=id,posts(*)").unwrap();
+        assert!(sql.contains("SELECT \"e1\".\"id\" AS \"id\", \"e1\".\"author_id\" AS \
+                              \"author_id\", \"e1\".\"editor_id\" AS \"editor_id\", \
+                              \"e1\".\"title\" AS \"title\" FROM"));
+    }
+
+    #[test]
+    fn invalid_embedded_resources() {
+        let invalid = |table: &str, query: &str| match generate(table, query) {
+            Err(Error::InvalidInputError(e)) => e,
+            r => panic!("unexpected {:?} for {}", r, query),
+        };
+        assert_eq!(invalid("posts", "select=id,users(name)"),
+                   "cannot embed users in posts, several foreign keys relate them, \
+                    use users!column to choose one");
+        assert_eq!(invalid("comments", "select=id,users(name)"),
+                   "cannot embed users in comments, no foreign key relates them");
+        assert_eq!(invalid("posts", "select=id,id:users!author_id(name)"), "id is selected twice");
+        match generate("posts", "select=id,users!author_id(password)") {
+            Err(Error::UnknowColumn(c, t)) => assert_eq!((&*c, &*t), ("password", "users")),
+            r => panic!("unexpected {:?}", r),
+        }
+        match generate("posts", "select=id,tags(name)") {
+            Err(Error::UnknowModel(t)) => assert_eq!(t, "tags"),
+            r => panic!("unexpected {:?}", r),
+        }
     }
 }
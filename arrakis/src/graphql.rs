@@ -0,0 +1,1383 @@
+// Copyright 2016 Jeremy Letang.
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! GraphQL endpoint, the schema is described in the `graphql_schema`
+//! module.
+//!
+//! A request is executed in two steps. The operation is first validated
+//! against the schema of the role and planned, each root field becoming a
+//! single sql query: the referenced rows are left joins and the referencing
+//! rows lateral joins aggregated as json, so nested selections never issue
+//! a query per row. The plan is then executed in one transaction, the
+//! mutations going through the insert, update and delete paths of the rest
+//! api. The introspection fields are answered from the schema alone.
+
+use common::{quote_column, quote_ident, quote_literal, quote_table};
+use cvt;
+use delete;
+use error::Error;
+use filters::{Filter, IsKind};
+use get;
+use graphql_parser::{self as ast, Document, OperationKind, ParseError, Pos, TypeRef};
+use graphql_schema::{self, Directive, EnumValue, Field, InputValue, Resolve, Schema,
+                     TypeKind};
+use json_schema::{Validator, Validators};
+use ordering::{self, Ordering};
+use patch;
+use post;
+use postgres::GenericConnection;
+use postgres::types::Type;
+use query::{self, Query};
+use schema::{Column, Table};
+use serde_json::{self, Map, Value};
+use std::cell::Cell;
+use std::collections::HashMap;
+
+/// Media type of the bodies made of the query alone.
+pub const GRAPHQL_CONTENT_TYPE: &'static str = "application/graphql";
+
+// maximum nesting of the selections, fragments included
+const MAX_DEPTH: usize = 32;
+
+const PARSE_FAILED: &'static str = "graphql_parse_failed";
+const VALIDATION_FAILED: &'static str = "graphql_validation_failed";
+
+/// A GraphQL request, its query with the values of its variables.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub query: String,
+    pub variables: Map<String, Value>,
+    pub operation_name: Option<String>,
+    // the requests sent with GET cannot execute mutations
+    read_only: bool,
+}
+
+impl Request {
+    pub fn new(query: &str) -> Request {
+        Request {
+            query: query.to_string(),
+            variables: Map::new(),
+            operation_name: None,
+            read_only: false,
+        }
+    }
+
+    /// Read a request from a json body, an object with a `query` member and
+    /// optional `variables` and `operationName` members.
+    pub fn from_json(body: &[u8]) -> Result<Request, Error> {
+        let value: Value = match serde_json::from_slice(body) {
+            Ok(v) => v,
+            Err(e) => return Err(Error::InvalidInputError(format!("{}", e))),
+        };
+        let m = match value {
+            Value::Object(m) => m,
+            _ => return Err(Error::InvalidInputError(
+                "expect a json object with a query member".into())),
+        };
+        let query = match m.get("query") {
+            Some(&Value::String(ref q)) => q.clone(),
+            _ => return Err(Error::InvalidInputError("missing graphql query".into())),
+        };
+        let variables = match m.get("variables") {
+            Some(&Value::Object(ref v)) => v.clone(),
+            None | Some(&Value::Null) => Map::new(),
+            _ => return Err(Error::InvalidInputError("variables must be an object".into())),
+        };
+        let operation_name = match m.get("operationName") {
+            Some(&Value::String(ref n)) => Some(n.clone()),
+            None | Some(&Value::Null) => None,
+            _ => return Err(Error::InvalidInputError("operationName must be a string".into())),
+        };
+        Ok(Request {
+            query: query,
+            variables: variables,
+            operation_name: operation_name,
+            read_only: false,
+        })
+    }
+
+    /// Read a request from the raw query string of a GET request, the
+    /// `variables` parameter being a json object. Such a request cannot
+    /// execute mutations.
+    pub fn parse(query_string: &str) -> Result<Request, Error> {
+        let mut request = Request::new("");
+        let mut has_query = false;
+        for param in query_string.split('&').filter(|s| !s.is_empty()) {
+            let (k, v) = match param.find('=') {
+                Some(pos) => (query::percent_decode(&param[..pos]),
+                              query::percent_decode(&param[pos+1..])),
+                None => (query::percent_decode(param), String::new()),
+            };
+            match &*k {
+                "query" => {
+                    request.query = v;
+                    has_query = true;
+                },
+                "variables" if !v.is_empty() => match serde_json::from_str(&*v) {
+                    Ok(Value::Object(m)) => request.variables = m,
+                    _ => return Err(Error::InvalidInputError(
+                        "variables must be a json object".into())),
+                },
+                "operationName" if !v.is_empty() => request.operation_name = Some(v),
+                _ => {},
+            }
+        }
+        if !has_query {
+            return Err(Error::InvalidInputError("missing graphql query".into()));
+        }
+        request.read_only = true;
+        Ok(request)
+    }
+}
+
+/// An error of a request, reported in the `errors` member of the response.
+#[derive(Debug, Clone)]
+pub struct QueryError {
+    pub message: String,
+    pub pos: Option<Pos>,
+    pub path: Vec<String>,
+    // the code of the error, the `code` of the arrakis errors
+    pub code: &'static str,
+}
+
+impl QueryError {
+    fn invalid(message: String, pos: Pos) -> QueryError {
+        QueryError { message: message, pos: Some(pos), path: vec![], code: VALIDATION_FAILED }
+    }
+
+    fn at(mut self, key: &str) -> QueryError {
+        self.path.insert(0, key.to_string());
+        self
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut m = Map::new();
+        m.insert("message".into(), Value::String(self.message.clone()));
+        if let Some(pos) = self.pos {
+            let mut location = Map::new();
+            location.insert("line".into(), Value::U64(pos.line as u64));
+            location.insert("column".into(), Value::U64(pos.column as u64));
+            m.insert("locations".into(), Value::Array(vec![Value::Object(location)]));
+        }
+        if !self.path.is_empty() {
+            let path = self.path.iter().map(|p| Value::String(p.clone())).collect();
+            m.insert("path".into(), Value::Array(path));
+        }
+        let mut extensions = Map::new();
+        extensions.insert("code".into(), Value::String(self.code.into()));
+        m.insert("extensions".into(), Value::Object(extensions));
+        Value::Object(m)
+    }
+}
+
+impl From<Error> for QueryError {
+    fn from(e: Error) -> QueryError {
+        QueryError { message: format!("{}", e), pos: None, path: vec![], code: e.code() }
+    }
+}
+
+impl From<ParseError> for QueryError {
+    fn from(e: ParseError) -> QueryError {
+        QueryError { message: e.message, pos: Some(e.pos), path: vec![], code: PARSE_FAILED }
+    }
+}
+
+/// Build the response of a request, `data` is absent when the request was
+/// not executed.
+pub fn response(data: Option<Value>, errors: Vec<QueryError>) -> Value {
+    let mut m = Map::new();
+    if !errors.is_empty() {
+        m.insert("errors".into(), Value::Array(errors.iter().map(|e| e.to_json()).collect()));
+    }
+    if let Some(data) = data {
+        m.insert("data".into(), data);
+    }
+    Value::Object(m)
+}
+
+/// The validated operation of a request, ready to be executed.
+pub struct Plan<'a> {
+    fields: Vec<(String, RootField<'a>)>,
+}
+
+enum RootField<'a> {
+    // answered from the schema
+    Value(Value),
+    // a json document read from the database, null if there are no rows
+    Select(String),
+    Insert(&'a Table, Vec<Value>, Vec<(String, Output<'a>)>),
+    Update(&'a Table, Query, Value, Vec<(String, Output<'a>)>),
+    Delete(&'a Table, Query, Vec<(String, Output<'a>)>),
+}
+
+// the fields of a mutation response
+enum Output<'a> {
+    Value(Value),
+    AffectedRows,
+    Returning(&'a Table, String, Object),
+}
+
+// the json object of a row of a table, built from the alias of the table and
+// its joins
+struct Object {
+    expr: String,
+    joins: Vec<String>,
+}
+
+// the fields of a selection set by response key, the fields with the same
+// key being merged
+type Fields<'p> = Vec<(String, Vec<&'p ast::Field>)>;
+
+impl<'a> Plan<'a> {
+    /// Whether the execution needs a database connection, i.e the operation
+    /// is not made of introspection fields only.
+    pub fn needs_database(&self) -> bool {
+        self.fields.iter().any(|&(_, ref f)| match *f {
+            RootField::Value(_) => false,
+            _ => true,
+        })
+    }
+
+    /// Execute the plan and return the data of the response, the rows of
+    /// the mutations are checked with the validators of their tables. The
+    /// connection can be omitted if the plan does not need the database.
+    pub fn execute(&self, conn: Option<&GenericConnection>,
+                   validators: &HashMap<String, Validators>) -> Result<Value, QueryError> {
+        let mut data = Map::new();
+        for &(ref key, ref field) in &self.fields {
+            let value = match (field, conn) {
+                (&RootField::Value(ref v), _) => v.clone(),
+                (_, Some(conn)) => execute_field(conn, validators, field).map_err(|e| e.at(key))?,
+                (_, None) => return Err(QueryError::from(Error::InternalError(
+                    "missing database connection".into()))),
+            };
+            data.insert(key.clone(), value);
+        }
+        Ok(Value::Object(data))
+    }
+}
+
+fn execute_field(conn: &GenericConnection, validators: &HashMap<String, Validators>,
+                 field: &RootField) -> Result<Value, QueryError> {
+    match *field {
+        RootField::Value(ref v) => Ok(v.clone()),
+        RootField::Select(ref sql) => Ok(select_json(conn, sql)?),
+        RootField::Insert(table, ref rows, ref outputs) => {
+            let mut ids = vec![];
+            let validator = table_validator(validators, table, |v| &v.create)?;
+            for row in rows {
+                let inserted = post::query(conn, table, validator, &Query::new(), row.clone())?;
+                if let Some(Value::Array(new_ids)) = inserted {
+                    ids.extend(new_ids);
+                }
+            }
+            mutation_response(conn, outputs, &*ids)
+        },
+        RootField::Update(table, ref query, ref set, ref outputs) => {
+            let validator = table_validator(validators, table, |v| &v.update)?;
+            let ids = match patch::query(conn, table, validator, query, set.clone())? {
+                Some(Value::Array(ids)) => ids,
+                _ => vec![],
+            };
+            mutation_response(conn, outputs, &*ids)
+        },
+        RootField::Delete(table, ref query, ref outputs) => {
+            // the deleted rows are read before they are deleted
+            let mut returning = vec![];
+            for &(ref key, ref output) in outputs {
+                if let Output::Returning(table, ref alias, ref object) = *output {
+                    let sql = rows_sql(table, alias, object, None, query.filters(),
+                                       &Query::new(), None);
+                    returning.push((key.clone(), select_json(conn, &*sql)?));
+                }
+            }
+            let n = delete::query(conn, table, query)?;
+            let mut m = Map::new();
+            for &(ref key, ref output) in outputs {
+                let value = match *output {
+                    Output::Value(ref v) => v.clone(),
+                    Output::AffectedRows => Value::U64(n),
+                    Output::Returning(..) => {
+                        let i = returning.iter().position(|&(ref k, _)| k == key).unwrap();
+                        returning.remove(i).1
+                    },
+                };
+                m.insert(key.clone(), value);
+            }
+            Ok(Value::Object(m))
+        },
+    }
+}
+
+// the response of an insert or an update, the modified rows are read back
+// from their ids
+// the validator of the rows written to a table by a mutation
+fn table_validator<'a, F>(validators: &'a HashMap<String, Validators>, table: &Table, f: F)
+                          -> Result<&'a Validator, Error>
+    where F: Fn(&'a Validators) -> &'a Validator {
+    validators.get(&table.name)
+        .map(f)
+        .ok_or(Error::InternalError(format!("missing validators of table {}", table.name)))
+}
+
+fn mutation_response(conn: &GenericConnection, outputs: &[(String, Output)], ids: &[Value])
+                     -> Result<Value, QueryError> {
+    let n = ids.len();
+    let mut m = Map::new();
+    for &(ref key, ref output) in outputs {
+        let value = match *output {
+            Output::Value(ref v) => v.clone(),
+            Output::AffectedRows => Value::U64(n as u64),
+            Output::Returning(_, _, _) if n == 0 => Value::Array(vec![]),
+            Output::Returning(table, ref alias, ref object) => {
+                let order = Query::builder().order(Ordering::Asc("id".into())).build();
+                let filters = [id_filter(ids)?];
+                let sql = rows_sql(table, alias, object, None, &filters, &order, None);
+                select_json(conn, &*sql)?
+            },
+        };
+        m.insert(key.clone(), value);
+    }
+    Ok(Value::Object(m))
+}
+
+fn id_filter(ids: &[Value]) -> Result<Filter, Error> {
+    let ids = ids.iter().map(cvt::json_value_to_string).collect::<Result<Vec<String>, Error>>()?;
+    Ok(Filter::In("id".into(), ids))
+}
+
+// the queries return a single json document as text
+fn select_json(conn: &GenericConnection, sql: &str) -> Result<Value, Error> {
+    debug!("arrakis graphql query: {}", sql);
+    let rows = conn.query(sql, &[])?;
+    if rows.is_empty() {
+        return Ok(Value::Null);
+    }
+    match rows.get(0).get::<_, Option<String>>(0) {
+        Some(s) => serde_json::from_str(&*s).map_err(|e| Error::InternalError(format!("{}", e))),
+        None => Ok(Value::Null),
+    }
+}
+
+/// Validate the operation of a request against the schema, and plan its
+/// execution. The plan borrows the tables of the schema.
+pub fn prepare<'a>(schema: &Schema<'a>, request: &Request, max_limit: Option<u32>)
+                   -> Result<Plan<'a>, Vec<QueryError>> {
+    let doc = ast::parse(&*request.query).map_err(|e| vec![QueryError::from(e)])?;
+    let op = select_operation(&doc, request).map_err(|e| vec![e])?;
+    let mut planner = Planner {
+        schema: schema,
+        doc: &doc,
+        variables: Map::new(),
+        defined: op.variables.iter().map(|v| v.name.clone()).collect(),
+        max_limit: max_limit,
+        aliases: Cell::new(0),
+    };
+    planner.coerce_variables(op, &request.variables)?;
+    let root = match op.kind {
+        OperationKind::Query => graphql_schema::QUERY,
+        OperationKind::Mutation if request.read_only => return Err(vec![QueryError::invalid(
+            "mutations cannot be sent with GET".into(), op.pos)]),
+        OperationKind::Mutation => match schema.mutation() {
+            Some(m) => m,
+            None => return Err(vec![QueryError::invalid(
+                "the schema has no mutation type".into(), op.pos)]),
+        },
+        OperationKind::Subscription => return Err(vec![QueryError::invalid(
+            "subscriptions are not supported".into(), op.pos)]),
+    };
+    let mut errors = vec![];
+    let mut fields = vec![];
+    let mut root_fields = vec![];
+    if let Err(e) = planner.collect(root, &*op.selection_set, &mut root_fields, &mut vec![]) {
+        return Err(vec![e]);
+    }
+    for (key, fs) in root_fields {
+        match planner.root_field(root, &*fs) {
+            Ok(f) => fields.push((key, f)),
+            Err(e) => errors.push(e.at(&*key)),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(Plan { fields: fields })
+}
+
+fn select_operation<'d>(doc: &'d Document, request: &Request)
+                        -> Result<&'d ast::Operation, QueryError> {
+    let pos = Pos { line: 1, column: 1 };
+    match request.operation_name {
+        Some(ref name) => doc.operations.iter()
+            .find(|op| op.name.as_ref() == Some(name))
+            .ok_or(QueryError::invalid(format!("unknown operation {}", name), pos)),
+        None if doc.operations.len() == 1 => Ok(&doc.operations[0]),
+        None if doc.operations.is_empty() =>
+            Err(QueryError::invalid("the document has no operation".into(), pos)),
+        None => Err(QueryError::invalid(
+            "operationName is required when the document has several operations".into(), pos)),
+    }
+}
+
+struct Planner<'p, 'a: 'p> {
+    schema: &'p Schema<'a>,
+    doc: &'p Document,
+    // the coerced values of the variables
+    variables: Map<String, Value>,
+    defined: Vec<String>,
+    max_limit: Option<u32>,
+    aliases: Cell<usize>,
+}
+
+impl<'p, 'a: 'p> Planner<'p, 'a> {
+    fn coerce_variables(&mut self, op: &ast::Operation, values: &Map<String, Value>)
+                        -> Result<(), Vec<QueryError>> {
+        let mut errors = vec![];
+        for def in &op.variables {
+            let input = match self.schema.get(def.ty.name()).map(|t| &t.kind) {
+                Some(&TypeKind::Object(_)) | None => false,
+                _ => true,
+            };
+            if !input {
+                errors.push(QueryError::invalid(
+                    format!("variable ${} cannot be of type {}", def.name, def.ty), def.pos));
+                continue;
+            }
+            let value = match (values.get(&def.name), &def.default) {
+                (Some(v), _) => v.clone(),
+                (None, &Some(ref default)) => match self.literal(default) {
+                    Ok(Some(v)) => v,
+                    _ => continue,
+                },
+                (None, &None) if def.ty.is_non_null() => {
+                    errors.push(QueryError::invalid(
+                        format!("variable ${} of type {} is required", def.name, def.ty),
+                        def.pos));
+                    continue;
+                },
+                (None, &None) => continue,
+            };
+            match coerce(self.schema, &def.ty, &value) {
+                Ok(v) => { self.variables.insert(def.name.clone(), v); },
+                Err(e) => errors.push(QueryError::invalid(
+                    format!("invalid value for variable ${}, {}", def.name, e), def.pos)),
+            }
+        }
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    fn alias(&self) -> String {
+        let n = self.aliases.get();
+        self.aliases.set(n + 1);
+        format!("t{}", n)
+    }
+
+    // collect the fields of a selection set applying to an object type,
+    // following the fragments
+    fn collect(&self, type_name: &str, selections: &'p [ast::Selection], fields: &mut Fields<'p>,
+               visited: &mut Vec<&'p str>) -> Result<(), QueryError> {
+        for selection in selections {
+            match *selection {
+                ast::Selection::Field(ref f) => {
+                    if !self.included(&*f.directives)? {
+                        continue;
+                    }
+                    let key = f.response_key();
+                    match fields.iter().position(|&(ref k, _)| k == key) {
+                        Some(i) => {
+                            let first = fields[i].1[0];
+                            if first.name != f.name || first.arguments != f.arguments {
+                                return Err(QueryError::invalid(
+                                    format!("fields {} conflict, they select different fields \
+                                             or arguments", key), f.pos));
+                            }
+                            fields[i].1.push(f);
+                        },
+                        None => fields.push((key.to_string(), vec![f])),
+                    }
+                },
+                ast::Selection::FragmentSpread(ref spread) => {
+                    if !self.included(&*spread.directives)? || visited.contains(&&*spread.name) {
+                        continue;
+                    }
+                    visited.push(&*spread.name);
+                    let fragment = match self.doc.fragment(&*spread.name) {
+                        Some(f) => f,
+                        None => return Err(QueryError::invalid(
+                            format!("unknown fragment {}", spread.name), spread.pos)),
+                    };
+                    self.type_condition(type_name, &*fragment.type_condition, spread.pos)?;
+                    self.collect(type_name, &*fragment.selection_set, fields, visited)?;
+                },
+                ast::Selection::InlineFragment(ref fragment) => {
+                    if !self.included(&*fragment.directives)? {
+                        continue;
+                    }
+                    if let Some(ref condition) = fragment.type_condition {
+                        self.type_condition(type_name, condition, fragment.pos)?;
+                    }
+                    self.collect(type_name, &*fragment.selection_set, fields, visited)?;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    // the schema has no interfaces nor unions, a fragment applies to a
+    // single object type
+    fn type_condition(&self, type_name: &str, condition: &str, pos: Pos)
+                      -> Result<(), QueryError> {
+        if condition == type_name {
+            return Ok(());
+        }
+        let message = match self.schema.get(condition) {
+            Some(_) => format!("a fragment on {} cannot be spread in {}", condition, type_name),
+            None => format!("unknown type {}", condition),
+        };
+        Err(QueryError::invalid(message, pos))
+    }
+
+    // the fields selected under the fields merged for a key
+    fn subfields(&self, type_name: &str, fields: &[&'p ast::Field], depth: usize)
+                 -> Result<Fields<'p>, QueryError> {
+        if depth > MAX_DEPTH {
+            return Err(QueryError::invalid("the query is nested too deeply".into(),
+                                           fields[0].pos));
+        }
+        let mut subfields = vec![];
+        let mut visited = vec![];
+        for f in fields {
+            self.collect(type_name, &*f.selection_set, &mut subfields, &mut visited)?;
+        }
+        Ok(subfields)
+    }
+
+    // the @skip and @include directives
+    fn included(&self, directives: &[ast::Directive]) -> Result<bool, QueryError> {
+        for d in directives {
+            let def = match self.schema.directive(&*d.name) {
+                Some(def) => def,
+                None => return Err(QueryError::invalid(format!("unknown directive @{}", d.name),
+                                                       d.pos)),
+            };
+            let args = self.arguments(&*def.args, &*d.arguments, &*d.name, d.pos)?;
+            let condition = args.get("if").and_then(|v| v.as_bool()).unwrap_or(false);
+            if (d.name == "skip") == condition {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // check the arguments of a field against their definition, and coerce
+    // them
+    fn arguments(&self, defs: &[InputValue], args: &[(String, ast::Value)], field: &str,
+                 pos: Pos) -> Result<Map<String, Value>, QueryError> {
+        let mut values = Map::new();
+        for &(ref name, ref value) in args {
+            let def = match defs.iter().find(|d| d.name == *name) {
+                Some(def) => def,
+                None => return Err(QueryError::invalid(
+                    format!("unknown argument {} of {}", name, field), pos)),
+            };
+            if values.contains_key(name) {
+                return Err(QueryError::invalid(
+                    format!("argument {} of {} is given twice", name, field), pos));
+            }
+            let value = match self.literal(value).map_err(|e| QueryError::invalid(e, pos))? {
+                Some(v) => v,
+                None => continue,
+            };
+            match coerce(self.schema, &def.ty, &value) {
+                Ok(v) => { values.insert(name.clone(), v); },
+                Err(e) => return Err(QueryError::invalid(
+                    format!("invalid value for argument {} of {}, {}", name, field, e), pos)),
+            }
+        }
+        for def in defs {
+            if values.contains_key(&def.name) {
+                continue;
+            }
+            match def.default {
+                Some(ref v) => { values.insert(def.name.clone(), v.clone()); },
+                None if def.ty.is_non_null() => return Err(QueryError::invalid(
+                    format!("argument {} of {} of type {} is required", def.name, field, def.ty),
+                    pos)),
+                None => {},
+            }
+        }
+        Ok(values)
+    }
+
+    // the json value of a literal, none for a variable without value
+    fn literal(&self, value: &ast::Value) -> Result<Option<Value>, String> {
+        let v = match *value {
+            ast::Value::Variable(ref name) => {
+                if !self.defined.contains(name) {
+                    return Err(format!("variable ${} is not defined", name));
+                }
+                return Ok(self.variables.get(name).cloned());
+            },
+            ast::Value::Int(i) => Value::I64(i),
+            ast::Value::Float(f) => Value::F64(f),
+            ast::Value::String(ref s) | ast::Value::Enum(ref s) => Value::String(s.clone()),
+            ast::Value::Boolean(b) => Value::Bool(b),
+            ast::Value::Null => Value::Null,
+            ast::Value::List(ref values) => {
+                let mut list = vec![];
+                for v in values {
+                    list.push(self.literal(v)?.unwrap_or(Value::Null));
+                }
+                Value::Array(list)
+            },
+            ast::Value::Object(ref fields) => {
+                let mut m = Map::new();
+                for &(ref k, ref v) in fields {
+                    if let Some(v) = self.literal(v)? {
+                        m.insert(k.clone(), v);
+                    }
+                }
+                Value::Object(m)
+            },
+        };
+        Ok(Some(v))
+    }
+
+    // leaf fields have no selection, object fields must have one
+    fn check_selection(&self, f: &ast::Field, ty: &TypeRef) -> Result<(), QueryError> {
+        let object = match self.schema.get(ty.name()).map(|t| &t.kind) {
+            Some(&TypeKind::Object(_)) => true,
+            _ => false,
+        };
+        if object && f.selection_set.is_empty() {
+            return Err(QueryError::invalid(
+                format!("field {} of type {} must have a selection of subfields", f.name, ty),
+                f.pos));
+        }
+        if !object && !f.selection_set.is_empty() {
+            return Err(QueryError::invalid(
+                format!("field {} of type {} cannot have a selection of subfields", f.name, ty),
+                f.pos));
+        }
+        Ok(())
+    }
+
+    fn field_def(&self, type_name: &str, f: &ast::Field) -> Result<&'p Field, QueryError> {
+        let schema = self.schema;
+        match schema.field(type_name, &*f.name) {
+            Some(def) => {
+                self.check_selection(f, &def.ty)?;
+                Ok(def)
+            },
+            None => Err(QueryError::invalid(
+                format!("unknown field {} on type {}", f.name, type_name), f.pos)),
+        }
+    }
+
+    fn root_field(&self, root: &str, fields: &[&'p ast::Field])
+                  -> Result<RootField<'a>, QueryError> {
+        let f = fields[0];
+        match &*f.name {
+            "__typename" => return Ok(RootField::Value(Value::String(root.into()))),
+            "__schema" if root == graphql_schema::QUERY => {
+                let sub = self.subfields("__Schema", fields, 1)?;
+                return Ok(RootField::Value(self.introspect(&Meta::Schema, "__Schema", &sub, 1)?));
+            },
+            "__type" if root == graphql_schema::QUERY => {
+                let defs = [InputValue {
+                    name: "name".into(),
+                    description: None,
+                    ty: TypeRef::non_null(TypeRef::named("String")),
+                    default: None,
+                }];
+                let args = self.arguments(&defs, &*f.arguments, "__type", f.pos)?;
+                let name = args.get("name").and_then(|n| n.as_str()).unwrap_or("");
+                if f.selection_set.is_empty() {
+                    return Err(QueryError::invalid(
+                        "field __type must have a selection of subfields".into(), f.pos));
+                }
+                if self.schema.get(name).is_none() {
+                    return Ok(RootField::Value(Value::Null));
+                }
+                let sub = self.subfields("__Type", fields, 1)?;
+                let meta = Meta::Type(TypeRef::named(name));
+                return Ok(RootField::Value(self.introspect(&meta, "__Type", &sub, 1)?));
+            },
+            _ => {},
+        }
+        let def = self.field_def(root, f)?;
+        let args = self.arguments(&*def.args, &*f.arguments, &*f.name, f.pos)?;
+        let invalid = |e: String| QueryError::invalid(e, f.pos);
+        match def.resolve {
+            Resolve::Select(ref t) => {
+                let table = self.schema.table(t).unwrap();
+                let query = list_query(table, &args).map_err(&invalid)?;
+                let alias = self.alias();
+                let object = self.object(table, &*alias, fields, 1)?;
+                let sql = rows_sql(table, &*alias, &object, None, &[], &query, self.max_limit);
+                Ok(RootField::Select(format!("SELECT _q.j::text FROM ({}) AS _q", sql)))
+            },
+            Resolve::SelectByPk(ref t) => {
+                let table = self.schema.table(t).unwrap();
+                let mut filters = vec![];
+                for (column, value) in &args {
+                    let c = table.columns.get(column).unwrap();
+                    let value = filter_value(c, value).map_err(|e| invalid(format!("{}", e)))?;
+                    filters.push(Filter::Equal(column.clone(), value));
+                }
+                let alias = self.alias();
+                let object = self.object(table, &*alias, fields, 1)?;
+                let sql = format!("SELECT _q.j::text FROM ({} LIMIT 1) AS _q",
+                                  select_sql(table, &*alias, &object, None, &*filters));
+                Ok(RootField::Select(sql))
+            },
+            Resolve::Insert(ref t) => {
+                let table = self.schema.table(t).unwrap();
+                let rows = match args.get("objects") {
+                    Some(&Value::Array(ref rows)) => rows.iter().map(row).collect(),
+                    _ => vec![],
+                };
+                let outputs = self.outputs(table, &*def.ty.name(), fields)?;
+                Ok(RootField::Insert(table, rows, outputs))
+            },
+            Resolve::Update(ref t) => {
+                let table = self.schema.table(t).unwrap();
+                let query = list_query(table, &args).map_err(&invalid)?;
+                let set = row(args.get("_set").unwrap_or(&Value::Null));
+                let outputs = self.outputs(table, &*def.ty.name(), fields)?;
+                Ok(RootField::Update(table, query, set, outputs))
+            },
+            Resolve::Delete(ref t) => {
+                let table = self.schema.table(t).unwrap();
+                let query = list_query(table, &args).map_err(&invalid)?;
+                let outputs = self.outputs(table, &*def.ty.name(), fields)?;
+                Ok(RootField::Delete(table, query, outputs))
+            },
+            _ => Err(QueryError::invalid(format!("unknown field {} on type {}", f.name, root),
+                                         f.pos)),
+        }
+    }
+
+    // the fields of a mutation response
+    fn outputs(&self, table: &'a Table, type_name: &str, fields: &[&'p ast::Field])
+               -> Result<Vec<(String, Output<'a>)>, QueryError> {
+        let mut outputs = vec![];
+        for (key, fs) in self.subfields(type_name, fields, 1)? {
+            let f = fs[0];
+            if f.name == "__typename" {
+                outputs.push((key, Output::Value(Value::String(type_name.into()))));
+                continue;
+            }
+            let def = self.field_def(type_name, f)?;
+            self.arguments(&*def.args, &*f.arguments, &*f.name, f.pos)?;
+            let output = match def.resolve {
+                Resolve::Returning(_) => {
+                    let alias = self.alias();
+                    let object = self.object(table, &*alias, &*fs, 2)?;
+                    Output::Returning(table, alias, object)
+                },
+                _ => Output::AffectedRows,
+            };
+            outputs.push((key, output));
+        }
+        Ok(outputs)
+    }
+
+    // the json object of the rows of a table selected by the fields, read
+    // from the table aliased as alias
+    fn object(&self, table: &Table, alias: &str, fields: &[&'p ast::Field], depth: usize)
+              -> Result<Object, QueryError> {
+        let mut columns = vec![];
+        let mut joins = vec![];
+        for (key, fs) in self.subfields(&*table.name, fields, depth)? {
+            let f = fs[0];
+            if f.name == "__typename" {
+                columns.push(format!("{} AS {}", quote_literal(&*table.name), quote_ident(&*key)));
+                continue;
+            }
+            let def = self.field_def(&*table.name, f)?;
+            let args = self.arguments(&*def.args, &*f.arguments, &*f.name, f.pos)?;
+            match def.resolve {
+                Resolve::Column(ref c) => {
+                    let column = table.columns.get(c).unwrap();
+                    let expr = cvt::json_expr(&*quote_column(Some(alias), c), &column.data_type);
+                    columns.push(format!("{} AS {}", expr, quote_ident(&*key)));
+                },
+                Resolve::Parent(ref relation) => {
+                    let other = self.schema.table(&*relation.table).unwrap();
+                    let other_alias = self.alias();
+                    let object = self.object(other, &*other_alias, &*fs, depth + 1)?;
+                    let foreign = quote_column(Some(&*other_alias), &*relation.foreign_column);
+                    joins.push(format!("LEFT JOIN {} AS {} ON {} = {}", quote_table(other),
+                                       quote_ident(&*other_alias), foreign,
+                                       quote_column(Some(alias), &*relation.column)));
+                    joins.extend(object.joins);
+                    columns.push(format!("CASE WHEN {} IS NULL THEN NULL ELSE {} END AS {}",
+                                         foreign, object.expr, quote_ident(&*key)));
+                },
+                Resolve::Children(ref relation) => {
+                    let other = self.schema.table(&*relation.table).unwrap();
+                    let query = list_query(other, &args)
+                        .map_err(|e| QueryError::invalid(e, f.pos))?;
+                    let other_alias = self.alias();
+                    let object = self.object(other, &*other_alias, &*fs, depth + 1)?;
+                    let condition = format!("{} = {}",
+                                            quote_column(Some(&*other_alias),
+                                                         &*relation.foreign_column),
+                                            quote_column(Some(alias), &*relation.column));
+                    let rows = rows_sql(other, &*other_alias, &object, Some(&*condition), &[],
+                                        &query, self.max_limit);
+                    let lateral = self.alias();
+                    joins.push(format!("LEFT JOIN LATERAL ({}) AS {} ON true", rows,
+                                       quote_ident(&*lateral)));
+                    columns.push(format!("{}.j AS {}", quote_ident(&*lateral), quote_ident(&*key)));
+                },
+                _ => return Err(QueryError::invalid(
+                    format!("unknown field {} on type {}", f.name, table.name), f.pos)),
+            }
+        }
+        let expr = if columns.is_empty() {
+            "'{}'::json".to_string()
+        } else {
+            format!("(SELECT row_to_json(_r) FROM (SELECT {}) AS _r)", columns.join(", "))
+        };
+        Ok(Object { expr: expr, joins: joins })
+    }
+
+    fn introspect(&self, meta: &Meta<'p>, type_name: &str, fields: &Fields<'p>, depth: usize)
+                  -> Result<Value, QueryError> {
+        let mut m = Map::new();
+        for &(ref key, ref fs) in fields {
+            let f = fs[0];
+            if f.name == "__typename" {
+                m.insert(key.clone(), Value::String(type_name.into()));
+                continue;
+            }
+            let def = self.field_def(type_name, f)?;
+            self.arguments(&*def.args, &*f.arguments, &*f.name, f.pos)?;
+            let sub_type = def.ty.name();
+            let value = match self.meta_field(meta, &*f.name) {
+                MetaValue::Leaf(v) => v,
+                MetaValue::Object(o) => {
+                    let sub = self.subfields(sub_type, &*fs, depth + 1)?;
+                    self.introspect(&o, sub_type, &sub, depth + 1)?
+                },
+                MetaValue::List(objects) => {
+                    let sub = self.subfields(sub_type, &*fs, depth + 1)?;
+                    let mut values = vec![];
+                    for o in &objects {
+                        values.push(self.introspect(o, sub_type, &sub, depth + 1)?);
+                    }
+                    Value::Array(values)
+                },
+            };
+            m.insert(key.clone(), value);
+        }
+        Ok(Value::Object(m))
+    }
+
+    fn meta_field(&self, meta: &Meta<'p>, name: &str) -> MetaValue<'p> {
+        let schema = self.schema;
+        let type_ref = |ty: &TypeRef| MetaValue::Object(Meta::Type(ty.clone()));
+        match (meta, name) {
+            (&Meta::Schema, "types") => MetaValue::List(schema.types().iter()
+                .map(|t| Meta::Type(TypeRef::named(&*t.name))).collect()),
+            (&Meta::Schema, "queryType") => type_ref(&TypeRef::named(graphql_schema::QUERY)),
+            (&Meta::Schema, "mutationType") => match schema.mutation() {
+                Some(m) => type_ref(&TypeRef::named(m)),
+                None => MetaValue::Leaf(Value::Null),
+            },
+            (&Meta::Schema, "directives") =>
+                MetaValue::List(schema.directives().iter().map(Meta::Directive).collect()),
+            (&Meta::Type(ref ty), _) => self.meta_type(ty, name),
+            (&Meta::Field(f), "name") => leaf_string(&*f.name),
+            (&Meta::Field(f), "description") => leaf_option(&f.description),
+            (&Meta::Field(f), "args") =>
+                MetaValue::List(f.args.iter().map(Meta::InputValue).collect()),
+            (&Meta::Field(f), "type") => type_ref(&f.ty),
+            (&Meta::InputValue(v), "name") => leaf_string(&*v.name),
+            (&Meta::InputValue(v), "description") => leaf_option(&v.description),
+            (&Meta::InputValue(v), "type") => type_ref(&v.ty),
+            (&Meta::InputValue(v), "defaultValue") =>
+                MetaValue::Leaf(v.default.as_ref().map_or(Value::Null, |d| {
+                    Value::String(print_value(d))
+                })),
+            (&Meta::EnumValue(v), "name") => leaf_string(&*v.name),
+            (&Meta::EnumValue(v), "description") => leaf_option(&v.description),
+            (&Meta::Directive(d), "name") => leaf_string(&*d.name),
+            (&Meta::Directive(d), "description") => leaf_option(&d.description),
+            (&Meta::Directive(d), "locations") => MetaValue::Leaf(Value::Array(
+                d.locations.iter().map(|l| Value::String(l.to_string())).collect())),
+            (&Meta::Directive(d), "args") =>
+                MetaValue::List(d.args.iter().map(Meta::InputValue).collect()),
+            (_, "isDeprecated") | (_, "isRepeatable") => MetaValue::Leaf(Value::Bool(false)),
+            _ => MetaValue::Leaf(Value::Null),
+        }
+    }
+
+    fn meta_type(&self, ty: &TypeRef, name: &str) -> MetaValue<'p> {
+        let schema = self.schema;
+        let t = match *ty {
+            TypeRef::NonNull(ref inner) | TypeRef::List(ref inner) => return match name {
+                "kind" => leaf_string(if ty.is_non_null() { "NON_NULL" } else { "LIST" }),
+                "ofType" => MetaValue::Object(Meta::Type((**inner).clone())),
+                _ => MetaValue::Leaf(Value::Null),
+            },
+            TypeRef::Named(ref n) => schema.get(n).unwrap(),
+        };
+        match (name, &t.kind) {
+            ("kind", _) => leaf_string(t.kind.name()),
+            ("name", _) => leaf_string(&*t.name),
+            ("description", _) => leaf_option(&t.description),
+            ("fields", &TypeKind::Object(ref fields)) =>
+                MetaValue::List(fields.iter().map(Meta::Field).collect()),
+            ("interfaces", &TypeKind::Object(_)) => MetaValue::List(vec![]),
+            ("enumValues", &TypeKind::Enum(ref values)) =>
+                MetaValue::List(values.iter().map(Meta::EnumValue).collect()),
+            ("inputFields", &TypeKind::InputObject(ref fields)) =>
+                MetaValue::List(fields.iter().map(Meta::InputValue).collect()),
+            ("isOneOf", &TypeKind::InputObject(_)) => MetaValue::Leaf(Value::Bool(false)),
+            _ => MetaValue::Leaf(Value::Null),
+        }
+    }
+}
+
+// the objects of the introspection types
+enum Meta<'p> {
+    Schema,
+    Type(TypeRef),
+    Field(&'p Field),
+    InputValue(&'p InputValue),
+    EnumValue(&'p EnumValue),
+    Directive(&'p Directive),
+}
+
+enum MetaValue<'p> {
+    Leaf(Value),
+    Object(Meta<'p>),
+    List(Vec<Meta<'p>>),
+}
+
+fn leaf_string<'p>(s: &str) -> MetaValue<'p> {
+    MetaValue::Leaf(Value::String(s.to_string()))
+}
+
+fn leaf_option<'p>(s: &Option<String>) -> MetaValue<'p> {
+    MetaValue::Leaf(s.as_ref().map_or(Value::Null, |s| Value::String(s.clone())))
+}
+
+// a default value written as a literal of the document
+fn print_value(v: &Value) -> String {
+    match *v {
+        Value::String(ref s) => serde_json::to_string(s).unwrap_or_default(),
+        _ => serde_json::to_string(v).unwrap_or_default(),
+    }
+}
+
+// select the json object of the rows of a table matching the filters, and the
+// condition joining them to their parent row
+fn select_sql(table: &Table, alias: &str, object: &Object, join: Option<&str>,
+              filters: &[Filter]) -> String {
+    let mut sql = format!("SELECT {} AS j FROM {} AS {}", object.expr, quote_table(table),
+                          quote_ident(alias));
+    for join in &object.joins {
+        sql = format!("{} {}", sql, join);
+    }
+    let mut conditions = join.into_iter().map(|j| j.to_string()).collect::<Vec<String>>();
+    conditions.extend(filters.iter().map(|f| f.to_string(Some(alias))));
+    if conditions.is_empty() {
+        return sql;
+    }
+    format!("{} WHERE {}", sql, conditions.join(" AND "))
+}
+
+// aggregate the rows of a query in a json array
+fn rows_sql(table: &Table, alias: &str, object: &Object, join: Option<&str>, filters: &[Filter],
+            query: &Query, max_limit: Option<u32>) -> String {
+    let mut filters = filters.to_vec();
+    filters.extend(query.filters().iter().cloned());
+    let mut sql = select_sql(table, alias, object, join, &*filters);
+    if !query.order().is_empty() {
+        let order = query.order().iter()
+            .map(|o| ordering::to_string(o, Some(alias)))
+            .collect::<Vec<String>>();
+        sql = format!("{} ORDER BY {}", sql, order.join(", "));
+    }
+    let sql = get::generate_limit(sql, query, max_limit);
+    let sql = get::generate_offset(sql, query);
+    format!("SELECT coalesce(json_agg(_l.j), '[]') AS j FROM ({}) AS _l", sql)
+}
+
+// the filters, orderings and pagination of the arguments of a list
+fn list_query(table: &Table, args: &Map<String, Value>) -> Result<Query, String> {
+    let mut builder = Query::builder();
+    if let Some(&Value::Object(ref exp)) = args.get("where") {
+        for filter in where_filters(table, exp)? {
+            builder = builder.filter(filter);
+        }
+    }
+    if let Some(&Value::Array(ref orders)) = args.get("order_by") {
+        for order in orders {
+            for (column, direction) in order.as_object().into_iter().flat_map(|m| m.iter()) {
+                builder = builder.order(match direction.as_str() {
+                    Some("desc") => Ordering::Desc(column.clone()),
+                    _ => Ordering::Asc(column.clone()),
+                });
+            }
+        }
+    }
+    for name in &["limit", "offset"] {
+        let n = match args.get(*name).and_then(|v| v.as_i64()) {
+            Some(n) if n < 0 => return Err(format!("{} cannot be negative", name)),
+            Some(n) if n > u32::max_value() as i64 =>
+                return Err(format!("{} cannot be greater than {}", name, u32::max_value())),
+            Some(n) => n as u32,
+            None => continue,
+        };
+        builder = if *name == "limit" { builder.limit(n) } else { builder.offset(n) };
+    }
+    Ok(builder.build())
+}
+
+// the filters of a bool expression, a null comparison is ignored
+fn where_filters(table: &Table, exp: &Map<String, Value>) -> Result<Vec<Filter>, String> {
+    let mut filters = vec![];
+    for (name, comparison) in exp {
+        let column = table.columns.get(name).unwrap();
+        let ops = match comparison.as_object() {
+            Some(ops) => ops,
+            None => continue,
+        };
+        for (op, value) in ops {
+            if value.is_null() {
+                continue;
+            }
+            let c = name.clone();
+            let scalar = |v: &Value| filter_value(column, v).map_err(|e| format!("{}", e));
+            let filter = match &**op {
+                "_eq" => Filter::Equal(c, scalar(value)?),
+                "_neq" => Filter::NotEqual(c, scalar(value)?),
+                "_gt" => Filter::GreaterThan(c, scalar(value)?),
+                "_gte" => Filter::GreaterThanEqual(c, scalar(value)?),
+                "_lt" => Filter::LesserThan(c, scalar(value)?),
+                "_lte" => Filter::LesserThanEqual(c, scalar(value)?),
+                "_like" => Filter::Like(c, scalar(value)?),
+                "_nlike" => Filter::Not(Box::new(Filter::Like(c, scalar(value)?))),
+                "_ilike" => Filter::ILike(c, scalar(value)?),
+                "_nilike" => Filter::Not(Box::new(Filter::ILike(c, scalar(value)?))),
+                "_in" | "_nin" => {
+                    let values = match value.as_array() {
+                        Some(l) => l.iter().map(&scalar).collect::<Result<Vec<String>, String>>()?,
+                        None => vec![],
+                    };
+                    match (&**op, values.is_empty()) {
+                        ("_in", true) => return Err(format!("_in of {} cannot be empty", name)),
+                        (_, true) => continue,
+                        ("_in", false) => Filter::In(c, values),
+                        (_, false) => Filter::NotIn(c, values),
+                    }
+                },
+                _ => match value.as_bool() {
+                    Some(true) => Filter::Is(c, IsKind::Null),
+                    _ => Filter::IsNot(c, IsKind::Null),
+                },
+            };
+            filters.push(filter);
+        }
+    }
+    Ok(filters)
+}
+
+// the unix timestamps are written as timestamp literals
+fn filter_value(column: &Column, v: &Value) -> Result<String, Error> {
+    match (&column.data_type, v.as_i64()) {
+        (&Type::Timestamp, Some(secs)) | (&Type::TimestampTZ, Some(secs)) =>
+            Ok(cvt::format_timestamp(secs, 0)),
+        _ => cvt::json_value_to_string(v),
+    }
+}
+
+// the body of an insert or an update from an input object, the timestamps
+// are converted by the post and patch modules once the body is validated
+fn row(input: &Value) -> Value {
+    match *input {
+        Value::Object(_) => input.clone(),
+        _ => Value::Object(Map::new()),
+    }
+}
+
+// coerce a value to an input type
+fn coerce(schema: &Schema, ty: &TypeRef, value: &Value) -> Result<Value, String> {
+    match *ty {
+        TypeRef::NonNull(ref inner) => match *value {
+            Value::Null => Err(format!("expected a non null {}", inner)),
+            _ => coerce(schema, inner, value),
+        },
+        TypeRef::List(ref inner) => match *value {
+            Value::Null => Ok(Value::Null),
+            Value::Array(ref values) => {
+                let mut list = vec![];
+                for v in values {
+                    list.push(coerce(schema, inner, v)?);
+                }
+                Ok(Value::Array(list))
+            },
+            // a single value is a list of one value
+            _ => Ok(Value::Array(vec![coerce(schema, inner, value)?])),
+        },
+        TypeRef::Named(_) if value.is_null() => Ok(Value::Null),
+        TypeRef::Named(ref name) => match schema.get(name).map(|t| &t.kind) {
+            Some(&TypeKind::Scalar) => coerce_scalar(name, value),
+            Some(&TypeKind::Enum(ref values)) => match value.as_str() {
+                Some(s) if values.iter().any(|v| v.name == s) => Ok(value.clone()),
+                _ => Err(format!("expected a value of {}, found {}", name, value)),
+            },
+            Some(&TypeKind::InputObject(ref fields)) => {
+                let input = match value.as_object() {
+                    Some(m) => m,
+                    None => return Err(format!("expected an object {}, found {}", name, value)),
+                };
+                // the fields keep the order of the input, orderings depend
+                // on it
+                let mut m = Map::new();
+                for (k, v) in input {
+                    let field = match fields.iter().find(|f| f.name == *k) {
+                        Some(f) => f,
+                        None => return Err(format!("unknown field {} of {}", k, name)),
+                    };
+                    let v = coerce(schema, &field.ty, v)
+                        .map_err(|e| format!("field {} of {}, {}", k, name, e))?;
+                    m.insert(k.clone(), v);
+                }
+                for field in fields {
+                    if m.contains_key(&field.name) {
+                        continue;
+                    }
+                    match field.default {
+                        Some(ref v) => { m.insert(field.name.clone(), v.clone()); },
+                        None if field.ty.is_non_null() => return Err(
+                            format!("field {} of {} is required", field.name, name)),
+                        None => {},
+                    }
+                }
+                Ok(Value::Object(m))
+            },
+            _ => Err(format!("{} is not an input type", name)),
+        },
+    }
+}
+
+fn coerce_scalar(name: &str, value: &Value) -> Result<Value, String> {
+    let int = match *value {
+        Value::I64(i) => Some(i),
+        Value::U64(u) if u <= ::std::i64::MAX as u64 => Some(u as i64),
+        Value::F64(f) if f.fract() == 0. && f.abs() < 9007199254740992. => Some(f as i64),
+        _ => None,
+    };
+    let coerced = match (name, int) {
+        ("Int", Some(i)) if i >= ::std::i32::MIN as i64 && i <= ::std::i32::MAX as i64 =>
+            Some(Value::I64(i)),
+        ("BigInt", Some(i)) | ("Timestamp", Some(i)) => Some(Value::I64(i)),
+        ("BigInt", None) => value.as_str().and_then(|s| s.parse::<i64>().ok()).map(Value::I64),
+        ("Float", _) => value.as_f64().map(Value::F64),
+        ("String", _) | ("Timestamp", None) => value.as_str().map(|_| value.clone()),
+        ("Boolean", _) => value.as_bool().map(Value::Bool),
+        _ => None,
+    };
+    coerced.ok_or(format!("expected {}, found {}", name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use method::Method;
+    use schema::ForeignKey;
+
+    fn column(name: &str, ty: Type, foreign_key: Option<(&str, &str)>) -> Column {
+        let mut c = Column::new(name, ty);
+        c.is_nullable = name != "id";
+        c.is_primary_key = name == "id";
+        c.foreign_key = foreign_key.map(|(t, c)| ForeignKey { table: t.into(), column: c.into() });
+        c
+    }
+
+    fn table(name: &str, columns: Vec<Column>) -> (String, Table) {
+        (name.to_string(), Table::new("public", name, columns))
+    }
+
+    // users, and their posts referencing them by author_id
+    fn tables() -> HashMap<String, Table> {
+        vec![
+            table("users", vec![column("id", Type::Int4, None),
+                                column("name", Type::Text, None)]),
+            table("posts", vec![column("id", Type::Int4, None),
+                                column("author_id", Type::Int4, Some(("users", "id"))),
+                                column("title", Type::Text, None)]),
+        ].into_iter().collect()
+    }
+
+    fn schema(tables: &HashMap<String, Table>) -> Schema {
+        Schema::new(tables, |_| vec![Method::Get, Method::Post, Method::Patch, Method::Delete])
+    }
+
+    fn errors<'a>(result: Result<Plan<'a>, Vec<QueryError>>) -> Vec<String> {
+        match result {
+            Ok(_) => panic!("the request is valid"),
+            Err(errors) => errors.into_iter().map(|e| e.message).collect(),
+        }
+    }
+
+    fn sql(plan: &Plan, key: &str) -> String {
+        match plan.fields.iter().find(|&&(ref k, _)| k == key) {
+            Some(&(_, RootField::Select(ref sql))) => sql.clone(),
+            _ => panic!("no select for {}", key),
+        }
+    }
+
+    #[test]
+    fn fragments() {
+        let tables = tables();
+        let schema = schema(&tables);
+        let prepare = |q: &str| prepare(&schema, &Request::new(q), None);
+        let plan = prepare("{ users { ...F ... on users { name } ...F } } \
+                            fragment F on users { id ...F }").unwrap();
+        assert!(sql(&plan, "users").contains(
+            "(SELECT row_to_json(_r) FROM (SELECT \"t0\".\"id\" AS \"id\", \
+             \"t0\".\"name\" AS \"name\") AS _r)"));
+        assert_eq!(errors(prepare("{ users { ...G } }")), vec!["unknown fragment G"]);
+        assert_eq!(errors(prepare("{ users { ...P } } fragment P on posts { id }")),
+                   vec!["a fragment on posts cannot be spread in users"]);
+        assert_eq!(errors(prepare("{ users { ... on nope { id } } }")),
+                   vec!["unknown type nope"]);
+        // skipped fragments are not checked
+        assert!(prepare("{ users { id ...G @skip(if: true) } }").is_ok());
+    }
+
+    #[test]
+    fn variables() {
+        let tables = tables();
+        let schema = schema(&tables);
+        let query = "query ($n: Int = 2, $name: String) { \
+                     users(limit: $n, where: {name: {_eq: $name}}) { id } }";
+        let mut request = Request::new(query);
+        request.variables.insert("name".into(), Value::String("bob".into()));
+        let plan = prepare(&schema, &request, None).unwrap();
+        let users = sql(&plan, "users");
+        assert!(users.contains("WHERE \"t0\".\"name\" = 'bob' LIMIT 2) AS _l"), "{}", users);
+
+        // a variable without value is omitted
+        let plan = prepare(&schema, &Request::new(query), None).unwrap();
+        assert!(!sql(&plan, "users").contains("WHERE"));
+
+        let prepare = |q: &str, vars: &str| {
+            let mut request = Request::new(q);
+            request.variables = serde_json::from_str(vars).unwrap();
+            prepare(&schema, &request, None)
+        };
+        assert_eq!(errors(prepare("query ($n: Int!) { users(limit: $n) { id } }", "{}")),
+                   vec!["variable $n of type Int! is required"]);
+        assert_eq!(errors(prepare("query ($n: Int) { users(limit: $n) { id } }",
+                                  r#"{"n":"a"}"#)),
+                   vec![r#"invalid value for variable $n, expected Int, found "a""#]);
+        assert_eq!(errors(prepare("{ users(limit: $n) { id } }", "{}")),
+                   vec!["variable $n is not defined"]);
+        assert_eq!(errors(prepare("query ($u: users) { users { id } }", "{}")),
+                   vec!["variable $u cannot be of type users"]);
+    }
+
+    #[test]
+    fn conflicting_fields() {
+        let tables = tables();
+        let schema = schema(&tables);
+        let prepare = |q: &str| prepare(&schema, &Request::new(q), None);
+        assert!(prepare("{ users { id id } }").is_ok());
+        assert_eq!(errors(prepare("{ users { a: id a: name } }")),
+                   vec!["fields a conflict, they select different fields or arguments"]);
+        assert_eq!(errors(prepare("{ u: users(limit: 1) { id } u: users(limit: 2) { id } }")),
+                   vec!["fields u conflict, they select different fields or arguments"]);
+        // the errors of the root fields are all reported
+        assert_eq!(errors(prepare("{ nope users { nope } }")),
+                   vec!["unknown field nope on type Query",
+                        "unknown field nope on type users"]);
+    }
+
+    #[test]
+    fn mutations_with_get() {
+        let tables = tables();
+        let schema = schema(&tables);
+        let get = |q: &str| Request::parse(&*format!("query={}", q)).unwrap();
+        let mutation = "mutation { delete_users(where: {}) { affected_rows } }";
+        let request = get(&*mutation.replace(' ', "%20").replace('{', "%7B").replace('}', "%7D"));
+        assert_eq!(errors(prepare(&schema, &request, None)),
+                   vec!["mutations cannot be sent with GET"]);
+        assert!(prepare(&schema, &get("%7B%20users%20%7B%20id%20%7D%20%7D"), None).is_ok());
+        let request = Request::new(mutation);
+        assert!(prepare(&schema, &request, None).is_ok());
+    }
+
+    #[test]
+    fn parent_rows() {
+        let tables = tables();
+        let schema = schema(&tables);
+        let plan = prepare(&schema, &Request::new("{ posts { title author { name } } }"),
+                           Some(100)).unwrap();
+        assert_eq!(sql(&plan, "posts"), [
+            "SELECT _q.j::text FROM (",
+            "SELECT coalesce(json_agg(_l.j), '[]') AS j FROM (",
+            "SELECT (SELECT row_to_json(_r) FROM (SELECT \"t0\".\"title\" AS \"title\", ",
+            "CASE WHEN \"t1\".\"id\" IS NULL THEN NULL ELSE ",
+            "(SELECT row_to_json(_r) FROM (SELECT \"t1\".\"name\" AS \"name\") AS _r) ",
+            "END AS \"author\") AS _r) AS j ",
+            "FROM \"public\".\"posts\" AS \"t0\" ",
+            "LEFT JOIN \"public\".\"users\" AS \"t1\" ON \"t1\".\"id\" = \"t0\".\"author_id\" ",
+            "LIMIT 100) AS _l) AS _q",
+        ].concat());
+    }
+
+    #[test]
+    fn children_rows() {
+        let tables = tables();
+        let schema = schema(&tables);
+        let query = "{ users(order_by: [{name: desc}]) { \
+                     posts_by_author_id(limit: 2, where: {title: {_eq: \"a\"}}) { title } } }";
+        let plan = prepare(&schema, &Request::new(query), None).unwrap();
+        assert_eq!(sql(&plan, "users"), [
+            "SELECT _q.j::text FROM (",
+            "SELECT coalesce(json_agg(_l.j), '[]') AS j FROM (",
+            "SELECT (SELECT row_to_json(_r) FROM (SELECT \"t2\".j AS \"posts_by_author_id\") ",
+            "AS _r) AS j ",
+            "FROM \"public\".\"users\" AS \"t0\" ",
+            "LEFT JOIN LATERAL (",
+            "SELECT coalesce(json_agg(_l.j), '[]') AS j FROM (",
+            "SELECT (SELECT row_to_json(_r) FROM (SELECT \"t1\".\"title\" AS \"title\") ",
+            "AS _r) AS j ",
+            "FROM \"public\".\"posts\" AS \"t1\" ",
+            "WHERE \"t1\".\"author_id\" = \"t0\".\"id\" AND \"t1\".\"title\" = 'a' ",
+            "LIMIT 2) AS _l",
+            ") AS \"t2\" ON true ",
+            "ORDER BY \"t0\".\"name\" DESC) AS _l) AS _q",
+        ].concat());
+    }
+
+    #[test]
+    fn limits_of_lists() {
+        let (_, users) = table("users", vec![column("id", Type::Int4, None)]);
+        let args = |name: &str, n: Value| {
+            let mut m = Map::new();
+            m.insert(name.to_string(), n);
+            list_query(&users, &m)
+        };
+        let max = u32::max_value() as i64;
+        assert_eq!(args("limit", Value::I64(max)).unwrap().limit(), Some(u32::max_value()));
+        assert_eq!(args("limit", Value::I64(max + 1)).unwrap_err(),
+                   "limit cannot be greater than 4294967295");
+        assert_eq!(args("offset", Value::I64(max + 1)).unwrap_err(),
+                   "offset cannot be greater than 4294967295");
+        assert_eq!(args("offset", Value::I64(-1)).unwrap_err(), "offset cannot be negative");
+        assert_eq!(args("offset", Value::I64(3)).unwrap().offset(), Some(3));
+    }
+}
@@ -0,0 +1,980 @@
+// Copyright 2016 Jeremy Letang.
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Parser of the GraphQL executable documents (operations and fragments),
+//! the type system definitions are not supported.
+
+use std::fmt;
+
+// maximum nesting of the selection sets, lists and objects of a document
+const MAX_DEPTH: usize = 64;
+
+/// Position of a token in the document, starting at line 1 column 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pos {
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    pub pos: Pos,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "syntax error, {} at {}:{}", self.message, self.pos.line, self.pos.column)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Document {
+    pub operations: Vec<Operation>,
+    pub fragments: Vec<Fragment>,
+}
+
+impl Document {
+    pub fn fragment(&self, name: &str) -> Option<&Fragment> {
+        self.fragments.iter().find(|f| f.name == name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationKind {
+    Query,
+    Mutation,
+    Subscription,
+}
+
+impl fmt::Display for OperationKind {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            OperationKind::Query => "query",
+            OperationKind::Mutation => "mutation",
+            OperationKind::Subscription => "subscription",
+        };
+        write!(fmt, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Operation {
+    pub kind: OperationKind,
+    pub name: Option<String>,
+    pub variables: Vec<VariableDefinition>,
+    pub directives: Vec<Directive>,
+    pub selection_set: Vec<Selection>,
+    pub pos: Pos,
+}
+
+#[derive(Debug, Clone)]
+pub struct VariableDefinition {
+    pub name: String,
+    pub ty: TypeRef,
+    pub default: Option<Value>,
+    pub pos: Pos,
+}
+
+#[derive(Debug, Clone)]
+pub struct Fragment {
+    pub name: String,
+    pub type_condition: String,
+    pub directives: Vec<Directive>,
+    pub selection_set: Vec<Selection>,
+    pub pos: Pos,
+}
+
+#[derive(Debug, Clone)]
+pub enum Selection {
+    Field(Field),
+    FragmentSpread(FragmentSpread),
+    InlineFragment(InlineFragment),
+}
+
+#[derive(Debug, Clone)]
+pub struct Field {
+    pub alias: Option<String>,
+    pub name: String,
+    pub arguments: Vec<(String, Value)>,
+    pub directives: Vec<Directive>,
+    pub selection_set: Vec<Selection>,
+    pub pos: Pos,
+}
+
+impl Field {
+    /// The key of the field in the response, its alias or its name.
+    pub fn response_key(&self) -> &str {
+        self.alias.as_ref().unwrap_or(&self.name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FragmentSpread {
+    pub name: String,
+    pub directives: Vec<Directive>,
+    pub pos: Pos,
+}
+
+#[derive(Debug, Clone)]
+pub struct InlineFragment {
+    pub type_condition: Option<String>,
+    pub directives: Vec<Directive>,
+    pub selection_set: Vec<Selection>,
+    pub pos: Pos,
+}
+
+#[derive(Debug, Clone)]
+pub struct Directive {
+    pub name: String,
+    pub arguments: Vec<(String, Value)>,
+    pub pos: Pos,
+}
+
+/// A value written in the document, the variables are replaced by their
+/// value when the arguments are coerced.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Variable(String),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Boolean(bool),
+    Null,
+    Enum(String),
+    List(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+/// The type of a variable, an argument or a field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypeRef {
+    Named(String),
+    List(Box<TypeRef>),
+    NonNull(Box<TypeRef>),
+}
+
+impl TypeRef {
+    pub fn named(name: &str) -> TypeRef {
+        TypeRef::Named(name.to_string())
+    }
+
+    pub fn non_null(ty: TypeRef) -> TypeRef {
+        TypeRef::NonNull(Box::new(ty))
+    }
+
+    pub fn list(ty: TypeRef) -> TypeRef {
+        TypeRef::List(Box::new(ty))
+    }
+
+    /// The name of the type without its list and non null wrappers.
+    pub fn name(&self) -> &str {
+        match *self {
+            TypeRef::Named(ref n) => n,
+            TypeRef::List(ref t) | TypeRef::NonNull(ref t) => t.name(),
+        }
+    }
+
+    pub fn is_non_null(&self) -> bool {
+        match *self {
+            TypeRef::NonNull(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for TypeRef {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TypeRef::Named(ref n) => write!(fmt, "{}", n),
+            TypeRef::List(ref t) => write!(fmt, "[{}]", t),
+            TypeRef::NonNull(ref t) => write!(fmt, "{}!", t),
+        }
+    }
+}
+
+/// Whether `s` can be used as a name in a document, i.e a letter or an
+/// underscore followed by letters, digits and underscores.
+pub fn is_name(s: &str) -> bool {
+    let mut bytes = s.bytes();
+    match bytes.next() {
+        Some(b'a'...b'z') | Some(b'A'...b'Z') | Some(b'_') => {},
+        _ => return false,
+    }
+    bytes.all(|b| match b {
+        b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' | b'_' => true,
+        _ => false,
+    })
+}
+
+pub fn parse(source: &str) -> Result<Document, ParseError> {
+    let tokens = Lexer::new(source).tokenize()?;
+    let mut parser = Parser { tokens: tokens, i: 0, depth: 0 };
+    parser.document()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Punct(char),
+    Spread,
+    Name(String),
+    Int(String),
+    Float(String),
+    Str(String),
+    End,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Token::Punct(c) => write!(fmt, "\"{}\"", c),
+            Token::Spread => write!(fmt, "\"...\""),
+            Token::Name(ref n) => write!(fmt, "name \"{}\"", n),
+            Token::Int(ref n) | Token::Float(ref n) => write!(fmt, "number {}", n),
+            Token::Str(_) => write!(fmt, "string"),
+            Token::End => write!(fmt, "end of document"),
+        }
+    }
+}
+
+struct Lexer {
+    chars: Vec<char>,
+    i: usize,
+    line: usize,
+    // index of the first char of the current line
+    line_start: usize,
+}
+
+impl Lexer {
+    fn new(source: &str) -> Lexer {
+        Lexer { chars: source.chars().collect(), i: 0, line: 1, line_start: 0 }
+    }
+
+    fn pos(&self) -> Pos {
+        Pos { line: self.line, column: self.i - self.line_start + 1 }
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, ParseError> {
+        Err(ParseError { message: message.to_string(), pos: self.pos() })
+    }
+
+    fn peek(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.i + offset).cloned()
+    }
+
+    fn newline(&mut self) {
+        // \r\n is a single line terminator
+        if self.peek(0) == Some('\r') && self.peek(1) == Some('\n') {
+            self.i += 1;
+        }
+        self.i += 1;
+        self.line += 1;
+        self.line_start = self.i;
+    }
+
+    fn tokenize(mut self) -> Result<Vec<(Token, Pos)>, ParseError> {
+        let mut tokens = vec![];
+        loop {
+            self.skip_ignored();
+            let pos = self.pos();
+            let c = match self.peek(0) {
+                Some(c) => c,
+                None => {
+                    tokens.push((Token::End, pos));
+                    return Ok(tokens);
+                },
+            };
+            let token = match c {
+                '!' | '$' | '&' | '(' | ')' | ':' | '=' | '@' | '[' | ']' | '{' | '|' | '}' => {
+                    self.i += 1;
+                    Token::Punct(c)
+                },
+                '.' => {
+                    if self.peek(1) != Some('.') || self.peek(2) != Some('.') {
+                        return self.error("expected \"...\"");
+                    }
+                    self.i += 3;
+                    Token::Spread
+                },
+                '"' => self.string()?,
+                '-' | '0'...'9' => self.number()?,
+                'a'...'z' | 'A'...'Z' | '_' => {
+                    let start = self.i;
+                    while let Some(c) = self.peek(0) {
+                        match c {
+                            'a'...'z' | 'A'...'Z' | '0'...'9' | '_' => self.i += 1,
+                            _ => break,
+                        }
+                    }
+                    Token::Name(self.chars[start..self.i].iter().cloned().collect())
+                },
+                _ => return self.error(&*format!("unexpected character {:?}", c)),
+            };
+            tokens.push((token, pos));
+        }
+    }
+
+    // white spaces, line terminators, commas, comments and the byte order mark
+    fn skip_ignored(&mut self) {
+        while let Some(c) = self.peek(0) {
+            match c {
+                ' ' | '\t' | ',' | '\u{feff}' => self.i += 1,
+                '\n' | '\r' => self.newline(),
+                '#' => {
+                    while let Some(c) = self.peek(0) {
+                        if c == '\n' || c == '\r' {
+                            break;
+                        }
+                        self.i += 1;
+                    }
+                },
+                _ => return,
+            }
+        }
+    }
+
+    fn digits(&mut self) -> usize {
+        let start = self.i;
+        while let Some('0'...'9') = self.peek(0) {
+            self.i += 1;
+        }
+        self.i - start
+    }
+
+    fn number(&mut self) -> Result<Token, ParseError> {
+        let start = self.i;
+        if self.peek(0) == Some('-') {
+            self.i += 1;
+        }
+        if self.peek(0) == Some('0') {
+            self.i += 1;
+            if let Some('0'...'9') = self.peek(0) {
+                return self.error("unexpected digit after 0");
+            }
+        } else if self.digits() == 0 {
+            return self.error("expected a digit");
+        }
+        let mut float = false;
+        if self.peek(0) == Some('.') {
+            self.i += 1;
+            float = true;
+            if self.digits() == 0 {
+                return self.error("expected a digit after the decimal point");
+            }
+        }
+        if self.peek(0) == Some('e') || self.peek(0) == Some('E') {
+            self.i += 1;
+            float = true;
+            if self.peek(0) == Some('+') || self.peek(0) == Some('-') {
+                self.i += 1;
+            }
+            if self.digits() == 0 {
+                return self.error("expected a digit in the exponent");
+            }
+        }
+        match self.peek(0) {
+            Some('a'...'z') | Some('A'...'Z') | Some('_') | Some('.') =>
+                return self.error("invalid number"),
+            _ => {},
+        }
+        let s = self.chars[start..self.i].iter().cloned().collect();
+        Ok(if float { Token::Float(s) } else { Token::Int(s) })
+    }
+
+    fn string(&mut self) -> Result<Token, ParseError> {
+        if self.peek(1) == Some('"') && self.peek(2) == Some('"') {
+            return self.block_string();
+        }
+        self.i += 1;
+        let mut s = String::new();
+        loop {
+            let c = match self.peek(0) {
+                Some(c) => c,
+                None => return self.error("unterminated string"),
+            };
+            self.i += 1;
+            match c {
+                '"' => return Ok(Token::Str(s)),
+                '\n' | '\r' => return self.error("unterminated string"),
+                '\\' => {
+                    let e = match self.peek(0) {
+                        Some(e) => e,
+                        None => return self.error("unterminated string"),
+                    };
+                    self.i += 1;
+                    match e {
+                        '"' => s.push('"'),
+                        '\\' => s.push('\\'),
+                        '/' => s.push('/'),
+                        'b' => s.push('\u{8}'),
+                        'f' => s.push('\u{c}'),
+                        'n' => s.push('\n'),
+                        'r' => s.push('\r'),
+                        't' => s.push('\t'),
+                        'u' => s.push(self.unicode_escape()?),
+                        _ => return self.error(&*format!("invalid escape sequence \\{}", e)),
+                    }
+                },
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, ParseError> {
+        let hex = self.chars[self.i..].iter().take(4).cloned().collect::<String>();
+        match u32::from_str_radix(&*hex, 16) {
+            Ok(v) if hex.len() == 4 => {
+                self.i += 4;
+                Ok(v)
+            },
+            _ => self.error("invalid unicode escape sequence"),
+        }
+    }
+
+    // after \u, a surrogate pair is written as two escapes
+    fn unicode_escape(&mut self) -> Result<char, ParseError> {
+        let mut code = self.hex4()?;
+        if code >= 0xd800 && code < 0xdc00 && self.peek(0) == Some('\\')
+            && self.peek(1) == Some('u') {
+            self.i += 2;
+            let low = self.hex4()?;
+            if low < 0xdc00 || low >= 0xe000 {
+                return self.error("invalid unicode surrogate pair");
+            }
+            code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+        }
+        match ::std::char::from_u32(code) {
+            Some(c) => Ok(c),
+            None => self.error("invalid unicode escape sequence"),
+        }
+    }
+
+    fn block_string(&mut self) -> Result<Token, ParseError> {
+        self.i += 3;
+        let mut raw = String::new();
+        loop {
+            match self.peek(0) {
+                None => return self.error("unterminated block string"),
+                Some('"') if self.peek(1) == Some('"') && self.peek(2) == Some('"') => {
+                    self.i += 3;
+                    return Ok(Token::Str(block_string_value(&*raw)));
+                },
+                Some('\\') if self.peek(1) == Some('"') && self.peek(2) == Some('"')
+                    && self.peek(3) == Some('"') => {
+                    self.i += 4;
+                    raw.push_str("\"\"\"");
+                },
+                Some('\n') | Some('\r') => {
+                    self.newline();
+                    raw.push('\n');
+                },
+                Some(c) => {
+                    self.i += 1;
+                    raw.push(c);
+                },
+            }
+        }
+    }
+}
+
+// remove the common indentation and the leading and trailing blank lines
+fn block_string_value(raw: &str) -> String {
+    let lines = raw.split('\n').collect::<Vec<&str>>();
+    let indent = lines.iter().skip(1)
+        .filter_map(|l| {
+            let n = l.len() - l.trim_left_matches(|c| c == ' ' || c == '\t').len();
+            if n < l.len() { Some(n) } else { None }
+        })
+        .min()
+        .unwrap_or(0);
+    let mut lines = lines.iter().enumerate()
+        .map(|(i, l)| if i == 0 || l.len() < indent { *l } else { &l[indent..] })
+        .collect::<Vec<&str>>();
+    while lines.first().map_or(false, |l| l.trim().is_empty()) {
+        lines.remove(0);
+    }
+    while lines.last().map_or(false, |l| l.trim().is_empty()) {
+        lines.pop();
+    }
+    lines.join("\n")
+}
+
+struct Parser {
+    tokens: Vec<(Token, Pos)>,
+    i: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.i].0
+    }
+
+    fn pos(&self) -> Pos {
+        self.tokens[self.i].1
+    }
+
+    // the end token is never consumed
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.i].0.clone();
+        if token != Token::End {
+            self.i += 1;
+        }
+        token
+    }
+
+    fn unexpected<T>(&self) -> Result<T, ParseError> {
+        Err(ParseError { message: format!("unexpected {}", self.peek()), pos: self.pos() })
+    }
+
+    fn is_punct(&self, c: char) -> bool {
+        *self.peek() == Token::Punct(c)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        match *self.peek() {
+            Token::Name(ref n) => n == keyword,
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), ParseError> {
+        if !self.is_punct(c) {
+            return Err(ParseError { message: format!("expected \"{}\", found {}", c, self.peek()),
+                                    pos: self.pos() });
+        }
+        self.i += 1;
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<String, ParseError> {
+        let name = match *self.peek() {
+            Token::Name(ref n) => n.clone(),
+            ref t => return Err(ParseError { message: format!("expected a name, found {}", t),
+                                             pos: self.pos() }),
+        };
+        self.i += 1;
+        Ok(name)
+    }
+
+    // guard against the documents nested deep enough to overflow the stack
+    fn enter(&mut self) -> Result<(), ParseError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(ParseError { message: "document is nested too deeply".into(),
+                                    pos: self.pos() });
+        }
+        Ok(())
+    }
+
+    fn document(&mut self) -> Result<Document, ParseError> {
+        let mut doc = Document::default();
+        if *self.peek() == Token::End {
+            return self.unexpected();
+        }
+        while *self.peek() != Token::End {
+            if self.is_punct('{') {
+                let pos = self.pos();
+                doc.operations.push(Operation {
+                    kind: OperationKind::Query,
+                    name: None,
+                    variables: vec![],
+                    directives: vec![],
+                    selection_set: self.selection_set()?,
+                    pos: pos,
+                });
+            } else if self.is_keyword("fragment") {
+                doc.fragments.push(self.fragment()?);
+            } else if self.is_keyword("query") || self.is_keyword("mutation")
+                || self.is_keyword("subscription") {
+                doc.operations.push(self.operation()?);
+            } else {
+                return self.unexpected();
+            }
+        }
+        Ok(doc)
+    }
+
+    fn operation(&mut self) -> Result<Operation, ParseError> {
+        let pos = self.pos();
+        let kind = match &*self.name()? {
+            "query" => OperationKind::Query,
+            "mutation" => OperationKind::Mutation,
+            _ => OperationKind::Subscription,
+        };
+        let name = match *self.peek() {
+            Token::Name(_) => Some(self.name()?),
+            _ => None,
+        };
+        let variables = self.variable_definitions()?;
+        let directives = self.directives(false)?;
+        Ok(Operation {
+            kind: kind,
+            name: name,
+            variables: variables,
+            directives: directives,
+            selection_set: self.selection_set()?,
+            pos: pos,
+        })
+    }
+
+    fn variable_definitions(&mut self) -> Result<Vec<VariableDefinition>, ParseError> {
+        let mut variables = vec![];
+        if !self.is_punct('(') {
+            return Ok(variables);
+        }
+        self.i += 1;
+        loop {
+            let pos = self.pos();
+            self.expect('$')?;
+            let name = self.name()?;
+            self.expect(':')?;
+            let ty = self.type_ref()?;
+            let default = if self.is_punct('=') {
+                self.i += 1;
+                Some(self.value(true)?)
+            } else {
+                None
+            };
+            // directives on variables are accepted and ignored
+            self.directives(true)?;
+            variables.push(VariableDefinition { name: name, ty: ty, default: default, pos: pos });
+            if self.is_punct(')') {
+                self.i += 1;
+                return Ok(variables);
+            }
+        }
+    }
+
+    fn type_ref(&mut self) -> Result<TypeRef, ParseError> {
+        self.enter()?;
+        let ty = if self.is_punct('[') {
+            self.i += 1;
+            let ty = self.type_ref()?;
+            self.expect(']')?;
+            TypeRef::list(ty)
+        } else {
+            TypeRef::Named(self.name()?)
+        };
+        self.depth -= 1;
+        if self.is_punct('!') {
+            self.i += 1;
+            return Ok(TypeRef::non_null(ty));
+        }
+        Ok(ty)
+    }
+
+    fn fragment(&mut self) -> Result<Fragment, ParseError> {
+        let pos = self.pos();
+        self.i += 1;
+        let name = self.name()?;
+        if name == "on" {
+            return Err(ParseError { message: "a fragment cannot be named \"on\"".into(),
+                                    pos: pos });
+        }
+        if !self.is_keyword("on") {
+            return self.unexpected();
+        }
+        self.i += 1;
+        let type_condition = self.name()?;
+        let directives = self.directives(false)?;
+        Ok(Fragment {
+            name: name,
+            type_condition: type_condition,
+            directives: directives,
+            selection_set: self.selection_set()?,
+            pos: pos,
+        })
+    }
+
+    fn selection_set(&mut self) -> Result<Vec<Selection>, ParseError> {
+        self.enter()?;
+        self.expect('{')?;
+        let mut selections = vec![];
+        while !self.is_punct('}') {
+            selections.push(self.selection()?);
+        }
+        self.i += 1;
+        self.depth -= 1;
+        Ok(selections)
+    }
+
+    fn selection(&mut self) -> Result<Selection, ParseError> {
+        let pos = self.pos();
+        if *self.peek() != Token::Spread {
+            return Ok(Selection::Field(self.field()?));
+        }
+        self.i += 1;
+        if self.is_keyword("on") || self.is_punct('{') || self.is_punct('@') {
+            let type_condition = if self.is_keyword("on") {
+                self.i += 1;
+                Some(self.name()?)
+            } else {
+                None
+            };
+            let directives = self.directives(false)?;
+            return Ok(Selection::InlineFragment(InlineFragment {
+                type_condition: type_condition,
+                directives: directives,
+                selection_set: self.selection_set()?,
+                pos: pos,
+            }));
+        }
+        let name = self.name()?;
+        Ok(Selection::FragmentSpread(FragmentSpread {
+            name: name,
+            directives: self.directives(false)?,
+            pos: pos,
+        }))
+    }
+
+    fn field(&mut self) -> Result<Field, ParseError> {
+        let pos = self.pos();
+        let mut name = self.name()?;
+        let mut alias = None;
+        if self.is_punct(':') {
+            self.i += 1;
+            alias = Some(name);
+            name = self.name()?;
+        }
+        let arguments = self.arguments(false)?;
+        let directives = self.directives(false)?;
+        let selection_set = if self.is_punct('{') { self.selection_set()? } else { vec![] };
+        Ok(Field {
+            alias: alias,
+            name: name,
+            arguments: arguments,
+            directives: directives,
+            selection_set: selection_set,
+            pos: pos,
+        })
+    }
+
+    fn arguments(&mut self, constant: bool) -> Result<Vec<(String, Value)>, ParseError> {
+        let mut arguments = vec![];
+        if !self.is_punct('(') {
+            return Ok(arguments);
+        }
+        self.i += 1;
+        loop {
+            let name = self.name()?;
+            self.expect(':')?;
+            arguments.push((name, self.value(constant)?));
+            if self.is_punct(')') {
+                self.i += 1;
+                return Ok(arguments);
+            }
+        }
+    }
+
+    fn directives(&mut self, constant: bool) -> Result<Vec<Directive>, ParseError> {
+        let mut directives = vec![];
+        while self.is_punct('@') {
+            let pos = self.pos();
+            self.i += 1;
+            let name = self.name()?;
+            directives.push(Directive {
+                name: name,
+                arguments: self.arguments(constant)?,
+                pos: pos,
+            });
+        }
+        Ok(directives)
+    }
+
+    // the default values of the variables cannot use variables
+    fn value(&mut self, constant: bool) -> Result<Value, ParseError> {
+        let pos = self.pos();
+        if *self.peek() == Token::End {
+            return self.unexpected();
+        }
+        let value = match self.next() {
+            Token::Punct('$') if !constant => Value::Variable(self.name()?),
+            Token::Int(s) => match s.parse::<i64>() {
+                Ok(i) => Value::Int(i),
+                Err(_) => return Err(ParseError { message: format!("integer {} is too large", s),
+                                                  pos: pos }),
+            },
+            Token::Float(s) => Value::Float(s.parse::<f64>().unwrap_or(0.)),
+            Token::Str(s) => Value::String(s),
+            Token::Name(n) => match &*n {
+                "true" => Value::Boolean(true),
+                "false" => Value::Boolean(false),
+                "null" => Value::Null,
+                _ => Value::Enum(n.clone()),
+            },
+            Token::Punct('[') => {
+                self.enter()?;
+                let mut values = vec![];
+                while !self.is_punct(']') {
+                    values.push(self.value(constant)?);
+                }
+                self.i += 1;
+                self.depth -= 1;
+                Value::List(values)
+            },
+            Token::Punct('{') => {
+                self.enter()?;
+                let mut fields = vec![];
+                while !self.is_punct('}') {
+                    let name = self.name()?;
+                    self.expect(':')?;
+                    fields.push((name, self.value(constant)?));
+                }
+                self.i += 1;
+                self.depth -= 1;
+                Value::Object(fields)
+            },
+            _ => {
+                self.i -= 1;
+                return self.unexpected();
+            },
+        };
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(source: &str) -> (String, usize, usize) {
+        let e = parse(source).unwrap_err();
+        (e.message, e.pos.line, e.pos.column)
+    }
+
+    fn fields(selections: &[Selection]) -> Vec<&Field> {
+        selections.iter()
+            .filter_map(|s| match *s {
+                Selection::Field(ref f) => Some(f),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn operations_and_fragments() {
+        let doc = parse(r#"
+            # a comment, and commas are ignored
+            query Q($id: Int! = 1, $tags: [String!]) @dir(x: true) {
+                user: users(where: {id: {_eq: $id}}, tags: $tags) {
+                    ...F
+                    ... on users { name }
+                    ... @include(if: true) { id }
+                }
+            }
+            mutation { delete_users { affected_rows } }
+            fragment F on users { id }
+        "#).unwrap();
+        assert_eq!(doc.operations.len(), 2);
+        let q = &doc.operations[0];
+        assert_eq!(q.kind, OperationKind::Query);
+        assert_eq!(q.name, Some("Q".into()));
+        assert_eq!(q.pos, Pos { line: 3, column: 13 });
+        assert_eq!(q.variables[0].name, "id");
+        assert_eq!(q.variables[0].ty, TypeRef::non_null(TypeRef::named("Int")));
+        assert_eq!(q.variables[0].default, Some(Value::Int(1)));
+        assert_eq!(q.variables[1].ty.to_string(), "[String!]");
+        assert_eq!(q.variables[1].default, None);
+        assert_eq!(q.directives[0].name, "dir");
+
+        let user = fields(&q.selection_set)[0];
+        assert_eq!(user.name, "users");
+        assert_eq!(user.response_key(), "user");
+        let filter = Value::Object(vec![
+            ("id".into(), Value::Object(vec![("_eq".into(), Value::Variable("id".into()))])),
+        ]);
+        assert_eq!(user.arguments, vec![("where".into(), filter),
+                                        ("tags".into(), Value::Variable("tags".into()))]);
+        match (&user.selection_set[0], &user.selection_set[1], &user.selection_set[2]) {
+            (&Selection::FragmentSpread(ref f), &Selection::InlineFragment(ref on),
+             &Selection::InlineFragment(ref include)) => {
+                assert_eq!(f.name, "F");
+                assert_eq!(on.type_condition, Some("users".into()));
+                assert_eq!(include.type_condition, None);
+                assert_eq!(include.directives[0].name, "include");
+            },
+            s => panic!("unexpected selections {:?}", s),
+        }
+
+        assert_eq!(doc.operations[1].kind, OperationKind::Mutation);
+        assert_eq!(doc.operations[1].name, None);
+        let f = doc.fragment("F").unwrap();
+        assert_eq!(f.type_condition, "users");
+        assert_eq!(fields(&f.selection_set)[0].name, "id");
+        assert!(doc.fragment("G").is_none());
+    }
+
+    #[test]
+    fn values() {
+        let doc = parse("{ a(i: -12, f: -1.5e2, e: ASC, n: null, b: false, \
+                         l: [1 [2]], s: \"\\u00e9\\n\\\"\", \
+                         o: {x: \"\"\"\n    block\n      \\\"\"\"\n  \"\"\"}) }").unwrap();
+        let a = fields(&doc.operations[0].selection_set)[0];
+        let values = a.arguments.iter().map(|&(_, ref v)| v.clone()).collect::<Vec<Value>>();
+        assert_eq!(values, vec![
+            Value::Int(-12),
+            Value::Float(-150.),
+            Value::Enum("ASC".into()),
+            Value::Null,
+            Value::Boolean(false),
+            Value::List(vec![Value::Int(1), Value::List(vec![Value::Int(2)])]),
+            Value::String("é\n\"".into()),
+            Value::Object(vec![("x".into(), Value::String("block\n  \"\"\"".into()))]),
+        ]);
+        // the default values of the variables are constants
+        assert_eq!(error("query ($a: Int = $b) { a }"),
+                   ("unexpected \"$\"".into(), 1, 18));
+    }
+
+    #[test]
+    fn errors_with_positions() {
+        assert_eq!(error(""), ("unexpected end of document".into(), 1, 1));
+        assert_eq!(error("{ a }\n{ b } ?"), ("unexpected character '?'".into(), 2, 7));
+        assert_eq!(error("query {\n  a(x: 01)\n}"),
+                   ("unexpected digit after 0".into(), 2, 9));
+        assert_eq!(error("{ a(x: ) }"), ("unexpected \")\"".into(), 1, 8));
+        assert_eq!(error("{ a"), ("expected a name, found end of document".into(), 1, 4));
+        assert_eq!(error("query ($a Int) { a }"),
+                   ("expected \":\", found name \"Int\"".into(), 1, 11));
+        assert_eq!(error("fragment on on T { a }"),
+                   ("a fragment cannot be named \"on\"".into(), 1, 1));
+        assert_eq!(error("{ a(x: 9223372036854775808) }"),
+                   ("integer 9223372036854775808 is too large".into(), 1, 8));
+        assert_eq!(error("{ a(x: \"\\q\") }"), ("invalid escape sequence \\q".into(), 1, 11));
+        assert_eq!(error("{ a .. }"), ("expected \"...\"".into(), 1, 5));
+        let e = parse("{\r\n  ?").unwrap_err();
+        assert_eq!(e.to_string(), "syntax error, unexpected character '?' at 2:3");
+    }
+
+    #[test]
+    fn depth_limit() {
+        let nested = |n: usize| format!("{}a{}", "{a ".repeat(n), " }".repeat(n));
+        assert!(parse(&*nested(MAX_DEPTH)).is_ok());
+        assert_eq!(error(&*nested(MAX_DEPTH + 1)).0, "document is nested too deeply");
+        let lists = |n: usize| format!("{{ a(x: {}1{}) }}", "[".repeat(n), "]".repeat(n));
+        // the arguments are in the selection set of the operation
+        assert!(parse(&*lists(MAX_DEPTH - 1)).is_ok());
+        assert_eq!(error(&*lists(MAX_DEPTH)).0, "document is nested too deeply");
+        let types = |n: usize| format!("query ($a: {}Int{}) {{ a }}", "[".repeat(n),
+                                       "]".repeat(n));
+        // the named type counts as a level
+        assert!(parse(&*types(MAX_DEPTH - 1)).is_ok());
+        assert_eq!(error(&*types(MAX_DEPTH)).0, "document is nested too deeply");
+        // far beyond the limit, the stack does not overflow
+        assert!(parse(&*nested(100000)).is_err());
+    }
+
+    #[test]
+    fn names() {
+        assert!(is_name("_a1"));
+        assert!(is_name("Users"));
+        assert!(!is_name("1a"));
+        assert!(!is_name("a-b"));
+        assert!(!is_name(""));
+    }
+}
@@ -0,0 +1,742 @@
+// Copyright 2016 Jeremy Letang.
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Schema of the GraphQL endpoint, generated from the inferred schema.
+//!
+//! Each table `t` the role can read is an object type `t` with its readable
+//! columns, a field per foreign key to the referenced row and a field per
+//! foreign key referencing the table to the referencing rows
+//! (`<table>_by_<column>`). It is queried with the `t` and `t_by_pk` fields
+//! of `Query`, and modified with the `insert_t`, `update_t` and `delete_t`
+//! fields of `Mutation` when the role is allowed to.
+
+use cvt;
+use graphql_parser::{is_name, TypeRef};
+use method::Method;
+use schema::{Column, Table};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+pub const QUERY: &'static str = "Query";
+pub const MUTATION: &'static str = "Mutation";
+
+const SCALARS: [(&'static str, &'static str); 6] = [
+    ("Boolean", "true or false"),
+    ("Int", "a signed 32 bits integer"),
+    ("BigInt", "a signed 64 bits integer"),
+    ("Float", "a double precision floating point number"),
+    ("String", "an utf-8 string, the values of bytea columns are written as \\x followed by \
+                their hex digits"),
+    ("Timestamp", "a unix timestamp in seconds, a timestamp literal is accepted in the filters"),
+];
+
+const TYPE_KINDS: [&'static str; 8] =
+    ["SCALAR", "OBJECT", "INTERFACE", "UNION", "ENUM", "INPUT_OBJECT", "LIST", "NON_NULL"];
+
+const DIRECTIVE_LOCATIONS: [&'static str; 19] = [
+    "QUERY", "MUTATION", "SUBSCRIPTION", "FIELD", "FRAGMENT_DEFINITION", "FRAGMENT_SPREAD",
+    "INLINE_FRAGMENT", "VARIABLE_DEFINITION", "SCHEMA", "SCALAR", "OBJECT", "FIELD_DEFINITION",
+    "ARGUMENT_DEFINITION", "INTERFACE", "UNION", "ENUM", "ENUM_VALUE", "INPUT_OBJECT",
+    "INPUT_FIELD_DEFINITION",
+];
+
+const ORDER_BY: &'static str = "order_by";
+
+// suffixes of the types generated for each table
+const BOOL_EXP: &'static str = "_bool_exp";
+const ORDER_BY_SUFFIX: &'static str = "_order_by";
+const INSERT_INPUT: &'static str = "_insert_input";
+const SET_INPUT: &'static str = "_set_input";
+const MUTATION_RESPONSE: &'static str = "_mutation_response";
+const COMPARISON_EXP: &'static str = "_comparison_exp";
+
+pub struct Schema<'a> {
+    tables: &'a HashMap<String, Table>,
+    types: Vec<Type>,
+    index: HashMap<String, usize>,
+    directives: Vec<Directive>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Type {
+    pub name: String,
+    pub description: Option<String>,
+    pub kind: TypeKind,
+}
+
+#[derive(Debug, Clone)]
+pub enum TypeKind {
+    Scalar,
+    Object(Vec<Field>),
+    InputObject(Vec<InputValue>),
+    Enum(Vec<EnumValue>),
+}
+
+impl TypeKind {
+    /// The name of the kind, as a value of the `__TypeKind` enum.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            TypeKind::Scalar => "SCALAR",
+            TypeKind::Object(_) => "OBJECT",
+            TypeKind::InputObject(_) => "INPUT_OBJECT",
+            TypeKind::Enum(_) => "ENUM",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Field {
+    pub name: String,
+    pub description: Option<String>,
+    pub args: Vec<InputValue>,
+    pub ty: TypeRef,
+    pub resolve: Resolve,
+}
+
+/// An argument, or a field of an input object.
+#[derive(Debug, Clone)]
+pub struct InputValue {
+    pub name: String,
+    pub description: Option<String>,
+    pub ty: TypeRef,
+    pub default: Option<Value>,
+}
+
+#[derive(Debug, Clone)]
+pub struct EnumValue {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Directive {
+    pub name: String,
+    pub description: Option<String>,
+    pub locations: Vec<&'static str>,
+    pub args: Vec<InputValue>,
+}
+
+/// How the value of a field is produced.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resolve {
+    // a column of the table of the object
+    Column(String),
+    // the row referenced by a foreign key of the table of the object
+    Parent(Relation),
+    // the rows referencing the table of the object
+    Children(Relation),
+    // the fields of the root types, with their table
+    Select(String),
+    SelectByPk(String),
+    Insert(String),
+    Update(String),
+    Delete(String),
+    // the fields of the mutation responses
+    AffectedRows,
+    Returning(String),
+    // the fields of the introspection types
+    Introspection,
+}
+
+/// A foreign key seen from one of its tables, `column` belongs to the table
+/// of the object and `foreign_column` to `table`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relation {
+    pub table: String,
+    pub column: String,
+    pub foreign_column: String,
+}
+
+impl<'a> Schema<'a> {
+    /// Build the schema of the tables, with the operations allowed by
+    /// `allowed_methods` for each table.
+    pub fn new<F>(tables: &'a HashMap<String, Table>, allowed_methods: F) -> Schema<'a>
+        where F: Fn(&str) -> Vec<Method> {
+        let mut schema = Schema {
+            tables: tables,
+            types: vec![],
+            index: HashMap::new(),
+            directives: builtin_directives(),
+        };
+        for &(name, description) in SCALARS.iter() {
+            schema.add(name, Some(description), TypeKind::Scalar);
+        }
+        schema.add(ORDER_BY, Some("the direction of an ordering"),
+                   TypeKind::Enum(vec![enum_value("asc", "ascending order"),
+                                       enum_value("desc", "descending order")]));
+        for &(name, _) in SCALARS.iter() {
+            let comparison = comparison_exp(name);
+            schema.add(&*format!("{}{}", name, COMPARISON_EXP), None, comparison);
+        }
+        for t in introspection_types() {
+            schema.add_type(t);
+        }
+
+        let mut names = tables.keys().collect::<Vec<&String>>();
+        names.sort();
+        let mut methods = HashMap::new();
+        let mut taken = schema.index.keys().cloned().collect::<HashSet<String>>();
+        taken.insert(QUERY.to_string());
+        taken.insert(MUTATION.to_string());
+        for name in names {
+            let allowed = allowed_methods(name);
+            if allowed.is_empty() || !is_name(name) || name.starts_with("__") {
+                continue;
+            }
+            let generated = vec![name.clone(), format!("{}{}", name, BOOL_EXP),
+                                 format!("{}{}", name, ORDER_BY_SUFFIX),
+                                 format!("{}{}", name, INSERT_INPUT),
+                                 format!("{}{}", name, SET_INPUT),
+                                 format!("{}{}", name, MUTATION_RESPONSE)];
+            if generated.iter().any(|n| taken.contains(n)) {
+                warn!("table {} is not exposed through graphql, its types collide with \
+                       other types", name);
+                continue;
+            }
+            taken.extend(generated);
+            methods.insert(name.clone(), allowed);
+        }
+
+        let mut names = methods.keys().cloned().collect::<Vec<String>>();
+        names.sort();
+        let readable = |t: &str| methods.get(t).map_or(false, |m| m.contains(&Method::Get));
+        let mut query = vec![];
+        let mut mutation = vec![];
+        for name in &names {
+            let table = tables.get(name).unwrap();
+            let allowed = methods.get(name).unwrap();
+            let filtered = allowed.iter().any(|m| *m != Method::Post);
+            if readable(name) {
+                let fields = object_fields(tables, table, &names, &readable);
+                schema.add(name, table.comment.as_ref().map(|c| &**c), TypeKind::Object(fields));
+                schema.add(&*format!("{}{}", name, ORDER_BY_SUFFIX), None,
+                           TypeKind::InputObject(order_by_fields(table)));
+                let select = list_field(name, name, Resolve::Select(name.clone()));
+                push_field(&mut query, QUERY, select);
+                if let Some(field) = by_pk_field(table) {
+                    push_field(&mut query, QUERY, field);
+                }
+            }
+            if filtered {
+                schema.add(&*format!("{}{}", name, BOOL_EXP), None,
+                           TypeKind::InputObject(bool_exp_fields(table)));
+            }
+            if allowed.contains(&Method::Post) {
+                schema.add(&*format!("{}{}", name, INSERT_INPUT), None,
+                           TypeKind::InputObject(input_fields(table, true)));
+                push_field(&mut mutation, MUTATION, mutation_field(name, "insert", vec![
+                    arg("objects", TypeRef::non_null(TypeRef::list(non_null(
+                        &*format!("{}{}", name, INSERT_INPUT))))),
+                ], Resolve::Insert(name.clone())));
+            }
+            if allowed.contains(&Method::Patch) {
+                schema.add(&*format!("{}{}", name, SET_INPUT), None,
+                           TypeKind::InputObject(input_fields(table, false)));
+                push_field(&mut mutation, MUTATION, mutation_field(name, "update", vec![
+                    arg("where", non_null(&*format!("{}{}", name, BOOL_EXP))),
+                    arg("_set", non_null(&*format!("{}{}", name, SET_INPUT))),
+                ], Resolve::Update(name.clone())));
+            }
+            if allowed.contains(&Method::Delete) {
+                push_field(&mut mutation, MUTATION, mutation_field(name, "delete", vec![
+                    arg("where", non_null(&*format!("{}{}", name, BOOL_EXP))),
+                ], Resolve::Delete(name.clone())));
+            }
+            if allowed.iter().any(|m| *m == Method::Post || *m == Method::Patch
+                                  || *m == Method::Delete) {
+                let mut fields = vec![
+                    field("affected_rows", non_null("Int"), Resolve::AffectedRows)
+                        .describe("the number of rows modified by the mutation"),
+                ];
+                if readable(name) && returns_ids(table) {
+                    fields.push(field("returning", TypeRef::non_null(TypeRef::list(
+                        non_null(name))), Resolve::Returning(name.clone()))
+                                .describe("the rows modified by the mutation"));
+                }
+                schema.add(&*format!("{}{}", name, MUTATION_RESPONSE), None,
+                           TypeKind::Object(fields));
+            }
+        }
+        schema.add(QUERY, None, TypeKind::Object(query));
+        if !mutation.is_empty() {
+            schema.add(MUTATION, None, TypeKind::Object(mutation));
+        }
+        schema
+    }
+
+    pub fn types(&self) -> &[Type] {
+        &*self.types
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Type> {
+        self.index.get(name).map(|&i| &self.types[i])
+    }
+
+    pub fn directives(&self) -> &[Directive] {
+        &*self.directives
+    }
+
+    pub fn directive(&self, name: &str) -> Option<&Directive> {
+        self.directives.iter().find(|d| d.name == name)
+    }
+
+    /// The name of the mutation root type, if the role can modify a table.
+    pub fn mutation(&self) -> Option<&str> {
+        self.get(MUTATION).map(|_| MUTATION)
+    }
+
+    /// A field of an object type.
+    pub fn field(&self, type_name: &str, name: &str) -> Option<&Field> {
+        match self.get(type_name).map(|t| &t.kind) {
+            Some(&TypeKind::Object(ref fields)) => fields.iter().find(|f| f.name == name),
+            _ => None,
+        }
+    }
+
+    pub fn table(&self, name: &str) -> Option<&'a Table> {
+        self.tables.get(name)
+    }
+
+    fn add(&mut self, name: &str, description: Option<&str>, kind: TypeKind) {
+        self.add_type(Type {
+            name: name.to_string(),
+            description: description.map(|d| d.to_string()),
+            kind: kind,
+        });
+    }
+
+    fn add_type(&mut self, t: Type) {
+        self.index.insert(t.name.clone(), self.types.len());
+        self.types.push(t);
+    }
+}
+
+/// The columns exposed as fields, their name must be a valid GraphQL name.
+pub fn exposed(column: &Column) -> bool {
+    is_name(&*column.name) && !column.name.starts_with("__")
+}
+
+// the mutation responses return the modified rows from their ids
+fn returns_ids(table: &Table) -> bool {
+    table.columns.get("id").map_or(false, |c| c.visibility.is_readable())
+}
+
+fn readable_columns(table: &Table) -> Vec<&Column> {
+    table.columns.iter()
+        .map(|(_, c)| c)
+        .filter(|c| c.visibility.is_readable() && exposed(c))
+        .collect()
+}
+
+fn scalar(column: &Column) -> TypeRef {
+    let ty = TypeRef::named(cvt::postgres_to_graphql_type(&column.data_type));
+    if column.is_nullable { ty } else { TypeRef::non_null(ty) }
+}
+
+fn object_fields<F>(tables: &HashMap<String, Table>, table: &Table, names: &[String],
+                    readable: &F) -> Vec<Field>
+    where F: Fn(&str) -> bool {
+    let mut fields = vec![];
+    for c in readable_columns(table) {
+        let mut f = field(&*c.name, scalar(c), Resolve::Column(c.name.clone()));
+        f.description = c.comment.clone();
+        fields.push(f);
+    }
+    // the referenced rows
+    for c in readable_columns(table) {
+        let fk = match c.foreign_key {
+            Some(ref fk) => fk,
+            None => continue,
+        };
+        let referenced = tables.get(&fk.table).and_then(|t| t.columns.get(&fk.column));
+        if !readable(&*fk.table) || !referenced.map_or(false, |c| c.visibility.is_readable()) {
+            continue;
+        }
+        // author_id references an author
+        let stripped = &c.name[..c.name.len() - if c.name.ends_with("_id") { 3 } else { 0 }];
+        let name = if stripped.len() < c.name.len() && !stripped.is_empty()
+            && !fields.iter().any(|f| f.name == stripped) {
+            stripped.to_string()
+        } else {
+            format!("{}_{}", c.name, fk.table)
+        };
+        let relation = Relation {
+            table: fk.table.clone(),
+            column: c.name.clone(),
+            foreign_column: fk.column.clone(),
+        };
+        let description = format!("the row of {} referenced by {}", fk.table, c.name);
+        push_field(&mut fields, &*table.name,
+                   field(&*name, TypeRef::named(&*fk.table), Resolve::Parent(relation))
+                   .describe(&*description));
+    }
+    // the referencing rows
+    for other in names {
+        if !readable(other) {
+            continue;
+        }
+        let other_table = tables.get(other).unwrap();
+        for c in readable_columns(other_table) {
+            let fk = match c.foreign_key {
+                Some(ref fk) if fk.table == table.name => fk,
+                _ => continue,
+            };
+            if !table.columns.get(&fk.column).map_or(false, |c| c.visibility.is_readable()) {
+                continue;
+            }
+            let relation = Relation {
+                table: other.clone(),
+                column: fk.column.clone(),
+                foreign_column: c.name.clone(),
+            };
+            let name = format!("{}_by_{}", other, c.name);
+            let description = format!("the rows of {} referencing this row by {}", other, c.name);
+            push_field(&mut fields, &*table.name,
+                       list_field(&*name, other, Resolve::Children(relation))
+                       .describe(&*description));
+        }
+    }
+    fields
+}
+
+fn push_field(fields: &mut Vec<Field>, type_name: &str, field: Field) {
+    if fields.iter().any(|f| f.name == field.name) {
+        warn!("graphql field {}.{} collides with another field", type_name, field.name);
+        return;
+    }
+    fields.push(field);
+}
+
+// a list of rows with the arguments to filter, order and paginate it
+fn list_field(name: &str, table: &str, resolve: Resolve) -> Field {
+    Field {
+        name: name.to_string(),
+        description: None,
+        args: vec![
+            arg("where", TypeRef::named(&*format!("{}{}", table, BOOL_EXP)))
+                .describe("the filters the rows must match"),
+            arg("order_by", TypeRef::list(non_null(&*format!("{}{}", table, ORDER_BY_SUFFIX))))
+                .describe("the orderings of the rows, applied in order"),
+            arg("limit", TypeRef::named("Int")),
+            arg("offset", TypeRef::named("Int")),
+        ],
+        ty: TypeRef::non_null(TypeRef::list(non_null(table))),
+        resolve: resolve,
+    }
+}
+
+fn by_pk_field(table: &Table) -> Option<Field> {
+    let pk = table.columns.iter()
+        .filter(|&(_, c)| c.is_primary_key)
+        .map(|(_, c)| c)
+        .collect::<Vec<&Column>>();
+    if pk.is_empty() || pk.iter().any(|c| !c.visibility.is_readable() || !exposed(c)) {
+        return None;
+    }
+    let args = pk.iter().map(|c| arg(&*c.name, TypeRef::non_null(TypeRef::named(
+        cvt::postgres_to_graphql_type(&c.data_type))))).collect();
+    Some(Field {
+        name: format!("{}_by_pk", table.name),
+        description: Some(format!("a row of {} from its primary key", table.name)),
+        args: args,
+        ty: TypeRef::named(&*table.name),
+        resolve: Resolve::SelectByPk(table.name.clone()),
+    })
+}
+
+fn mutation_field(table: &str, action: &str, args: Vec<InputValue>, resolve: Resolve) -> Field {
+    Field {
+        name: format!("{}_{}", action, table),
+        description: Some(format!("{} rows of {}", action, table)),
+        args: args,
+        ty: TypeRef::named(&*format!("{}{}", table, MUTATION_RESPONSE)),
+        resolve: resolve,
+    }
+}
+
+fn bool_exp_fields(table: &Table) -> Vec<InputValue> {
+    readable_columns(table).iter().map(|c| {
+        let ty = cvt::postgres_to_graphql_type(&c.data_type);
+        arg(&*c.name, TypeRef::named(&*format!("{}{}", ty, COMPARISON_EXP)))
+    }).collect()
+}
+
+fn order_by_fields(table: &Table) -> Vec<InputValue> {
+    readable_columns(table).iter().map(|c| arg(&*c.name, TypeRef::named(ORDER_BY))).collect()
+}
+
+// the columns which can be set, the columns required by the create json
+// schema are non null in the insert input
+fn input_fields(table: &Table, insert: bool) -> Vec<InputValue> {
+    table.columns.iter()
+        .map(|(_, c)| c)
+        .filter(|c| if insert { c.can_insert() } else { c.can_update() })
+        .filter(|c| exposed(c))
+        .map(|c| {
+            let ty = TypeRef::named(cvt::postgres_to_graphql_type(&c.data_type));
+            let required = insert && !c.is_nullable && c.default.is_none();
+            let mut input = arg(&*c.name, if required { TypeRef::non_null(ty) } else { ty });
+            input.description = c.comment.clone();
+            input
+        })
+        .collect()
+}
+
+fn comparison_exp(scalar: &str) -> TypeKind {
+    let mut fields = vec![
+        arg("_eq", TypeRef::named(scalar)),
+        arg("_neq", TypeRef::named(scalar)),
+    ];
+    if scalar != "Boolean" {
+        for op in &["_gt", "_gte", "_lt", "_lte"] {
+            fields.push(arg(op, TypeRef::named(scalar)));
+        }
+        fields.push(arg("_in", TypeRef::list(non_null(scalar))));
+        fields.push(arg("_nin", TypeRef::list(non_null(scalar))));
+    }
+    if scalar == "String" {
+        for op in &["_like", "_nlike", "_ilike", "_nilike"] {
+            fields.push(arg(op, TypeRef::named(scalar))
+                        .describe("a sql pattern, % matches any sequence of characters"));
+        }
+    }
+    fields.push(arg("_is_null", TypeRef::named("Boolean")));
+    TypeKind::InputObject(fields)
+}
+
+fn builtin_directives() -> Vec<Directive> {
+    let locations = vec!["FIELD", "FRAGMENT_SPREAD", "INLINE_FRAGMENT"];
+    vec![
+        Directive {
+            name: "include".into(),
+            description: Some("include the selection only when the argument is true".into()),
+            locations: locations.clone(),
+            args: vec![arg("if", non_null("Boolean"))],
+        },
+        Directive {
+            name: "skip".into(),
+            description: Some("skip the selection when the argument is true".into()),
+            locations: locations,
+            args: vec![arg("if", non_null("Boolean"))],
+        },
+    ]
+}
+
+fn introspection_types() -> Vec<Type> {
+    let deprecated = || vec![arg("includeDeprecated", TypeRef::named("Boolean"))
+                             .default_value(Value::Bool(false))];
+    let object = |name: &str, fields: Vec<Field>| Type {
+        name: name.to_string(),
+        description: None,
+        kind: TypeKind::Object(fields),
+    };
+    let enumeration = |name: &str, values: &[&str]| Type {
+        name: name.to_string(),
+        description: None,
+        kind: TypeKind::Enum(values.iter().map(|v| EnumValue {
+            name: v.to_string(),
+            description: None,
+        }).collect()),
+    };
+    let meta = |name: &str, ty: TypeRef| field(name, ty, Resolve::Introspection);
+    let list = |name: &str| TypeRef::list(non_null(name));
+    let non_null_list = |name: &str| TypeRef::non_null(TypeRef::list(non_null(name)));
+    let string = || TypeRef::named("String");
+    let mut type_fields = meta("fields", list("__Field"));
+    type_fields.args = deprecated();
+    let mut enum_values = meta("enumValues", list("__EnumValue"));
+    enum_values.args = deprecated();
+    let mut input_fields = meta("inputFields", list("__InputValue"));
+    input_fields.args = deprecated();
+    let mut field_args = meta("args", non_null_list("__InputValue"));
+    field_args.args = deprecated();
+    let directive_args = field_args.clone();
+    vec![
+        object("__Schema", vec![
+            meta("description", string()),
+            meta("types", non_null_list("__Type")),
+            meta("queryType", non_null("__Type")),
+            meta("mutationType", TypeRef::named("__Type")),
+            meta("subscriptionType", TypeRef::named("__Type")),
+            meta("directives", non_null_list("__Directive")),
+        ]),
+        object("__Type", vec![
+            meta("kind", non_null("__TypeKind")),
+            meta("name", string()),
+            meta("description", string()),
+            meta("specifiedByURL", string()),
+            type_fields,
+            meta("interfaces", list("__Type")),
+            meta("possibleTypes", list("__Type")),
+            enum_values,
+            input_fields,
+            meta("ofType", TypeRef::named("__Type")),
+            meta("isOneOf", TypeRef::named("Boolean")),
+        ]),
+        object("__Field", vec![
+            meta("name", non_null("String")),
+            meta("description", string()),
+            field_args,
+            meta("type", non_null("__Type")),
+            meta("isDeprecated", non_null("Boolean")),
+            meta("deprecationReason", string()),
+        ]),
+        object("__InputValue", vec![
+            meta("name", non_null("String")),
+            meta("description", string()),
+            meta("type", non_null("__Type")),
+            meta("defaultValue", string()),
+            meta("isDeprecated", non_null("Boolean")),
+            meta("deprecationReason", string()),
+        ]),
+        object("__EnumValue", vec![
+            meta("name", non_null("String")),
+            meta("description", string()),
+            meta("isDeprecated", non_null("Boolean")),
+            meta("deprecationReason", string()),
+        ]),
+        object("__Directive", vec![
+            meta("name", non_null("String")),
+            meta("description", string()),
+            meta("locations", non_null_list("__DirectiveLocation")),
+            directive_args,
+            meta("isRepeatable", non_null("Boolean")),
+        ]),
+        enumeration("__TypeKind", &TYPE_KINDS[..]),
+        enumeration("__DirectiveLocation", &DIRECTIVE_LOCATIONS[..]),
+    ]
+}
+
+impl Field {
+    fn describe(mut self, description: &str) -> Field {
+        self.description = Some(description.to_string());
+        self
+    }
+}
+
+impl InputValue {
+    fn describe(mut self, description: &str) -> InputValue {
+        self.description = Some(description.to_string());
+        self
+    }
+
+    fn default_value(mut self, value: Value) -> InputValue {
+        self.default = Some(value);
+        self
+    }
+}
+
+fn field(name: &str, ty: TypeRef, resolve: Resolve) -> Field {
+    Field { name: name.to_string(), description: None, args: vec![], ty: ty, resolve: resolve }
+}
+
+fn arg(name: &str, ty: TypeRef) -> InputValue {
+    InputValue { name: name.to_string(), description: None, ty: ty, default: None }
+}
+
+fn enum_value(name: &str, description: &str) -> EnumValue {
+    EnumValue { name: name.to_string(), description: Some(description.to_string()) }
+}
+
+fn non_null(name: &str) -> TypeRef {
+    TypeRef::non_null(TypeRef::named(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use postgres::types::Type as PgType;
+    use schema::ForeignKey;
+
+    fn column(name: &str, ty: PgType, foreign_key: Option<&str>) -> Column {
+        let mut c = Column::new(name, ty);
+        c.is_nullable = name != "id";
+        c.is_primary_key = name == "id";
+        c.foreign_key = foreign_key.map(|t| ForeignKey { table: t.into(), column: "id".into() });
+        c
+    }
+
+    fn table(name: &str, columns: Vec<Column>) -> (String, Table) {
+        (name.to_string(), Table::new("public", name, columns))
+    }
+
+    fn tables() -> HashMap<String, Table> {
+        let mut total = column("total", PgType::Int8, None);
+        total.is_generated = true;
+        let mut name = column("name", PgType::Text, None);
+        name.is_nullable = false;
+        vec![
+            table("users", vec![column("id", PgType::Int4, None), name, total]),
+            table("posts", vec![column("id", PgType::Int8, None),
+                                column("author_id", PgType::Int4, Some("users")),
+                                column("at", PgType::TimestampTZ, None)]),
+            table("not-a-name", vec![column("id", PgType::Int4, None)]),
+        ].into_iter().collect()
+    }
+
+    fn names<T, F: Fn(&T) -> &str>(values: &[T], name: F) -> Vec<String> {
+        values.iter().map(|v| name(v).to_string()).collect()
+    }
+
+    fn input_fields<'s>(schema: &'s Schema, name: &str) -> &'s [InputValue] {
+        match schema.get(name).map(|t| &t.kind) {
+            Some(&TypeKind::InputObject(ref fields)) => fields,
+            _ => panic!("no input object {}", name),
+        }
+    }
+
+    #[test]
+    fn tables_and_relations() {
+        let tables = tables();
+        let all = vec![Method::Get, Method::Post, Method::Patch, Method::Delete];
+        let schema = Schema::new(&tables, |_| all.clone());
+        assert!(schema.get("not-a-name").is_none());
+        let query = match schema.get(QUERY).unwrap().kind {
+            TypeKind::Object(ref fields) => names(fields, |f| &*f.name),
+            _ => panic!("Query is not an object"),
+        };
+        assert_eq!(query, vec!["posts", "posts_by_pk", "users", "users_by_pk"]);
+
+        let author = schema.field("posts", "author").unwrap();
+        assert_eq!(author.ty, TypeRef::named("users"));
+        assert_eq!(author.resolve, Resolve::Parent(Relation {
+            table: "users".into(),
+            column: "author_id".into(),
+            foreign_column: "id".into(),
+        }));
+        let posts = schema.field("users", "posts_by_author_id").unwrap();
+        assert_eq!(posts.ty.to_string(), "[posts!]!");
+        assert_eq!(names(&posts.args, |a| &*a.name), vec!["where", "order_by", "limit", "offset"]);
+        assert_eq!(schema.field("posts", "at").unwrap().ty, TypeRef::named("Timestamp"));
+        assert_eq!(schema.field("posts", "id").unwrap().ty.to_string(), "BigInt!");
+        assert_eq!(schema.field("Query", "users_by_pk").unwrap().args[0].ty.to_string(), "Int!");
+    }
+
+    #[test]
+    fn mutations() {
+        let tables = tables();
+        let schema = Schema::new(&tables, |t| match t {
+            "users" => vec![Method::Get, Method::Post],
+            _ => vec![Method::Get],
+        });
+        assert_eq!(schema.mutation(), Some(MUTATION));
+        assert!(schema.field(MUTATION, "insert_users").is_some());
+        assert!(schema.field(MUTATION, "update_users").is_none());
+        assert!(schema.field(MUTATION, "insert_posts").is_none());
+        // the generated columns cannot be set, the required ones are non null
+        let insert = input_fields(&schema, "users_insert_input");
+        assert_eq!(names(insert, |f| &*f.name), vec!["id", "name"]);
+        assert_eq!(insert[1].ty.to_string(), "String!");
+        assert!(schema.field("users_mutation_response", "returning").is_some());
+
+        let read_only = Schema::new(&tables, |_| vec![Method::Get]);
+        assert_eq!(read_only.mutation(), None);
+        assert!(read_only.get("users_bool_exp").is_some());
+        let none = Schema::new(&tables, |_| vec![]);
+        assert!(none.get("users").is_none());
+    }
+}
@@ -0,0 +1,403 @@
+// Copyright 2016 Jeremy Letang.
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Bulk import of csv bodies.
+//!
+//! The rows are loaded with `COPY ... FROM STDIN` in a temporary table of
+//! text columns, then inserted in the table, converting the values to the
+//! types of the columns. The import is done in a single transaction, if a
+//! row cannot be inserted nothing is loaded and the failing lines are
+//! reported.
+
+use common;
+use error::Error;
+use method::Method;
+use postgres::GenericConnection;
+use postgres::error::Error as PgError;
+use queries::{FetchQueries, Queries};
+use schema::Table;
+use serde_json::{Map, Value};
+use std::str::FromStr;
+
+const IMPORT_TABLE: &'static str = "arrakis_import";
+const LINE_COLUMN: &'static str = "arrakis_line";
+
+// number of failing lines reported, the search stops after it
+const MAX_FAILED_LINES: usize = 100;
+// number of rows retried one by one to find the failing lines, each retry is
+// a statement so the search of a large import is bounded
+const MAX_RETRIED_LINES: usize = 10000;
+
+const UPDATE: &'static str = "update";
+const NOTHING: &'static str = "nothing";
+
+/// What to do with the rows conflicting with an existing row on the primary
+/// key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnConflict {
+    Update,
+    Nothing,
+}
+
+impl FromStr for OnConflict {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            UPDATE => Ok(OnConflict::Update),
+            NOTHING => Ok(OnConflict::Nothing),
+            _ => Err(Error::InvalidInputError(
+                format!("invalid on_conflict {}, expected update or nothing", s))),
+        }
+    }
+}
+
+/// Options of a csv import, read from the `delimiter`, `null` and
+/// `on_conflict` control parameters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportOptions {
+    pub delimiter: char,
+    // the string of the null values, unquoted empty fields by default
+    pub null: String,
+    // conflicting rows fail the import if not set
+    pub on_conflict: Option<OnConflict>,
+}
+
+impl Default for ImportOptions {
+    fn default() -> ImportOptions {
+        ImportOptions {
+            delimiter: ',',
+            null: String::new(),
+            on_conflict: None,
+        }
+    }
+}
+
+impl ImportOptions {
+    pub fn from_queries(queries: &Queries, prefix: &str) -> Result<ImportOptions, Error> {
+        let mut options = ImportOptions::default();
+        if let Some(d) = queries.delimiter(prefix) {
+            let mut chars = d.chars();
+            options.delimiter = match (chars.next(), chars.next()) {
+                (Some(c), None) if (c as u32) < 128 && c != '"' && c != '\r' && c != '\n' => c,
+                _ => return Err(Error::InvalidInputError(
+                    format!("invalid delimiter {}, expected a single character", d))),
+            };
+        }
+        if let Some(n) = queries.null(prefix) {
+            options.null = n.to_string();
+        }
+        if let Some(c) = queries.on_conflict(prefix) {
+            options.on_conflict = Some(OnConflict::from_str(c)?);
+        }
+        Ok(options)
+    }
+}
+
+/// Load a csv body in the table, the first line must contain the names of
+/// the columns. Returns the number of loaded rows.
+pub fn copy_csv(conn: &GenericConnection, table: &Table, options: &ImportOptions, body: &str)
+                -> Result<Option<Value>, Error> {
+    let columns = read_header(body, options.delimiter)?;
+    validate_header(table, &*columns, options.on_conflict)?;
+
+    let line_column = line_column(&*columns);
+    let query = generate_create(&*columns, &*line_column);
+    debug!("arrakis query: {}", query);
+    conn.batch_execute(&*query)?;
+
+    let query = generate_copy(&*columns, options);
+    debug!("arrakis query: {}", query);
+    let stmt = conn.prepare(&*query)?;
+    if let Err(e) = stmt.copy_in(&[], &mut body.as_bytes()) {
+        return Err(copy_error(e));
+    }
+
+    let query = generate_insert(table, &*columns, &*line_column, options.on_conflict, false)?;
+    debug!("arrakis query: {}", query);
+    // on failure the insert is retried row by row to find the failing lines
+    let res = {
+        let trans = conn.transaction()?;
+        let res = trans.execute(&*query, &[]);
+        if res.is_ok() {
+            trans.commit()?;
+        }
+        res
+    };
+    match res {
+        Ok(n) => {
+            let mut m = Map::new();
+            m.insert("loaded".to_string(), Value::U64(n));
+            Ok(Some(Value::Object(m)))
+        },
+        Err(e) => {
+            let query = generate_insert(table, &*columns, &*line_column, options.on_conflict,
+                                        true)?;
+            Err(find_failed_lines(conn, &*query, &*line_column, e)?)
+        },
+    }
+}
+
+// read the column names of the first line
+fn read_header(body: &str, delimiter: char) -> Result<Vec<String>, Error> {
+    let mut columns = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = body.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            },
+            '"' => quoted = !quoted,
+            '\r' | '\n' if !quoted => break,
+            c if c == delimiter && !quoted => {
+                columns.push(field.clone());
+                field.clear();
+            },
+            c => field.push(c),
+        }
+    }
+    columns.push(field);
+    if columns.iter().any(|c| c.is_empty()) {
+        return Err(Error::InvalidInputError(
+            "the first line of the csv must contain the column names".into()));
+    }
+    Ok(columns)
+}
+
+// the updated columns of the conflicting rows are the columns of the csv
+fn validate_header(table: &Table, columns: &[String], on_conflict: Option<OnConflict>)
+                   -> Result<(), Error> {
+    let mut m = Map::new();
+    for c in columns {
+        if m.insert(c.clone(), Value::Null).is_some() {
+            return Err(Error::InvalidInputError(format!("duplicate column {}", c)));
+        }
+    }
+    let m = Value::Object(m);
+    common::validate_table_fields(table, &m, &Method::Post)?;
+    if on_conflict == Some(OnConflict::Update) {
+        common::validate_table_fields(table, &m, &Method::Patch)?;
+    }
+    Ok(())
+}
+
+// the column numbering the lines in the temporary table, suffixed if the
+// csv has a column of the same name
+fn line_column(columns: &[String]) -> String {
+    let mut name = LINE_COLUMN.to_string();
+    let mut n = 0;
+    while columns.contains(&name) {
+        n += 1;
+        name = format!("{}_{}", LINE_COLUMN, n);
+    }
+    name
+}
+
+fn generate_create(columns: &[String], line_column: &str) -> String {
+    let columns = columns.iter()
+        .map(|c| format!("{} text", common::quote_ident(c)))
+        .collect::<Vec<String>>();
+    format!("CREATE TEMPORARY TABLE {} ({}, {} bigserial) ON COMMIT DROP",
+            common::quote_ident(IMPORT_TABLE), columns.join(", "),
+            common::quote_ident(line_column))
+}
+
+fn generate_copy(columns: &[String], options: &ImportOptions) -> String {
+    format!("COPY {} ({}) FROM STDIN (FORMAT csv, HEADER true, DELIMITER {}, NULL {})",
+            common::quote_ident(IMPORT_TABLE), quote_columns(None, columns),
+            common::quote_literal(&*options.delimiter.to_string()),
+            common::quote_literal(&*options.null))
+}
+
+// insert the rows of the temporary table, converting the text values to the
+// types of the columns with json_populate_record. A single row is inserted if
+// `single_line` is set, the line being the first parameter.
+fn generate_insert(table: &Table, columns: &[String], line_column: &str,
+                   on_conflict: Option<OnConflict>, single_line: bool)
+                   -> Result<String, Error> {
+    let mut query = format!("INSERT INTO {} ({}) SELECT {} FROM {} AS i, \
+                             json_populate_record(NULL::{}, row_to_json(i)) AS r",
+                            common::quote_table(table), quote_columns(None, columns),
+                            quote_columns(Some("r"), columns),
+                            common::quote_ident(IMPORT_TABLE), common::quote_table(table));
+    let line = common::quote_column(Some("i"), line_column);
+    if single_line {
+        query = format!("{} WHERE {} = $1", query, line);
+    }
+    query = format!("{} ORDER BY {}", query, line);
+    let conflict = match on_conflict {
+        None => return Ok(query),
+        Some(c) => c,
+    };
+    let keys = table.columns.iter()
+        .filter(|&(_, c)| c.is_primary_key)
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<String>>();
+    if keys.is_empty() {
+        return Err(Error::InvalidInputError(
+            format!("on_conflict needs a primary key, table {} has none", table.name)));
+    }
+    let updates = columns.iter()
+        .filter(|c| !keys.contains(c))
+        .map(|c| format!("{} = EXCLUDED.{}", common::quote_ident(c), common::quote_ident(c)))
+        .collect::<Vec<String>>();
+    let action = match conflict {
+        OnConflict::Update if !updates.is_empty() =>
+            format!("DO UPDATE SET {}", updates.join(", ")),
+        _ => "DO NOTHING".to_string(),
+    };
+    Ok(format!("{} ON CONFLICT ({}) {}", query, quote_columns(None, &*keys), action))
+}
+
+// insert the first rows one by one, each in a savepoint, to collect the
+// failing lines. The lines are numbered from the header, which is the line 1.
+fn find_failed_lines(conn: &GenericConnection, query: &str, line_column: &str, e: PgError)
+                     -> Result<Error, Error> {
+    let mut failed = vec![];
+    let stmt = conn.prepare(&*query)?;
+    let rows = conn.query(&*format!("SELECT {} FROM {} ORDER BY 1 LIMIT {}",
+                                    common::quote_ident(line_column),
+                                    common::quote_ident(IMPORT_TABLE),
+                                    MAX_RETRIED_LINES), &[])?;
+    for row in &rows {
+        let line: i64 = row.get(0);
+        let trans = conn.transaction()?;
+        if let Err(e) = stmt.execute(&[&line]) {
+            failed.push(((line + 1) as u64, db_message(&e)));
+            if failed.len() >= MAX_FAILED_LINES {
+                break;
+            }
+        }
+        // rolled back when dropped
+        drop(trans);
+    }
+    if failed.is_empty() {
+        // the rows conflict with each other or with the table, or the
+        // failing rows are after the retried ones
+        let msg = match rows.len() < MAX_RETRIED_LINES {
+            true => db_message(&e),
+            false => format!("{}, the failing lines are not in the first {} rows",
+                             db_message(&e), MAX_RETRIED_LINES),
+        };
+        return Ok(Error::ImportFailed(vec![], msg));
+    }
+    Ok(Error::ImportFailed(failed, "some rows cannot be inserted".into()))
+}
+
+// errors of the copy itself are malformed csv lines, the line is given by
+// the context of the error, e.g "COPY arrakis_import, line 3: ..."
+fn copy_error(e: PgError) -> Error {
+    let line = match e {
+        PgError::Db(ref db) => db.where_.as_ref().and_then(|w| copy_error_line(w)),
+        _ => None,
+    };
+    match line {
+        Some(l) => Error::ImportFailed(vec![(l, db_message(&e))], "malformed csv".into()),
+        None => Error::from(e),
+    }
+}
+
+fn copy_error_line(context: &str) -> Option<u64> {
+    context.split(", ").find(|p| p.starts_with("line "))
+        .and_then(|p| p[5..].split(|c: char| !c.is_digit(10)).next())
+        .and_then(|n| n.parse::<u64>().ok())
+}
+
+// the message of a database error, the others are not shown to the client
+fn db_message(e: &PgError) -> String {
+    match *e {
+        PgError::Db(ref db) => db.message.clone(),
+        _ => "internal database error".to_string(),
+    }
+}
+
+fn quote_columns(table: Option<&str>, columns: &[String]) -> String {
+    columns.iter()
+        .map(|c| common::quote_column(table, c))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use postgres::types::Type;
+    use schema::Column;
+
+    fn table(primary_key: bool) -> Table {
+        let mut id = Column::new("id", Type::Text);
+        id.is_primary_key = primary_key;
+        Table::new("public", "t", vec![id, Column::new("name", Type::Text)])
+    }
+
+    fn strings(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn header() {
+        assert_eq!(read_header("id,name\r\n1,a\r\n", ',').unwrap(), strings(&["id", "name"]));
+        assert_eq!(read_header("id;name", ';').unwrap(), strings(&["id", "name"]));
+        assert_eq!(read_header("\"a,b\",\"say \"\"hi\"\"\"\n", ',').unwrap(),
+                   strings(&["a,b", "say \"hi\""]));
+        assert_eq!(read_header("\"two\nlines\",x\n1,2", ',').unwrap(),
+                   strings(&["two\nlines", "x"]));
+        assert!(read_header("", ',').is_err());
+        assert!(read_header("id,,name\n", ',').is_err());
+        assert!(read_header("\n1,2", ',').is_err());
+    }
+
+    #[test]
+    fn copy_error_lines() {
+        assert_eq!(copy_error_line("COPY arrakis_import, line 3: \"1,2,3\""), Some(3));
+        assert_eq!(copy_error_line("COPY arrakis_import, line 12, column id: \"x\""), Some(12));
+        assert_eq!(copy_error_line("COPY arrakis_import"), None);
+        assert_eq!(copy_error_line("SQL function"), None);
+    }
+
+    #[test]
+    fn line_column_names() {
+        assert_eq!(line_column(&*strings(&["id", "name"])), "arrakis_line");
+        assert_eq!(line_column(&*strings(&["arrakis_line", "arrakis_line_1"])),
+                   "arrakis_line_2");
+        assert_eq!(generate_create(&*strings(&["arrakis_line"]), "arrakis_line_1"),
+                   "CREATE TEMPORARY TABLE \"arrakis_import\" (\"arrakis_line\" text, \
+                    \"arrakis_line_1\" bigserial) ON COMMIT DROP");
+    }
+
+    #[test]
+    fn insert() {
+        let columns = strings(&["id", "name"]);
+        let prefix = "INSERT INTO \"public\".\"t\" (\"id\", \"name\") \
+                      SELECT \"r\".\"id\", \"r\".\"name\" FROM \"arrakis_import\" AS i, \
+                      json_populate_record(NULL::\"public\".\"t\", row_to_json(i)) AS r";
+        assert_eq!(generate_insert(&table(true), &*columns, "l", None, false).unwrap(),
+                   format!("{} ORDER BY \"i\".\"l\"", prefix));
+        assert_eq!(generate_insert(&table(true), &*columns, "l", None, true).unwrap(),
+                   format!("{} WHERE \"i\".\"l\" = $1 ORDER BY \"i\".\"l\"", prefix));
+    }
+
+    #[test]
+    fn insert_on_conflict() {
+        let columns = strings(&["id", "name"]);
+        let update = generate_insert(&table(true), &*columns, "l", Some(OnConflict::Update),
+                                     false).unwrap();
+        assert!(update.ends_with(" ORDER BY \"i\".\"l\" ON CONFLICT (\"id\") \
+                                  DO UPDATE SET \"name\" = EXCLUDED.\"name\""), update);
+        let nothing = generate_insert(&table(true), &*columns, "l", Some(OnConflict::Nothing),
+                                      false).unwrap();
+        assert!(nothing.ends_with(" ON CONFLICT (\"id\") DO NOTHING"), nothing);
+        // only the key is loaded, there is nothing to update
+        let keys = generate_insert(&table(true), &*strings(&["id"]), "l",
+                                   Some(OnConflict::Update), false).unwrap();
+        assert!(keys.ends_with(" ON CONFLICT (\"id\") DO NOTHING"), keys);
+        assert!(generate_insert(&table(false), &*columns, "l", Some(OnConflict::Update), false)
+                .is_err());
+    }
+}
@@ -7,36 +7,129 @@
 
 use postgres::Connection;
 use postgres::rows::Row;
-use schema::{Table, Column};
-use postgres::types::Type;
-use std::collections::{HashMap, BTreeMap};
+use postgres::types::{Oid, Type};
+use schema::{Table, Column, Columns, ColumnPrivileges, ForeignKey, TablePrivileges, Visibility};
+use std::collections::HashMap;
 
+// every column of the user tables and views with its type oid, constraints,
+// enum labels, comments and identity or generation, ordered by position. The
+// identity and generation are read from information_schema, whose columns
+// exist before postgres 10 and 12. The privileges are the ones of the role
+// used to connect to the database.
 const INFER_SCHEMA_QUERY: &'static str =
-    "select table_name, column_name, column_default, is_nullable, udt_name, \
-     character_maximum_length, is_updatable \
-     FROM INFORMATION_SCHEMA.COLUMNS \
-     WHERE table_schema NOT IN ('information_schema', 'pg_catalog')";
+    "SELECT c.relname, a.attname, pg_get_expr(d.adbin, d.adrelid), NOT a.attnotnull, \
+     a.atttypid, \
+     CASE WHEN a.atttypid IN (1042, 1043) AND a.atttypmod > 0 THEN a.atttypmod - 4 END, \
+     pg_column_is_updatable(c.oid, a.attnum, false), n.nspname, \
+     has_table_privilege(c.oid, 'SELECT'), has_table_privilege(c.oid, 'INSERT'), \
+     has_table_privilege(c.oid, 'UPDATE'), has_table_privilege(c.oid, 'DELETE'), \
+     has_column_privilege(c.oid, a.attnum, 'SELECT'), \
+     has_column_privilege(c.oid, a.attnum, 'INSERT'), \
+     has_column_privilege(c.oid, a.attnum, 'UPDATE'), \
+     a.attnum, col_description(c.oid, a.attnum), obj_description(c.oid, 'pg_class'), \
+     EXISTS(SELECT 1 FROM pg_constraint k WHERE k.conrelid = c.oid AND k.contype = 'p' \
+            AND a.attnum = ANY(k.conkey)), \
+     fk.relname, fk.attname, \
+     ARRAY(SELECT e.enumlabel::text FROM pg_enum e WHERE e.enumtypid = a.atttypid \
+           ORDER BY e.enumsortorder), \
+     ARRAY(SELECT pg_get_constraintdef(k.oid) FROM pg_constraint k \
+           WHERE k.conrelid = c.oid AND k.contype = 'c' AND k.conkey = ARRAY[a.attnum] \
+           ORDER BY k.conname), \
+     ic.identity_generation::text, COALESCE(ic.is_generated = 'ALWAYS', false) \
+     FROM pg_attribute a \
+     JOIN pg_class c ON c.oid = a.attrelid \
+     JOIN pg_namespace n ON n.oid = c.relnamespace \
+     LEFT JOIN pg_attrdef d ON d.adrelid = a.attrelid AND d.adnum = a.attnum \
+     LEFT JOIN information_schema.columns ic ON ic.table_schema = n.nspname \
+     AND ic.table_name = c.relname AND ic.column_name = a.attname \
+     LEFT JOIN LATERAL ( \
+         SELECT fc.relname, fa.attname FROM pg_constraint k \
+         JOIN pg_class fc ON fc.oid = k.confrelid \
+         JOIN pg_attribute fa ON fa.attrelid = k.confrelid AND fa.attnum = k.confkey[1] \
+         WHERE k.conrelid = c.oid AND k.contype = 'f' AND k.conkey = ARRAY[a.attnum] \
+         LIMIT 1) fk ON true \
+     WHERE c.relkind IN ('r', 'v', 'm', 'f', 'p') AND a.attnum > 0 AND NOT a.attisdropped \
+     AND n.nspname NOT IN ('information_schema', 'pg_catalog') \
+     AND n.nspname NOT LIKE 'pg_toast%' \
+     ORDER BY c.relname, a.attnum";
 
-fn as_bool(s: String) -> bool {
-    match &*s {
-        "YES" => true,
-        "NO" => false,
-        _ => unreachable!(),
+fn get_type(oid: Oid, table: &str, column: &str) -> Type {
+    match Type::from_oid(oid) {
+        Some(ty) => ty,
+        None => {
+            // user defined types (enums, domains, composites) are not known
+            // statically, they are handled as text.
+            warn!("unknown type oid {} for column {}.{}", oid, table, column);
+            Type::Unknown
+        }
+    }
+}
+
+fn get_table_privileges(row: &Row) -> TablePrivileges {
+    TablePrivileges {
+        select: row.get(8),
+        insert: row.get(9),
+        update: row.get(10),
+        delete: row.get(11),
+    }
+}
+
+fn get_column_privileges(row: &Row) -> ColumnPrivileges {
+    ColumnPrivileges {
+        select: row.get(12),
+        insert: row.get(13),
+        update: row.get(14),
     }
 }
 
-fn get_column(row: &Row, ty: Type) -> Column {
+fn get_foreign_key(row: &Row) -> Option<ForeignKey> {
+    match (row.get(19), row.get(20)) {
+        (Some(table), Some(column)) => Some(ForeignKey { table: table, column: column }),
+        _ => None,
+    }
+}
+
+fn get_column(row: &Row, ty: Type, check_privileges: bool) -> Column {
+    // columns the role cannot read or write are exposed accordingly, the
+    // insert and update privileges are checked by the methods using them
+    let (visibility, privileges) = match check_privileges {
+        true => {
+            let p = get_column_privileges(row);
+            (Visibility::from_access(p.select, p.insert || p.update), p)
+        },
+        false => (Visibility::Visible, ColumnPrivileges::default()),
+    };
+    // the identity columns have no default expression, their value comes
+    // from a sequence. The kind of identity stands for it, so they are not
+    // required in the new rows.
+    let default: Option<String> = row.get(2);
+    let identity: Option<String> = row.get(23);
+    let default = match (default, &identity) {
+        (Some(d), _) => Some(d),
+        (None, &Some(ref i)) => Some(format!("GENERATED {} AS IDENTITY", i)),
+        (None, &None) => None,
+    };
+    let generated: bool = row.get(24);
     Column {
         name: row.get(1),
-        default: row.get(2),
-        is_nullable: as_bool(row.get(3)),
+        default: default,
+        is_nullable: row.get(3),
         data_type: ty,
         character_maximum_length: row.get(5),
-        is_updatable: as_bool(row.get(6)),
+        is_updatable: row.get(6),
+        visibility: visibility,
+        privileges: privileges,
+        position: row.get(15),
+        comment: row.get(16),
+        is_primary_key: row.get(18),
+        foreign_key: get_foreign_key(row),
+        enum_labels: row.get(21),
+        checks: row.get(22),
+        is_generated: generated || identity.as_ref().map_or(false, |i| i == "ALWAYS"),
     }
 }
 
-pub fn epur_tables(mut tables: HashMap<String, Table>, included: &[&str], excluded: &[&str])
+pub fn epur_tables(mut tables: HashMap<String, Table>, included: &[String], excluded: &[String])
                    -> Result<HashMap<String, Table>, String> {
     if !included.is_empty() {
         let mut _tables = HashMap::new();
@@ -63,22 +156,71 @@ pub fn epur_tables(mut tables: HashMap<String, Table>, included: &[&str], exclud
     }
 }
 
-pub fn infer_schema(conn: &Connection, included: &[&str], excluded: &[&str])
+/// Load the tables of the database.
+///
+/// When `check_privileges` is set, the tables the connected role has no
+/// privilege on are ignored, and the methods and columns of the others are
+/// restricted to the privileges of the role. Tables are identified by their
+/// name alone, serving two tables with the same name from different schemas
+/// is an error, unless their name is excluded or not included.
+pub fn infer_schema(conn: &Connection, schemas: &[String], included: &[String],
+                    excluded: &[String], check_privileges: bool)
                     -> Result<HashMap<String, Table>, String> {
+    let rows = match conn.query(INFER_SCHEMA_QUERY, &[]) {
+        Ok(rows) => rows,
+        Err(e) => return Err(format!("unable to introspect the database schema, {}", e)),
+    };
     let mut tables: HashMap<String, Table> = HashMap::new();
-    for row in &conn.query(&*INFER_SCHEMA_QUERY, &[]).unwrap() {
+    // the tables with the same name as a table loaded from another schema
+    let mut conflicts: Vec<(String, String, String)> = vec![];
+    for row in &rows {
+        let table_schema: String = row.get(7);
+        if !schemas.is_empty() && !schemas.contains(&table_schema) {
+            continue;
+        }
+        let privileges = match check_privileges {
+            true => get_table_privileges(&row),
+            false => TablePrivileges::default(),
+        };
+        if !privileges.any() {
+            continue;
+        }
         let table_name: String = row.get(0);
-        if tables.get(&table_name).is_none() {
-            tables.insert(table_name.clone(),
-                          Table{name: table_name.clone(), columns: BTreeMap::new()});
+        // tables are served by name, the schemas must not share table names
+        let loaded_schema = tables.get(&table_name).map(|t| t.schema.clone());
+        match loaded_schema {
+            Some(schema) if schema != table_schema => {
+                let conflict = (table_name, schema, table_schema);
+                if !conflicts.contains(&conflict) {
+                    conflicts.push(conflict);
+                }
+                continue;
+            },
+            Some(_) => {},
+            None => {
+                tables.insert(table_name.clone(), Table{
+                    name: table_name.clone(),
+                    schema: table_schema,
+                    columns: Columns::new(),
+                    privileges: privileges,
+                    comment: row.get(17),
+                });
+            },
         }
         let column_name: String = row.get(1);
-        let ty_query = format!("SELECT atttypid FROM pg_attribute \
-                                WHERE attrelid = '{}'::regclass and attname = '{}'",
-                               table_name, column_name);
-        let ty = Type::from_oid(conn.query(&*ty_query, &[]).unwrap().get(0).get(0)).unwrap();
-        tables.get_mut(&table_name).unwrap().columns.insert(row.get(1), get_column(&row, ty));
+        let ty = get_type(row.get(4), &*table_name, &*column_name);
+        // rows are ordered by position, so are the columns
+        tables.get_mut(&table_name).unwrap().columns
+            .insert(get_column(&row, ty, check_privileges));
     }
 
-    return epur_tables(tables, included, excluded);
+    let tables = epur_tables(tables, included, excluded)?;
+    // the excluded tables do not conflict
+    for (table, schema, other) in conflicts {
+        if tables.contains_key(&table) {
+            return Err(format!("table {} exists in schemas {} and {}, restrict the schemas \
+                                or the tables to serve one of them", table, schema, other));
+        }
+    }
+    Ok(tables)
 }
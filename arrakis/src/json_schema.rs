@@ -0,0 +1,841 @@
+// Copyright 2016 Jeremy Letang.
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! JSON Schema (draft 2020-12) of the rows of the tables.
+//!
+//! The schemas are derived from the columns: their types, lengths,
+//! nullability, constant defaults, enum labels and the check constraints
+//! which can be expressed as JSON Schema keywords. They are served to the
+//! clients and validate the bodies of the POST and PATCH requests.
+//!
+//! Timestamps are unix timestamps in seconds, as in the json responses. The
+//! `~` check constraints are postgres advanced regular expressions, their
+//! syntax is not the one of JSON Schema or of the `regex` crate, so only the
+//! patterns made of the constructs they share become `pattern` keywords.
+
+use error::Error;
+use postgres::types::Type;
+use regex::Regex;
+use schema::{Column, Table};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+const DIALECT: &'static str = "https://json-schema.org/draft/2020-12/schema";
+
+const CREATE: &'static str = "create";
+const UPDATE: &'static str = "update";
+
+// operators of the check constraints, the longest first
+const OPERATORS: [&'static str; 10] = [">=", "<=", "<>", "!=", "~~", "~*", ">", "<", "=", "~"];
+
+/// The kind of request a schema validates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    // a new row, the columns without a default which cannot be null are
+    // required
+    Create,
+    // some columns of existing rows
+    Update,
+}
+
+impl FromStr for Variant {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            CREATE => Ok(Variant::Create),
+            UPDATE => Ok(Variant::Update),
+            _ => Err(Error::InvalidInputError(
+                format!("invalid variant {}, expected create or update", s))),
+        }
+    }
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Variant::Create => CREATE,
+            Variant::Update => UPDATE,
+        };
+        write!(fmt, "{}", s)
+    }
+}
+
+/// The schema of the bodies of a table, made of the columns the clients can
+/// write.
+pub fn table_schema(table: &Table, variant: Variant) -> Value {
+    let mut properties = Map::new();
+    let mut required = vec![];
+    let writable = |c: &Column| match variant {
+        Variant::Create => c.can_insert(),
+        Variant::Update => c.can_update(),
+    };
+    for (name, c) in table.columns.iter().filter(|&(_, c)| writable(c)) {
+        properties.insert(name.clone(), column_schema(c));
+        if variant == Variant::Create && !c.is_nullable && c.default.is_none() {
+            required.push(Value::String(name.clone()));
+        }
+    }
+    let mut m = Map::new();
+    m.insert("$schema".into(), Value::String(DIALECT.into()));
+    m.insert("title".into(), Value::String(format!("{} ({})", table.name, variant)));
+    if let Some(ref c) = table.comment {
+        m.insert("description".into(), Value::String(c.clone()));
+    }
+    m.insert("type".into(), Value::String("object".into()));
+    m.insert("properties".into(), Value::Object(properties));
+    if !required.is_empty() {
+        m.insert("required".into(), Value::Array(required));
+    }
+    if variant == Variant::Update {
+        m.insert("minProperties".into(), Value::U64(1));
+    }
+    m.insert("additionalProperties".into(), Value::Bool(false));
+    Value::Object(m)
+}
+
+fn column_schema(c: &Column) -> Value {
+    let mut m = Map::new();
+    if let Some(t) = json_type(&c.data_type) {
+        let t = Value::String(t.into());
+        let t = match c.is_nullable {
+            true => Value::Array(vec![t, Value::String("null".into())]),
+            false => t,
+        };
+        m.insert("type".into(), t);
+    }
+    let range = match c.data_type {
+        Type::Char => Some((i8::min_value() as i64, i8::max_value() as i64)),
+        Type::Int2 => Some((i16::min_value() as i64, i16::max_value() as i64)),
+        Type::Int4 => Some((i32::min_value() as i64, i32::max_value() as i64)),
+        _ => None,
+    };
+    if let Some((min, max)) = range {
+        m.insert("minimum".into(), Value::I64(min));
+        m.insert("maximum".into(), Value::I64(max));
+    }
+    if c.data_type == Type::Bytea {
+        m.insert("pattern".into(), Value::String("^\\\\x([0-9a-fA-F]{2})*$".into()));
+    }
+    if let Some(l) = c.character_maximum_length {
+        m.insert("maxLength".into(), Value::I64(l as i64));
+    }
+    if !c.enum_labels.is_empty() {
+        let labels = c.enum_labels.iter().map(|l| Value::String(l.clone())).collect();
+        add_keyword(&mut m, "enum", Value::Array(labels), c.is_nullable);
+    }
+    if let Some(d) = c.default.as_ref().and_then(|d| default_value(d, &c.data_type)) {
+        m.insert("default".into(), d);
+    }
+    if let Some(ref comment) = c.comment {
+        m.insert("description".into(), Value::String(comment.clone()));
+    }
+    // the constants of the checks of the timestamp columns are timestamp
+    // literals, not unix timestamps
+    let checks = match c.data_type {
+        Type::Timestamp | Type::TimestampTZ => &[][..],
+        _ => &c.checks[..],
+    };
+    for check in checks {
+        for (k, v) in check_keywords(&*c.name, check) {
+            add_keyword(&mut m, k, v, c.is_nullable);
+        }
+    }
+    Value::Object(m)
+}
+
+// the type of the json values accepted for a column, the other types are
+// not checked, postgres parses them from strings
+fn json_type(ty: &Type) -> Option<&'static str> {
+    match *ty {
+        Type::Bool => Some("boolean"),
+        Type::Char | Type::Int2 | Type::Int4 | Type::Int8 | Type::Timestamp |
+        Type::TimestampTZ => Some("integer"),
+        Type::Float4 | Type::Float8 | Type::Numeric => Some("number"),
+        Type::Text | Type::Varchar | Type::Bpchar | Type::Bytea => Some("string"),
+        _ => None,
+    }
+}
+
+// add a keyword to a schema, in an allOf if the schema already has it. A
+// check constraint holds for null, so null is added to the allowed values of
+// the nullable columns.
+fn add_keyword(m: &mut Map<String, Value>, keyword: &str, value: Value, nullable: bool) {
+    let (keyword, value) = match (keyword, nullable, value) {
+        ("const", true, v) => ("enum", Value::Array(vec![v, Value::Null])),
+        ("enum", true, Value::Array(mut values)) => {
+            values.push(Value::Null);
+            ("enum", Value::Array(values))
+        },
+        (k, _, v) => (k, v),
+    };
+    if !m.contains_key(keyword) {
+        m.insert(keyword.into(), value);
+        return;
+    }
+    let mut sub = Map::new();
+    sub.insert(keyword.into(), value);
+    if !m.contains_key("allOf") {
+        m.insert("allOf".into(), Value::Array(vec![]));
+    }
+    if let Some(&mut Value::Array(ref mut all)) = m.get_mut("allOf") {
+        all.push(Value::Object(sub));
+    }
+}
+
+// the json value of a constant default, e.g 0, true or 'foo'::text
+fn default_value(expr: &str, ty: &Type) -> Option<Value> {
+    let v = match literal(expr) {
+        Some(v) => v,
+        None => return None,
+    };
+    match (json_type(ty), v) {
+        (Some("integer"), Value::String(s)) => s.parse::<i64>().ok().map(Value::I64),
+        (Some("number"), Value::String(s)) => s.parse::<f64>().ok().map(Value::F64),
+        (Some("integer"), v @ Value::I64(_)) => Some(v),
+        (Some("number"), v @ Value::I64(_)) | (Some("number"), v @ Value::F64(_)) => Some(v),
+        (Some("boolean"), v @ Value::Bool(_)) => Some(v),
+        (Some("string"), v @ Value::String(_)) | (None, v @ Value::String(_)) => Some(v),
+        _ => None,
+    }
+}
+
+// the keywords equivalent to a check constraint. Only the conjunctions of
+// comparisons of the column, or of its length, with constants are
+// understood, the other parts of the constraint are ignored.
+fn check_keywords(column: &str, check: &str) -> Vec<(&'static str, Value)> {
+    let mut s = check.trim();
+    if !s.starts_with("CHECK ") {
+        return vec![];
+    }
+    s = &s[6..];
+    if s.ends_with(" NOT VALID") {
+        s = &s[..s.len() - 10];
+    }
+    split_top_level(unwrap(s), " AND ").into_iter()
+        .flat_map(|part| comparison_keywords(column, unwrap(part)))
+        .collect()
+}
+
+fn comparison_keywords(column: &str, expr: &str) -> Vec<(&'static str, Value)> {
+    let (lhs, op, rhs) = match find_operator(expr) {
+        Some((i, op)) => (unwrap(&expr[..i]), op, unwrap(&expr[i + op.len() + 2..])),
+        None => return vec![],
+    };
+    let length = ["length(", "char_length(", "character_length("].iter()
+        .find(|f| lhs.starts_with(**f) && lhs.ends_with(')'))
+        .map(|f| unwrap(&lhs[f.len()..lhs.len() - 1]));
+    let target = match length {
+        Some(arg) if ident(arg) == column => Target::Length,
+        Some(_) => return vec![],
+        None if ident(lhs) == column => Target::Value,
+        None => return vec![],
+    };
+    if op == "=" && rhs.starts_with("ANY ") && target == Target::Value {
+        let array = unwrap(&rhs[4..]);
+        if !array.starts_with("ARRAY[") || !array.ends_with(']') {
+            return vec![];
+        }
+        let values = split_top_level(&array[6..array.len() - 1], ", ").into_iter()
+            .map(|v| literal(v))
+            .collect::<Option<Vec<Value>>>();
+        return match values {
+            Some(v) => vec![("enum", Value::Array(v))],
+            None => vec![],
+        };
+    }
+    let value = match literal(rhs) {
+        Some(v) => v,
+        None => return vec![],
+    };
+    match (target, op, value) {
+        (Target::Value, "~", Value::String(p)) => match portable_pattern(&*p) {
+            true => vec![("pattern", Value::String(p))],
+            false => vec![],
+        },
+        (Target::Value, "=", v) => vec![("const", v)],
+        (Target::Value, op, v) => match (op, is_number(&v)) {
+            (">", true) => vec![("exclusiveMinimum", v)],
+            (">=", true) => vec![("minimum", v)],
+            ("<", true) => vec![("exclusiveMaximum", v)],
+            ("<=", true) => vec![("maximum", v)],
+            _ => vec![],
+        },
+        (Target::Length, op, Value::I64(n)) => {
+            let (min, max) = match op {
+                ">" => (Some(n + 1), None),
+                ">=" => (Some(n), None),
+                "<" => (None, Some(n - 1)),
+                "<=" => (None, Some(n)),
+                "=" => (Some(n), Some(n)),
+                _ => (None, None),
+            };
+            let mut keywords = vec![];
+            if let Some(min) = min {
+                keywords.push(("minLength", Value::I64(::std::cmp::max(min, 0))));
+            }
+            if let Some(max) = max {
+                keywords.push(("maxLength", Value::I64(::std::cmp::max(max, 0))));
+            }
+            keywords
+        },
+        _ => vec![],
+    }
+}
+
+// whether a postgres regular expression means the same as a JSON Schema and
+// a `regex` crate pattern: no director prefix, and no escape other than the
+// classes and the escaped punctuation. E.g \b is a backspace for postgres and
+// a word boundary for the others, \m or \y do not exist for the others.
+fn portable_pattern(p: &str) -> bool {
+    if p.starts_with("***") {
+        return false;
+    }
+    let mut chars = p.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            continue;
+        }
+        match chars.next() {
+            Some('d') | Some('D') | Some('s') | Some('S') | Some('w') | Some('W') => {},
+            Some(c) if !c.is_alphanumeric() => {},
+            _ => return false,
+        }
+    }
+    true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    Value,
+    Length,
+}
+
+// the position and the operator of the first comparison outside of the
+// parentheses and strings, postgres writes them between spaces
+fn find_operator(expr: &str) -> Option<(usize, &'static str)> {
+    let bytes = expr.as_bytes();
+    let mut depth = 0;
+    let mut quoted = false;
+    for i in 0..bytes.len() {
+        match bytes[i] {
+            b'\'' => quoted = !quoted,
+            b'(' | b'[' if !quoted => depth += 1,
+            b')' | b']' if !quoted => depth -= 1,
+            b' ' if !quoted && depth == 0 => {
+                let rest = &expr[i + 1..];
+                let op = OPERATORS.iter()
+                    .find(|op| rest.starts_with(**op) && rest[op.len()..].starts_with(' '));
+                if let Some(op) = op {
+                    return Some((i, *op));
+                }
+            },
+            _ => {},
+        }
+    }
+    None
+}
+
+// split on a separator outside of the parentheses and strings
+fn split_top_level<'a>(s: &'a str, sep: &str) -> Vec<&'a str> {
+    let bytes = s.as_bytes();
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\'' => quoted = !quoted,
+            b'(' | b'[' if !quoted => depth += 1,
+            b')' | b']' if !quoted => depth -= 1,
+            _ if !quoted && depth == 0 && bytes[i..].starts_with(sep.as_bytes()) => {
+                parts.push(&s[start..i]);
+                i += sep.len();
+                start = i;
+                continue;
+            },
+            _ => {},
+        }
+        i += 1;
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+// remove the casts and the parentheses around an expression
+fn unwrap(s: &str) -> &str {
+    let mut s = s.trim();
+    loop {
+        let t = strip_parens(strip_cast(s));
+        if t == s {
+            return s;
+        }
+        s = t;
+    }
+}
+
+// remove the cast of a single value, e.g (0)::numeric or 'a'::text
+fn strip_cast(s: &str) -> &str {
+    let bytes = s.as_bytes();
+    let mut depth = 0;
+    let mut quoted = false;
+    for i in 0..bytes.len() {
+        match bytes[i] {
+            b'\'' => quoted = !quoted,
+            b'(' | b'[' if !quoted => depth += 1,
+            b')' | b']' if !quoted => depth -= 1,
+            // an operator, the cast applies to its operand only
+            b' ' if !quoted && depth == 0 => return s,
+            b':' if !quoted && depth == 0 && bytes[i..].starts_with(b"::") => {
+                let is_type = bytes[i + 2..].iter()
+                    .all(|&b| match b {
+                        b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' => true,
+                        b => b" _.\"[]".contains(&b),
+                    });
+                return match is_type && i > 0 {
+                    true => s[..i].trim(),
+                    false => s,
+                };
+            },
+            _ => {},
+        }
+    }
+    s
+}
+
+// remove the parentheses around the whole expression
+fn strip_parens(s: &str) -> &str {
+    if !s.starts_with('(') || !s.ends_with(')') {
+        return s;
+    }
+    let bytes = s.as_bytes();
+    let mut depth = 0;
+    let mut quoted = false;
+    for i in 0..bytes.len() {
+        match bytes[i] {
+            b'\'' => quoted = !quoted,
+            b'(' if !quoted => depth += 1,
+            b')' if !quoted => {
+                depth -= 1;
+                if depth == 0 && i != bytes.len() - 1 {
+                    return s;
+                }
+            },
+            _ => {},
+        }
+    }
+    s[1..s.len() - 1].trim()
+}
+
+// the name of a column reference, unquoting it if needed
+fn ident(s: &str) -> String {
+    if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+        return s[1..s.len() - 1].replace("\"\"", "\"");
+    }
+    s.to_string()
+}
+
+// the value of a constant: a string, a number or a boolean
+fn literal(s: &str) -> Option<Value> {
+    let s = unwrap(s);
+    match s {
+        "true" => return Some(Value::Bool(true)),
+        "false" => return Some(Value::Bool(false)),
+        _ => {},
+    }
+    if s.len() >= 2 && s.starts_with('\'') && s.ends_with('\'') {
+        let inner = &s[1..s.len() - 1];
+        if inner.replace("''", "").contains('\'') {
+            return None;
+        }
+        return Some(Value::String(inner.replace("''", "'")));
+    }
+    if let Ok(i) = s.parse::<i64>() {
+        return Some(Value::I64(i));
+    }
+    match s.parse::<f64>() {
+        Ok(f) if f.is_finite() => Some(Value::F64(f)),
+        _ => None,
+    }
+}
+
+fn is_number(v: &Value) -> bool {
+    as_f64(v).is_some()
+}
+
+fn as_f64(v: &Value) -> Option<f64> {
+    match *v {
+        Value::I64(i) => Some(i as f64),
+        Value::U64(u) => Some(u as f64),
+        Value::F64(f) => Some(f),
+        _ => None,
+    }
+}
+
+/// The validators of the new rows and of the updates of a table, built once
+/// per schema load.
+#[derive(Debug)]
+pub struct Validators {
+    pub create: Validator,
+    pub update: Validator,
+}
+
+impl Validators {
+    pub fn new(table: &Table) -> Validators {
+        Validators {
+            create: Validator::new(table, Variant::Create),
+            update: Validator::new(table, Variant::Update),
+        }
+    }
+}
+
+/// Validate the bodies of a table against its schema. Only the keywords
+/// used by `table_schema` are supported.
+#[derive(Debug)]
+pub struct Validator {
+    table: String,
+    schema: Value,
+    // the patterns which are not valid regexes are not checked here, the
+    // database still enforces them
+    patterns: HashMap<String, Regex>,
+}
+
+impl Validator {
+    pub fn new(table: &Table, variant: Variant) -> Validator {
+        let schema = table_schema(table, variant);
+        let mut patterns = HashMap::new();
+        collect_patterns(&schema, &mut patterns);
+        Validator {
+            table: table.name.clone(),
+            schema: schema,
+            patterns: patterns,
+        }
+    }
+
+    /// The errors of a value, as the json pointer of the invalid value and
+    /// a message.
+    pub fn errors(&self, value: &Value) -> Vec<(String, String)> {
+        let mut errors = vec![];
+        self.check(&self.schema, value, "", &mut errors);
+        errors
+    }
+
+    pub fn validate(&self, value: &Value) -> Result<(), Error> {
+        let errors = self.errors(value);
+        if errors.is_empty() {
+            return Ok(());
+        }
+        Err(Error::ValidationFailed(self.table.clone(), errors))
+    }
+
+    fn check(&self, schema: &Value, value: &Value, pointer: &str,
+             errors: &mut Vec<(String, String)>) {
+        let schema = match schema.as_object() {
+            Some(s) => s,
+            None => return,
+        };
+        if let Some(t) = schema.get("type") {
+            let types = match *t {
+                Value::Array(ref types) => types.iter().filter_map(|t| t.as_str()).collect(),
+                Value::String(ref t) => vec![&**t],
+                _ => vec![],
+            };
+            if !types.iter().any(|t| has_type(value, t)) {
+                let message = format!("expected {}, found {}", types.join(" or "),
+                                      type_name(value));
+                push(errors, pointer, message);
+                return;
+            }
+        }
+        if let Some(&Value::Array(ref values)) = schema.get("enum") {
+            if !values.iter().any(|v| json_eq(v, value)) {
+                let values = values.iter().map(|v| format!("{}", v)).collect::<Vec<String>>();
+                push(errors, pointer, format!("expected one of {}", values.join(", ")));
+            }
+        }
+        if let Some(v) = schema.get("const") {
+            if !json_eq(v, value) {
+                push(errors, pointer, format!("expected {}", v));
+            }
+        }
+        if let Some(n) = as_f64(value) {
+            let bounds = ["minimum", "exclusiveMinimum", "maximum", "exclusiveMaximum"];
+            for keyword in bounds.iter() {
+                let b = match schema.get(*keyword).and_then(as_f64) {
+                    Some(b) => b,
+                    None => continue,
+                };
+                let (holds, text) = match *keyword {
+                    "minimum" => (n >= b, "greater than or equal to"),
+                    "exclusiveMinimum" => (n > b, "greater than"),
+                    "maximum" => (n <= b, "less than or equal to"),
+                    _ => (n < b, "less than"),
+                };
+                if !holds {
+                    push(errors, pointer, format!("expected a number {} {}", text, b));
+                }
+            }
+        }
+        if let Value::String(ref s) = *value {
+            let len = s.chars().count() as u64;
+            if let Some(min) = schema.get("minLength").and_then(Value::as_u64) {
+                if len < min {
+                    push(errors, pointer, format!("expected at least {} characters", min));
+                }
+            }
+            if let Some(max) = schema.get("maxLength").and_then(Value::as_u64) {
+                if len > max {
+                    push(errors, pointer, format!("expected at most {} characters", max));
+                }
+            }
+            if let Some(p) = schema.get("pattern").and_then(Value::as_str) {
+                if self.patterns.get(p).map_or(false, |r| !r.is_match(s)) {
+                    push(errors, pointer, format!("expected a string matching {}", p));
+                }
+            }
+        }
+        if let Value::Object(ref m) = *value {
+            let properties = schema.get("properties").and_then(Value::as_object);
+            if let Some(&Value::Array(ref required)) = schema.get("required") {
+                for r in required.iter().filter_map(Value::as_str) {
+                    if !m.contains_key(r) {
+                        push(errors, pointer, format!("missing required column {}", r));
+                    }
+                }
+            }
+            if let Some(min) = schema.get("minProperties").and_then(Value::as_u64) {
+                if (m.len() as u64) < min {
+                    push(errors, pointer, format!("expected at least {} columns", min));
+                }
+            }
+            for (k, v) in m {
+                match properties.and_then(|p| p.get(k)) {
+                    Some(s) => {
+                        let pointer = format!("{}/{}", pointer, escape_pointer(k));
+                        self.check(s, v, &*pointer, errors);
+                    },
+                    None if schema.get("additionalProperties") == Some(&Value::Bool(false)) =>
+                        push(errors, pointer, format!("unknown column {}", k)),
+                    None => {},
+                }
+            }
+        }
+        if let Some(&Value::Array(ref all)) = schema.get("allOf") {
+            for s in all {
+                self.check(s, value, pointer, errors);
+            }
+        }
+    }
+}
+
+fn push(errors: &mut Vec<(String, String)>, pointer: &str, message: String) {
+    errors.push((pointer.to_string(), message));
+}
+
+fn collect_patterns(schema: &Value, patterns: &mut HashMap<String, Regex>) {
+    match *schema {
+        Value::Object(ref m) => {
+            for (k, v) in m {
+                match (&**k, v) {
+                    // the dot of postgres matches the new lines
+                    ("pattern", &Value::String(ref p)) => {
+                        if let Ok(r) = Regex::new(&*format!("(?s){}", p)) {
+                            patterns.insert(p.clone(), r);
+                        }
+                    },
+                    (_, v) => collect_patterns(v, patterns),
+                }
+            }
+        },
+        Value::Array(ref values) => {
+            for v in values {
+                collect_patterns(v, patterns);
+            }
+        },
+        _ => {},
+    }
+}
+
+fn has_type(value: &Value, ty: &str) -> bool {
+    match (ty, value) {
+        ("null", &Value::Null) => true,
+        ("boolean", &Value::Bool(_)) => true,
+        ("integer", &Value::I64(_)) | ("integer", &Value::U64(_)) => true,
+        // a number with a zero fractional part is an integer
+        ("integer", &Value::F64(f)) => f.fract() == 0.0,
+        ("number", v) => is_number(v),
+        ("string", &Value::String(_)) => true,
+        ("array", &Value::Array(_)) => true,
+        ("object", &Value::Object(_)) => true,
+        _ => false,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match *value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::I64(_) | Value::U64(_) | Value::F64(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+// equality of json values, numbers are compared by value
+fn json_eq(a: &Value, b: &Value) -> bool {
+    match (as_f64(a), as_f64(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn escape_pointer(s: &str) -> String {
+    s.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn column(name: &str, ty: Type) -> Column {
+        let mut c = Column::new(name, ty);
+        c.is_nullable = false;
+        c
+    }
+
+    fn table(columns: Vec<Column>) -> Table {
+        Table::new("public", "t", columns)
+    }
+
+    fn json(s: &str) -> Value {
+        serde_json::from_str(s).unwrap()
+    }
+
+    fn pointers(v: &Validator, body: &str) -> Vec<String> {
+        v.errors(&json(body)).into_iter().map(|(p, _)| p).collect()
+    }
+
+    #[test]
+    fn variants() {
+        assert_eq!("create".parse::<Variant>().unwrap(), Variant::Create);
+        assert_eq!("update".parse::<Variant>().unwrap(), Variant::Update);
+        assert!("replace".parse::<Variant>().is_err());
+        assert_eq!(Variant::Update.to_string(), "update");
+    }
+
+    #[test]
+    fn timestamps_are_integers() {
+        let mut at = column("at", Type::TimestampTZ);
+        at.checks = vec!["CHECK ((at > '2020-01-01 00:00:00+00'::timestamp with time zone))"
+                         .into()];
+        let t = table(vec![at]);
+        let schema = table_schema(&t, Variant::Create);
+        assert_eq!(schema.pointer("/properties/at"), Some(&json(r#"{"type":"integer"}"#)));
+        let v = Validator::new(&t, Variant::Create);
+        assert!(v.validate(&json(r#"{"at":1500000000}"#)).is_ok());
+        assert_eq!(pointers(&v, r#"{"at":"2020-01-02 00:00:00"}"#), vec!["/at"]);
+    }
+
+    #[test]
+    fn required_columns() {
+        let mut serial = column("id", Type::Int4);
+        serial.default = Some("nextval('t_id_seq'::regclass)".into());
+        let mut identity = column("n", Type::Int8);
+        identity.default = Some("GENERATED BY DEFAULT AS IDENTITY".into());
+        let mut generated = column("total", Type::Int8);
+        generated.default = Some("(n * 2)".into());
+        generated.is_generated = true;
+        let mut nullable = column("note", Type::Text);
+        nullable.is_nullable = true;
+        let t = table(vec![serial, identity, generated, nullable, column("name", Type::Text)]);
+
+        let create = table_schema(&t, Variant::Create);
+        assert_eq!(create.pointer("/required"), Some(&json(r#"["name"]"#)));
+        assert!(create.pointer("/properties/total").is_none());
+        assert!(create.pointer("/properties/n/default").is_none());
+        let update = table_schema(&t, Variant::Update);
+        assert!(update.pointer("/required").is_none());
+        assert!(update.pointer("/properties/total").is_none());
+        assert_eq!(update.pointer("/minProperties"), Some(&Value::U64(1)));
+    }
+
+    #[test]
+    fn keywords_of_checks() {
+        let keywords = |check: &str| {
+            check_keywords("price", check).into_iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(keywords("CHECK ((price > (0)::numeric))"), vec!["exclusiveMinimum=0"]);
+        assert_eq!(keywords("CHECK (((price >= 1) AND (price <= 10)))"),
+                   vec!["minimum=1", "maximum=10"]);
+        assert_eq!(keywords("CHECK ((length(price) < 5)) NOT VALID"), vec!["maxLength=4"]);
+        assert_eq!(keywords("CHECK ((price = ANY (ARRAY['a'::text, 'b'::text])))"),
+                   vec![r#"enum=["a","b"]"#]);
+        assert_eq!(keywords("CHECK ((price ~ '^[a-z]+$'::text))"),
+                   vec![r#"pattern="^[a-z]+$""#]);
+        // another column, or an expression which is not understood
+        assert!(keywords("CHECK ((other > 0))").is_empty());
+        assert!(keywords("CHECK (((price * 2) > 0))").is_empty());
+        assert!(keywords("CHECK ((price ~ '\\mword'::text))").is_empty());
+    }
+
+    #[test]
+    fn portable_patterns() {
+        assert!(portable_pattern("^[a-z]+$"));
+        assert!(portable_pattern("^\\d{3}-\\w+\\.$"));
+        // a backspace for postgres, a word boundary for the others
+        assert!(!portable_pattern("\\bword"));
+        assert!(!portable_pattern("\\mword\\M"));
+        assert!(!portable_pattern("(a)\\1"));
+        assert!(!portable_pattern("***=a.b"));
+    }
+
+    #[test]
+    fn validation_errors() {
+        let mut name = column("name", Type::Varchar);
+        name.character_maximum_length = Some(3);
+        name.checks = vec!["CHECK (((name)::text ~ '^a.*'::text))".into()];
+        let mut kind = column("kind", Type::Unknown);
+        kind.enum_labels = vec!["x".into(), "y".into()];
+        kind.is_nullable = true;
+        let t = table(vec![name, column("qty", Type::Int2), kind]);
+        let v = Validator::new(&t, Variant::Create);
+
+        assert!(v.validate(&json(r#"{"name":"ab","qty":1,"kind":null}"#)).is_ok());
+        // the dot matches the new lines, as for postgres
+        assert!(v.validate(&json(r#"{"name":"a\n","qty":1}"#)).is_ok());
+        assert_eq!(pointers(&v, r#"{"name":"abcd","qty":1}"#), vec!["/name"]);
+        assert_eq!(pointers(&v, r#"{"name":"b","qty":1}"#), vec!["/name"]);
+        assert_eq!(pointers(&v, r#"{"name":"a","qty":40000}"#), vec!["/qty"]);
+        assert_eq!(pointers(&v, r#"{"name":"a","qty":1,"kind":"z"}"#), vec!["/kind"]);
+        assert_eq!(pointers(&v, r#"{"name":"a","qty":1,"other":1}"#), vec![""]);
+        assert_eq!(pointers(&v, r#"{"name":"a"}"#), vec![""]);
+        match v.validate(&json(r#"{"qty":"1"}"#)) {
+            Err(Error::ValidationFailed(table, errors)) => {
+                assert_eq!(table, "t");
+                assert_eq!(errors, vec![
+                    ("".to_string(), "missing required column name".to_string()),
+                    ("/qty".to_string(), "expected integer, found string".to_string()),
+                ]);
+            },
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn validators_of_a_table() {
+        let validators = Validators::new(&table(vec![column("name", Type::Text)]));
+        assert!(validators.create.validate(&json(r#"{}"#)).is_err());
+        assert!(validators.update.validate(&json(r#"{}"#)).is_err());
+        assert!(validators.update.validate(&json(r#"{"name":"a"}"#)).is_ok());
+    }
+}
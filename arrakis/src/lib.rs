@@ -8,24 +8,48 @@
 #![allow(unused_variables)]
 
 extern crate env_logger;
+extern crate fallible_iterator;
+extern crate futures;
+extern crate futures_cpupool;
 #[macro_use]
 extern crate log;
+extern crate openssl;
 extern crate postgres;
 extern crate r2d2;
 extern crate r2d2_postgres;
+extern crate regex;
 extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate serde_json;
+extern crate serde_yaml;
+extern crate toml;
 
+pub mod arrow;
+pub mod auth;
+pub mod cbor;
 pub mod config;
 pub mod common;
+pub mod context;
 pub mod cvt;
 pub mod error;
 pub mod filters;
+pub mod format;
+pub mod graphql;
+pub mod graphql_parser;
+pub mod graphql_schema;
+pub mod import;
 pub mod infer_schema;
+pub mod json_schema;
 pub mod method;
+pub mod msgpack;
+pub mod openapi;
 pub mod ordering;
 pub mod queries;
+pub mod query;
 pub mod schema;
+pub mod stream;
+pub mod tls;
 
 pub mod delete;
 mod doc_consts;
@@ -33,26 +57,79 @@ pub mod get;
 pub mod patch;
 pub mod post;
 
+use auth::Authenticator;
 use config::Config;
+use context::Context;
 use doc_consts as dc;
 use error::Error;
+use format::Format;
+use graphql_schema::Schema;
+use futures::Future;
+use futures::future::{self, BoxFuture};
+use futures_cpupool::{CpuFuture, CpuPool};
+use import::{ImportOptions, OnConflict};
 use infer_schema::infer_schema;
+use json_schema::{Validators, Variant};
 use method::Method;
-use queries::Queries;
-use r2d2_postgres::{TlsMode, PostgresConnectionManager};
-use schema::Table;
+use queries::{FetchQueries, Queries};
+use query::{Embed, Query};
+use postgres::GenericConnection;
+use postgres::transaction::Transaction;
+use r2d2::ManageConnection;
+use r2d2_postgres::PostgresConnectionManager;
+use schema::{Table, Visibility};
+use stream::RowWriter;
 use serde_json::Value;
 use std::collections::HashMap;
 use std::error::Error as StdError;
+use std::sync::{Arc, RwLock};
+use std::thread;
 use std::time::Duration;
 
 pub use postgres::params::{
     ConnectParams, IntoConnectParams, UserInfo, ConnectTarget};
 
+// delay in seconds before listening again for reloads after a failure
+const RELOAD_LISTEN_RETRY: u64 = 5;
+
 #[derive(Debug, Clone)]
 pub struct Arrakis {
     conn: r2d2::Pool<PostgresConnectionManager>,
-    tables: HashMap<String, Table>,
+    // swapped on reload, requests keep the snapshot they started with
+    loaded: Arc<RwLock<Loaded>>,
+    config: Config,
+    auth: Option<Arc<Authenticator>>,
+    workers: Workers,
+}
+
+// the tables and the validators of their bodies, built together on each
+// schema load
+#[derive(Debug, Clone)]
+struct Loaded {
+    tables: Arc<HashMap<String, Table>>,
+    validators: Arc<HashMap<String, Validators>>,
+}
+
+impl Loaded {
+    fn new(tables: HashMap<String, Table>) -> Loaded {
+        let validators = tables.iter()
+            .map(|(name, t)| (name.clone(), Validators::new(t)))
+            .collect();
+        Loaded {
+            tables: Arc::new(tables),
+            validators: Arc::new(validators),
+        }
+    }
+}
+
+// threads running the blocking database calls of the async api
+#[derive(Clone)]
+struct Workers(CpuPool);
+
+impl ::std::fmt::Debug for Workers {
+    fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(fmt, "Workers")
+    }
 }
 
 impl Arrakis {
@@ -61,121 +138,630 @@ impl Arrakis {
         return Arrakis::with_config(params, Config::default());
     }
 
+    /// Create an instance using the database url of the configuration.
+    pub fn from_config(config: Config) -> Result<Arrakis, String> {
+        let url = match config.database_url() {
+            Some(url) => url.to_string(),
+            None => return Err(format!("missing database url in configuration")),
+        };
+        return Arrakis::with_config(&*url, config);
+    }
+
     pub fn with_config<P>(params: P, config: Config) -> Result<Arrakis, String>
         where P: IntoConnectParams {
         // be sure the config is not invalid
-        if config.excluded().len() != 0 && config.included().len() != 0 {
-            return Err(format!("cannot specify both excluded and included schemas"));
-        }
+        config.validate()?;
+
+        let auth = match config.auth() {
+            Some(conf) => Some(Arc::new(Authenticator::from_config(conf)?)),
+            None => None,
+        };
 
         // build conf with specific timeout
-        let r2d2_config = r2d2::Config::builder()
+        let mut r2d2_config = r2d2::Config::builder()
+            .pool_size(config.pool_size())
+            .min_idle(config.min_idle())
             .initialization_fail_fast(true)
             .connection_timeout(Duration::from_secs(config.timeout()))
-            .error_handler(Box::new(r2d2::NopErrorHandler))
-            .build();
+            .error_handler(Box::new(r2d2::NopErrorHandler));
+        if let Some(t) = config.idle_timeout() {
+            r2d2_config = r2d2_config.idle_timeout(Some(Duration::from_secs(t)));
+        }
+        if let Some(t) = config.max_lifetime() {
+            r2d2_config = r2d2_config.max_lifetime(Some(Duration::from_secs(t)));
+        }
+        let r2d2_config = r2d2_config.build();
 
         // build our postgres manager
-        let manager = match PostgresConnectionManager::new(params, TlsMode::None) {
+        let tls_mode = tls::make_tls_mode(config.tls())?;
+        let manager = match PostgresConnectionManager::new(params, tls_mode) {
             Ok(m) => m,
-            Err(e) => return Err(format!("{}, {}", e.description(), e.cause().unwrap()))
+            Err(e) => return Err(match e.cause() {
+                Some(cause) => format!("{}, {}", e.description(), cause),
+                None => format!("{}", e),
+            }),
         };
 
+        // try a first connection, so tls negotiation and certificate
+        // verification errors are reported as is instead of a pool timeout
+        if let Err(e) = manager.connect() {
+            return Err(format!("unable to connect to postgres (tls mode {}), {}",
+                               config.tls().ssl_mode(), e));
+        }
+
         // build the pool
         let pool = match r2d2::Pool::new(r2d2_config, manager) {
             Ok(pool) => pool,
             Err(e) => return Err(format!("{}", e))
         };
 
-        let tables = infer_schema(&*pool.get().unwrap(), config.included(), config.excluded());
+        let tables = match pool.get() {
+            Ok(conn) => load_tables(&*conn, &config)?,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        let workers = Workers(CpuPool::new(config.workers()));
         Ok(Arrakis {
             conn: pool,
-            tables: tables?,
+            loaded: Arc::new(RwLock::new(Loaded::new(tables))),
+            config: config,
+            auth: auth,
+            workers: workers,
         })
     }
 
-    pub fn get_tables(&self) -> &HashMap<String, Table> {
-        return &self.tables;
+    /// The current schema, a later reload does not modify the returned
+    /// tables.
+    pub fn get_tables(&self) -> Arc<HashMap<String, Table>> {
+        return self.loaded.read().unwrap().tables.clone();
+    }
+
+    // the current tables and validators
+    fn snapshot(&self) -> Loaded {
+        self.loaded.read().unwrap().clone()
+    }
+
+    /// Introspect the database again and swap in the new schema, requests in
+    /// flight finish with the previous one. On error the current schema is
+    /// kept.
+    pub fn reload(&self) -> Result<(), String> {
+        let tables = match self.conn.get() {
+            Ok(conn) => load_tables(&*conn, &self.config)?,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        info!("schema reloaded, {} tables", tables.len());
+        *self.loaded.write().unwrap() = Loaded::new(tables);
+        Ok(())
     }
 
-    pub fn any(&self, method: &Method, model: &str, queries: &Queries, body: String)
+    /// Same as `reload`, but executed on the worker threads, see `any_async`.
+    pub fn reload_async(&self) -> BoxFuture<(), String> {
+        let arrakis = self.clone();
+        self.workers.0.spawn_fn(move || arrakis.reload()).boxed()
+    }
+
+    /// Whether `token` is the configured reload token, compared in constant
+    /// time. Always false when no reload token is configured.
+    pub fn reload_token_matches(&self, token: Option<&str>) -> bool {
+        match (self.config.reload_token(), token) {
+            (Some(expected), Some(token)) =>
+                auth::constant_time_eq(expected.as_bytes(), token.as_bytes()),
+            _ => false,
+        }
+    }
+
+    /// Reload the schema each time a notification is received on `channel`,
+    /// e.g from a ddl event trigger:
+    ///
+    /// ```sql
+    /// CREATE FUNCTION arrakis_reload() RETURNS event_trigger AS $$
+    /// BEGIN NOTIFY arrakis_reload; END;
+    /// $$ LANGUAGE plpgsql;
+    /// CREATE EVENT TRIGGER arrakis_reload ON ddl_command_end
+    ///     EXECUTE PROCEDURE arrakis_reload();
+    /// ```
+    ///
+    /// The listener holds one connection of the pool.
+    pub fn listen_reload(&self, channel: &str) -> thread::JoinHandle<()> {
+        let arrakis = self.clone();
+        let listen = format!("LISTEN {}", common::quote_ident(channel));
+        thread::spawn(move || loop {
+            if let Err(e) = arrakis.wait_reload(&*listen) {
+                error!("schema reload listener failed, {}", e);
+            }
+            thread::sleep(Duration::from_secs(RELOAD_LISTEN_RETRY));
+        })
+    }
+
+    // listen on a connection until it fails
+    fn wait_reload(&self, listen: &str) -> Result<(), String> {
+        let conn = match self.conn.get() {
+            Ok(conn) => conn,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        conn.batch_execute(listen).map_err(|e| format!("{}", e))?;
+        let notifications = conn.notifications();
+        for n in notifications.blocking_iter() {
+            if let Err(e) = n {
+                return Err(format!("{}", e));
+            }
+            if let Err(e) = self.reload() {
+                error!("unable to reload schema, {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_config(&self) -> &Config {
+        return &self.config;
+    }
+
+    /// Build the context of a request from its bearer token, requests are
+    /// executed as the login role when authentication is disabled.
+    pub fn authenticate(&self, token: Option<&str>) -> Result<Context, Error> {
+        match self.auth {
+            Some(ref auth) => auth.authenticate(token),
+            None => Ok(Context::new()),
+        }
+    }
+
+    /// Add the headers and cookies of the request listed in the configuration
+    /// to its context, see `Context::set_request_metadata`.
+    pub fn set_request_metadata(&self, ctx: &mut Context, headers: &[(String, String)]) {
+        ctx.set_request_metadata(headers, self.config.request_headers(),
+                                 self.config.request_cookies());
+    }
+
+    pub fn any(&self, ctx: &Context, method: &Method, model: &str, query: &Query, body: Value)
                -> Result<Option<Value>, Error> {
         match *method {
-            Method::Get => self.get(model, queries),
-            Method::Post => self.post(model, queries, body),
-            Method::Put => self.put(model, queries, body),
-            Method::Patch => self.patch(model, queries, body),
-            Method::Delete => self.delete(model, queries),
+            Method::Get => self.get(ctx, model, query),
+            Method::Post => self.post(ctx, model, query, body),
+            Method::Put => self.put(ctx, model, query, body),
+            Method::Patch => self.patch(ctx, model, query, body),
+            Method::Delete => self.delete(ctx, model, query),
         }
     }
 
-    pub fn get(&self, model: &str, queries: &Queries) -> Result<Option<Value>, Error> {
-        self.model_exists(model)?;
-        return get::query(&*(self.conn.get().unwrap()), self.tables.get(model).unwrap(), queries);
+    /// Same as `any` but takes the raw query string parameters and body, as
+    /// received by the http adapters.
+    pub fn any_raw(&self, ctx: &Context, method: &Method, model: &str, queries: &Queries,
+                   body: &str) -> Result<Option<Value>, Error> {
+        self.any_encoded(ctx, method, model, queries, Format::Json, body.as_bytes())
+    }
+
+    /// Same as `any_raw`, with a body in the given format, see
+    /// `Format::read_value`.
+    pub fn any_encoded(&self, ctx: &Context, method: &Method, model: &str, queries: &Queries,
+                       format: Format, body: &[u8]) -> Result<Option<Value>, Error> {
+        let query = self.parse_query(queries)?;
+        let body = read_body(method, format, body)?;
+        self.any(ctx, method, model, &query, body)
+    }
+
+    /// Same as `any`, but executed on the worker threads so the caller is
+    /// not blocked by the database. At most `Config::workers` requests are
+    /// executed at the same time, the others are queued.
+    pub fn any_async(&self, ctx: Context, method: Method, model: String, query: Query,
+                     body: Value) -> BoxFuture<Option<Value>, Error> {
+        let arrakis = self.clone();
+        self.workers.0.spawn_fn(move || arrakis.any(&ctx, &method, &*model, &query, body))
+            .boxed()
+    }
+
+    /// Same as `any_raw`, but executed on the worker threads, see `any_async`.
+    pub fn any_raw_async(&self, ctx: Context, method: &Method, model: &str, queries: &Queries,
+                         body: &str) -> BoxFuture<Option<Value>, Error> {
+        self.any_encoded_async(ctx, method, model, queries, Format::Json, body.as_bytes())
+    }
+
+    /// Same as `any_encoded`, but executed on the worker threads, see
+    /// `any_async`.
+    pub fn any_encoded_async(&self, ctx: Context, method: &Method, model: &str,
+                             queries: &Queries, format: Format, body: &[u8])
+                             -> BoxFuture<Option<Value>, Error> {
+        let query = match self.parse_query(queries) {
+            Ok(q) => q,
+            Err(e) => return future::err(e).boxed(),
+        };
+        let body = match read_body(method, format, body) {
+            Ok(b) => b,
+            Err(e) => return future::err(e).boxed(),
+        };
+        self.any_async(ctx, *method, model.to_string(), query, body)
+    }
+
+    /// Parse the raw query string parameters with the configured control
+    /// prefix.
+    pub fn parse_query(&self, queries: &Queries) -> Result<Query, Error> {
+        Query::from_queries_with_prefix(queries, self.config.control_prefix())
+    }
+
+    /// Choose the format of a GET response from the Accept header and the
+    /// format control parameter.
+    pub fn negotiate_format(&self, accept: Option<&str>, queries: &Queries)
+                            -> Result<Format, Error> {
+        Format::negotiate(accept, queries.format(self.config.control_prefix()))
+    }
+
+    /// Choose the format of the response of a POST, PUT, PATCH or DELETE
+    /// request, see `Format::negotiate_value`.
+    pub fn negotiate_value_format(&self, accept: Option<&str>, queries: &Queries)
+                                  -> Result<Format, Error> {
+        Format::negotiate_value(accept, queries.format(self.config.control_prefix()))
+    }
+
+    /// Check that a GET request can be streamed, i.e the method is allowed
+    /// and the query is valid for the table. Http adapters which cannot
+    /// report errors once the response started should call it first.
+    pub fn prepare_get(&self, ctx: &Context, model: &str, query: &Query) -> Result<(), Error> {
+        self.get_allowed(ctx, model, query)?;
+        let tables = self.get_tables();
+        let table = tables.get(model).ok_or(Error::UnknowModel(model.into()))?;
+        get::generate_query(&tables, table, query, self.config.max_limit())?;
+        Ok(())
+    }
+
+    /// Execute a GET request and give the rows to the writer as they are
+    /// read from the database, instead of loading the whole result.
+    pub fn get_stream(&self, ctx: &Context, model: &str, query: &Query, writer: &mut RowWriter)
+                      -> Result<(), Error> {
+        self.get_allowed(ctx, model, query)?;
+        let tables = self.get_tables();
+        let table = tables.get(model).ok_or(Error::UnknowModel(model.into()))?;
+        let conn = self.connection()?;
+        let trans = self.begin(&conn, ctx)?;
+        // the rows are read only as fast as the client receives them, past
+        // the stream timeout the server ends the session instead of keeping
+        // the transaction open. the setting exists since postgres 9.6, the
+        // select sets nothing on the older versions.
+        trans.execute("SELECT set_config(name, $1, true) FROM pg_settings \
+                       WHERE name = 'idle_in_transaction_session_timeout'",
+                      &[&self.config.stream_timeout().to_string()])?;
+        get::stream(&trans, &tables, table, query, self.config.max_limit(), writer)?;
+        trans.commit()?;
+        Ok(())
+    }
+
+    /// Same as `get_stream`, but executed on the worker threads. Errors are
+    /// reported to the writer with `RowWriter::fail`.
+    pub fn get_stream_async<W>(&self, ctx: Context, model: String, query: Query, mut writer: W)
+                               -> CpuFuture<(), ()>
+        where W: RowWriter + Send + 'static {
+        let arrakis = self.clone();
+        self.workers.0.spawn_fn(move || {
+            if let Err(e) = arrakis.get_stream(&ctx, &*model, &query, &mut writer) {
+                writer.fail(e);
+            }
+            Ok(())
+        })
+    }
+
+    /// Execute a GET request and render the rows in the given format, see
+    /// `Format::row_writer`.
+    pub fn get_formatted(&self, ctx: &Context, model: &str, query: &Query, format: Format)
+                         -> Result<Vec<u8>, Error> {
+        let mut out = vec![];
+        {
+            let mut writer = format.row_writer(&mut out);
+            self.get_stream(ctx, model, query, &mut *writer)?;
+        }
+        Ok(out)
+    }
+
+    /// Same as `get_formatted`, but executed on the worker threads.
+    pub fn get_formatted_async(&self, ctx: Context, model: String, query: Query, format: Format)
+                               -> BoxFuture<Vec<u8>, Error> {
+        let arrakis = self.clone();
+        self.workers.0.spawn_fn(move || arrakis.get_formatted(&ctx, &*model, &query, format))
+            .boxed()
+    }
+
+    pub fn get(&self, ctx: &Context, model: &str, query: &Query)
+               -> Result<Option<Value>, Error> {
+        self.get_allowed(ctx, model, query)?;
+        let tables = self.get_tables();
+        let table = tables.get(model).ok_or(Error::UnknowModel(model.into()))?;
+        self.in_transaction(ctx, |conn| {
+            get::query(conn, &tables, table, query, self.config.max_limit())
+        })
     }
 
-    pub fn post(&self, model: &str, queries: &Queries, body: String)
+    pub fn post(&self, ctx: &Context, model: &str, query: &Query, body: Value)
                 -> Result<Option<Value>, Error> {
-        self.model_exists(model)?;
-        let body = read_json(&body)?;
-        return Ok(Some(Value::Bool(true)));
+        self.method_allowed(ctx, &Method::Post, model)?;
+        let loaded = self.snapshot();
+        let table = loaded.tables.get(model).ok_or(Error::UnknowModel(model.into()))?;
+        let validator = &loaded.validators[model].create;
+        self.in_transaction(ctx, |conn| {
+            post::query(conn, table, validator, query, body)
+        })
+    }
+
+    /// Parse the options of a csv import from the query string parameters.
+    pub fn parse_import_options(&self, queries: &Queries) -> Result<ImportOptions, Error> {
+        ImportOptions::from_queries(queries, self.config.control_prefix())
+    }
+
+    /// Load the rows of a csv body in a table, see the `import` module.
+    /// Updating the conflicting rows needs the PATCH method on top of POST.
+    pub fn post_csv(&self, ctx: &Context, model: &str, options: &ImportOptions, body: &str)
+                    -> Result<Option<Value>, Error> {
+        self.method_allowed(ctx, &Method::Post, model)?;
+        if options.on_conflict == Some(OnConflict::Update) {
+            self.method_allowed(ctx, &Method::Patch, model)?;
+        }
+        let tables = self.get_tables();
+        let table = tables.get(model).ok_or(Error::UnknowModel(model.into()))?;
+        self.in_transaction(ctx, |conn| {
+            import::copy_csv(conn, table, options, body)
+        })
+    }
+
+    /// Same as `post_csv`, but executed on the worker threads.
+    pub fn post_csv_async(&self, ctx: Context, model: String, options: ImportOptions,
+                          body: String) -> BoxFuture<Option<Value>, Error> {
+        let arrakis = self.clone();
+        self.workers.0.spawn_fn(move || arrakis.post_csv(&ctx, &*model, &options, &*body))
+            .boxed()
     }
 
-    pub fn put(&self, model: &str, queries: &Queries, body: String)
+    /// Insert the rows of a ndjson body, one json object per line.
+    pub fn post_lines(&self, ctx: &Context, model: &str, body: &str)
+                      -> Result<Option<Value>, Error> {
+        self.method_allowed(ctx, &Method::Post, model)?;
+        let loaded = self.snapshot();
+        let table = loaded.tables.get(model).ok_or(Error::UnknowModel(model.into()))?;
+        let validator = &loaded.validators[model].create;
+        self.in_transaction(ctx, |conn| {
+            post::query_lines(conn, table, validator, body)
+        })
+    }
+
+    /// Same as `post_lines`, but executed on the worker threads.
+    pub fn post_lines_async(&self, ctx: Context, model: String, body: String)
+                            -> BoxFuture<Option<Value>, Error> {
+        let arrakis = self.clone();
+        self.workers.0.spawn_fn(move || arrakis.post_lines(&ctx, &*model, &*body)).boxed()
+    }
+
+    pub fn put(&self, ctx: &Context, model: &str, query: &Query, body: Value)
                -> Result<Option<Value>, Error> {
-        self.model_exists(model)?;
-        let body = read_json(&body)?;
+        self.method_allowed(ctx, &Method::Put, model)?;
         return Ok(Some(Value::Bool(true)));
     }
 
-    pub fn patch(&self, model: &str, queries: &Queries, body: String)
+    pub fn patch(&self, ctx: &Context, model: &str, query: &Query, body: Value)
                  -> Result<Option<Value>, Error> {
-        self.model_exists(model)?;
-        let body = read_json(&body)?;
-        patch::query(&*(self.conn.get().unwrap()),
-                     self.tables.get(model).unwrap(),
-                     queries,
-                     body)
+        self.method_allowed(ctx, &Method::Patch, model)?;
+        let loaded = self.snapshot();
+        let table = loaded.tables.get(model).ok_or(Error::UnknowModel(model.into()))?;
+        let validator = &loaded.validators[model].update;
+        self.in_transaction(ctx, |conn| {
+            patch::query(conn, table, validator, query, body)
+        })
     }
 
-    pub fn delete(&self, model: &str, queries: &Queries)
+    pub fn delete(&self, ctx: &Context, model: &str, query: &Query)
                   -> Result<Option<Value>, Error> {
+        self.method_allowed(ctx, &Method::Delete, model)?;
+        let tables = self.get_tables();
+        let table = tables.get(model).ok_or(Error::UnknowModel(model.into()))?;
+        self.in_transaction(ctx, |conn| {
+            match delete::query(conn, table, query) {
+                Ok(_) => Ok(None),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    /// Execute a GraphQL request against the schema of the tables allowed
+    /// to the role of the context, see the `graphql` module. The errors are
+    /// reported in the returned response.
+    pub fn graphql(&self, ctx: &Context, request: &graphql::Request) -> Value {
+        let loaded = self.snapshot();
+        let role = ctx.role.as_ref().map(|r| &**r);
+        let schema = Schema::new(&*loaded.tables, |t| self.allowed_methods(role, t));
+        let plan = match graphql::prepare(&schema, request, self.config.max_limit()) {
+            Ok(plan) => plan,
+            Err(errors) => return graphql::response(None, errors),
+        };
+        match self.execute_graphql(ctx, &plan, &*loaded.validators) {
+            Ok(data) => graphql::response(Some(data), vec![]),
+            Err(e) => graphql::response(Some(Value::Null), vec![e]),
+        }
+    }
+
+    /// Same as `graphql`, but executed on the worker threads.
+    pub fn graphql_async(&self, ctx: Context, request: graphql::Request)
+                         -> BoxFuture<Value, Error> {
+        let arrakis = self.clone();
+        self.workers.0.spawn_fn(move || Ok(arrakis.graphql(&ctx, &request))).boxed()
+    }
+
+    // the whole operation is executed in one transaction, the introspection
+    // queries do not need a connection.
+    fn execute_graphql(&self, ctx: &Context, plan: &graphql::Plan,
+                       validators: &HashMap<String, Validators>)
+                       -> Result<Value, graphql::QueryError> {
+        if !plan.needs_database() {
+            return plan.execute(None, validators);
+        }
+        let conn = self.connection()?;
+        let trans = self.begin(&conn, ctx)?;
+        let data = plan.execute(Some(&trans), validators)?;
+        trans.commit().map_err(Error::from)?;
+        Ok(data)
+    }
+
+    // get a connection from the pool, an exhausted pool is reported as an
+    // unavailable service instead of panicking.
+    fn connection(&self) -> Result<r2d2::PooledConnection<PostgresConnectionManager>, Error> {
+        match self.conn.get() {
+            Ok(conn) => Ok(conn),
+            Err(e) =>
+                Err(Error::Unavailable(format!("no database connection available, {}", e))),
+        }
+    }
+
+    // open the transaction of a request, the configured settings are set
+    // local to it so they never leak to the other users of the connection.
+    fn begin<'a>(&self, conn: &'a postgres::Connection, ctx: &Context)
+                 -> Result<Transaction<'a>, Error> {
+        let trans = conn.transaction()?;
+        if self.config.read_only() {
+            trans.batch_execute("SET TRANSACTION READ ONLY")?;
+        }
+        if let Some(t) = self.config.statement_timeout() {
+            trans.batch_execute(&*format!("SET LOCAL statement_timeout = {}", t))?;
+        }
+        ctx.apply(&trans)?;
+        Ok(trans)
+    }
+
+    // run f in a transaction where the context of the request is applied,
+    // the transaction is committed only if f succeed.
+    fn in_transaction<F>(&self, ctx: &Context, f: F) -> Result<Option<Value>, Error>
+        where F: FnOnce(&GenericConnection) -> Result<Option<Value>, Error> {
+        let conn = self.connection()?;
+        let trans = self.begin(&conn, ctx)?;
+        let res = f(&trans)?;
+        trans.commit()?;
+        Ok(res)
+    }
+
+    /// The methods a role can use on a table, see `Config::allowed_methods`,
+    /// restricted to the privileges of the database role on the table.
+    pub fn allowed_methods(&self, role: Option<&str>, model: &str) -> Vec<Method> {
+        let allowed = self.config.allowed_methods(role, model);
+        match self.get_tables().get(model) {
+            Some(t) => allowed.into_iter().filter(|m| t.privileges.allows(m)).collect(),
+            None => allowed,
+        }
+    }
+
+    // an unknown table is reported before the method, whatever the method
+    fn method_allowed(&self, ctx: &Context, method: &Method, model: &str) -> Result<(), Error> {
         self.model_exists(model)?;
-        match delete::query(&*(self.conn.get().unwrap()),
-                            self.tables.get(model).unwrap(), queries) {
-            Ok(_) => Ok(None),
-            Err(e) => Err(e),
+        let allowed = self.allowed_methods(ctx.role.as_ref().map(|r| &**r), model);
+        if !allowed.contains(method) {
+            return Err(Error::MethodNotAllowed(method.to_string(), allowed));
         }
+        Ok(())
+    }
+
+    // GET must be allowed on the table and on the tables of the embedded
+    // resources
+    fn get_allowed(&self, ctx: &Context, model: &str, query: &Query) -> Result<(), Error> {
+        self.method_allowed(ctx, &Method::Get, model)?;
+        let mut embedded = query.embedded().iter().collect::<Vec<&Embed>>();
+        while let Some(e) = embedded.pop() {
+            self.method_allowed(ctx, &Method::Get, &*e.table)?;
+            embedded.extend(e.embedded.iter());
+        }
+        Ok(())
+    }
+
+    /// The error of a request on a table with a method arrakis does not
+    /// serve (e.g OPTIONS): the table is unknown, or the method is not one
+    /// of the methods the role of the context can use on it.
+    pub fn unsupported_method(&self, ctx: &Context, method: &str, model: &str) -> Error {
+        if let Err(e) = self.model_exists(model) {
+            return e;
+        }
+        let allowed = self.allowed_methods(ctx.role.as_ref().map(|r| &**r), model);
+        Error::MethodNotAllowed(method.to_string(), allowed)
     }
 
     fn model_exists(&self, model: &str) -> Result<(), Error> {
-        if !self.tables.contains_key(model) {
+        if !self.get_tables().contains_key(model) {
             return Err(Error::UnknowModel(model.into()));
         }
         return Ok(());
     }
 
     pub fn make_doc(&self) -> String {
-        format!("{}\n{}\n{}", dc::DOC_BASE, self.generate_table_doc(), dc::DOC_END)
+        let control = dc::CONTROL_PARAMETERS.replace("PREFIX", self.config.control_prefix());
+        format!("{}\n{}\n{}\n{}", dc::DOC_BASE, control, self.generate_table_doc(), dc::DOC_END)
+    }
+
+    /// The OpenAPI 3 document of the tables and of the methods allowed
+    /// without a role.
+    pub fn make_openapi(&self) -> Value {
+        openapi::document(&*self.get_tables(), &self.config, |t| self.allowed_methods(None, t))
+    }
+
+    /// The JSON Schema of the bodies of a table, the `variant` control
+    /// parameter chooses between the create (default) and update variants.
+    pub fn make_json_schema(&self, model: &str, queries: &Queries) -> Result<Value, Error> {
+        let tables = self.get_tables();
+        let table = tables.get(model).ok_or(Error::UnknowModel(model.into()))?;
+        let variant = match queries.variant(self.config.control_prefix()) {
+            Some(v) => v.parse::<Variant>()?,
+            None => Variant::Create,
+        };
+        Ok(json_schema::table_schema(table, variant))
     }
 
     fn generate_table_doc(&self) -> String {
         let mut s = format!("<h2>Availables types</h2>");
-        for (k, v) in &self.tables {
-            let trs = v.columns.iter().map(|(_, c)| {
-                format!("    <tr><td>{}</td><td>{}</td><td>{}</td></tr>",
-                        c.name, c.data_type, cvt::postgres_to_json_type(&c.data_type))
-            }).collect::<Vec<String>>().join("\n");
-            s = format!("{}\n<h3>/api/{}</h3>\n{}{}{}",
-                        s, k, dc::TABLE_HEADER, trs, dc::TABLE_FOOTER);
+        for (k, v) in self.get_tables().iter() {
+            let trs = v.columns.iter()
+                .filter(|&(_, c)| c.visibility != Visibility::Hidden)
+                .map(|(_, c)| {
+                    format!("    <tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                            c.name, c.data_type, cvt::postgres_to_json_type(&c.data_type),
+                            c.visibility)
+                }).collect::<Vec<String>>().join("\n");
+            let methods = self.allowed_methods(None, k).iter()
+                .map(|m| m.to_string())
+                .collect::<Vec<String>>()
+                .join(", ");
+            let comment = v.comment.as_ref()
+                .map_or(String::new(), |c| format!("<p>{}</p>\n", c));
+            s = format!("{}\n<h3>/api/{}</h3>\n{}<p>Allowed methods: {}</p>\n{}{}{}",
+                        s, k, comment, methods, dc::TABLE_HEADER, trs, dc::TABLE_FOOTER);
         }
         return s;
     }
 }
 
-fn read_json(s: &str) -> Result<Value, Error> {
-    match serde_json::from_str(s) {
-        Ok(v) => Ok(v),
-        Err(e) => Err(Error::InvalidInputError(format!("{}", e))),
+// introspect the schema and apply the configuration to it
+fn load_tables(conn: &postgres::Connection, config: &Config)
+               -> Result<HashMap<String, Table>, String> {
+    // with authentication the requests are executed as the role of the
+    // token, the privileges of the login role do not apply to them (see the
+    // limitation documented on `AuthConfig`).
+    let check_privileges = config.auth().is_none();
+    let mut tables = infer_schema(conn, config.schemas(), config.included(),
+                                  config.excluded(), check_privileges)?;
+    apply_column_visibilities(&mut tables, config)?;
+    Ok(tables)
+}
+
+// set the visibility of the columns listed in the configuration
+fn apply_column_visibilities(tables: &mut HashMap<String, Table>, config: &Config)
+                             -> Result<(), String> {
+    for table in config.column_visibility_tables() {
+        // the table may have been excluded
+        let t = match tables.get_mut(table) {
+            Some(t) => t,
+            None => continue,
+        };
+        for (column, visibility) in config.column_visibilities(table) {
+            match t.columns.get_mut(column) {
+                Some(c) => c.visibility = c.visibility.restrict(visibility),
+                None => return Err(format!("column {}.{} in configuration does not exist",
+                                           table, column)),
+            }
+        }
+    }
+    Ok(())
+}
+
+// the body of the methods which take one
+fn read_body(method: &Method, format: Format, body: &[u8]) -> Result<Value, Error> {
+    match *method {
+        Method::Post | Method::Put | Method::Patch => format.read_value(body),
+        Method::Get | Method::Delete => Ok(Value::Null),
     }
 }
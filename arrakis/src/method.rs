@@ -5,7 +5,10 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-#[derive(Debug)]
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Method {
     Get,
     Post,
@@ -13,3 +16,45 @@ pub enum Method {
     Patch,
     Delete,
 }
+
+const GET: &'static str = "GET";
+const POST: &'static str = "POST";
+const PUT: &'static str = "PUT";
+const PATCH: &'static str = "PATCH";
+const DELETE: &'static str = "DELETE";
+
+impl Method {
+    /// Every method supported by arrakis.
+    pub fn all() -> Vec<Method> {
+        vec![Method::Get, Method::Post, Method::Put, Method::Patch, Method::Delete]
+    }
+}
+
+impl FromStr for Method {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::Method::*;
+        match &*s.to_uppercase() {
+            GET => Ok(Get),
+            POST => Ok(Post),
+            PUT => Ok(Put),
+            PATCH => Ok(Patch),
+            DELETE => Ok(Delete),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        use self::Method::*;
+        let s = match *self {
+            Get => GET,
+            Post => POST,
+            Put => PUT,
+            Patch => PATCH,
+            Delete => DELETE,
+        };
+        write!(fmt, "{}", s)
+    }
+}
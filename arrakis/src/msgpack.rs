@@ -0,0 +1,456 @@
+// Copyright 2016 Jeremy Letang.
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! MessagePack encoding of the responses and decoding of the request bodies.
+//!
+//! Bytes are written as bin and timestamps with the timestamp extension
+//! type. In request bodies, bin values are read as hex strings, which
+//! postgres converts to the types of the columns, and timestamps as unix
+//! timestamps in seconds like in the json bodies. The bodies are validated
+//! against the JSON Schema of the table, which only accepts whole seconds.
+
+use cvt::{self, Cell};
+use error::Error;
+use serde_json::{Map, Value};
+use std::io::{self, Write};
+use schema::Column;
+use std::mem;
+use stream::RowWriter;
+
+const TIMESTAMP_EXT: i8 = -1;
+
+// maximum nesting of arrays and maps in request bodies
+const MAX_DEPTH: usize = 64;
+
+/// Write the rows as the MessagePack encoding of the json response, i.e
+/// `{"data": [{...}, ...]}`.
+///
+/// MessagePack arrays start with their length and the response is streamed,
+/// so the length cannot be written afterwards: the encoded rows are held in
+/// memory until the last one is read, and the response is only sent then.
+/// The memory used by a request grows with its result, which `max_limit`
+/// bounds, cbor should be preferred for large results.
+pub struct MsgpackRowWriter<W> {
+    out: W,
+    columns: Vec<String>,
+    rows: Vec<u8>,
+    count: usize,
+}
+
+impl<W: Write> MsgpackRowWriter<W> {
+    pub fn new(out: W) -> MsgpackRowWriter<W> {
+        MsgpackRowWriter {
+            out: out,
+            columns: vec![],
+            rows: vec![],
+            count: 0,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.out
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> RowWriter for MsgpackRowWriter<W> {
+    fn begin(&mut self, columns: &[&Column]) -> io::Result<()> {
+        self.columns = columns.iter().map(|c| c.name.clone()).collect();
+        Ok(())
+    }
+
+    fn row(&mut self, values: Vec<Cell>) -> io::Result<()> {
+        write_len(&mut self.rows, self.columns.len(), 0x80, 0xde)?;
+        for (c, v) in self.columns.iter().zip(values.iter()) {
+            write_str(&mut self.rows, c)?;
+            write_cell(&mut self.rows, v)?;
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        write_len(&mut self.out, 1, 0x80, 0xde)?;
+        write_str(&mut self.out, "data")?;
+        write_len(&mut self.out, self.count, 0x90, 0xdc)?;
+        self.out.write_all(&*self.rows)?;
+        self.out.flush()
+    }
+}
+
+pub fn write_cell<W: Write>(out: &mut W, cell: &Cell) -> io::Result<()> {
+    match *cell {
+        Cell::Null => out.write_all(&[0xc0]),
+        Cell::Bool(b) => out.write_all(&[if b { 0xc3 } else { 0xc2 }]),
+        Cell::Int(i) => write_int(out, i),
+        Cell::Float(f) => {
+            out.write_all(&[0xcb])?;
+            out.write_all(&be_bytes(unsafe { mem::transmute::<f64, u64>(f) }, 8))
+        },
+        Cell::Text(ref s) => write_str(out, s),
+        Cell::Bytes(ref b) => {
+            match b.len() {
+                n if n <= 0xff => out.write_all(&[0xc4, n as u8])?,
+                n if n <= 0xffff => {
+                    out.write_all(&[0xc5])?;
+                    out.write_all(&be_bytes(n as u64, 2))?;
+                },
+                n => {
+                    out.write_all(&[0xc6])?;
+                    out.write_all(&be_bytes(n as u64, 4))?;
+                },
+            }
+            out.write_all(b)
+        },
+        Cell::Timestamp(t) => {
+            // timestamp 96: nanoseconds then seconds
+            let (secs, nanos) = cvt::split_micros(t);
+            out.write_all(&[0xc7, 12, TIMESTAMP_EXT as u8])?;
+            out.write_all(&be_bytes(nanos as u64, 4))?;
+            out.write_all(&be_bytes(secs as u64, 8))
+        },
+        Cell::Json(ref v) => write_value(out, v),
+    }
+}
+
+/// Write a json value, e.g the response of a POST request.
+pub fn write_value<W: Write>(out: &mut W, v: &Value) -> io::Result<()> {
+    match *v {
+        Value::Null => out.write_all(&[0xc0]),
+        Value::Bool(b) => out.write_all(&[if b { 0xc3 } else { 0xc2 }]),
+        Value::I64(i) => write_int(out, i),
+        Value::U64(u) if u > i64::max_value() as u64 => {
+            out.write_all(&[0xcf])?;
+            out.write_all(&be_bytes(u, 8))
+        },
+        Value::U64(u) => write_int(out, u as i64),
+        Value::F64(f) => write_cell(out, &Cell::Float(f)),
+        Value::String(ref s) => write_str(out, s),
+        Value::Array(ref values) => {
+            write_len(out, values.len(), 0x90, 0xdc)?;
+            for v in values {
+                write_value(out, v)?;
+            }
+            Ok(())
+        },
+        Value::Object(ref map) => {
+            write_len(out, map.len(), 0x80, 0xde)?;
+            for (k, v) in map {
+                write_str(out, k)?;
+                write_value(out, v)?;
+            }
+            Ok(())
+        },
+    }
+}
+
+fn write_int<W: Write>(out: &mut W, i: i64) -> io::Result<()> {
+    match i {
+        0...127 => out.write_all(&[i as u8]),
+        -32...-1 => out.write_all(&[i as u8]),
+        i if i > 0 && i <= 0xff => out.write_all(&[0xcc, i as u8]),
+        i if i > 0 && i <= 0xffff => {
+            out.write_all(&[0xcd])?;
+            out.write_all(&be_bytes(i as u64, 2))
+        },
+        i if i > 0 && i <= 0xffff_ffff => {
+            out.write_all(&[0xce])?;
+            out.write_all(&be_bytes(i as u64, 4))
+        },
+        i if i > 0 => {
+            out.write_all(&[0xcf])?;
+            out.write_all(&be_bytes(i as u64, 8))
+        },
+        i if i >= -0x80 => out.write_all(&[0xd0, i as u8]),
+        i if i >= -0x8000 => {
+            out.write_all(&[0xd1])?;
+            out.write_all(&be_bytes(i as u64, 2))
+        },
+        i if i >= -0x8000_0000 => {
+            out.write_all(&[0xd2])?;
+            out.write_all(&be_bytes(i as u64, 4))
+        },
+        i => {
+            out.write_all(&[0xd3])?;
+            out.write_all(&be_bytes(i as u64, 8))
+        },
+    }
+}
+
+fn write_str<W: Write>(out: &mut W, s: &str) -> io::Result<()> {
+    match s.len() {
+        n if n < 32 => out.write_all(&[0xa0 | n as u8])?,
+        n if n <= 0xff => out.write_all(&[0xd9, n as u8])?,
+        n if n <= 0xffff => {
+            out.write_all(&[0xda])?;
+            out.write_all(&be_bytes(n as u64, 2))?;
+        },
+        n => {
+            out.write_all(&[0xdb])?;
+            out.write_all(&be_bytes(n as u64, 4))?;
+        },
+    }
+    out.write_all(s.as_bytes())
+}
+
+// header of a map or an array, `fix` is the marker of the short form and
+// `marker` the one of the 16 bits length, followed by the 32 bits one
+fn write_len<W: Write>(out: &mut W, len: usize, fix: u8, marker: u8) -> io::Result<()> {
+    match len {
+        n if n < 16 => out.write_all(&[fix | n as u8]),
+        n if n <= 0xffff => {
+            out.write_all(&[marker])?;
+            out.write_all(&be_bytes(n as u64, 2))
+        },
+        n => {
+            out.write_all(&[marker + 1])?;
+            out.write_all(&be_bytes(n as u64, 4))
+        },
+    }
+}
+
+// the `n` low bytes of v, big endian
+fn be_bytes(v: u64, n: usize) -> Vec<u8> {
+    (0..n).rev().map(|i| (v >> (i * 8)) as u8).collect()
+}
+
+/// Read a request body as a json value.
+pub fn decode(body: &[u8]) -> Result<Value, Error> {
+    let mut reader = Reader { buf: body, pos: 0 };
+    let value = reader.value(0)?;
+    if reader.pos != body.len() {
+        return Err(invalid("trailing bytes after the value"));
+    }
+    Ok(value)
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.buf.len() - self.pos < n {
+            return Err(invalid("unexpected end of body"));
+        }
+        let b = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(b)
+    }
+
+    fn uint(&mut self, n: usize) -> Result<u64, Error> {
+        Ok(self.bytes(n)?.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64))
+    }
+
+    // a signed integer of n bytes
+    fn int(&mut self, n: usize) -> Result<i64, Error> {
+        let shift = 64 - n * 8;
+        Ok(((self.uint(n)? << shift) as i64) >> shift)
+    }
+
+    fn str(&mut self, n: usize) -> Result<String, Error> {
+        match String::from_utf8(self.bytes(n)?.to_vec()) {
+            Ok(s) => Ok(s),
+            Err(_) => Err(invalid("invalid utf-8 string")),
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, Error> {
+        if depth > MAX_DEPTH {
+            return Err(invalid("too many nested values"));
+        }
+        let marker = self.bytes(1)?[0];
+        let v = match marker {
+            0x00...0x7f => Value::U64(marker as u64),
+            0x80...0x8f => self.map((marker & 0x0f) as usize, depth)?,
+            0x90...0x9f => self.array((marker & 0x0f) as usize, depth)?,
+            0xa0...0xbf => Value::String(self.str((marker & 0x1f) as usize)?),
+            0xc0 => Value::Null,
+            0xc2 => Value::Bool(false),
+            0xc3 => Value::Bool(true),
+            0xc4...0xc6 => {
+                let n = self.uint(1 << (marker - 0xc4))? as usize;
+                Value::String(cvt::bytes_to_hex(self.bytes(n)?))
+            },
+            0xc7...0xc9 => {
+                let n = self.uint(1 << (marker - 0xc7))? as usize;
+                self.ext(n)?
+            },
+            0xca => {
+                let bits = self.uint(4)? as u32;
+                Value::F64(unsafe { mem::transmute::<u32, f32>(bits) } as f64)
+            },
+            0xcb => Value::F64(unsafe { mem::transmute::<u64, f64>(self.uint(8)?) }),
+            0xcc...0xcf => Value::U64(self.uint(1 << (marker - 0xcc))?),
+            0xd0...0xd3 => Value::I64(self.int(1 << (marker - 0xd0))?),
+            0xd4...0xd8 => self.ext(1 << (marker - 0xd4))?,
+            0xd9...0xdb => {
+                let n = self.uint(1 << (marker - 0xd9))? as usize;
+                Value::String(self.str(n)?)
+            },
+            0xdc | 0xdd => {
+                let n = self.uint(2 << (marker - 0xdc))? as usize;
+                self.array(n, depth)?
+            },
+            0xde | 0xdf => {
+                let n = self.uint(2 << (marker - 0xde))? as usize;
+                self.map(n, depth)?
+            },
+            0xe0...0xff => Value::I64(marker as i8 as i64),
+            _ => return Err(invalid("unknown marker")),
+        };
+        Ok(v)
+    }
+
+    fn array(&mut self, n: usize, depth: usize) -> Result<Value, Error> {
+        let mut arr = vec![];
+        for _ in 0..n {
+            arr.push(self.value(depth + 1)?);
+        }
+        Ok(Value::Array(arr))
+    }
+
+    fn map(&mut self, n: usize, depth: usize) -> Result<Value, Error> {
+        let mut map = Map::new();
+        for _ in 0..n {
+            let key = match self.value(depth + 1)? {
+                Value::String(s) => s,
+                _ => return Err(invalid("map keys must be strings")),
+            };
+            let value = self.value(depth + 1)?;
+            map.insert(key, value);
+        }
+        Ok(Value::Object(map))
+    }
+
+    // only the timestamp extension is supported
+    fn ext(&mut self, n: usize) -> Result<Value, Error> {
+        let ty = self.int(1)? as i8;
+        if ty != TIMESTAMP_EXT {
+            return Err(invalid("unsupported extension type"));
+        }
+        let (secs, nanos) = match n {
+            4 => (self.uint(4)? as i64, 0),
+            8 => {
+                let v = self.uint(8)?;
+                ((v & 0x3_ffff_ffff) as i64, (v >> 34) as u32)
+            },
+            12 => {
+                let nanos = self.uint(4)? as u32;
+                (self.int(8)?, nanos)
+            },
+            _ => return Err(invalid("invalid timestamp")),
+        };
+        Ok(cvt::timestamp_value(secs, nanos))
+    }
+}
+
+fn invalid(s: &str) -> Error {
+    Error::InvalidInputError(format!("invalid msgpack body, {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(v: &Value) -> Vec<u8> {
+        let mut out = vec![];
+        write_value(&mut out, v).unwrap();
+        out
+    }
+
+    fn encode_cell(c: &Cell) -> Vec<u8> {
+        let mut out = vec![];
+        write_cell(&mut out, c).unwrap();
+        out
+    }
+
+    #[test]
+    fn integer_boundaries() {
+        let unsigned = [(0, vec![0x00]), (127, vec![0x7f]), (128, vec![0xcc, 0x80]),
+                        (255, vec![0xcc, 0xff]), (256, vec![0xcd, 0x01, 0x00]),
+                        (65535, vec![0xcd, 0xff, 0xff]),
+                        (65536, vec![0xce, 0x00, 0x01, 0x00, 0x00]),
+                        (0xffff_ffff, vec![0xce, 0xff, 0xff, 0xff, 0xff]),
+                        (0x1_0000_0000, vec![0xcf, 0, 0, 0, 0x01, 0, 0, 0, 0]),
+                        (u64::max_value(), vec![0xcf, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+                                                0xff])];
+        for &(n, ref bytes) in &unsigned {
+            assert_eq!(&encode(&Value::U64(n)), bytes, "{}", n);
+            assert_eq!(decode(bytes).unwrap(), Value::U64(n));
+        }
+        let signed = [(-1, vec![0xff]), (-32, vec![0xe0]), (-33, vec![0xd0, 0xdf]),
+                      (-128, vec![0xd0, 0x80]), (-129, vec![0xd1, 0xff, 0x7f]),
+                      (-32768, vec![0xd1, 0x80, 0x00]),
+                      (-32769, vec![0xd2, 0xff, 0xff, 0x7f, 0xff]),
+                      (-0x8000_0000, vec![0xd2, 0x80, 0, 0, 0]),
+                      (-0x8000_0001, vec![0xd3, 0xff, 0xff, 0xff, 0xff, 0x7f, 0xff, 0xff, 0xff]),
+                      (i64::min_value(), vec![0xd3, 0x80, 0, 0, 0, 0, 0, 0, 0])];
+        for &(n, ref bytes) in &signed {
+            assert_eq!(&encode(&Value::I64(n)), bytes, "{}", n);
+            assert_eq!(decode(bytes).unwrap(), Value::I64(n));
+        }
+        // positive values are read as unsigned
+        assert_eq!(decode(&encode(&Value::I64(i64::max_value()))).unwrap(),
+                   Value::U64(i64::max_value() as u64));
+        // the signed markers are read as signed values
+        assert_eq!(decode(&[0xd0, 0x05]).unwrap(), Value::I64(5));
+    }
+
+    #[test]
+    fn floats() {
+        assert_eq!(encode(&Value::F64(1.5)), vec![0xcb, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(decode(&[0xcb, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0]).unwrap(), Value::F64(1.5));
+        assert_eq!(decode(&[0xca, 0xc0, 0x20, 0x00, 0x00]).unwrap(), Value::F64(-2.5));
+    }
+
+    #[test]
+    fn timestamps() {
+        let t = Cell::Timestamp(1_500_000_000_123_456);
+        let bytes = encode_cell(&t);
+        assert_eq!(&bytes[..3], &[0xc7, 12, 0xff]);
+        assert_eq!(decode(&*bytes).unwrap(), Value::F64(1_500_000_000.123456));
+        // before the epoch
+        let bytes = encode_cell(&Cell::Timestamp(-1_500_000));
+        assert_eq!(decode(&*bytes).unwrap(), Value::F64(-1.5));
+        // timestamp 32, seconds only
+        assert_eq!(decode(&[0xd6, 0xff, 0x59, 0x68, 0x2f, 0x00]).unwrap(),
+                   Value::I64(1_500_000_000));
+        // timestamp 64, 30 bits of nanoseconds and 34 bits of seconds
+        let v: u64 = (123_456_000 << 34) | 1_500_000_000;
+        let mut bytes = vec![0xd7, 0xff];
+        bytes.extend(be_bytes(v, 8));
+        assert_eq!(decode(&*bytes).unwrap(), Value::F64(1_500_000_000.123456));
+        assert!(decode(&[0xd4, 0x01, 0x00]).is_err());
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let long = "x".repeat(40);
+        let v: Value = ::serde_json::from_str(&*format!(
+            r#"{{"a":[1,-2,"x",null,true,false,{{"b":2.25}}],"long":"{}"}}"#, long)).unwrap();
+        assert_eq!(decode(&*encode(&v)).unwrap(), v);
+        assert_eq!(decode(&*encode_cell(&Cell::Bytes(vec![1, 0xab]))).unwrap(),
+                   Value::String("\\x01ab".into()));
+        assert_eq!(decode(&*encode_cell(&Cell::Json(v.clone()))).unwrap(), v);
+    }
+
+    #[test]
+    fn invalid_bodies() {
+        assert!(decode(&[]).is_err());
+        assert!(decode(&[0x92, 0x01]).is_err());
+        assert!(decode(&[0x01, 0x02]).is_err());
+        assert!(decode(&[0x81, 0x01, 0x01]).is_err());
+        assert!(decode(&[0xa2, 0xff, 0xfe]).is_err());
+        assert!(decode(&[0xc1]).is_err());
+        assert!(decode(&vec![0x91; MAX_DEPTH + 2]).is_err());
+    }
+}
@@ -0,0 +1,490 @@
+// Copyright 2016 Jeremy Letang.
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! OpenAPI 3 document of the api, generated from the inferred schema.
+
+use config::Config;
+use error::PROBLEM_CONTENT_TYPE;
+use format::Format;
+use method::Method;
+use postgres::types::Type;
+use queries::{self, DELIMITER, FORMAT, LIMIT, NULL, OFFSET, ON_CONFLICT, ORDER, SELECT};
+use schema::{Column, Table, Visibility};
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+
+const OPENAPI_VERSION: &'static str = "3.0.3";
+
+// the status codes of the error responses shared by every operation
+const ERROR_STATUSES: [u16; 7] = [400, 401, 403, 405, 500, 503, 504];
+
+// key of the schema of the error bodies
+const PROBLEM: &'static str = "Problem";
+
+const FORMATS: [Format; 6] =
+    [Format::Json, Format::Csv, Format::Ndjson, Format::Msgpack, Format::Cbor, Format::Arrow];
+
+/// Build the document of the tables, with the operations allowed by
+/// `allowed_methods` for each table.
+pub fn document<F>(tables: &HashMap<String, Table>, config: &Config, allowed_methods: F)
+                   -> Value
+    where F: Fn(&str) -> Vec<Method> {
+    let mut names = tables.keys().collect::<Vec<&String>>();
+    names.sort();
+
+    let mut paths = Map::new();
+    let mut schemas = Map::new();
+    let mut keys = HashSet::new();
+    keys.insert(PROBLEM.to_string());
+    for name in names {
+        let table = tables.get(name).unwrap();
+        let methods = allowed_methods(name);
+        if methods.is_empty() {
+            continue;
+        }
+        let key = component_key(name, &mut keys);
+        schemas.insert(key.clone(), table_schema(table));
+        paths.insert(format!("/api/{}", name), path_item(name, &*key, table, &*methods, config));
+    }
+    schemas.insert(PROBLEM.into(), problem_schema());
+
+    let mut components = Map::new();
+    components.insert("schemas".into(), Value::Object(schemas));
+    components.insert("parameters".into(), control_parameters(config));
+    components.insert("responses".into(), object(vec![
+        ("Error", object(vec![
+            ("description", string("the request failed, see the code of the problem")),
+            ("content", object(vec![
+                (PROBLEM_CONTENT_TYPE, object(vec![
+                    ("schema", reference("schemas", PROBLEM)),
+                ])),
+            ])),
+        ])),
+    ]));
+
+    let mut doc = Map::new();
+    doc.insert("openapi".into(), string(OPENAPI_VERSION));
+    doc.insert("info".into(), object(vec![
+        ("title", string("arrakis")),
+        ("version", string(env!("CARGO_PKG_VERSION"))),
+    ]));
+    doc.insert("paths".into(), Value::Object(paths));
+    if config.auth().is_some() {
+        components.insert("securitySchemes".into(), object(vec![
+            ("bearer", object(vec![
+                ("type", string("http")),
+                ("scheme", string("bearer")),
+                ("bearerFormat", string("JWT")),
+            ])),
+        ]));
+        doc.insert("security".into(),
+                   Value::Array(vec![object(vec![("bearer", Value::Array(vec![]))])]));
+    }
+    doc.insert("components".into(), Value::Object(components));
+    Value::Object(doc)
+}
+
+// the operations of a table, `key` is the key of its schema in the
+// components. PUT is not implemented, it is never documented.
+fn path_item(name: &str, key: &str, table: &Table, methods: &[Method], config: &Config)
+             -> Value {
+    let prefix = config.control_prefix();
+    let filters = filter_parameters(table, prefix);
+    let mut item = Map::new();
+    for m in methods.iter().filter(|m| **m != Method::Put) {
+        let mut op = Map::new();
+        let id = format!("{}_{}", m.to_string().to_lowercase(), name);
+        op.insert("operationId".into(), string(&*id));
+        if let Some(ref c) = table.comment {
+            op.insert("description".into(), string(c));
+        }
+        let mut params = match *m {
+            Method::Get => vec![SELECT, LIMIT, OFFSET, ORDER, FORMAT],
+            Method::Post => vec![DELIMITER, NULL, ON_CONFLICT],
+            _ => vec![],
+        }.into_iter().map(|p| reference("parameters", p)).collect::<Vec<Value>>();
+        if *m != Method::Post {
+            params.extend(filters.iter().cloned());
+        }
+        op.insert("parameters".into(), Value::Array(params));
+        match *m {
+            Method::Post => {
+                op.insert("requestBody".into(), insert_body(key, table));
+            },
+            Method::Patch => {
+                op.insert("requestBody".into(), request_body(reference("schemas", key)));
+            },
+            _ => {},
+        }
+        let success = match *m {
+            Method::Get => ("200", rows_response(key)),
+            Method::Post => ("200", data_response("the ids of the inserted rows, or the number \
+                                                   of loaded rows of a csv body",
+                                                  Value::Object(Map::new()))),
+            Method::Delete =>
+                ("204", object(vec![("description", string("the rows were deleted"))])),
+            _ => ("200", data_response("the ids of the updated rows", object(vec![
+                ("type", string("array")),
+                ("items", Value::Object(Map::new())),
+            ]))),
+        };
+        let mut responses = Map::new();
+        responses.insert(success.0.into(), success.1);
+        for s in ERROR_STATUSES.iter() {
+            responses.insert(s.to_string(), reference("responses", "Error"));
+        }
+        op.insert("responses".into(), Value::Object(responses));
+        item.insert(m.to_string().to_lowercase(), Value::Object(op));
+    }
+    Value::Object(item)
+}
+
+// a filter parameter for each readable column, named after the column
+fn filter_parameters(table: &Table, prefix: &str) -> Vec<Value> {
+    table.columns.iter()
+        .filter(|&(_, c)| c.visibility.is_readable())
+        .map(|(name, c)| {
+            let param = if name.starts_with('"') ||
+                queries::CONTROLS.iter().any(|ctl| queries::is_control(name, prefix, ctl)) {
+                queries::quote_param(name)
+            } else {
+                name.clone()
+            };
+            let mut operators = vec!["eq", "ne", "gt", "gte", "lt", "lte", "in", "notin", "is",
+                                     "isnot"];
+            if is_text(&c.data_type) {
+                operators.extend_from_slice(&["like", "ilike"]);
+            }
+            let description = format!("filter on {}, one of the operators {} followed by a dot \
+                                       and the value, e.g eq.42, prefixed by not. to negate it",
+                                      name, operators.join(", "));
+            object(vec![
+                ("name", string(&*param)),
+                ("in", string("query")),
+                ("description", string(&*description)),
+                ("schema", object(vec![("type", string("string"))])),
+            ])
+        })
+        .collect()
+}
+
+fn control_parameters(config: &Config) -> Value {
+    let prefix = config.control_prefix();
+    let formats = FORMATS.iter().map(|f| string(&*f.to_string())).collect();
+    let mut limit = vec![("type", string("integer")), ("minimum", Value::U64(0))];
+    if let Some(max) = config.max_limit() {
+        limit.push(("maximum", Value::U64(max as u64)));
+    }
+    let params = vec![
+        (SELECT, "comma separated columns to return", object(vec![("type", string("string"))])),
+        (LIMIT, "maximum number of rows to return", object(limit)),
+        (OFFSET, "number of rows to skip", object(vec![
+            ("type", string("integer")),
+            ("minimum", Value::U64(0)),
+        ])),
+        (ORDER, "comma separated columns to sort by, each followed by .asc or .desc",
+         object(vec![("type", string("string"))])),
+        (FORMAT, "format of the response, takes precedence over the Accept header",
+         object(vec![("type", string("string")), ("enum", Value::Array(formats))])),
+        (DELIMITER, "delimiter of the fields of a csv body", object(vec![
+            ("type", string("string")),
+            ("minLength", Value::U64(1)),
+            ("maxLength", Value::U64(1)),
+        ])),
+        (NULL, "string of the null values of a csv body", object(vec![("type", string("string"))])),
+        (ON_CONFLICT, "what to do with the rows of a csv body conflicting on the primary key",
+         object(vec![
+             ("type", string("string")),
+             ("enum", Value::Array(vec![string("update"), string("nothing")])),
+         ])),
+    ];
+    let mut m = Map::new();
+    for (name, description, schema) in params {
+        m.insert(name.into(), object(vec![
+            ("name", string(&*format!("{}{}", prefix, name))),
+            ("in", string("query")),
+            ("description", string(description)),
+            ("schema", schema),
+        ]));
+    }
+    Value::Object(m)
+}
+
+// the columns exposed by the api, the columns which cannot be set by the
+// clients are read only and the ones never returned write only
+fn table_schema(table: &Table) -> Value {
+    let mut properties = Map::new();
+    for (name, c) in table.columns.iter().filter(|&(_, c)| c.visibility != Visibility::Hidden) {
+        properties.insert(name.clone(), column_schema(c));
+    }
+    let mut m = Map::new();
+    m.insert("type".into(), string("object"));
+    if let Some(ref c) = table.comment {
+        m.insert("description".into(), string(c));
+    }
+    m.insert("properties".into(), Value::Object(properties));
+    m.insert("additionalProperties".into(), Value::Bool(false));
+    Value::Object(m)
+}
+
+fn column_schema(c: &Column) -> Value {
+    let (ty, format) = match c.data_type {
+        Type::Bool => ("boolean", None),
+        Type::Char | Type::Int2 | Type::Int4 => ("integer", Some("int32")),
+        Type::Int8 => ("integer", Some("int64")),
+        Type::Float4 => ("number", Some("float")),
+        Type::Float8 => ("number", Some("double")),
+        // seconds since the epoch
+        Type::Timestamp | Type::TimestampTZ => ("integer", Some("int64")),
+        _ => ("string", None),
+    };
+    let mut m = Map::new();
+    m.insert("type".into(), string(ty));
+    if let Some(f) = format {
+        m.insert("format".into(), string(f));
+    }
+    if c.data_type == Type::Bytea {
+        m.insert("pattern".into(), string("^\\\\x([0-9a-fA-F]{2})*$"));
+    }
+    if let Some(l) = c.character_maximum_length {
+        m.insert("maxLength".into(), Value::I64(l as i64));
+    }
+    if c.is_nullable {
+        m.insert("nullable".into(), Value::Bool(true));
+    }
+    match c.visibility {
+        Visibility::ReadOnly => { m.insert("readOnly".into(), Value::Bool(true)); },
+        Visibility::WriteOnly => { m.insert("writeOnly".into(), Value::Bool(true)); },
+        _ => {},
+    }
+    if let Some(ref comment) = c.comment {
+        m.insert("description".into(), string(comment));
+    }
+    if c.is_primary_key {
+        m.insert("x-primary-key".into(), Value::Bool(true));
+    }
+    if let Some(ref fk) = c.foreign_key {
+        m.insert("x-foreign-key".into(), string(&*format!("{}.{}", fk.table, fk.column)));
+    }
+    Value::Object(m)
+}
+
+// the row of a POST request, the columns without a default which cannot be
+// null are required
+fn insert_body(key: &str, table: &Table) -> Value {
+    let required = table.columns.iter()
+        .filter(|&(_, c)| c.can_insert() && !c.is_nullable && c.default.is_none())
+        .map(|(name, _)| string(name))
+        .collect::<Vec<Value>>();
+    let row = if required.is_empty() {
+        reference("schemas", key)
+    } else {
+        object(vec![("allOf", Value::Array(vec![
+            reference("schemas", key),
+            object(vec![("required", Value::Array(required))]),
+        ]))])
+    };
+    let mut body = request_body(row);
+    if let Value::Object(ref mut b) = body {
+        if let Some(&mut Value::Object(ref mut content)) = b.get_mut("content") {
+            let text = object(vec![("schema", object(vec![("type", string("string"))]))]);
+            content.insert(Format::Csv.content_type().into(), text.clone());
+            content.insert(Format::Ndjson.content_type().into(), text);
+        }
+    }
+    body
+}
+
+fn request_body(schema: Value) -> Value {
+    let mut content = Map::new();
+    for f in &[Format::Json, Format::Msgpack, Format::Cbor] {
+        content.insert(f.content_type().into(), object(vec![("schema", schema.clone())]));
+    }
+    object(vec![
+        ("required", Value::Bool(true)),
+        ("content", Value::Object(content)),
+    ])
+}
+
+fn rows_response(key: &str) -> Value {
+    let rows = object(vec![
+        ("type", string("array")),
+        ("items", reference("schemas", key)),
+    ]);
+    let mut response = data_response("the selected rows", rows);
+    if let Value::Object(ref mut r) = response {
+        if let Some(&mut Value::Object(ref mut content)) = r.get_mut("content") {
+            for f in FORMATS.iter().filter(|f| **f != Format::Json) {
+                let binary = object(vec![("schema", object(vec![
+                    ("type", string("string")),
+                    ("format", string("binary")),
+                ]))]);
+                content.insert(f.content_type().into(), binary);
+            }
+        }
+    }
+    response
+}
+
+// a json response with the value in the data envelope
+fn data_response(description: &str, data: Value) -> Value {
+    object(vec![
+        ("description", string(description)),
+        ("content", object(vec![
+            (Format::Json.content_type(), object(vec![
+                ("schema", object(vec![
+                    ("type", string("object")),
+                    ("properties", object(vec![("data", data)])),
+                ])),
+            ])),
+        ])),
+    ])
+}
+
+// the body of the errors, see `Error::to_problem`
+fn problem_schema() -> Value {
+    let s = || object(vec![("type", string("string"))]);
+    object(vec![
+        ("type", string("object")),
+        ("properties", object(vec![
+            ("type", s()),
+            ("title", s()),
+            ("status", object(vec![("type", string("integer"))])),
+            ("detail", s()),
+            ("code", s()),
+            ("table", s()),
+            ("column", s()),
+            ("filter", s()),
+            ("hint", s()),
+            ("lines", object(vec![
+                ("type", string("array")),
+                ("items", object(vec![
+                    ("type", string("object")),
+                    ("properties", object(vec![
+                        ("line", object(vec![("type", string("integer"))])),
+                        ("detail", s()),
+                    ])),
+                ])),
+            ])),
+            ("errors", object(vec![
+                ("type", string("array")),
+                ("items", object(vec![
+                    ("type", string("object")),
+                    ("properties", object(vec![
+                        ("pointer", s()),
+                        ("detail", s()),
+                    ])),
+                ])),
+            ])),
+        ])),
+        ("required", Value::Array(vec![string("type"), string("title"), string("status"),
+                                       string("detail"), string("code")])),
+    ])
+}
+
+fn is_text(ty: &Type) -> bool {
+    match *ty {
+        Type::Bool | Type::Char | Type::Int2 | Type::Int4 | Type::Int8 | Type::Float4 |
+        Type::Float8 | Type::Bytea | Type::Timestamp | Type::TimestampTZ => false,
+        _ => true,
+    }
+}
+
+// the key of a table in the components, the keys must match
+// ^[a-zA-Z0-9._-]+$ so the other characters are replaced, and a suffix keeps
+// the keys unique
+fn component_key(name: &str, used: &mut HashSet<String>) -> String {
+    let base = name.chars()
+        .map(|c| match c {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '.' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+    let mut key = base.clone();
+    let mut n = 1;
+    while used.contains(&key) {
+        n += 1;
+        key = format!("{}_{}", base, n);
+    }
+    used.insert(key.clone());
+    key
+}
+
+fn reference(kind: &str, name: &str) -> Value {
+    // json pointer escaping of the name
+    let name = name.replace('~', "~0").replace('/', "~1");
+    object(vec![("$ref", string(&*format!("#/components/{}/{}", kind, name)))])
+}
+
+fn object(fields: Vec<(&str, Value)>) -> Value {
+    let mut m = Map::new();
+    for (k, v) in fields {
+        m.insert(k.to_string(), v);
+    }
+    Value::Object(m)
+}
+
+fn string(s: &str) -> Value {
+    Value::String(s.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(name: &str) -> Table {
+        let mut id = Column::new("id", Type::Int4);
+        id.is_nullable = false;
+        id.is_primary_key = true;
+        Table::new("public", name, vec![id])
+    }
+
+    fn document_of(names: &[&str]) -> Value {
+        let tables = names.iter()
+            .map(|n| (n.to_string(), table(n)))
+            .collect::<HashMap<String, Table>>();
+        document(&tables, &Config::default(), |_| {
+            vec![Method::Get, Method::Post, Method::Put, Method::Patch, Method::Delete]
+        })
+    }
+
+    #[test]
+    fn component_keys() {
+        let mut used = HashSet::new();
+        used.insert(PROBLEM.to_string());
+        assert_eq!(component_key("users", &mut used), "users");
+        assert_eq!(component_key("my table", &mut used), "my_table");
+        assert_eq!(component_key("my_table", &mut used), "my_table_2");
+        assert_eq!(component_key("my/table", &mut used), "my_table_3");
+        assert_eq!(component_key("Problem", &mut used), "Problem_2");
+        assert_eq!(component_key("v1.été-x", &mut used), "v1._t_-x");
+    }
+
+    #[test]
+    fn references_use_the_keys() {
+        let doc = document_of(&["a b", "Problem"]);
+        let schemas = doc.pointer("/components/schemas").unwrap().as_object().unwrap();
+        let mut keys = schemas.keys().cloned().collect::<Vec<String>>();
+        keys.sort();
+        assert_eq!(keys, vec!["Problem", "Problem_2", "a_b"]);
+        let items = doc.pointer("/paths/~1api~1a b/get/responses/200/content/application~1json\
+                                 /schema/properties/data/items/$ref");
+        assert_eq!(items.and_then(|r| r.as_str()), Some("#/components/schemas/a_b"));
+        let problem = doc.pointer("/components/responses/Error/content/\
+                                   application~1problem+json/schema/$ref");
+        assert_eq!(problem.and_then(|r| r.as_str()), Some("#/components/schemas/Problem"));
+    }
+
+    #[test]
+    fn put_is_not_documented() {
+        let doc = document_of(&["t"]);
+        let item = doc.pointer("/paths/~1api~1t").unwrap().as_object().unwrap();
+        let mut methods = item.keys().cloned().collect::<Vec<String>>();
+        methods.sort();
+        assert_eq!(methods, vec!["delete", "get", "patch", "post"]);
+    }
+}
@@ -5,8 +5,10 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use common::quote_column;
 use std::str::FromStr;
 
+#[derive(Debug, Clone)]
 pub enum Ordering {
     Asc(String),
     Desc(String)
@@ -31,10 +33,25 @@ impl FromStr for Ordering {
     }
 }
 
+impl Ordering {
+    pub fn column(&self) -> &str {
+        match *self {
+            Ordering::Asc(ref s) | Ordering::Desc(ref s) => &*s,
+        }
+    }
+}
+
 pub fn to_string(o: &Ordering, prefix: Option<&str>) -> String {
-    let s = match o {
-        &Ordering::Asc(ref s) => format!("{} {}", s, "ASC"),
-        &Ordering::Desc(ref s) => format!("{} {}", s, "DESC"),
-    };
-    prefix.map_or(s.clone(), |p| format!("{}.{}", p, s))
+    match o {
+        &Ordering::Asc(ref s) => format!("{} {}", quote_column(prefix, s), "ASC"),
+        &Ordering::Desc(ref s) => format!("{} {}", quote_column(prefix, s), "DESC"),
+    }
+}
+
+/// Format the ordering as used in the `order` query parameter.
+pub fn to_query_value(o: &Ordering) -> String {
+    match o {
+        &Ordering::Asc(ref s) => format!("{}.asc", s),
+        &Ordering::Desc(ref s) => format!("{}.desc", s),
+    }
 }
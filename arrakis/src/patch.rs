@@ -6,10 +6,11 @@
 // except according to those terms.
 
 use common;
-use cvt;
 use error::Error;
-use queries::Queries;
-use postgres::Connection;
+use json_schema::Validator;
+use method::Method;
+use query::Query;
+use postgres::GenericConnection;
 use schema::Table;
 use serde_json::Value;
 use std::ops::Deref;
@@ -22,35 +23,38 @@ WHERE CustomerID=1;
  */
 
 pub fn generate_update(table: &Table) -> String {
-    format!("UPDATE {}", table.name)
+    format!("UPDATE {}", common::quote_table(table))
 }
 
-pub fn generate_set(query: String, table: &Table, val: &Value) -> Result<String, Error> {
+pub fn generate_set(query: String, table: &Table, validator: &Validator, val: &Value)
+                    -> Result<String, Error> {
     let fields_str = String::new();
-    common::validate_table_fields(table, val)?;
+    common::validate_table_fields(table, val, &Method::Patch)?;
+    validator.validate(val)?;
+    let val = &common::timestamp_literals(table, val);
     // here we know this is an object
     // it would have not passed the previous check if it was not.
     let m = val.as_object().unwrap();
-    let fields: Vec<String> = m.iter().map(|(k, v)| {
-        format!("{}='{}'", k, cvt::json_value_to_string(v))
-    }).collect();
+    let fields = m.iter().map(|(k, v)| {
+        Ok(format!("{}={}", common::quote_ident(k), common::quote_value(v)?))
+    }).collect::<Result<Vec<String>, Error>>()?;
     let fields_str = fields.iter().map(Deref::deref).collect::<Vec<&str>>().join(", ");
     Ok(format!("{} SET {}", query, fields_str))
 }
 
-pub fn generate_returning(query: String) -> String {
-    format!("{} RETURNING id", query)
+pub fn generate_returning(query: String, table: &Table) -> String {
+    format!("{} RETURNING {}", query, common::select_id(table))
 }
 
-pub fn query(conn: &Connection, table: &Table, queries: &Queries, val: Value)
-             -> Result<Option<Value>, Error> {
+pub fn query(conn: &GenericConnection, table: &Table, validator: &Validator, q: &Query,
+             val: Value) -> Result<Option<Value>, Error> {
     let query = generate_update(table);
-    let query = generate_set(query, table, &val)?;
-    let query = common::generate_where(query, table, queries)?;
-    let query = generate_returning(query);
+    let query = generate_set(query, table, validator, &val)?;
+    let query = common::generate_where(query, table, q)?;
+    let query = generate_returning(query, table);
     debug!("arrakis query: {}", query);
     match conn.query(&*query, &[]) {
         Ok(rows) => Ok(Some(get::collect_ids(rows))),
-        Err(e) => Err(Error::InternalError("internal database error".into()))
+        Err(e) => Err(Error::from(e)),
     }
 }
@@ -6,15 +6,19 @@
 // except according to those terms.
 
 use common;
-use cvt;
 use error::Error;
-use queries::Queries;
-use postgres::Connection;
+use json_schema::Validator;
+use method::Method;
+use query::Query;
+use postgres::GenericConnection;
 use schema::Table;
-use serde_json::Value;
+use serde_json::{self, Map, Value};
 use std::ops::Deref;
 use get;
 
+// number of rows inserted by each statement of a bulk insert
+const BULK_SIZE: usize = 500;
+
 pub fn generate_insert() -> String {
     format!("INSERT")
 }
@@ -22,37 +26,176 @@ pub fn generate_insert() -> String {
 pub fn generate_into(query: String, table: &Table, val: &Value) -> String {
     let m = val.as_object().unwrap();
     let intos: Vec<String> = m.iter().map(|(k, _)| {
-        format!("{}", k)
+        common::quote_ident(k)
     }).collect();
     let intos = intos.iter().map(Deref::deref).collect::<Vec<&str>>().join(", ");
-    format!("{} INTO {} ({})", query, table.name, intos)
+    format!("{} INTO {} ({})", query, common::quote_table(table), intos)
 }
 
 pub fn generate_values(query: String, table: &Table, val: &Value) -> Result<String, Error> {
     let m = val.as_object().unwrap();
-    let fields: Vec<String> = m.iter().map(|(k, v)| {
-        format!("{}='{}'", k, cvt::json_value_to_string(v))
-    }).collect();
-    let fields_str = fields.iter().map(Deref::deref).collect::<Vec<&str>>().join(", ");
-    Ok(format!("{} SET {}", query, fields_str))
+    let values = m.iter()
+        .map(|(_, v)| common::quote_value(v))
+        .collect::<Result<Vec<String>, Error>>()?;
+    let values_str = values.iter().map(Deref::deref).collect::<Vec<&str>>().join(", ");
+    Ok(format!("{} VALUES ({})", query, values_str))
 }
 
-pub fn generate_returning(query: String) -> String {
-    format!("{} RETURNING id", query)
+pub fn generate_returning(query: String, table: &Table) -> String {
+    format!("{} RETURNING {}", query, common::select_id(table))
 }
 
-pub fn query(conn: &Connection, table: &Table, queries: &Queries, val: Value)
-             -> Result<Option<Value>, Error> {
-    common::validate_table_fields(table, &val)?;
+pub fn query(conn: &GenericConnection, table: &Table, validator: &Validator, q: &Query,
+             val: Value) -> Result<Option<Value>, Error> {
+    common::validate_table_fields(table, &val, &Method::Post)?;
+    validator.validate(&val)?;
+    let val = common::timestamp_literals(table, &val);
     // here we know this is an object
     // it would have not passed the previous check if it was not.
     let query = generate_insert();
     let query = generate_into(query, table, &val);
     let query = generate_values(query, table, &val)?;
-    let query = generate_returning(query);
+    let query = generate_returning(query, table);
     debug!("arrakis query: {}", query);
     match conn.query(&*query, &[]) {
         Ok(rows) => Ok(Some(get::collect_ids(rows))),
-        Err(e) => Err(Error::InternalError("internal database error".into()))
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// Insert the rows of a ndjson body, one json object per line, and return
+/// their ids. The body is read whole by the http adapters, it is not
+/// streamed, and the lines are parsed one by one. Consecutive rows with the
+/// same columns are inserted with a single statement, the values being bound
+/// as a parameter.
+pub fn query_lines(conn: &GenericConnection, table: &Table, validator: &Validator, body: &str)
+                   -> Result<Option<Value>, Error> {
+    let mut ids = vec![];
+    let mut batch: Vec<Value> = vec![];
+    for (i, line) in body.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let val: Value = match serde_json::from_str(line) {
+            Ok(v) => v,
+            Err(e) => return Err(Error::ImportFailed(vec![((i + 1) as u64, format!("{}", e))],
+                                                     "invalid json line".into())),
+        };
+        common::validate_table_fields(table, &val, &Method::Post)?;
+        if let Err(e) = validator.validate(&val) {
+            return Err(Error::ImportFailed(vec![((i + 1) as u64, format!("{}", e))],
+                                           "invalid row".into()));
+        }
+        if !batch.is_empty() && (batch.len() == BULK_SIZE || !same_columns(&batch[0], &val)) {
+            insert_batch(conn, table, &*batch, &mut ids)?;
+            batch.clear();
+        }
+        batch.push(common::timestamp_literals(table, &val));
+    }
+    if !batch.is_empty() {
+        insert_batch(conn, table, &*batch, &mut ids)?;
+    }
+    Ok(Some(Value::Array(ids)))
+}
+
+fn same_columns(a: &Value, b: &Value) -> bool {
+    match (a.as_object(), b.as_object()) {
+        (Some(a), Some(b)) => a.len() == b.len() && a.keys().all(|k| b.contains_key(k)),
+        _ => false,
+    }
+}
+
+// the rows of the batch have the same columns, they are sent as a json
+// array converted to the types of the columns by json_populate_recordset,
+// like the rows of the csv imports.
+fn insert_batch(conn: &GenericConnection, table: &Table, batch: &[Value], ids: &mut Vec<Value>)
+                -> Result<(), Error> {
+    let columns = batch[0].as_object().unwrap().keys().cloned().collect::<Vec<String>>();
+    let query = generate_batch_insert(table, &*columns);
+    let rows = batch_rows(&*columns, batch);
+    debug!("arrakis query: {}", query);
+    match conn.query(&*query, &[&rows]) {
+        Ok(rows) => {
+            if let Value::Array(new_ids) = get::collect_ids(rows) {
+                ids.extend(new_ids);
+            }
+            Ok(())
+        },
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+fn generate_batch_insert(table: &Table, columns: &[String]) -> String {
+    let into = columns.iter().map(|c| common::quote_ident(c)).collect::<Vec<String>>();
+    let select = columns.iter()
+        .map(|c| common::quote_column(Some("r"), c))
+        .collect::<Vec<String>>();
+    let query = format!("INSERT INTO {} ({}) SELECT {} FROM \
+                         json_populate_recordset(NULL::{}, $1::text::json) AS r",
+                        common::quote_table(table), into.join(", "), select.join(", "),
+                        common::quote_table(table));
+    generate_returning(query, table)
+}
+
+// the json array of the rows, the scalar values are sent as text so postgres
+// reads them with the input function of the column type, as for the quoted
+// values of the other requests.
+fn batch_rows(columns: &[String], batch: &[Value]) -> String {
+    let rows = batch.iter().map(|row| {
+        let row = row.as_object().unwrap();
+        let mut m = Map::new();
+        for c in columns {
+            let v = match *row.get(c).unwrap() {
+                Value::Bool(b) => Value::String(b.to_string()),
+                Value::I64(i) => Value::String(i.to_string()),
+                Value::U64(u) => Value::String(u.to_string()),
+                Value::F64(f) => Value::String(f.to_string()),
+                ref v => v.clone(),
+            };
+            m.insert(c.clone(), v);
+        }
+        Value::Object(m)
+    }).collect();
+    Value::Array(rows).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn batch_insert() {
+        let table = Table::new("public", "t", vec![]);
+        assert_eq!(generate_batch_insert(&table, &*strings(&["id", "na\"me"])),
+                   "INSERT INTO \"public\".\"t\" (\"id\", \"na\"\"me\") \
+                    SELECT \"r\".\"id\", \"r\".\"na\"\"me\" FROM \
+                    json_populate_recordset(NULL::\"public\".\"t\", $1::text::json) AS r \
+                    RETURNING \"id\"");
+    }
+
+    #[test]
+    fn batch_values() {
+        let batch = vec![
+            serde_json::from_str(r#"{"b":true,"a":"x'); --"}"#).unwrap(),
+            serde_json::from_str(r#"{"a":null,"b":1.5}"#).unwrap(),
+            serde_json::from_str(r#"{"b":-2,"a":{"k":[1]}}"#).unwrap(),
+        ];
+        assert_eq!(batch_rows(&*strings(&["b", "a"]), &*batch),
+                   r#"[{"b":"true","a":"x'); --"},{"b":"1.5","a":null},"#.to_string() +
+                   r#"{"b":"-2","a":{"k":[1]}}]"#);
+    }
+
+    #[test]
+    fn rows_with_the_same_columns() {
+        let a: Value = serde_json::from_str(r#"{"x":1,"y":2}"#).unwrap();
+        let b: Value = serde_json::from_str(r#"{"y":3,"x":4}"#).unwrap();
+        let c: Value = serde_json::from_str(r#"{"x":1}"#).unwrap();
+        assert!(same_columns(&a, &b));
+        assert!(!same_columns(&a, &c));
+        assert!(!same_columns(&a, &Value::Null));
     }
 }
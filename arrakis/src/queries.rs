@@ -15,45 +15,101 @@ pub const SELECT: &'static str = "select";
 pub const LIMIT: &'static str = "limit";
 pub const OFFSET: &'static str = "offset";
 pub const ORDER: &'static str = "order";
+pub const FORMAT: &'static str = "format";
+pub const DELIMITER: &'static str = "delimiter";
+pub const NULL: &'static str = "null";
+pub const ON_CONFLICT: &'static str = "on_conflict";
+pub const VARIANT: &'static str = "variant";
+
+/// Names of the control parameters, without prefix.
+pub const CONTROLS: [&'static str; 9] = [SELECT, LIMIT, OFFSET, ORDER, FORMAT, DELIMITER, NULL,
+                                     ON_CONFLICT, VARIANT];
 
 pub type Queries<'r> = BTreeMap<&'r str, &'r str>;
 
+/// Access to the control parameters and filters of the query string.
+///
+/// Control parameters (`select`, `limit`, `offset`, `order`, `format`, the
+/// `delimiter`, `null` and `on_conflict` options of csv imports and the
+/// `variant` of the json schemas) are looked up
+/// with the given prefix, e.g `_limit` for the prefix `_`. Every other
+/// parameter is a filter on the column of the same name, a column named like
+/// a control parameter can be filtered by quoting it: `"order"=eq.1`.
 pub trait FetchQueries {
-    fn select(&self) -> Option<Vec<&str>>;
-    fn limit(&self) -> Option<&str>;
-    fn offset(&self) -> Option<&str>;
-    fn order(&self) -> Option<Vec<Ordering>>;
+    fn select(&self, prefix: &str) -> Option<Vec<&str>>;
+    fn limit(&self, prefix: &str) -> Option<&str>;
+    fn offset(&self, prefix: &str) -> Option<&str>;
+    fn order(&self, prefix: &str) -> Option<Vec<Ordering>>;
+    fn format(&self, prefix: &str) -> Option<&str>;
+    fn delimiter(&self, prefix: &str) -> Option<&str>;
+    fn null(&self, prefix: &str) -> Option<&str>;
+    fn on_conflict(&self, prefix: &str) -> Option<&str>;
+    fn variant(&self, prefix: &str) -> Option<&str>;
     // map of column -> filter
-    fn filters(&self) -> Result<BTreeMap<&str, Filter>, Error>;
+    fn filters(&self, prefix: &str) -> Result<BTreeMap<String, Filter>, Error>;
 }
 
 pub fn queries_from_hashmap<'r>(m: &'r HashMap<String, Vec<String>>) -> Queries<'r> {
     m.iter().map(|(k, v)| (&**k, &*(v[0]))).collect::<Queries<'r>>()
 }
 
-impl<'r> FetchQueries for Queries<'r> {
-    // this is really naive
-    // do not handle foreign key for now.
-    fn select(&self) -> Option<Vec<&str>> {
-        match self.get(SELECT) {
-            Some(ref val) => {
-                Some(val.split(',').collect())
+/// Whether a query parameter is the given control parameter.
+pub fn is_control(key: &str, prefix: &str, name: &str) -> bool {
+    key.len() == prefix.len() + name.len() && key.starts_with(prefix) && key.ends_with(name)
+}
+
+/// Quote the column name of a filter parameter so it is not mistaken for a
+/// control parameter, this is not sql quoting (see `common::quote_ident`).
+pub fn quote_param(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+// the column of a filter parameter, unquoting it if needed
+fn unquote_column(key: &str) -> String {
+    if key.len() >= 2 && key.starts_with('"') && key.ends_with('"') {
+        return key[1..key.len()-1].replace("\"\"", "\"");
+    }
+    key.to_string()
+}
+
+/// Split a select list on the commas which are not inside the parentheses
+/// of an embedded resource, e.g `id,author(id,name)` is `id` and
+/// `author(id,name)`.
+pub fn split_select(s: &str) -> Vec<&str> {
+    let mut items = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                items.push(&s[start..i]);
+                start = i + 1;
             },
-            None => None
+            _ => {},
         }
     }
+    items.push(&s[start..]);
+    items
+}
+
+impl<'r> FetchQueries for Queries<'r> {
+    fn select(&self, prefix: &str) -> Option<Vec<&str>> {
+        get_control(self, prefix, SELECT).map(split_select)
+    }
 
-    fn limit(&self) -> Option<&str> {
-        self.get(LIMIT).map(|val| *val)
+    fn limit(&self, prefix: &str) -> Option<&str> {
+        get_control(self, prefix, LIMIT)
     }
 
-    fn offset(&self) -> Option<&str> {
-        self.get(OFFSET).map(|val| *val)
+    fn offset(&self, prefix: &str) -> Option<&str> {
+        get_control(self, prefix, OFFSET)
     }
 
-    fn order(&self) -> Option<Vec<Ordering>> {
-        match self.get(ORDER) {
-            Some(ref val) => {
+    fn order(&self, prefix: &str) -> Option<Vec<Ordering>> {
+        match get_control(self, prefix, ORDER) {
+            Some(val) => {
                 Some(val.split(',')
                      .collect::<Vec<&str>>().iter()
                      .filter_map(|ref s| Ordering::from_str(s).ok())
@@ -63,19 +119,114 @@ impl<'r> FetchQueries for Queries<'r> {
         }
     }
 
-    fn filters(&self) -> Result<BTreeMap<&str, Filter>, Error> {
+    fn format(&self, prefix: &str) -> Option<&str> {
+        get_control(self, prefix, FORMAT)
+    }
+
+    fn delimiter(&self, prefix: &str) -> Option<&str> {
+        get_control(self, prefix, DELIMITER)
+    }
+
+    fn null(&self, prefix: &str) -> Option<&str> {
+        get_control(self, prefix, NULL)
+    }
+
+    fn on_conflict(&self, prefix: &str) -> Option<&str> {
+        get_control(self, prefix, ON_CONFLICT)
+    }
+
+    fn variant(&self, prefix: &str) -> Option<&str> {
+        get_control(self, prefix, VARIANT)
+    }
+
+    fn filters(&self, prefix: &str) -> Result<BTreeMap<String, Filter>, Error> {
         let mut filters = BTreeMap::new();
         for (k, v) in self.iter() {
-            match *k {
-                SELECT | LIMIT | OFFSET | ORDER => {},
-                _ => {
-                    match Filter::new(k, v) {
-                        Ok(f) => { filters.insert(*k, f); },
-                        Err(e) => return Err(e)
-                    }
-                }
+            if CONTROLS.iter().any(|c| is_control(k, prefix, c)) {
+                continue;
+            }
+            let column = unquote_column(k);
+            match Filter::new(&*column, v) {
+                Ok(f) => { filters.insert(column, f); },
+                Err(e) => return Err(e)
             }
         }
         return Ok(filters);
     }
 }
+
+fn get_control<'a>(queries: &Queries<'a>, prefix: &str, name: &str) -> Option<&'a str> {
+    queries.iter().find(|&(k, _)| is_control(k, prefix, name)).map(|(_, v)| *v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use query::Query;
+    use filters::Filter;
+
+    fn queries<'a>(params: &[(&'a str, &'a str)]) -> Queries<'a> {
+        params.iter().cloned().collect()
+    }
+
+    #[test]
+    fn control_prefix() {
+        assert!(is_control("limit", "", LIMIT));
+        assert!(is_control("_limit", "_", LIMIT));
+        assert!(!is_control("limit", "_", LIMIT));
+        assert!(!is_control("_limit", "", LIMIT));
+        assert!(!is_control("x_limit", "_", LIMIT));
+        assert!(!is_control("_limits", "_", LIMIT));
+
+        let q = queries(&[("_limit", "10"), ("limit", "eq.3"), ("_select", "id")]);
+        assert_eq!(q.limit("_"), Some("10"));
+        assert_eq!(q.select("_"), Some(vec!["id"]));
+        let filters = q.filters("_").unwrap();
+        assert_eq!(filters.len(), 1);
+        assert_eq!(filters["limit"].to_string(None), "\"limit\" = '3'");
+        // without prefix the same parameters are controls
+        assert!(q.filters("").is_err());
+
+        let q = queries(&[("_variant", "update"), ("variant", "eq.1")]);
+        assert_eq!(q.variant("_"), Some("update"));
+        assert_eq!(q.filters("_").unwrap().len(), 1);
+        assert!(q.filters("_").unwrap().contains_key("variant"));
+    }
+
+    #[test]
+    fn select_lists() {
+        assert_eq!(split_select("id"), vec!["id"]);
+        assert_eq!(split_select("id,author(id,name),posts(title,comments(body))"),
+                   vec!["id", "author(id,name)", "posts(title,comments(body))"]);
+        // unbalanced parentheses are reported by the parsing of the items
+        assert_eq!(split_select("a(b,c"), vec!["a(b,c"]);
+        assert_eq!(split_select("a),b"), vec!["a),b"]);
+    }
+
+    #[test]
+    fn quoted_columns() {
+        assert_eq!(quote_param("order"), "\"order\"");
+        assert_eq!(quote_param("a\"b"), "\"a\"\"b\"");
+        assert_eq!(unquote_column("\"order\""), "order");
+        assert_eq!(unquote_column("\"a\"\"b\""), "a\"b");
+        assert_eq!(unquote_column("\""), "\"");
+        assert_eq!(unquote_column("name"), "name");
+
+        let q = queries(&[("\"order\"", "eq.1"), ("order", "id.desc")]);
+        let filters = q.filters("").unwrap();
+        assert_eq!(filters.len(), 1);
+        assert_eq!(filters["order"].column(), "order");
+        assert_eq!(q.order("").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn round_trip_of_quoted_columns() {
+        for column in &["order", "select", "\"quoted\"", "a\"b", "limit"] {
+            let q = Query::builder().filter(Filter::Equal(column.to_string(), "1".into())).build();
+            let parsed = Query::parse(&*q.to_query_string()).unwrap();
+            assert_eq!(parsed.filters().len(), 1, "{}", column);
+            assert_eq!(parsed.filters()[0].column(), *column);
+            assert!(parsed.limit().is_none());
+        }
+    }
+}
@@ -0,0 +1,431 @@
+// Copyright 2016 Jeremy Letang.
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use error::Error;
+use filters::Filter;
+use ordering::{self, Ordering};
+use queries::{self, FetchQueries, Queries};
+
+/// Typed representation of the query string accepted by arrakis.
+///
+/// A `Query` can be parsed from the query string grammar used by the http
+/// adapters (`select=id,name&id=gt.42&order=id.desc&limit=10`), or built
+/// programmatically:
+///
+/// ```ignore
+/// let query = Query::builder()
+///     .select(vec!["id", "name"])
+///     .filter(Filter::GreaterThan("id".into(), "42".into()))
+///     .order(Ordering::Desc("id".into()))
+///     .limit(10)
+///     .build();
+/// ```
+///
+/// Embedded resources add the rows related by a foreign key to each row:
+/// `select=id,users(name)` embeds the row of `users` referenced by the rows,
+/// or the array of the rows of `users` referencing them. When several
+/// foreign keys relate the two tables, the column of the foreign key to use
+/// follows a `!`, and the resource can be given another key:
+/// `select=*,author:users!author_id(name),editor:users!editor_id(name)`.
+/// `*` selects every readable column.
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    select_: Option<Vec<String>>,
+    embedded_: Vec<Embed>,
+    filters_: Vec<Filter>,
+    order_: Vec<Ordering>,
+    limit_: Option<u32>,
+    offset_: Option<u32>,
+}
+
+impl Query {
+    pub fn new() -> Query {
+        Default::default()
+    }
+
+    pub fn builder() -> Builder {
+        Builder {
+            query: Query::new(),
+        }
+    }
+
+    /// Build a query from already split query string parameters.
+    pub fn from_queries(queries: &Queries) -> Result<Query, Error> {
+        Query::from_queries_with_prefix(queries, "")
+    }
+
+    /// Build a query from already split query string parameters, the control
+    /// parameters being prefixed with `prefix` (e.g `_limit`).
+    pub fn from_queries_with_prefix(queries: &Queries, prefix: &str) -> Result<Query, Error> {
+        let mut query = Query::new();
+        if let Some(items) = queries.select(prefix) {
+            let (columns, embedded) = parse_select(&*items)?;
+            query.select_ = columns;
+            query.embedded_ = embedded;
+        }
+        query.filters_ = queries.filters(prefix)?.into_iter().map(|(_, f)| f).collect();
+        query.order_ = queries.order(prefix).unwrap_or(vec![]);
+        query.limit_ = parse_u32(queries::LIMIT, queries.limit(prefix))?;
+        query.offset_ = parse_u32(queries::OFFSET, queries.offset(prefix))?;
+        Ok(query)
+    }
+
+    /// Parse a raw (percent encoded) query string, without the leading `?`.
+    pub fn parse(s: &str) -> Result<Query, Error> {
+        let decoded = s.split('&')
+            .filter(|s| !s.is_empty())
+            .map(|s| match s.find('=') {
+                Some(pos) => (percent_decode(&s[..pos]), percent_decode(&s[pos+1..])),
+                None => (percent_decode(s), String::new()),
+            })
+            .collect::<Vec<(String, String)>>();
+        let queries = decoded.iter().map(|&(ref k, ref v)| (&**k, &**v)).collect::<Queries>();
+        Query::from_queries(&queries)
+    }
+
+    /// Serialize the query back to the query string grammar, the output
+    /// can be parsed again with `Query::parse`.
+    pub fn to_query_string(&self) -> String {
+        let mut params = vec![];
+        if self.select_.is_some() || !self.embedded_.is_empty() {
+            params.push(format!("{}={}", queries::SELECT,
+                                select_to_string(self.select(), &*self.embedded_)));
+        }
+        for f in &self.filters_ {
+            // columns named like a control parameter are quoted
+            let column = match f.column() {
+                c if queries::CONTROLS.contains(&c) => queries::quote_param(c),
+                c if c.starts_with('"') => queries::quote_param(c),
+                c => c.to_string(),
+            };
+            params.push(format!("{}={}", percent_encode(&*column),
+                                percent_encode(&*f.to_query_value())));
+        }
+        if !self.order_.is_empty() {
+            params.push(format!("{}={}", queries::ORDER,
+                                self.order_.iter().map(|o| percent_encode(&*ordering::to_query_value(o)))
+                                .collect::<Vec<String>>().join(",")));
+        }
+        if let Some(limit) = self.limit_ {
+            params.push(format!("{}={}", queries::LIMIT, limit));
+        }
+        if let Some(offset) = self.offset_ {
+            params.push(format!("{}={}", queries::OFFSET, offset));
+        }
+        params.join("&")
+    }
+
+    pub fn select(&self) -> Option<&[String]> {
+        self.select_.as_ref().map(|s| &**s)
+    }
+
+    pub fn embedded(&self) -> &[Embed] {
+        &*self.embedded_
+    }
+
+    pub fn filters(&self) -> &[Filter] {
+        &*self.filters_
+    }
+
+    pub fn order(&self) -> &[Ordering] {
+        &*self.order_
+    }
+
+    pub fn limit(&self) -> Option<u32> {
+        self.limit_
+    }
+
+    pub fn offset(&self) -> Option<u32> {
+        self.offset_
+    }
+}
+
+/// A resource embedded in the selected rows, see `Query`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Embed {
+    // the related table
+    pub table: String,
+    // the column of the foreign key relating the tables, in either of them,
+    // required when several foreign keys relate them
+    pub column: Option<String>,
+    // the key of the resource in the rows, default to the table
+    pub alias: Option<String>,
+    // the selected columns, every readable column if None
+    pub select: Option<Vec<String>>,
+    pub embedded: Vec<Embed>,
+}
+
+impl Embed {
+    /// Embed every readable column of the rows of `table` related to the
+    /// selected rows.
+    pub fn new(table: &str) -> Embed {
+        Embed {
+            table: table.to_string(),
+            column: None,
+            alias: None,
+            select: None,
+            embedded: vec![],
+        }
+    }
+
+    /// The key of the resource in the selected rows.
+    pub fn key(&self) -> &str {
+        self.alias.as_ref().unwrap_or(&self.table)
+    }
+}
+
+pub struct Builder {
+    query: Query,
+}
+
+impl Builder {
+    pub fn build(self) -> Query {
+        self.query
+    }
+
+    pub fn select(mut self, columns: Vec<&str>) -> Builder {
+        self.query.select_ = Some(columns.iter().map(|c| c.to_string()).collect());
+        self
+    }
+
+    pub fn embed(mut self, embed: Embed) -> Builder {
+        self.query.embedded_.push(embed);
+        self
+    }
+
+    pub fn filter(mut self, filter: Filter) -> Builder {
+        self.query.filters_.push(filter);
+        self
+    }
+
+    pub fn order(mut self, order: Ordering) -> Builder {
+        self.query.order_.push(order);
+        self
+    }
+
+    pub fn limit(mut self, limit: u32) -> Builder {
+        self.query.limit_ = Some(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: u32) -> Builder {
+        self.query.offset_ = Some(offset);
+        self
+    }
+}
+
+// the columns (None for `*`) and the embedded resources of a select list
+fn parse_select(items: &[&str]) -> Result<(Option<Vec<String>>, Vec<Embed>), Error> {
+    let mut columns = vec![];
+    let mut all = false;
+    let mut embedded = vec![];
+    for item in items {
+        match item.find('(') {
+            Some(open) if item.ends_with(')') =>
+                embedded.push(parse_embed(&item[..open], &item[open+1..item.len()-1])?),
+            _ if item.contains('(') || item.contains(')') => return Err(Error::InvalidInputError(
+                format!("cannot select {}, unbalanced parentheses", item))),
+            _ if *item == "*" => all = true,
+            _ => columns.push(item.to_string()),
+        }
+    }
+    if all && !columns.is_empty() {
+        return Err(Error::InvalidInputError(
+            format!("cannot select both * and the columns {}", columns.join(", "))));
+    }
+    Ok((if all { None } else { Some(columns) }, embedded))
+}
+
+// an embedded resource, e.g head author:users!author_id and inner name,email
+fn parse_embed(head: &str, inner: &str) -> Result<Embed, Error> {
+    let (alias, rest) = match head.find(':') {
+        Some(pos) => (Some(&head[..pos]), &head[pos+1..]),
+        None => (None, head),
+    };
+    let (table, column) = match rest.find('!') {
+        Some(pos) => (&rest[..pos], Some(&rest[pos+1..])),
+        None => (rest, None),
+    };
+    if table.is_empty() || alias == Some("") || column == Some("") || inner.is_empty() {
+        return Err(Error::InvalidInputError(
+            format!("cannot select {}({}), expected [key:]table[!column](columns)", head, inner)));
+    }
+    let (select, embedded) = parse_select(&*queries::split_select(inner))?;
+    Ok(Embed {
+        table: table.to_string(),
+        column: column.map(|c| c.to_string()),
+        alias: alias.map(|a| a.to_string()),
+        select: select,
+        embedded: embedded,
+    })
+}
+
+// the select list of columns and embedded resources, `*` if columns is None
+fn select_to_string(columns: Option<&[String]>, embedded: &[Embed]) -> String {
+    let mut items = match columns {
+        Some(columns) => columns.iter().map(|c| percent_encode(c)).collect::<Vec<String>>(),
+        None => vec!["*".to_string()],
+    };
+    for e in embedded {
+        let mut item = String::new();
+        if let Some(ref alias) = e.alias {
+            item = format!("{}:", percent_encode(alias));
+        }
+        item += &*percent_encode(&*e.table);
+        if let Some(ref column) = e.column {
+            item = format!("{}!{}", item, percent_encode(column));
+        }
+        let select = e.select.as_ref().map(|s| &**s);
+        items.push(format!("{}({})", item, select_to_string(select, &*e.embedded)));
+    }
+    items.join(",")
+}
+
+fn parse_u32(name: &str, value: Option<&str>) -> Result<Option<u32>, Error> {
+    match value {
+        Some(v) => match v.trim().parse::<u32>() {
+            Ok(i) => Ok(Some(i)),
+            Err(_) => Err(Error::InvalidFilterType(name.into(), "u32".into())),
+        },
+        None => Ok(None),
+    }
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' | b'*' | b','
+                | b'(' | b')' | b'!' | b':' => out.push(b as char),
+            _ => out.push_str(&*format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// Decode a percent encoded query string component, `+` is a space.
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = ::std::str::from_utf8(&bytes[i+1..i+3]).unwrap_or("");
+                match u8::from_str_radix(hex, 16) {
+                    Ok(b) => { out.push(b); i += 3; },
+                    Err(_) => { out.push(b'%'); i += 1; },
+                }
+            },
+            b'+' => { out.push(b' '); i += 1; },
+            b => { out.push(b); i += 1; },
+        }
+    }
+    String::from_utf8_lossy(&*out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filters::{Filter, IsKind};
+    use ordering::Ordering;
+
+    #[test]
+    fn parse_query_string() {
+        let q = Query::parse("select=id,name&id=gt.42&name=like.a*&order=id.desc,name\
+                              &limit=10&offset=20").unwrap();
+        assert_eq!(q.select(), Some(&["id".to_string(), "name".to_string()][..]));
+        assert_eq!(q.filters().len(), 2);
+        assert_eq!(q.filters()[0].to_string(None), "\"id\" > '42'");
+        assert_eq!(q.filters()[1].to_string(None), "\"name\" LIKE 'a%'");
+        assert_eq!(q.order().len(), 2);
+        assert_eq!(q.limit(), Some(10));
+        assert_eq!(q.offset(), Some(20));
+    }
+
+    #[test]
+    fn round_trip() {
+        // filters are parsed in the order of their columns
+        let q = Query::builder()
+            .select(vec!["id", "full name", "order"])
+            .filter(Filter::Equal("full name".into(), "a&b=c %d".into()))
+            .filter(Filter::In("id".into(), vec!["1".into(), "2".into()]))
+            .filter(Filter::Not(Box::new(Filter::Is("note".into(), IsKind::Null))))
+            .filter(Filter::Equal("order".into(), "1".into()))
+            .filter(Filter::ILike("title".into(), "%rust%".into()))
+            .order(Ordering::Desc("id".into()))
+            .order(Ordering::Asc("full name".into()))
+            .limit(10)
+            .offset(5)
+            .build();
+        let s = q.to_query_string();
+        assert_eq!(s, "select=id,full%20name,order&full%20name=eq.a%26b%3Dc%20%25d\
+                       &id=in.1,2&note=not.is.NULL&%22order%22=eq.1&title=ilike.*rust*\
+                       &order=id.desc,full%20name.asc&limit=10&offset=5");
+        let parsed = Query::parse(&*s).unwrap();
+        assert_eq!(parsed.to_query_string(), s);
+        assert_eq!(parsed.filters()[0].to_string(None), "\"full name\" = 'a&b=c %d'");
+        assert_eq!(parsed.filters()[3].column(), "order");
+    }
+
+    #[test]
+    fn empty_query() {
+        let q = Query::parse("").unwrap();
+        assert_eq!(q.to_query_string(), "");
+        assert!(q.select().is_none());
+    }
+
+    #[test]
+    fn invalid_limit() {
+        assert!(Query::parse("limit=-1").is_err());
+        assert!(Query::parse("offset=a").is_err());
+    }
+
+    #[test]
+    fn embedded_resources() {
+        let q = Query::parse("select=id,author:users!author_id(name,email),\
+                              comments(*,users(name))").unwrap();
+        assert_eq!(q.select(), Some(&["id".to_string()][..]));
+        let author = Embed {
+            column: Some("author_id".into()),
+            alias: Some("author".into()),
+            select: Some(vec!["name".into(), "email".into()]),
+            ..Embed::new("users")
+        };
+        let comments = Embed {
+            embedded: vec![Embed { select: Some(vec!["name".into()]), ..Embed::new("users") }],
+            ..Embed::new("comments")
+        };
+        assert_eq!(q.embedded(), &[author.clone(), comments.clone()]);
+        assert_eq!(author.key(), "author");
+        assert_eq!(comments.key(), "comments");
+        assert_eq!(q.to_query_string(),
+                   "select=id,author:users!author_id(name,email),comments(*,users(name))");
+
+        let q = Query::builder().embed(Embed::new("users")).build();
+        assert_eq!(q.select(), None);
+        assert_eq!(q.to_query_string(), "select=*,users(*)");
+        assert_eq!(Query::parse(&*q.to_query_string()).unwrap().embedded(), q.embedded());
+    }
+
+    #[test]
+    fn invalid_embedded_resources() {
+        for s in &["select=id,author(name", "select=id,author)", "select=author()",
+                   "select=:users(id)", "select=users!(id)", "select=(id)", "select=*,id"] {
+            match Query::parse(s) {
+                Err(Error::InvalidInputError(_)) => {},
+                r => panic!("unexpected {:?} for {}", r, s),
+            }
+        }
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("a%20b+c%2"), "a b c%2");
+        assert_eq!(percent_decode("%e2%82%ac"), "\u{20ac}");
+    }
+}
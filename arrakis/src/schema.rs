@@ -5,9 +5,140 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::collections::BTreeMap;
+use method::Method;
+use std::collections::HashMap;
+use std::fmt;
+use std::slice;
+use std::str::FromStr;
 use postgres::types::Type;
 
+/// Which operations a column is exposed to through the api.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visibility {
+    // readable and writable
+    Visible,
+    // never returned nor writable, as if the column did not exist
+    Hidden,
+    // returned but cannot be set by the clients
+    ReadOnly,
+    // can be set by the clients but is never returned
+    WriteOnly,
+}
+
+const VISIBLE: &'static str = "visible";
+const HIDDEN: &'static str = "hidden";
+const READ_ONLY: &'static str = "read_only";
+const WRITE_ONLY: &'static str = "write_only";
+
+impl Visibility {
+    pub fn is_readable(&self) -> bool {
+        *self == Visibility::Visible || *self == Visibility::ReadOnly
+    }
+
+    pub fn is_writable(&self) -> bool {
+        *self == Visibility::Visible || *self == Visibility::WriteOnly
+    }
+
+    pub fn from_access(readable: bool, writable: bool) -> Visibility {
+        match (readable, writable) {
+            (true, true) => Visibility::Visible,
+            (true, false) => Visibility::ReadOnly,
+            (false, true) => Visibility::WriteOnly,
+            (false, false) => Visibility::Hidden,
+        }
+    }
+
+    /// The most restrictive of the two visibilities.
+    pub fn restrict(&self, other: Visibility) -> Visibility {
+        Visibility::from_access(self.is_readable() && other.is_readable(),
+                                self.is_writable() && other.is_writable())
+    }
+}
+
+impl Default for Visibility {
+    fn default() -> Visibility {
+        Visibility::Visible
+    }
+}
+
+impl FromStr for Visibility {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            VISIBLE => Ok(Visibility::Visible),
+            HIDDEN => Ok(Visibility::Hidden),
+            READ_ONLY => Ok(Visibility::ReadOnly),
+            WRITE_ONLY => Ok(Visibility::WriteOnly),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Visibility {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Visibility::Visible => VISIBLE,
+            Visibility::Hidden => HIDDEN,
+            Visibility::ReadOnly => READ_ONLY,
+            Visibility::WriteOnly => WRITE_ONLY,
+        };
+        write!(fmt, "{}", s)
+    }
+}
+
+/// Privileges of the database role on a column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColumnPrivileges {
+    pub select: bool,
+    pub insert: bool,
+    pub update: bool,
+}
+
+impl Default for ColumnPrivileges {
+    fn default() -> ColumnPrivileges {
+        ColumnPrivileges { select: true, insert: true, update: true }
+    }
+}
+
+/// Privileges of the database role on a table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TablePrivileges {
+    pub select: bool,
+    pub insert: bool,
+    pub update: bool,
+    pub delete: bool,
+}
+
+impl TablePrivileges {
+    /// Whether the privileges are enough to serve the given method.
+    pub fn allows(&self, method: &Method) -> bool {
+        match *method {
+            Method::Get => self.select,
+            Method::Post => self.insert,
+            Method::Put => self.insert && self.update,
+            Method::Patch => self.update,
+            Method::Delete => self.delete,
+        }
+    }
+
+    pub fn any(&self) -> bool {
+        self.select || self.insert || self.update || self.delete
+    }
+}
+
+impl Default for TablePrivileges {
+    fn default() -> TablePrivileges {
+        TablePrivileges { select: true, insert: true, update: true, delete: true }
+    }
+}
+
+/// Column referenced by a single column foreign key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForeignKey {
+    pub table: String,
+    pub column: String,
+}
+
 #[derive(Debug, Clone)]
 pub struct Column {
     pub name: String,
@@ -16,10 +147,159 @@ pub struct Column {
     pub data_type: Type,
     pub character_maximum_length: Option<i32>,
     pub is_updatable: bool,
+    pub visibility: Visibility,
+    pub privileges: ColumnPrivileges,
+    // position of the column in the table, starting at 1
+    pub position: i16,
+    pub comment: Option<String>,
+    pub is_primary_key: bool,
+    pub foreign_key: Option<ForeignKey>,
+    // labels of an enum type, empty for the other types
+    pub enum_labels: Vec<String>,
+    // definitions of the check constraints on the column alone
+    pub checks: Vec<String>,
+    // the database computes the value of the generated columns and of the
+    // identity columns generated always, it cannot be written
+    pub is_generated: bool,
+}
+
+impl Column {
+    /// A nullable column of the given type, without default nor constraints,
+    /// which every operation can use. The position is 0 until the column is
+    /// given one.
+    pub fn new(name: &str, data_type: Type) -> Column {
+        Column {
+            name: name.into(),
+            default: None,
+            is_nullable: true,
+            data_type: data_type,
+            character_maximum_length: None,
+            is_updatable: true,
+            visibility: Visibility::Visible,
+            privileges: ColumnPrivileges::default(),
+            position: 0,
+            comment: None,
+            is_primary_key: false,
+            foreign_key: None,
+            enum_labels: vec![],
+            checks: vec![],
+            is_generated: false,
+        }
+    }
+
+    /// Whether the clients can set the column of the rows they insert.
+    pub fn can_insert(&self) -> bool {
+        self.visibility.is_writable() && self.privileges.insert && !self.is_generated
+    }
+
+    /// Whether the clients can set the column of the rows they update.
+    pub fn can_update(&self) -> bool {
+        self.visibility.is_writable() && self.privileges.update && !self.is_generated
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Table {
     pub name: String,
-    pub columns: BTreeMap<String, Column>,
+    pub schema: String,
+    pub columns: Columns,
+    pub privileges: TablePrivileges,
+    pub comment: Option<String>,
+}
+
+impl Table {
+    /// A table with all the privileges and the given columns.
+    pub fn new(schema: &str, name: &str, columns: Vec<Column>) -> Table {
+        let mut c = Columns::new();
+        for column in columns {
+            c.insert(column);
+        }
+        Table {
+            name: name.into(),
+            schema: schema.into(),
+            columns: c,
+            privileges: TablePrivileges::default(),
+            comment: None,
+        }
+    }
+}
+
+/// The columns of a table, in declaration order and indexed by name.
+#[derive(Debug, Clone, Default)]
+pub struct Columns {
+    columns: Vec<Column>,
+    index: HashMap<String, usize>,
+}
+
+impl Columns {
+    pub fn new() -> Columns {
+        Default::default()
+    }
+
+    /// Add a column after the existing ones, or replace the column with
+    /// the same name.
+    pub fn insert(&mut self, column: Column) {
+        match self.index.get(&column.name) {
+            Some(&i) => {
+                self.columns[i] = column;
+                return;
+            },
+            None => {},
+        };
+        self.index.insert(column.name.clone(), self.columns.len());
+        self.columns.push(column);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Column> {
+        self.index.get(name).map(|&i| &self.columns[i])
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Column> {
+        match self.index.get(name) {
+            Some(&i) => Some(&mut self.columns[i]),
+            None => None,
+        }
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.index.contains_key(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.columns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    /// Iterate over the names and columns in declaration order.
+    pub fn iter(&self) -> Iter {
+        Iter { inner: self.columns.iter() }
+    }
+}
+
+impl<'a> IntoIterator for &'a Columns {
+    type Item = (&'a String, &'a Column);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+pub struct Iter<'a> {
+    inner: slice::Iter<'a, Column>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a String, &'a Column);
+
+    fn next(&mut self) -> Option<(&'a String, &'a Column)> {
+        self.inner.next().map(|c| (&c.name, c))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
 }
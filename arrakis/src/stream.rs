@@ -0,0 +1,156 @@
+// Copyright 2016 Jeremy Letang.
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use cvt::Cell;
+use error::Error;
+use get;
+use schema::Column;
+use serde_json;
+use std::io::{self, Write};
+
+/// Receives the rows of a streamed GET request as they are read from the
+/// database.
+///
+/// Writing to a slow client blocks the writer, and so the reading of the
+/// next rows.
+pub trait RowWriter {
+    /// Called once the query succeeded, before the first row, with the
+    /// selected columns.
+    fn begin(&mut self, columns: &[&Column]) -> io::Result<()>;
+    /// Called for each row, the values are in the order of the columns.
+    fn row(&mut self, values: Vec<Cell>) -> io::Result<()>;
+    /// Called after the last row.
+    fn end(&mut self) -> io::Result<()>;
+    /// Called when the request failed, before or after `begin`. Once the
+    /// writer began, the error can only be reported by aborting the output.
+    fn fail(&mut self, e: Error) {
+        error!("streamed request failed, {}", e);
+    }
+}
+
+/// Write the rows as the json response of a GET request,
+/// i.e `{"data": [{...}, ...]}`.
+pub struct JsonRowWriter<W> {
+    out: W,
+    columns: Vec<String>,
+    first: bool,
+}
+
+impl<W: Write> JsonRowWriter<W> {
+    pub fn new(out: W) -> JsonRowWriter<W> {
+        JsonRowWriter {
+            out: out,
+            columns: vec![],
+            first: true,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.out
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> RowWriter for JsonRowWriter<W> {
+    fn begin(&mut self, columns: &[&Column]) -> io::Result<()> {
+        self.columns = columns.iter().map(|c| c.name.clone()).collect();
+        self.out.write_all(b"{\"data\":[")
+    }
+
+    fn row(&mut self, values: Vec<Cell>) -> io::Result<()> {
+        if !self.first {
+            self.out.write_all(b",")?;
+        }
+        self.first = false;
+        let values = values.into_iter().map(Cell::into_json).collect();
+        let row = get::row_to_json(&*self.columns, values);
+        match serde_json::to_writer(&mut self.out, &row) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(io::Error::new(io::ErrorKind::Other, format!("{}", e))),
+        }
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        self.out.write_all(b"]}")?;
+        self.out.flush()
+    }
+}
+
+pub fn write_error(e: io::Error) -> Error {
+    Error::InternalError(format!("unable to write the response, {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use postgres::types::Type;
+
+    fn column(name: &str) -> Column {
+        Column::new(name, Type::Text)
+    }
+
+    // accepts `limit` bytes, then fails like a closed connection
+    struct ClosedAfter {
+        written: Vec<u8>,
+        limit: usize,
+    }
+
+    impl Write for ClosedAfter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.written.len() + buf.len() > self.limit {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection closed"));
+            }
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_rows() {
+        let (id, name) = (column("id"), column("name"));
+        let mut w = JsonRowWriter::new(vec![]);
+        w.begin(&[&id, &name]).unwrap();
+        w.row(vec![Cell::Int(1), Cell::Text("a".into())]).unwrap();
+        w.row(vec![Cell::Int(2), Cell::Null]).unwrap();
+        w.end().unwrap();
+        assert_eq!(String::from_utf8(w.into_inner()).unwrap(),
+                   r#"{"data":[{"id":1,"name":"a"},{"id":2,"name":null}]}"#);
+    }
+
+    #[test]
+    fn json_without_rows() {
+        let id = column("id");
+        let mut w = JsonRowWriter::new(vec![]);
+        w.begin(&[&id]).unwrap();
+        w.end().unwrap();
+        assert_eq!(String::from_utf8(w.into_inner()).unwrap(), r#"{"data":[]}"#);
+    }
+
+    #[test]
+    fn client_gone_mid_stream() {
+        let id = column("id");
+        let mut w = JsonRowWriter::new(ClosedAfter { written: vec![], limit: 20 });
+        w.begin(&[&id]).unwrap();
+        w.row(vec![Cell::Int(1)]).unwrap();
+        let e = w.row(vec![Cell::Int(123456789)]).unwrap_err();
+        match write_error(e) {
+            Error::InternalError(msg) => assert!(msg.contains("connection closed"), msg),
+            e => panic!("unexpected error {:?}", e),
+        }
+        // the body already sent is truncated, never closed
+        let written = w.into_inner().written;
+        assert!(written.starts_with(b"{\"data\":[{\"id\":1},"));
+        assert!(!written.ends_with(b"]}"));
+    }
+}
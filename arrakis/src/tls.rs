@@ -0,0 +1,178 @@
+// Copyright 2016 Jeremy Letang.
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use config::TlsConfig;
+use openssl::ssl::{SslConnectorBuilder, SslMethod, SSL_VERIFY_NONE};
+use openssl::x509::X509_FILETYPE_PEM;
+use postgres::tls::openssl::OpenSsl;
+use r2d2_postgres::TlsMode;
+use std::fmt;
+use std::str::FromStr;
+
+/// How the connections to postgres are secured, the names and semantics
+/// follow the libpq `sslmode` parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SslMode {
+    // never use tls
+    Disable,
+    // use tls if the server supports it, certificates are not verified
+    Prefer,
+    // always use tls, certificates are not verified
+    Require,
+    // always use tls, verify the server certificate chain and host name
+    VerifyFull,
+}
+
+const DISABLE: &'static str = "disable";
+const PREFER: &'static str = "prefer";
+const REQUIRE: &'static str = "require";
+const VERIFY_FULL: &'static str = "verify-full";
+
+impl FromStr for SslMode {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::SslMode::*;
+        match &*s.to_lowercase() {
+            DISABLE => Ok(Disable),
+            PREFER => Ok(Prefer),
+            REQUIRE => Ok(Require),
+            VERIFY_FULL => Ok(VerifyFull),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for SslMode {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        use self::SslMode::*;
+        let s = match *self {
+            Disable => DISABLE,
+            Prefer => PREFER,
+            Require => REQUIRE,
+            VerifyFull => VERIFY_FULL,
+        };
+        write!(fmt, "{}", s)
+    }
+}
+
+/// Build the tls mode used by the postgres connection manager.
+pub fn make_tls_mode(conf: &TlsConfig) -> Result<TlsMode, String> {
+    let mode = conf.ssl_mode();
+    if mode == SslMode::Disable {
+        return Ok(TlsMode::None);
+    }
+
+    let mut builder = match SslConnectorBuilder::new(SslMethod::tls()) {
+        Ok(b) => b,
+        Err(e) => return Err(format!("unable to initialize tls, {}", e)),
+    };
+
+    {
+        let ctx = builder.builder_mut();
+        if let Some(ref ca_file) = conf.ca_file {
+            if let Err(e) = ctx.set_ca_file(ca_file) {
+                return Err(format!("unable to load ca bundle {}, {}", ca_file, e));
+            }
+        }
+        match (conf.cert_file.as_ref(), conf.key_file.as_ref()) {
+            (Some(cert), Some(key)) => {
+                if let Err(e) = ctx.set_certificate_file(cert, X509_FILETYPE_PEM) {
+                    return Err(format!("unable to load client certificate {}, {}", cert, e));
+                }
+                if let Err(e) = ctx.set_private_key_file(key, X509_FILETYPE_PEM) {
+                    return Err(format!("unable to load client key {}, {}", key, e));
+                }
+                if let Err(e) = ctx.check_private_key() {
+                    return Err(format!("client key {} do not match certificate {}, {}",
+                                       key, cert, e));
+                }
+            },
+            (None, None) => {},
+            _ => return Err(format!("client certificate and key must be specified together")),
+        }
+        if mode != SslMode::VerifyFull {
+            ctx.set_verify(SSL_VERIFY_NONE);
+        }
+    }
+
+    let mut negotiator = OpenSsl::from(builder.build());
+    if mode != SslMode::VerifyFull {
+        negotiator.danger_disable_hostname_verification(true);
+    }
+
+    match mode {
+        SslMode::Prefer => Ok(TlsMode::Prefer(Box::new(negotiator))),
+        _ => Ok(TlsMode::Require(Box::new(negotiator))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::TlsConfig;
+    use r2d2_postgres::TlsMode;
+    use std::str::FromStr;
+
+    fn conf(mode: &str) -> TlsConfig {
+        TlsConfig { mode: mode.into(), ..Default::default() }
+    }
+
+    fn error(conf: &TlsConfig) -> String {
+        match make_tls_mode(conf) {
+            Err(e) => e,
+            Ok(_) => panic!("expected an error for {:?}", conf),
+        }
+    }
+
+    #[test]
+    fn ssl_mode_names() {
+        for &mode in &[SslMode::Disable, SslMode::Prefer, SslMode::Require, SslMode::VerifyFull] {
+            assert_eq!(SslMode::from_str(&*mode.to_string()), Ok(mode));
+        }
+        assert_eq!(SslMode::from_str("VERIFY-FULL"), Ok(SslMode::VerifyFull));
+        assert_eq!(SslMode::from_str("verify-ca"), Err(()));
+        assert_eq!(conf("unknown").ssl_mode(), SslMode::Disable);
+    }
+
+    #[test]
+    fn disable_and_prefer_modes() {
+        match make_tls_mode(&conf("disable")) {
+            Ok(TlsMode::None) => {},
+            _ => panic!("expected no tls"),
+        }
+        match make_tls_mode(&conf("prefer")) {
+            Ok(TlsMode::Prefer(_)) => {},
+            _ => panic!("expected prefer"),
+        }
+        match make_tls_mode(&conf("verify-full")) {
+            Ok(TlsMode::Require(_)) => {},
+            _ => panic!("expected require"),
+        }
+    }
+
+    #[test]
+    fn missing_files() {
+        let mut c = conf("require");
+        c.ca_file = Some("/nonexistent/ca.pem".into());
+        assert!(error(&c).starts_with("unable to load ca bundle /nonexistent/ca.pem"));
+
+        let mut c = conf("require");
+        c.cert_file = Some("/nonexistent/client.crt".into());
+        c.key_file = Some("/nonexistent/client.key".into());
+        assert!(error(&c).starts_with("unable to load client certificate /nonexistent/client.crt"));
+    }
+
+    #[test]
+    fn certificate_without_key() {
+        let mut c = conf("require");
+        c.cert_file = Some("client.crt".into());
+        assert_eq!(error(&c), "client certificate and key must be specified together");
+        let mut c = conf("require");
+        c.key_file = Some("client.key".into());
+        assert_eq!(error(&c), "client certificate and key must be specified together");
+    }
+}
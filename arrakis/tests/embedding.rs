@@ -0,0 +1,77 @@
+// Copyright 2016 Jeremy Letang.
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The database tests are ignored by default, they run against the database
+// of ARRAKIS_TEST_DATABASE_URL with:
+//
+//     ARRAKIS_TEST_DATABASE_URL=postgres://... cargo test -- --ignored
+
+extern crate arrakis;
+extern crate postgres;
+extern crate serde_json;
+
+use arrakis::Arrakis;
+use arrakis::config::Config;
+use arrakis::context::Context;
+use arrakis::query::Query;
+use postgres::{Connection, TlsMode};
+use serde_json::Value;
+use std::env;
+
+const SCHEMA: &'static str = "arrakis_test_embedding";
+
+const SETUP: &'static str = "
+DROP SCHEMA IF EXISTS arrakis_test_embedding CASCADE;
+CREATE SCHEMA arrakis_test_embedding;
+CREATE TABLE arrakis_test_embedding.users (
+    id integer PRIMARY KEY,
+    name text
+);
+CREATE TABLE arrakis_test_embedding.posts (
+    id integer PRIMARY KEY,
+    author_id integer REFERENCES arrakis_test_embedding.users (id),
+    title text
+);
+CREATE TABLE arrakis_test_embedding.comments (
+    id integer PRIMARY KEY,
+    post_id integer REFERENCES arrakis_test_embedding.posts (id),
+    body text
+);
+INSERT INTO arrakis_test_embedding.users VALUES (1, 'alice');
+INSERT INTO arrakis_test_embedding.posts VALUES (1, 1, 'first'), (2, NULL, 'second');
+INSERT INTO arrakis_test_embedding.comments VALUES (1, 1, 'nice');";
+
+fn setup() -> Arrakis {
+    let url = env::var("ARRAKIS_TEST_DATABASE_URL")
+        .expect("ARRAKIS_TEST_DATABASE_URL must be set to run the database tests");
+    let conn = Connection::connect(&*url, TlsMode::None).unwrap();
+    conn.batch_execute(SETUP).unwrap();
+    let config = Config::builder().schemas(vec![SCHEMA]).build();
+    Arrakis::with_config(&*url, config).unwrap()
+}
+
+fn json(s: &str) -> Value {
+    serde_json::from_str(s).unwrap()
+}
+
+#[test]
+#[ignore]
+fn embedded_resources() {
+    let ar = setup();
+    let ctx = Context::new();
+
+    let q = Query::parse("select=title,author:users(name),comments(body)&order=id").unwrap();
+    let res = ar.get(&ctx, "posts", &q).unwrap().unwrap();
+    assert_eq!(res, json("[{\"title\": \"first\", \"author\": {\"name\": \"alice\"}, \
+                                \"comments\": [{\"body\": \"nice\"}]}, \
+                          {\"title\": \"second\", \"author\": null, \"comments\": []}]"));
+
+    let q = Query::parse("select=name,posts(title,comments(*))").unwrap();
+    let res = ar.get(&ctx, "users", &q).unwrap().unwrap();
+    assert_eq!(res, json("[{\"name\": \"alice\", \"posts\": [{\"title\": \"first\", \
+                          \"comments\": [{\"id\": 1, \"post_id\": 1, \"body\": \"nice\"}]}]}]"));
+}
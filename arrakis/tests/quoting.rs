@@ -0,0 +1,100 @@
+// Copyright 2016 Jeremy Letang.
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The database tests are ignored by default, they run against the database
+// of ARRAKIS_TEST_DATABASE_URL with:
+//
+//     ARRAKIS_TEST_DATABASE_URL=postgres://... cargo test -- --ignored
+
+extern crate arrakis;
+extern crate postgres;
+extern crate serde_json;
+
+use arrakis::Arrakis;
+use arrakis::common::{quote_column, quote_ident};
+use arrakis::config::Config;
+use arrakis::context::Context;
+use arrakis::filters::Filter;
+use arrakis::ordering::Ordering;
+use arrakis::query::Query;
+use postgres::{Connection, TlsMode};
+use serde_json::Value;
+use std::env;
+
+const SCHEMA: &'static str = "arrakis_test_quoting";
+
+const SETUP: &'static str = "
+DROP SCHEMA IF EXISTS arrakis_test_quoting CASCADE;
+CREATE SCHEMA arrakis_test_quoting;
+CREATE TABLE arrakis_test_quoting.\"user\" (
+    id serial PRIMARY KEY,
+    \"CreatedAt\" text,
+    \"order\" integer
+);
+CREATE TABLE arrakis_test_quoting.\"order\" (
+    id serial PRIMARY KEY,
+    \"select\" text
+);";
+
+fn setup() -> Arrakis {
+    let url = env::var("ARRAKIS_TEST_DATABASE_URL")
+        .expect("ARRAKIS_TEST_DATABASE_URL must be set to run the database tests");
+    let conn = Connection::connect(&*url, TlsMode::None).unwrap();
+    conn.batch_execute(SETUP).unwrap();
+    let config = Config::builder().schemas(vec![SCHEMA]).build();
+    Arrakis::with_config(&*url, config).unwrap()
+}
+
+fn json(s: &str) -> Value {
+    serde_json::from_str(s).unwrap()
+}
+
+#[test]
+fn quote_ident_escapes_double_quotes() {
+    assert_eq!(quote_ident("user"), "\"user\"");
+    assert_eq!(quote_ident("CreatedAt"), "\"CreatedAt\"");
+    assert_eq!(quote_ident("a\"b"), "\"a\"\"b\"");
+    assert_eq!(quote_column(Some("order"), "select"), "\"order\".\"select\"");
+    assert_eq!(quote_column(None, "select"), "\"select\"");
+}
+
+#[test]
+#[ignore]
+fn reserved_and_mixed_case_names() {
+    let ar = setup();
+    let ctx = Context::new();
+
+    let body = json("{\"CreatedAt\": \"yesterday\", \"order\": 1}");
+    ar.post(&ctx, "user", &Query::new(), body).unwrap();
+    let body = json("{\"CreatedAt\": \"today\", \"order\": 2}");
+    ar.post(&ctx, "user", &Query::new(), body).unwrap();
+
+    let q = Query::builder()
+        .select(vec!["CreatedAt", "order"])
+        .filter(Filter::GreaterThan("order".into(), "0".into()))
+        .order(Ordering::Desc("CreatedAt".into()))
+        .build();
+    let res = ar.get(&ctx, "user", &q).unwrap().unwrap();
+    assert_eq!(res, json("[{\"CreatedAt\": \"yesterday\", \"order\": 1}, \
+                          {\"CreatedAt\": \"today\", \"order\": 2}]"));
+
+    let q = Query::builder()
+        .filter(Filter::Equal("CreatedAt".into(), "today".into()))
+        .build();
+    ar.patch(&ctx, "user", &q, json("{\"order\": 3}")).unwrap();
+    ar.delete(&ctx, "user", &Query::builder()
+              .filter(Filter::Equal("order".into(), "1".into()))
+              .build()).unwrap();
+    let q = Query::builder().select(vec!["order"]).build();
+    let res = ar.get(&ctx, "user", &q).unwrap().unwrap();
+    assert_eq!(res, json("[{\"order\": 3}]"));
+
+    ar.post(&ctx, "order", &Query::new(), json("{\"select\": \"from\"}")).unwrap();
+    let q = Query::builder().select(vec!["select"]).build();
+    let res = ar.get(&ctx, "order", &q).unwrap().unwrap();
+    assert_eq!(res, json("[{\"select\": \"from\"}]"));
+}
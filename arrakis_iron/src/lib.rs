@@ -14,13 +14,16 @@ extern crate serde_json;
 mod response;
 
 use arrakis::Arrakis;
+use arrakis::error::Error as ArrakisError;
+use arrakis::format::Format;
 use arrakis::method::Method as ArrakisMethod;
 use arrakis::queries::queries_from_hashmap;
 use iron::{Handler, IronResult, Request, Response, Url, Plugin};
+use iron::headers::{Authorization, Bearer};
 use iron::request::Body;
 use iron::method::Method;
-use iron::status::Status;
-use response::{write_arrakis_response, write_error_response};
+use response::{write_arrakis_response, write_encoded_response, write_formatted_response,
+               write_stream_response};
 use std::io::Read;
 use urlencoded::UrlEncodedQuery;
 use std::collections::HashMap;
@@ -45,28 +48,83 @@ impl ArrakisHandler {
             Err(e) => Err(format!("{}", e))
         }
     }
+
+    pub fn from_config(config: Config) -> Result<ArrakisHandler, String> {
+        match Arrakis::from_config(config) {
+            Ok(a) => Ok(ArrakisHandler {ar: a}),
+            Err(e) => Err(format!("{}", e))
+        }
+    }
 }
 
 impl Handler for ArrakisHandler {
     fn handle(&self, req: &mut Request) -> IronResult<Response> {
-        let body = read_body(&mut req.body);
+        let raw_body = read_body(&mut req.body);
+        let body = String::from_utf8_lossy(&*raw_body).into_owned();
         let model = extract_model_from_url(&req.url);
 
         let qmap = match req.get::<UrlEncodedQuery>() {
             Ok(hashmap) => hashmap,
             Err(_) =>
                 // return Ok(response::write_error_response(
-                // &format!("unable to read queries: {}", e),
+                // "invalid_queries", &format!("unable to read queries: {}", e),
                 //    Status::InternalServerError)),
                 HashMap::new(),
         };
         let queries = queries_from_hashmap(&qmap);
 
+        let token = req.headers.get::<Authorization<Bearer>>().map(|a| &*a.0.token);
+        let headers = req.headers.iter()
+            .map(|h| (h.name().to_string(), h.value_string()))
+            .collect::<Vec<(String, String)>>();
+        let mut ctx = match self.ar.authenticate(token) {
+            Ok(ctx) => ctx,
+            Err(e) => return Ok(write_arrakis_response(Err(e))),
+        };
+        self.ar.set_request_metadata(&mut ctx, &*headers);
+        ctx.client_ip = Some(req.remote_addr.ip().to_string());
+
         match arrakis_of_iron_method(&req.method) {
-            Some(m) => Ok(write_arrakis_response(self.ar.any(&m, &*model, &queries, body))),
+            Some(m) => {
+                let content_type = find_header(&*headers, "content-type")
+                    .and_then(Format::of_content_type);
+                let accept = find_header(&*headers, "accept");
+                let format = if m == ArrakisMethod::Get {
+                    self.ar.negotiate_format(accept, &queries)
+                } else {
+                    self.ar.negotiate_value_format(accept, &queries)
+                };
+                let format = match format {
+                    Ok(f) => f,
+                    Err(e) => return Ok(write_arrakis_response(Err(e))),
+                };
+                if m == ArrakisMethod::Post && content_type == Some(Format::Csv) {
+                    let res = self.ar.parse_import_options(&queries)
+                        .and_then(|o| self.ar.post_csv(&ctx, &*model, &o, &*body));
+                    return Ok(write_encoded_response(res, format));
+                }
+                if m == ArrakisMethod::Post && content_type == Some(Format::Ndjson) {
+                    let res = self.ar.post_lines(&ctx, &*model, &*body);
+                    return Ok(write_encoded_response(res, format));
+                }
+                if m == ArrakisMethod::Get {
+                    if self.ar.get_config().stream() {
+                        return Ok(write_stream_response(&self.ar, ctx, &*model, &queries,
+                                                        format));
+                    }
+                    if format != Format::Json {
+                        let res = self.ar.parse_query(&queries)
+                            .and_then(|q| self.ar.get_formatted(&ctx, &*model, &q, format));
+                        return Ok(write_formatted_response(res, format, &*model));
+                    }
+                }
+                let body_format = content_type.unwrap_or(Format::Json);
+                let res = self.ar.any_encoded(&ctx, &m, &*model, &queries, body_format, &*raw_body);
+                Ok(write_encoded_response(res, format))
+            },
             None => {
-                let estr = format!("method not allowed {}", &req.method);
-                Ok(write_error_response(&*estr, Status::MethodNotAllowed))
+                let err = self.ar.unsupported_method(&ctx, &*format!("{}", &req.method), &*model);
+                Ok(write_arrakis_response(Err(err)))
             }
         }
     }
@@ -83,12 +141,18 @@ fn arrakis_of_iron_method(m: &Method) -> Option<ArrakisMethod> {
     }
 }
 
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter()
+        .find(|&&(ref n, _)| n.to_lowercase() == name)
+        .map(|&(_, ref v)| &**v)
+}
+
 fn extract_model_from_url(url: &Url) -> String {
     return url.path().last().unwrap().to_string();
 }
 
-fn read_body<'a, 'b>(body: &mut Body<'a, 'b>) -> String {
-    let mut buf = String::new();
-    let _ = body.read_to_string(&mut buf);
+fn read_body<'a, 'b>(body: &mut Body<'a, 'b>) -> Vec<u8> {
+    let mut buf = vec![];
+    let _ = body.read_to_end(&mut buf);
     return buf;
 }
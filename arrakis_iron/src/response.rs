@@ -5,65 +5,141 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use arrakis::error::Error as ArError;
-use iron::headers::ContentLength;
+use arrakis::Arrakis;
+use arrakis::context::Context;
+use arrakis::error::{self, Error as ArError};
+use arrakis::format::Format;
+use arrakis::queries::Queries;
+use arrakis::query::Query;
+use iron::headers::{ContentLength, ContentType};
+use iron::mime::{Mime, TopLevel, SubLevel};
 use iron::modifiers::Header;
 use iron::status::Status;
 use iron::prelude::Set;
 use iron::Response;
+use iron::response::WriteBody;
 use serde_json::Value as JsonValue;
-use serde_json::Map as JsonMap;
+use std::io::{self, Write};
 
-pub fn arrakis_error_to_status(ar_err: ArError) -> Status {
-    match ar_err {
-        ArError::InvalidFilter(..) => Status::BadRequest,
-        ArError::InvalidFilterType(..) => Status::BadRequest,
-        ArError::InvalidFilterSyntax(..) => Status::BadRequest,
-        ArError::InvalidColumnType(..) => Status::BadRequest,
-        ArError::NotFound(..) => Status::NotFound,
-        ArError::UnknowModel(..) => Status::BadRequest,
-        ArError::UnknowColumn(..) => Status::BadRequest,
-        ArError::InternalError(..) => Status::InternalServerError,
-        _ => Status::BadRequest,
+pub fn make_success_response(value: JsonValue, format: Format) -> Vec<u8> {
+    format.encode_value(value)
+}
+
+pub fn make_error_response(problem: &JsonValue) -> Vec<u8> {
+    ::serde_json::ser::to_vec(problem).unwrap()
+}
+
+pub fn make_arrakis_response(ar_res: Result<Option<JsonValue>, ArError>, format: Format)
+                             -> (Vec<u8>, Status, Mime) {
+    match ar_res {
+        Ok(jv) => match jv {
+            Some(v) => (make_success_response(v, format), Status::Ok, format_mime(format)),
+            None => (vec![], Status::NoContent, format_mime(format)),
+        },
+        Err(e) => (make_error_response(&e.to_problem()),
+                   Status::from_u16(e.status()),
+                   problem_mime()),
     }
 }
 
-pub fn make_success_response(value: JsonValue) -> Vec<u8> {
-    let mut map = JsonMap::new();
-    map.insert(String::from("data"), value);
-    let value = JsonValue::Object(map);
-    ::serde_json::ser::to_vec(&value).unwrap()
+pub fn write_arrakis_response(ar_res: Result<Option<JsonValue>, ArError>) -> Response {
+    write_encoded_response(ar_res, Format::Json)
 }
 
-pub fn make_error_response(estr: &str) -> Vec<u8> {
-    let mut map = JsonMap::new();
-    map.insert("error".into(), JsonValue::String(estr.into()));
-    let value = JsonValue::Object(map);
-    ::serde_json::ser::to_vec(&value).unwrap()
+/// Same as `write_arrakis_response`, the value being encoded in the given
+/// format, see `Format::encode_value`.
+pub fn write_encoded_response(ar_res: Result<Option<JsonValue>, ArError>, format: Format)
+                              -> Response {
+    let headers = match ar_res {
+        Err(ref e) => e.headers(),
+        _ => vec![],
+    };
+    let (body, code, mime) = make_arrakis_response(ar_res, format);
+    let mut res = write_response(&*body, code, mime);
+    for (name, value) in headers {
+        res.headers.set_raw(name, vec![value.into_bytes()]);
+    }
+    return res;
 }
 
-pub fn make_arrakis_response(ar_res: Result<Option<JsonValue>, ArError>) -> (Vec<u8>, Status) {
+pub fn write_formatted_response(ar_res: Result<Vec<u8>, ArError>, format: Format, model: &str)
+                                -> Response {
     match ar_res {
-        Ok(jv) => match jv {
-            Some(v) => (make_success_response(v), Status::Ok),
-            None => (vec![], Status::NoContent),
+        Ok(body) => {
+            let res = write_response(&*body, Status::Ok, format_mime(format));
+            set_content_disposition(res, format, model)
         },
-        Err(e) => (make_error_response(&*format!("{}", e)), arrakis_error_to_status(e))
+        Err(e) => write_arrakis_response(Err(e)),
     }
 }
 
-pub fn write_arrakis_response(ar_res: Result<Option<JsonValue>, ArError>) -> Response {
-    let (body, code) = make_arrakis_response(ar_res);
-    return write_response(&*body, code)
+pub fn write_error_response(code: &str, estr: &str, status: Status) -> Response {
+    let title = status.canonical_reason().unwrap_or("");
+    let body = make_error_response(&error::problem(status.to_u16(), code, title, estr));
+    return write_response(&*body, status, problem_mime());
+}
+
+// body of a streamed GET request, the rows are written as they are read
+// from the database.
+struct StreamBody {
+    ar: Arrakis,
+    ctx: Context,
+    model: String,
+    query: Query,
+    format: Format,
+}
+
+impl WriteBody for StreamBody {
+    fn write_body(&mut self, res: &mut Write) -> io::Result<()> {
+        let mut writer = self.format.row_writer(res);
+        match self.ar.get_stream(&self.ctx, &*self.model, &self.query, &mut *writer) {
+            Ok(()) => Ok(()),
+            // the status is already sent, abort the response
+            Err(e) => Err(io::Error::new(io::ErrorKind::Other, format!("{}", e))),
+        }
+    }
+}
+
+/// Stream the response of a GET request, the request is validated first
+/// so the usual errors are still reported with their status.
+pub fn write_stream_response(ar: &Arrakis, ctx: Context, model: &str, queries: &Queries,
+                             format: Format) -> Response {
+    let query = match ar.parse_query(queries).and_then(|q| {
+        ar.prepare_get(&ctx, model, &q).map(|_| q)
+    }) {
+        Ok(q) => q,
+        Err(e) => return write_arrakis_response(Err(e)),
+    };
+    let body = StreamBody {
+        ar: ar.clone(),
+        ctx: ctx,
+        model: model.to_string(),
+        query: query,
+        format: format,
+    };
+    let res = Response::with((Status::Ok, Box::new(body) as Box<WriteBody>));
+    let res = res.set(Header(ContentType(format_mime(format))));
+    return set_content_disposition(res, format, model);
+}
+
+fn set_content_disposition(mut res: Response, format: Format, model: &str) -> Response {
+    if let Some(value) = format.content_disposition(model) {
+        res.headers.set_raw("Content-Disposition", vec![value.into_bytes()]);
+    }
+    return res;
+}
+
+fn format_mime(format: Format) -> Mime {
+    format.content_type().parse().unwrap()
 }
 
-pub fn write_error_response(estr: &str, code: Status) -> Response {
-    let body = make_error_response(estr);
-    return write_response(&*body, code);
+fn problem_mime() -> Mime {
+    Mime(TopLevel::Application, SubLevel::Ext("problem+json".into()), vec![])
 }
 
-fn write_response(body: &[u8], code: Status) -> Response {
+fn write_response(body: &[u8], code: Status, mime: Mime) -> Response {
     let mut res = Response::with((code, body));
     res = res.set(Header(ContentLength(body.len() as u64)));
+    res = res.set(Header(ContentType(mime)));
     return res;
 }
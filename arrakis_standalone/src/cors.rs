@@ -5,12 +5,13 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use arrakis::config::CorsConfig;
 use futures::{future, Future, BoxFuture};
 use hyper::server::{Service, Request, Response};
 use hyper::{self, Method};
 use hyper::header::{AccessControlAllowOrigin, ContentLength, CacheControl,
                     AccessControlAllowHeaders, AccessControlAllowMethods,
-                    CacheDirective};
+                    AccessControlMaxAge, CacheDirective, Origin, Vary};
 use hyper::status::StatusCode;
 use hyper::header::ContentType;
 use hyper::mime::{Mime, TopLevel, SubLevel};
@@ -18,25 +19,46 @@ use unicase::UniCase;
 
 pub struct Cors<S> {
     service: S,
+    conf: CorsConfig,
 }
 
 impl<S> Cors<S>
     where S: Service<Request=Request, Response=Response, Error=hyper::Error>,
           <S as hyper::client::Service>::Future: Send + 'static {
-    pub fn new(service: S) -> Cors<S> {
+    pub fn new(service: S, conf: CorsConfig) -> Cors<S> {
         Cors {
             service: service,
+            conf: conf,
         }
     }
 
-    fn handle_options(& self) -> BoxFuture<Response, hyper::Error> {
-        let response = Response::new()
+    // the value of the allow origin header for the origin of the request,
+    // None if the origin is not allowed.
+    fn allow_origin(&self, req: &Request) -> Option<AccessControlAllowOrigin> {
+        let origins = &self.conf.allowed_origins;
+        if origins.is_empty() || origins.iter().any(|o| o == "*") {
+            return Some(AccessControlAllowOrigin::Any);
+        }
+        match req.headers().get::<Origin>() {
+            Some(origin) => {
+                let origin = format!("{}", origin);
+                if origins.contains(&origin) {
+                    Some(AccessControlAllowOrigin::Value(origin))
+                } else {
+                    None
+                }
+            },
+            None => None,
+        }
+    }
+
+    fn handle_options(&self, req: Request) -> BoxFuture<Response, hyper::Error> {
+        let mut response = Response::new()
             .with_header(ContentLength(0))
-            .with_header(AccessControlAllowOrigin::Any)
             .with_header(
-                AccessControlAllowHeaders(vec![
-                    UniCase("content-type".to_owned()),
-                ])
+                AccessControlAllowHeaders(
+                    self.conf.allowed_headers.iter().map(|h| UniCase(h.clone())).collect()
+                )
             )
             .with_header(
                 AccessControlAllowMethods(vec![
@@ -55,13 +77,18 @@ impl<S> Cors<S>
                 ])
             )
             .with_status(StatusCode::Ok);
-        future::ok(response).boxed()
+        if let Some(max_age) = self.conf.max_age {
+            response = response.with_header(AccessControlMaxAge(max_age));
+        }
+        let allow = self.allow_origin(&req);
+        future::ok(with_allow_origin(response, allow)).boxed()
     }
 
     fn handle_others(&self, req: Request) -> BoxFuture<Response, hyper::Error> {
+        let allow = self.allow_origin(&req);
         self.service.call(req)
             .and_then(move |res| {
-                Ok(res.with_header(AccessControlAllowOrigin::Any))
+                Ok(with_allow_origin(res, allow))
             }).boxed()
     }
 }
@@ -76,8 +103,17 @@ impl<S> Service for Cors<S>
 
     fn call(&self, req: Self::Request) -> Self::Future {
         match req.method() {
-            &Method::Options => self.handle_options(),
+            &Method::Options => self.handle_options(req),
             _ => self.handle_others(req),
         }
     }
 }
+
+fn with_allow_origin(res: Response, allow: Option<AccessControlAllowOrigin>) -> Response {
+    match allow {
+        Some(AccessControlAllowOrigin::Any) => res.with_header(AccessControlAllowOrigin::Any),
+        Some(allow) => res.with_header(allow)
+            .with_header(Vary::Items(vec![UniCase("origin".to_owned())])),
+        None => res,
+    }
+}
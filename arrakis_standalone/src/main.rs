@@ -8,6 +8,8 @@
 #![feature(slice_patterns)]
 
 extern crate arrakis;
+extern crate chan;
+extern crate chan_signal;
 extern crate clap;
 extern crate futures;
 extern crate hyper;
@@ -21,27 +23,42 @@ extern crate unicase;
 
 use arrakis::Arrakis;
 use arrakis::config::Config;
+use chan_signal::Signal;
 use cors::Cors;
 use clap::{App, Arg};
 use service::{ArrakisService, Conf};
 use hyper::server::Http;
 use metrics::Metrics;
 use hyper::server::NewService;
+use std::io::{self, Write};
+use std::process;
+use std::str::FromStr;
+use std::thread;
 
 mod cors;
 mod metrics;
 mod response;
 mod service;
+mod stream;
 
 const DEFAULT_HTTP_ADDR: &'static str = "0.0.0.0:1492";
 
 struct CmdLineArgs {
     pub addr: String,
-    pub pq_addr: String,
+    pub config: Option<String>,
+    pub pq_addr: Option<String>,
+    pub timeout: Option<String>,
+    pub pool_size: Option<String>,
+    pub statement_timeout: Option<String>,
+    pub sslmode: Option<String>,
+    pub sslrootcert: Option<String>,
+    pub sslcert: Option<String>,
+    pub sslkey: Option<String>,
     pub disable_metrics: bool,
     pub include: Option<String>,
     pub exclude: Option<String>,
     pub with_docs: bool,
+    pub read_only: bool,
 }
 
 fn parse_cmdline() -> CmdLineArgs {
@@ -53,11 +70,44 @@ fn parse_cmdline() -> CmdLineArgs {
              .long("addr")
              .help("http address of the server")
              .default_value(DEFAULT_HTTP_ADDR))
+        .arg(Arg::with_name("config")
+             .long("config")
+             .help("configuration file (toml, yaml or json), overridden by the \
+                    ARRAKIS_* environment variables and the command line arguments")
+             .takes_value(true))
         .arg(Arg::with_name("pq-addr")
              .long("pq-addr")
              .help("postgres server address")
-             .takes_value(true)
-             .required(true))
+             .takes_value(true))
+        .arg(Arg::with_name("timeout")
+             .long("timeout")
+             .help("postgres connection timeout in seconds")
+             .takes_value(true))
+        .arg(Arg::with_name("pool-size")
+             .long("pool-size")
+             .help("maximum number of postgres connections")
+             .takes_value(true))
+        .arg(Arg::with_name("statement-timeout")
+             .long("statement-timeout")
+             .help("maximum duration of a request statement in milliseconds")
+             .takes_value(true))
+        .arg(Arg::with_name("sslmode")
+             .long("sslmode")
+             .help("tls mode of the postgres connections")
+             .possible_values(&["disable", "prefer", "require", "verify-full"])
+             .takes_value(true))
+        .arg(Arg::with_name("sslrootcert")
+             .long("sslrootcert")
+             .help("ca bundle used to verify the postgres server certificate")
+             .takes_value(true))
+        .arg(Arg::with_name("sslcert")
+             .long("sslcert")
+             .help("client certificate used to authenticate to postgres")
+             .takes_value(true))
+        .arg(Arg::with_name("sslkey")
+             .long("sslkey")
+             .help("private key of the client certificate")
+             .takes_value(true))
         .arg(Arg::with_name("include")
              .long("include")
              .help("specify which tables should be included from the auto generated api")
@@ -72,15 +122,34 @@ fn parse_cmdline() -> CmdLineArgs {
         .arg(Arg::with_name("with-docs")
              .long("with-docs")
              .help("with builtin docs endpoint"))
+        .arg(Arg::with_name("read-only")
+             .long("read-only")
+             .help("only allow GET requests"))
         .get_matches();
 
     CmdLineArgs {
         addr: matches.value_of("addr").unwrap().into(),
-        pq_addr: matches.value_of("pq-addr").unwrap().into(),
+        config: matches.value_of("config").map_or(None, |s| Some(s.into())),
+        pq_addr: matches.value_of("pq-addr").map_or(None, |s| Some(s.into())),
+        timeout: matches.value_of("timeout").map_or(None, |s| Some(s.into())),
+        pool_size: matches.value_of("pool-size").map_or(None, |s| Some(s.into())),
+        statement_timeout: matches.value_of("statement-timeout").map_or(None, |s| Some(s.into())),
+        sslmode: matches.value_of("sslmode").map_or(None, |s| Some(s.into())),
+        sslrootcert: matches.value_of("sslrootcert").map_or(None, |s| Some(s.into())),
+        sslcert: matches.value_of("sslcert").map_or(None, |s| Some(s.into())),
+        sslkey: matches.value_of("sslkey").map_or(None, |s| Some(s.into())),
         disable_metrics: matches.is_present("disable-metrics"),
         include: matches.value_of("include").map_or(None, |s| Some(s.into())),
         exclude: matches.value_of("exclude").map_or(None, |s| Some(s.into())),
         with_docs: matches.is_present("with-docs"),
+        read_only: matches.is_present("read-only"),
+    }
+}
+
+fn parse_arg<T: FromStr>(name: &str, value: &str) -> Result<T, String> {
+    match value.parse::<T>() {
+        Ok(v) => Ok(v),
+        Err(_) => Err(format!("invalid value {} for {}", value, name)),
     }
 }
 
@@ -89,25 +158,91 @@ fn split_list(l: Option<&String>) -> Vec<&str> {
         .filter_map(|s| if s.is_empty() { None } else { Some(*s) }).collect()
 }
 
+// load the config file if any, then apply the environment and the command
+// line arguments on top of it.
+fn make_config(args: &CmdLineArgs) -> Result<Config, String> {
+    let config = match args.config {
+        Some(ref path) => Config::from_file(path)?,
+        None => Config::default(),
+    };
+    let config = config.merge_env()?;
+    let mut tls = config.tls().clone();
+    if let Some(ref mode) = args.sslmode { tls.mode = mode.clone(); }
+    if let Some(ref ca) = args.sslrootcert { tls.ca_file = Some(ca.clone()); }
+    if let Some(ref cert) = args.sslcert { tls.cert_file = Some(cert.clone()); }
+    if let Some(ref key) = args.sslkey { tls.key_file = Some(key.clone()); }
+    let mut builder = config.into_builder().tls(tls);
+    if let Some(ref pq_addr) = args.pq_addr {
+        builder = builder.database_url(pq_addr);
+    }
+    if let Some(ref timeout) = args.timeout {
+        builder = builder.timeout(parse_arg("timeout", timeout)?);
+    }
+    if let Some(ref pool_size) = args.pool_size {
+        builder = builder.pool_size(parse_arg("pool-size", pool_size)?);
+    }
+    if let Some(ref statement_timeout) = args.statement_timeout {
+        builder = builder.statement_timeout(parse_arg("statement-timeout", statement_timeout)?);
+    }
+    if args.read_only {
+        builder = builder.read_only(true);
+    }
+    if args.exclude.is_some() {
+        builder = builder.excluded(split_list(args.exclude.as_ref()));
+    }
+    if args.include.is_some() {
+        builder = builder.included(split_list(args.include.as_ref()));
+    }
+    Ok(builder.build())
+}
+
+fn reload_on_sighup(arrakis: Arrakis, signal: chan::Receiver<Signal>) {
+    thread::spawn(move || {
+        for _ in signal.iter() {
+            info!("received SIGHUP, reloading the schema");
+            if let Err(e) = arrakis.reload() {
+                error!("unable to reload the schema, {}", e);
+            }
+        }
+    });
+}
+
+// report a startup error and exit with a non zero status
+fn exit_with_error<E: ::std::fmt::Display>(e: &E) -> ! {
+    let _ = writeln!(io::stderr(), "error: {}", e);
+    process::exit(1);
+}
+
 fn main() {
+    // the signal mask is inherited by the threads, so it must be set before
+    // any of them is spawned, otherwise they could receive SIGHUP and exit.
+    let sighup = chan_signal::notify(&[Signal::HUP]);
     let _ = pretty_env_logger::init();
     let args = parse_cmdline();
 
-    let config = Config::builder()
-        .timeout(1)
-        .excluded(split_list(args.exclude.as_ref()))
-        .included(split_list(args.include.as_ref()))
-        .build();
+    let config = match make_config(&args) {
+        Ok(config) => config,
+        Err(e) => exit_with_error(&e),
+    };
+    let cors_config = config.cors().clone();
 
-    let arrakis = match Arrakis::with_config(&*args.pq_addr, config) {
+    let arrakis = match Arrakis::from_config(config) {
         Ok(auto) => auto,
-        Err(e) => { println!("error: {}", e); return; },
+        Err(e) => exit_with_error(&e),
     };
 
     info!("this instance will manage the following tables: {}",
           arrakis.get_tables().iter().map(|(t, _)| &**t).collect::<Vec<&str>>().join(", "));
+    reload_on_sighup(arrakis.clone(), sighup);
+    if let Some(channel) = arrakis.get_config().reload_channel() {
+        info!("reloading the schema on notifications of channel {}", channel);
+        arrakis.listen_reload(channel);
+    }
+
     let arrakis_service = ArrakisService::with_conf(arrakis, Conf{with_docs: args.with_docs});
-    let cors = move || Ok(Cors::new(arrakis_service.new_service().unwrap()));
+    let cors = move || {
+        Ok(Cors::new(arrakis_service.new_service().unwrap(), cors_config.clone()))
+    };
 
     info!("Arrakis listening on http://{}", args.addr);
     if !args.disable_metrics {
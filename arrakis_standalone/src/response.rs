@@ -5,70 +5,98 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use arrakis::error::Error as ArrakisError;
+use arrakis::error::{self, Error as ArrakisError};
+use arrakis::format::Format;
 use hyper::header::{ContentLength, ContentType};
 use hyper::mime::{Mime, TopLevel, SubLevel};
 use hyper::status::StatusCode;
 use hyper::server::Response as HyperResponse;
 use hyper::Body;
 use serde_json::Value as JsonValue;
-use serde_json::Map as JsonMap;
 
-pub fn arrakis_error_to_status_code(ar_err: ArrakisError) -> StatusCode {
-    match ar_err {
-        ArrakisError::InternalError(..) => StatusCode::InternalServerError,
-        ArrakisError::NotFound(..) => StatusCode::NotFound,
-        _ => StatusCode::BadRequest,
-    }
-}
-
-pub fn make_success_response(value: JsonValue) -> Vec<u8> {
-    let mut map = JsonMap::new();
-    map.insert(String::from("data"), value);
-    let value = JsonValue::Object(map);
-    ::serde_json::ser::to_vec(&value).unwrap()
+pub fn make_success_response(value: JsonValue, format: Format) -> Vec<u8> {
+    format.encode_value(value)
 }
 
-pub fn make_error_response(estr: &str) -> Vec<u8> {
-    let mut map = JsonMap::new();
-    map.insert("error".into(), JsonValue::String(estr.into()));
-    let value = JsonValue::Object(map);
-    ::serde_json::ser::to_vec(&value).unwrap()
+pub fn make_error_response(problem: &JsonValue) -> Vec<u8> {
+    ::serde_json::ser::to_vec(problem).unwrap()
 }
 
-pub fn make_arrakis_response(ar_res: Result<Option<JsonValue>, ArrakisError>) -> (Vec<u8>, StatusCode) {
+pub fn make_arrakis_response(ar_res: Result<Option<JsonValue>, ArrakisError>, format: Format)
+                             -> (Vec<u8>, StatusCode, Mime) {
     match ar_res {
         Ok(jv) => match jv {
-            Some(v) => (make_success_response(v), StatusCode::Ok),
-            None => (vec![], StatusCode::NoContent),
+            Some(v) => (make_success_response(v, format), StatusCode::Ok, format_mime(format)),
+            None => (vec![], StatusCode::NoContent, format_mime(format)),
         },
-        Err(e) => (make_error_response(&*format!("{}", e)), arrakis_error_to_status_code(e))
+        Err(e) => (make_error_response(&e.to_problem()),
+                   StatusCode::from_u16(e.status()),
+                   problem_mime()),
     }
 }
 
 pub fn write_arrakis_response(ar_res: Result<Option<JsonValue>, ArrakisError>)
                               -> HyperResponse {
-    let (body, code) = make_arrakis_response(ar_res);
+    write_encoded_response(ar_res, Format::Json)
+}
+
+/// Same as `write_arrakis_response`, the value being encoded in the given
+/// format, see `Format::encode_value`.
+pub fn write_encoded_response(ar_res: Result<Option<JsonValue>, ArrakisError>, format: Format)
+                              -> HyperResponse {
+    let headers = match ar_res {
+        Err(ref e) => e.headers(),
+        _ => vec![],
+    };
+    let (body, code, mime) = make_arrakis_response(ar_res, format);
     let len = body.len();
-    write_response(body, len as u64, code)
+    let mut res = write_response(body, len as u64, code, mime);
+    for (name, value) in headers {
+        res.headers_mut().set_raw(name, vec![value.into_bytes()]);
+    }
+    res
 }
 
-pub fn write_error_response(estr: &str, code: StatusCode)
+pub fn write_formatted_response(ar_res: Result<Vec<u8>, ArrakisError>, format: Format,
+                                model: &str) -> HyperResponse {
+    match ar_res {
+        Ok(body) => {
+            let len = body.len();
+            let mut res = write_response(body, len as u64, StatusCode::Ok, format_mime(format));
+            set_content_disposition(&mut res, format, model);
+            res
+        },
+        Err(e) => write_arrakis_response(Err(e)),
+    }
+}
+
+pub fn set_content_disposition(res: &mut HyperResponse, format: Format, model: &str) {
+    if let Some(value) = format.content_disposition(model) {
+        res.headers_mut().set_raw("Content-Disposition", vec![value.into_bytes()]);
+    }
+}
+
+pub fn write_error_response(code: &str, estr: &str, status: StatusCode)
                             -> HyperResponse {
-    let body = make_error_response(estr);
+    let title = status.canonical_reason().unwrap_or("");
+    let body = make_error_response(&error::problem(status.to_u16(), code, title, estr));
     let len = body.len();
-    write_response(body, len as u64, code)
+    write_response(body, len as u64, status, problem_mime())
+}
+
+pub fn format_mime(format: Format) -> Mime {
+    format.content_type().parse().unwrap()
+}
+
+fn problem_mime() -> Mime {
+    Mime(TopLevel::Application, SubLevel::Ext("problem+json".into()), vec![])
 }
 
-fn write_response<T: Into<Body>>(body: T, len: u64, code: StatusCode)
+fn write_response<T: Into<Body>>(body: T, len: u64, code: StatusCode, mime: Mime)
                   -> HyperResponse {
     HyperResponse::new()
         .with_header(ContentLength(len))
-        .with_header(
-            ContentType(
-                Mime(TopLevel::Application, SubLevel::Json, vec![])
-            )
-        )
+        .with_header(ContentType(mime))
         .with_status(code)
         .with_body(body.into())
 
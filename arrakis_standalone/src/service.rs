@@ -6,16 +6,23 @@
 // except according to those terms.
 
 use arrakis::Arrakis;
+use arrakis::context::Context;
+use arrakis::error::Error as ArrakisError;
+use arrakis::format::Format;
+use arrakis::graphql::{self, Request as GraphqlRequest};
 use arrakis::queries::Queries;
+use arrakis::query;
 use arrakis::method::Method as ArrakisMethod;
 use futures::{Stream, Future};
-use futures::future::BoxFuture;
-use hyper::header::{ContentType, ContentLength};
+use futures::future::{self, BoxFuture};
+use hyper::header::{Authorization, Bearer, ContentType, ContentLength};
 use hyper::mime::{Mime, TopLevel, SubLevel};
 use hyper::{self, Method};
 use hyper::status::StatusCode;
 use hyper::server::{NewService, Service, Request, Response};
-use response::{write_arrakis_response, write_error_response};
+use response::{write_arrakis_response, write_encoded_response, write_error_response,
+               write_formatted_response};
+use stream;
 
 #[derive(Debug, Default, Clone)]
 pub struct Conf {
@@ -54,25 +61,132 @@ impl Service for ArrakisService {
         // read body
         let arrakis = self.ar.clone();
         let conf = self.conf.clone();
-        let (method, uri, _, _, _body) = req.deconstruct();
+        let client_ip = req.remote_addr().ip().to_string();
+        let (method, uri, _, headers, _body) = req.deconstruct();
+        let token = headers.get::<Authorization<Bearer>>().map(|a| a.0.token.clone());
+        let headers = headers.iter()
+            .map(|h| (h.name().to_string(), h.value_string()))
+            .collect::<Vec<(String, String)>>();
         _body.fold(vec![], move |mut acc, chunk| {
             acc.extend_from_slice(chunk.as_ref());
             Ok::<_, hyper::Error>(acc)
-        }).and_then(move |v| {
-            let body: String = unsafe { String::from_utf8_unchecked(v.clone()) };
-            let queries = parse_queries(uri.query().unwrap_or(""));
+        }).and_then(move |v| -> BoxFuture<Response, hyper::Error> {
+            // binary bodies (msgpack, cbor) are read from v
+            let body: String = String::from_utf8_lossy(&*v).into_owned();
+            let decoded = parse_queries(uri.query().unwrap_or(""));
+            let queries = decoded.iter().map(|&(ref k, ref v)| (&**k, &**v)).collect::<Queries>();
             let path = uri.path().trim_matches('/').split("/").collect::<Vec<&str>>();
-            match *path {
-                ["builtins", builtin] => Ok(serve_builtins(builtin, arrakis, conf)),
+            let res = match *path {
+                ["builtins", "schema", model] =>
+                    execute_schema_builtins(model, &queries, arrakis),
+                ["builtins", "reload"] =>
+                    return execute_reload_builtins(&method, token.as_ref(), arrakis),
+                ["builtins", builtin] =>
+                    serve_builtins(builtin, arrakis, conf),
+                ["graphql"] => {
+                    let request = match method {
+                        Method::Get => GraphqlRequest::parse(uri.query().unwrap_or("")),
+                        Method::Post => match find_header(&*headers, "content-type") {
+                            Some(ct) if ct.starts_with(graphql::GRAPHQL_CONTENT_TYPE) =>
+                                Ok(GraphqlRequest::new(&*body)),
+                            _ => GraphqlRequest::from_json(&*v),
+                        },
+                        _ => Err(ArrakisError::MethodNotAllowed(
+                            format!("{}", &method), vec![ArrakisMethod::Get, ArrakisMethod::Post])),
+                    };
+                    let request = match request {
+                        Ok(r) => r,
+                        Err(e) => return future::ok(write_arrakis_response(Err(e))).boxed(),
+                    };
+                    let ctx = match request_context(&arrakis, token.as_ref(), &*headers,
+                                                    &*client_ip) {
+                        Ok(ctx) => ctx,
+                        Err(e) => return future::ok(write_arrakis_response(Err(e))).boxed(),
+                    };
+                    return arrakis.graphql_async(ctx, request)
+                        .then(|res| Ok::<_, hyper::Error>(match res {
+                            Ok(value) => write_graphql_response(&value),
+                            Err(e) => write_arrakis_response(Err(e)),
+                        }))
+                        .boxed();
+                },
                 ["api", model] => match arrakis_of_hyper_method(&method) {
-                    Some(m) => Ok(write_arrakis_response(arrakis.any(&m, model, &queries, body))),
+                    Some(m) => {
+                        let ctx = match request_context(&arrakis, token.as_ref(), &*headers,
+                                                        &*client_ip) {
+                            Ok(ctx) => ctx,
+                            Err(e) => return future::ok(write_arrakis_response(Err(e))).boxed(),
+                        };
+                        let content_type = find_header(&*headers, "content-type")
+                            .and_then(Format::of_content_type);
+                        let accept = find_header(&*headers, "accept");
+                        let format = if m == ArrakisMethod::Get {
+                            arrakis.negotiate_format(accept, &queries)
+                        } else {
+                            arrakis.negotiate_value_format(accept, &queries)
+                        };
+                        let format = match format {
+                            Ok(f) => f,
+                            Err(e) => return future::ok(write_arrakis_response(Err(e))).boxed(),
+                        };
+                        if m == ArrakisMethod::Post && content_type == Some(Format::Csv) {
+                            let options = match arrakis.parse_import_options(&queries) {
+                                Ok(o) => o,
+                                Err(e) => return future::ok(write_arrakis_response(Err(e))).boxed(),
+                            };
+                            return arrakis.post_csv_async(ctx, model.to_string(), options, body)
+                                .then(move |res| {
+                                    Ok::<_, hyper::Error>(write_encoded_response(res, format))
+                                })
+                                .boxed();
+                        }
+                        if m == ArrakisMethod::Post && content_type == Some(Format::Ndjson) {
+                            return arrakis.post_lines_async(ctx, model.to_string(), body)
+                                .then(move |res| {
+                                    Ok::<_, hyper::Error>(write_encoded_response(res, format))
+                                })
+                                .boxed();
+                        }
+                        if m == ArrakisMethod::Get && arrakis.get_config().stream() {
+                            return stream::stream_get(&arrakis, ctx, model, &queries, format);
+                        }
+                        if m == ArrakisMethod::Get && format != Format::Json {
+                            let query = match arrakis.parse_query(&queries) {
+                                Ok(q) => q,
+                                Err(e) => return future::ok(write_arrakis_response(Err(e))).boxed(),
+                            };
+                            let model = model.to_string();
+                            return arrakis.get_formatted_async(ctx, model.clone(), query, format)
+                                .then(move |res| {
+                                    let res = write_formatted_response(res, format, &*model);
+                                    Ok::<_, hyper::Error>(res)
+                                })
+                                .boxed();
+                        }
+                        // the database is queried on the arrakis workers,
+                        // not on the event loop
+                        let body_format = content_type.unwrap_or(Format::Json);
+                        return arrakis.any_encoded_async(ctx, &m, model, &queries, body_format,
+                                                         &*v)
+                            .then(move |res| {
+                                Ok::<_, hyper::Error>(write_encoded_response(res, format))
+                            })
+                            .boxed();
+                    },
                     None => {
-                        let estr = format!("method not allowed {}", &method);
-                        Ok(write_error_response(&*estr, StatusCode::MethodNotAllowed))
+                        // the allowed methods depend on the role
+                        let err = match request_context(&arrakis, token.as_ref(), &*headers,
+                                                        &*client_ip) {
+                            Ok(ctx) => arrakis.unsupported_method(&ctx, &*format!("{}", &method),
+                                                                  model),
+                            Err(e) => e,
+                        };
+                        write_arrakis_response(Err(err))
                     }
                 },
-                _ => Ok(write_error_response("not found", StatusCode::NotFound))
-            }
+                _ => write_error_response("not_found", "not found", StatusCode::NotFound)
+            };
+            future::ok(res).boxed()
         }).boxed()
     }
 }
@@ -99,31 +213,100 @@ fn execute_docs_builtins(arrakis: Arrakis) -> Response {
 
 }
 
+fn execute_openapi_builtins(arrakis: Arrakis) -> Response {
+    let response_body = ::serde_json::ser::to_vec(&arrakis.make_openapi()).unwrap();
+    let len = response_body.len() as u64;
+    Response::new()
+        .with_header(ContentLength(len))
+        .with_header(ContentType(Mime(TopLevel::Application, SubLevel::Json, vec![])))
+        .with_status(StatusCode::Ok)
+        .with_body(response_body)
+}
+
+fn write_graphql_response(value: &::serde_json::Value) -> Response {
+    let response_body = ::serde_json::ser::to_vec(value).unwrap();
+    let len = response_body.len() as u64;
+    Response::new()
+        .with_header(ContentLength(len))
+        .with_header(ContentType(Mime(TopLevel::Application, SubLevel::Json, vec![])))
+        .with_status(StatusCode::Ok)
+        .with_body(response_body)
+}
+
+fn execute_schema_builtins(model: &str, queries: &Queries, arrakis: Arrakis) -> Response {
+    let schema = match arrakis.make_json_schema(model, queries) {
+        Ok(s) => s,
+        Err(e) => return write_arrakis_response(Err(e)),
+    };
+    let response_body = ::serde_json::ser::to_vec(&schema).unwrap();
+    let len = response_body.len() as u64;
+    Response::new()
+        .with_header(ContentLength(len))
+        .with_header(ContentType(Mime(TopLevel::Application,
+                                      SubLevel::Ext("schema+json".into()), vec![])))
+        .with_status(StatusCode::Ok)
+        .with_body(response_body)
+}
+
+// reload the schema, only if a reload token is configured and sent as a
+// bearer token. The introspection runs on the worker threads.
+fn execute_reload_builtins(method: &Method, token: Option<&String>, arrakis: Arrakis)
+                           -> BoxFuture<Response, hyper::Error> {
+    if arrakis.get_config().reload_token().is_none() {
+        let res = write_error_response("not_found", "not found", StatusCode::NotFound);
+        return future::ok(res).boxed();
+    }
+    if *method != Method::Post {
+        let err = ArrakisError::MethodNotAllowed(format!("{}", method), vec![ArrakisMethod::Post]);
+        return future::ok(write_arrakis_response(Err(err))).boxed();
+    }
+    if !arrakis.reload_token_matches(token.map(|t| &**t)) {
+        let err = ArrakisError::Unauthorized("invalid reload token".into());
+        return future::ok(write_arrakis_response(Err(err))).boxed();
+    }
+    arrakis.reload_async()
+        .then(|res| Ok::<_, hyper::Error>(match res {
+            Ok(()) => write_arrakis_response(Ok(None)),
+            Err(e) => write_arrakis_response(Err(ArrakisError::InternalError(e))),
+        }))
+        .boxed()
+}
+
 fn serve_builtins(builtin: &str, arrakis: Arrakis, conf: Conf) -> Response {
     match builtin {
         "docs" => execute_docs_builtins(arrakis),
+        "openapi.json" => execute_openapi_builtins(arrakis),
         _ => {
             let estr = format!("unknown builtin {}", builtin);
-            write_error_response(&*estr, StatusCode::BadRequest)
+            write_error_response("unknown_builtin", &*estr, StatusCode::BadRequest)
         }
     }
 }
 
-fn parse_queries(queries: &str) -> Queries {
-    match queries.len() {
-        0 => Default::default(),
-        _ => {
-            queries.split('&').collect::<Vec<&str>>().iter().map(|&s| {
-                match s.find('=') {
-                    Some(pos) => {
-                        let (b, e) = s.split_at(pos+1);
-                        (&(b[..b.len()-1]), e)
-                    },
-                    None => (s, "")
-                }
-            }).collect::<Queries>()
-        }
-    }
+// the context of a request authenticated by its bearer token
+fn request_context(arrakis: &Arrakis, token: Option<&String>, headers: &[(String, String)],
+                   client_ip: &str) -> Result<Context, ArrakisError> {
+    let mut ctx = arrakis.authenticate(token.map(|t| &**t))?;
+    arrakis.set_request_metadata(&mut ctx, headers);
+    ctx.client_ip = Some(client_ip.to_string());
+    Ok(ctx)
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter()
+        .find(|&&(ref n, _)| n.to_lowercase() == name)
+        .map(|&(_, ref v)| &**v)
+}
+
+// the decoded keys and values of a raw query string
+fn parse_queries(queries: &str) -> Vec<(String, String)> {
+    queries.split('&')
+        .filter(|s| !s.is_empty())
+        .map(|s| match s.find('=') {
+            Some(pos) => (query::percent_decode(&s[..pos]), query::percent_decode(&s[pos+1..])),
+            None => (query::percent_decode(s), String::new()),
+        })
+        .collect()
 }
 
 fn arrakis_of_hyper_method(m: &Method) -> Option<ArrakisMethod> {
@@ -136,3 +319,32 @@ fn arrakis_of_hyper_method(m: &Method) -> Option<ArrakisMethod> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::parse_queries;
+    use arrakis::filters::Filter;
+    use arrakis::queries::Queries;
+    use arrakis::query::Query;
+
+    #[test]
+    fn query_round_trip() {
+        let q = Query::builder()
+            .select(vec!["id", "full name"])
+            .filter(Filter::Equal("full name".into(), "a&b=c %d".into()))
+            .limit(10)
+            .build();
+        let s = q.to_query_string();
+        let decoded = parse_queries(&*s);
+        assert_eq!(decoded[1], ("full name".to_string(), "eq.a&b=c %d".to_string()));
+        let queries = decoded.iter().map(|&(ref k, ref v)| (&**k, &**v)).collect::<Queries>();
+        let parsed = Query::from_queries(&queries).unwrap();
+        assert_eq!(parsed.to_query_string(), s);
+    }
+
+    #[test]
+    fn empty_queries() {
+        assert!(parse_queries("").is_empty());
+        assert_eq!(parse_queries("limit"), vec![("limit".to_string(), String::new())]);
+    }
+}
@@ -0,0 +1,160 @@
+// Copyright 2016 Jeremy Letang.
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use arrakis::Arrakis;
+use arrakis::context::Context;
+use arrakis::cvt::Cell;
+use arrakis::error::Error as ArrakisError;
+use arrakis::format::Format;
+use arrakis::queries::Queries;
+use arrakis::schema::Column;
+use arrakis::stream::RowWriter;
+use futures::{Future, Sink};
+use futures::future::{self, BoxFuture};
+use futures::sync::{mpsc, oneshot};
+use hyper::{self, Body, Chunk};
+use hyper::header::ContentType;
+use hyper::status::StatusCode;
+use hyper::server::Response;
+use response::{format_mime, set_content_disposition, write_arrakis_response,
+               write_error_response};
+use std::io::{self, Write};
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+// size of the chunks sent to the client
+const CHUNK_SIZE: usize = 16 * 1024;
+
+// the response, sent to hyper once the first chunk of the body is ready
+type PendingResponse = Arc<Mutex<Option<(oneshot::Sender<Response>, Response)>>>;
+
+/// Stream the response of a GET request using chunked transfer encoding.
+pub fn stream_get(arrakis: &Arrakis, ctx: Context, model: &str, queries: &Queries,
+                  format: Format) -> BoxFuture<Response, hyper::Error> {
+    let query = match arrakis.parse_query(queries) {
+        Ok(q) => q,
+        Err(e) => return future::ok(write_arrakis_response(Err(e))).boxed(),
+    };
+    let (res_tx, res_rx) = oneshot::channel();
+    let (body_tx, body) = Body::pair();
+    let mut response = Response::new()
+        .with_header(ContentType(format_mime(format)))
+        .with_status(StatusCode::Ok)
+        .with_body(body);
+    set_content_disposition(&mut response, format, model);
+    let pending = Arc::new(Mutex::new(Some((res_tx, response))));
+    let writer = StreamWriter {
+        inner: format.send_row_writer(ChunkedBody::new(pending.clone(), body_tx)),
+        pending: pending,
+    };
+    let work = arrakis.get_stream_async(ctx, model.to_string(), query, writer);
+    res_rx.then(move |res| {
+        // the response is only sent by the worker, the work started and
+        // is not cancelled when dropped.
+        drop(work);
+        match res {
+            Ok(res) => Ok(res),
+            Err(_) => Ok(write_error_response("internal_error", "response aborted",
+                                              StatusCode::InternalServerError)),
+        }
+    }).boxed()
+}
+
+// send the pending response if not already done
+fn send_pending(pending: &PendingResponse, response: Option<Response>) {
+    if let Some((tx, default)) = pending.lock().unwrap().take() {
+        tx.complete(response.unwrap_or(default));
+    }
+}
+
+struct StreamWriter {
+    inner: Box<RowWriter + Send>,
+    pending: PendingResponse,
+}
+
+impl RowWriter for StreamWriter {
+    fn begin(&mut self, columns: &[&Column]) -> io::Result<()> {
+        self.inner.begin(columns)
+    }
+
+    fn row(&mut self, values: Vec<Cell>) -> io::Result<()> {
+        self.inner.row(values)
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        self.inner.end()?;
+        // empty bodies are not flushed
+        send_pending(&self.pending, None);
+        Ok(())
+    }
+
+    fn fail(&mut self, e: ArrakisError) {
+        let started = self.pending.lock().unwrap().is_none();
+        if started {
+            // the body is truncated, the client sees an invalid response
+            error!("streamed request failed after the response started, {}", e);
+        } else {
+            send_pending(&self.pending, Some(write_arrakis_response(Err(e))));
+        }
+    }
+}
+
+// buffer the output and send it to the client by chunks, blocking while
+// the client did not read the previous chunk.
+struct ChunkedBody {
+    pending: PendingResponse,
+    tx: Option<mpsc::Sender<Result<Chunk, hyper::Error>>>,
+    buf: Vec<u8>,
+}
+
+impl ChunkedBody {
+    fn new(pending: PendingResponse, tx: mpsc::Sender<Result<Chunk, hyper::Error>>)
+           -> ChunkedBody {
+        ChunkedBody {
+            pending: pending,
+            tx: Some(tx),
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    fn send_chunk(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        send_pending(&self.pending, None);
+        let chunk = mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+        let tx = match self.tx.take() {
+            Some(tx) => tx,
+            None => return Err(disconnected()),
+        };
+        match tx.send(Ok(Chunk::from(chunk))).wait() {
+            Ok(tx) => {
+                self.tx = Some(tx);
+                Ok(())
+            },
+            Err(_) => Err(disconnected()),
+        }
+    }
+}
+
+impl Write for ChunkedBody {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.send_chunk()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_chunk()
+    }
+}
+
+fn disconnected() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "client disconnected")
+}